@@ -0,0 +1,76 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use almetica::protocol::packet::{CCheckVersion, SGetUserList, SGetUserListCharacter};
+use almetica::protocol::serde::{from_vec, to_vec, DeserializerPool};
+use serde::Deserialize;
+
+const DATA: [u8; 28] = [
+    0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0x14, 0x0, 0x0, 0x0, 0x0, 0x0, 0x8e, 0x96, 0x5, 0x0, 0x14, 0x0,
+    0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xdf, 0x93, 0x5, 0x0,
+];
+
+// Mirrors the read loop of a session: The packet is read into a buffer and then decoded.
+fn decode_fresh() -> CCheckVersion {
+    let mut data_buf = vec![0u8; DATA.len()];
+    data_buf.copy_from_slice(&DATA);
+    from_vec(data_buf).unwrap()
+}
+
+fn decode_pooled(pool: &mut DeserializerPool) -> CCheckVersion {
+    let mut deserializer = pool.acquire(DATA.len());
+    deserializer.buffer_mut().copy_from_slice(&DATA);
+    let packet = CCheckVersion::deserialize(&mut deserializer).unwrap();
+    pool.release(deserializer);
+    packet
+}
+
+// Number of characters in the user list. Keeps the packet below the maximal body length.
+const USER_LIST_LENGTH: usize = 64;
+
+// A user list is the biggest linked list a client receives.
+fn user_list_data() -> Vec<u8> {
+    let characters = (0..USER_LIST_LENGTH)
+        .map(|i| SGetUserListCharacter {
+            name: format!("Character{:02}", i),
+            level: 65,
+            lobby_slot: i as i32 + 1,
+            ..Default::default()
+        })
+        .collect();
+    to_vec(SGetUserList {
+        characters,
+        max_characters: USER_LIST_LENGTH as i32,
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+fn decode_user_list(data: &[u8]) -> SGetUserList {
+    from_vec(data.to_vec()).unwrap()
+}
+
+fn deserialize_benchmark(c: &mut Criterion) {
+    let mut pool = DeserializerPool::default();
+
+    let mut group = c.benchmark_group("deserialize_benchmark");
+    group.bench_function("fresh", |b| b.iter(decode_fresh));
+    group.bench_function("pooled", |b| b.iter(|| decode_pooled(&mut pool)));
+    group.finish();
+}
+
+fn user_list_benchmark(c: &mut Criterion) {
+    let data = user_list_data();
+    assert_eq!(decode_user_list(&data).characters.len(), USER_LIST_LENGTH);
+
+    let mut group = c.benchmark_group("user_list_benchmark");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("decode", |b| b.iter(|| decode_user_list(&data)));
+    group.finish();
+}
+
+criterion_group!(
+    deserialize_bench,
+    deserialize_benchmark,
+    user_list_benchmark
+);
+criterion_main!(deserialize_bench);
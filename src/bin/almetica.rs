@@ -8,13 +8,14 @@ use almetica::model::entity::Account;
 use almetica::model::migrations;
 use almetica::model::repository::account;
 use almetica::model::PasswordHashAlgorithm;
-use almetica::networkserver;
+use almetica::networkserver::{self, NoGeoLookup};
 use almetica::protocol::opcode::Opcode;
+use almetica::protocol::validation;
 use almetica::webserver;
 use almetica::Result;
 use anyhow::{bail, Context};
 use async_macros::join;
-use async_std::sync::Sender;
+use async_std::sync::{Receiver, Sender};
 use async_std::task::{self, JoinHandle};
 use chrono::Utc;
 use clap::{crate_version, App, Arg, ArgMatches};
@@ -75,6 +76,19 @@ async fn main() {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            App::new("validate-captures")
+                .about("Decodes all captured packets (*.bin) of a folder and reports failures")
+                .arg(
+                    Arg::new("path")
+                        .short('p')
+                        .long("path")
+                        .value_name("FOLDER")
+                        .about("folder with the capture files")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
     init_logging(&matches);
@@ -120,9 +134,44 @@ async fn run_command(matches: &ArgMatches) -> Result<()> {
 
     if let Some(matches) = matches.subcommand_matches("run") {
         info!("Starting almetica version {}", crate_version!());
+        if config.server.insecure_skip_login_checks {
+            warn!("!!! THE VERSION AND LOGIN TICKET CHECKS ARE DISABLED. EVERY CLIENT CAN LOG IN AS ANY ACCOUNT. NEVER USE THIS IN PRODUCTION !!!");
+        }
         start_server(matches, &config).await?;
     } else if let Some(matches) = matches.subcommand_matches("create-account") {
         create_account(matches, &config).await?;
+    } else if let Some(matches) = matches.subcommand_matches("validate-captures") {
+        validate_captures(matches, &config)?;
+    }
+    Ok(())
+}
+
+/// Only runs the packet decoder against the captured packets. Doesn't start the ECS or the servers.
+fn validate_captures(matches: &ArgMatches, config: &Configuration) -> Result<()> {
+    let (opcode_mapping, _reverse_opcode_mapping) = load_opcode_mapping(&config.data.path)
+        .context(format!(
+            "Can't read opcode mapping file {:?}",
+            &config.data.path
+        ))?;
+
+    let path = PathBuf::from(matches.value_of("path").unwrap_or_default());
+    let report = validation::validate_corpus(&path, &opcode_mapping)?;
+
+    for failure in report.failures.iter() {
+        error!(
+            "Can't decode {:?} in {:?} at offset {}: {}",
+            failure.opcode, failure.file, failure.offset, failure.error
+        );
+    }
+    info!(
+        "Validated captures: {} successful, {} failed, {} skipped",
+        report.successes,
+        report.failures.len(),
+        report.skipped
+    );
+
+    if !report.is_successful() {
+        bail!("{} packets couldn't be decoded", report.failures.len());
     }
     Ok(())
 }
@@ -160,7 +209,8 @@ async fn start_server(_matches: &ArgMatches, config: &Configuration) -> Result<(
     let pool = sqlx_pool(&config).await?;
 
     info!("Starting the ECS");
-    let (global_world_handle, global_tx_channel) = start_global_world(config.clone(), pool.clone());
+    let (global_world_handle, global_tx_channel, accept_channel) =
+        start_global_world(config.clone(), pool.clone());
 
     info!("Starting the web server");
     let web_handle = start_web_server(pool, config.clone());
@@ -168,6 +218,7 @@ async fn start_server(_matches: &ArgMatches, config: &Configuration) -> Result<(
     info!("Starting the network server");
     let network_handle = start_network_server(
         global_tx_channel,
+        accept_channel,
         opcode_mapping,
         reverse_opcode_mapping,
         config.clone(),
@@ -183,19 +234,21 @@ async fn start_server(_matches: &ArgMatches, config: &Configuration) -> Result<(
     Ok(())
 }
 
-/// Starts the global world on a new thread and returns a channel into the global world.
+/// Starts the global world on a new thread and returns a channel into the global world and the
+/// channel that is closed once the global world shuts down.
 fn start_global_world(
     config: Configuration,
     pool: PgPool,
-) -> (JoinHandle<Result<()>>, Sender<EcsMessage>) {
+) -> (JoinHandle<Result<()>>, Sender<EcsMessage>, Receiver<()>) {
     let mut global_world = GlobalWorld::new(&config, &pool);
     let channel = global_world.channel.clone();
+    let accept_channel = global_world.accept_channel.clone();
     let join_handle = task::spawn_blocking(move || {
         global_world.run();
         Ok(())
     });
 
-    (join_handle, channel)
+    (join_handle, channel, accept_channel)
 }
 
 /// Starts the web server handling all HTTP requests.
@@ -210,11 +263,24 @@ fn start_web_server(pool: PgPool, config: Configuration) -> JoinHandle<Result<()
 /// Starts the network server that handles all TCP game client connections.
 fn start_network_server(
     global_channel: Sender<EcsMessage>,
+    accept_channel: Receiver<()>,
     map: Vec<Opcode>,
     reverse_map: HashMap<Opcode, u16>,
     config: Configuration,
 ) -> JoinHandle<Result<()>> {
-    task::spawn(async { networkserver::run(global_channel, map, reverse_map, config).await })
+    task::spawn(async {
+        // No GeoIP database is shipped, so the origins of the connections can't be checked.
+        let geo_lookup = Box::new(NoGeoLookup);
+        networkserver::run(
+            global_channel,
+            accept_channel,
+            map,
+            reverse_map,
+            config,
+            geo_lookup,
+        )
+        .await
+    })
 }
 
 async fn sqlx_pool(config: &Configuration) -> Result<PgPool> {
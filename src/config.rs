@@ -1,12 +1,32 @@
 /// Module for the configuration handling.
+use crate::ecs::message::Message;
+use crate::ecs::resource::{EvictionPolicy, LoginArbiterFields, PostLoginPacket};
+use crate::networkserver::IpRange;
+use crate::protocol::framing;
+use crate::protocol::opcode::Opcode;
+use crate::protocol::{ChannelFullPolicy, ConnectionPhase, OutOfPhasePolicy, PacketLogLevel};
 use crate::*;
+use anyhow::{bail, ensure, Context};
 use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
 use std::fs::File;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
+use tracing::warn;
+
+/// Version of the configuration format. Files without a version are version 0 and are migrated
+/// to the current version while they are read.
+pub const CONFIGURATION_VERSION: u64 = 1;
+
+/// Sections of the configuration file.
+const SECTIONS: [&str; 4] = ["server", "database", "data", "game"];
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Configuration {
+    /// Version of the configuration format. Always the current version after reading.
+    #[serde(default)]
+    pub version: u64,
     pub server: ServerConfiguration,
     pub database: DatabaseConfiguration,
     pub data: DataConfiguration,
@@ -20,6 +40,105 @@ pub struct ServerConfiguration {
     pub web_port: u16,
     #[serde(alias = "game-port")]
     pub game_port: u16,
+    /// Maximal number of pending connections of the game port.
+    #[serde(alias = "listen-backlog", default = "default_listen_backlog")]
+    pub listen_backlog: i32,
+    /// Sets SO_REUSEADDR on the game port, so a restarted server can bind it right away.
+    #[serde(alias = "reuse-address", default = "default_reuse_address")]
+    pub reuse_address: bool,
+    /// Sets SO_REUSEPORT on the game port. Only supported on unix.
+    #[serde(alias = "reuse-port", default)]
+    pub reuse_port: bool,
+    /// Records the latency of the request handlers per opcode.
+    #[serde(alias = "handler-latency-histograms", default)]
+    pub handler_latency_histograms: bool,
+    /// Seconds a login ticket is accepted after it was created.
+    #[serde(alias = "ticket-ttl-secs", default = "default_ticket_ttl_secs")]
+    pub ticket_ttl_secs: u64,
+    /// Seconds a new connection has to check it's version before it's dropped.
+    #[serde(
+        alias = "version-check-grace-secs",
+        default = "default_version_check_grace_secs"
+    )]
+    pub version_check_grace_secs: u64,
+    /// Opcodes of the packets that are dumped with their decoded content at trace level.
+    #[serde(alias = "trace-packets", default)]
+    pub trace_packets: Vec<Opcode>,
+    /// Log level of sent and received packets by opcode: "trace", "debug", "info", "warn" or
+    /// "error".
+    #[serde(alias = "packet-log-levels", default)]
+    pub packet_log_levels: HashMap<Opcode, PacketLogLevel>,
+    /// Log level of the packets that have no entry in `packet-log-levels`.
+    #[serde(
+        alias = "default-packet-log-level",
+        default = "default_packet_log_level"
+    )]
+    pub default_packet_log_level: PacketLogLevel,
+    /// Number of packets in a row that can't be decoded before a connection is dropped.
+    #[serde(alias = "max-decode-failures", default = "default_max_decode_failures")]
+    pub max_decode_failures: usize,
+    /// Logs the data of packets that can't be decoded. Leaks client data, so keep it off in production.
+    #[serde(alias = "log-bad-frames", default)]
+    pub log_bad_frames: bool,
+    /// Number of queued responses of a connection above which a slow client is reported.
+    #[serde(
+        alias = "send-queue-warning-threshold",
+        default = "default_send_queue_warning_threshold"
+    )]
+    pub send_queue_warning_threshold: usize,
+    /// Minimal body length of packets by opcode. Shorter packets are padded with zeros.
+    #[serde(alias = "min-body-lengths", default)]
+    pub min_body_lengths: HashMap<Opcode, usize>,
+    /// Opcodes of the packets that are processed before all other packets of a tick.
+    #[serde(alias = "priority-opcodes", default = "default_priority_opcodes")]
+    pub priority_opcodes: Vec<Opcode>,
+    /// Number of responses that can be queued for a connection.
+    #[serde(
+        alias = "response-channel-capacity",
+        default = "default_response_channel_capacity"
+    )]
+    pub response_channel_capacity: usize,
+    /// What to do when the response queue of a connection is full: "drop-newest", "drop-oldest"
+    /// or "disconnect".
+    #[serde(alias = "channel-full-policy", default = "default_channel_full_policy")]
+    pub channel_full_policy: ChannelFullPolicy,
+    /// Accepts every client version and login ticket. Only for local development with a client
+    /// that has no auth backend. Refused by release builds.
+    #[serde(alias = "insecure-skip-login-checks", default)]
+    pub insecure_skip_login_checks: bool,
+    /// Client versions that pass the version check as pairs of the values with index 0 and 1.
+    /// Every version is allowed if the list is empty.
+    #[serde(alias = "allowed-versions", default)]
+    pub allowed_versions: Vec<(i32, i32)>,
+    /// Unknown fields of the accepted login arbiter ("status", "unk1", "unk2" and "unk3") by the
+    /// schema version (value with index 0 of the version check) they apply from.
+    #[serde(alias = "login-arbiter-fields", default)]
+    pub login_arbiter_fields: HashMap<u32, LoginArbiterFields>,
+    /// Connections from these IP ranges (CIDR notation) are closed right after they are accepted.
+    #[serde(alias = "blocked-ip-ranges", default)]
+    pub blocked_ip_ranges: Vec<IpRange>,
+    /// Only connections from these countries (ISO 3166-1 alpha-2 codes) or ASNs are accepted.
+    /// Every origin is accepted if both lists are empty. The origins are checked with the GeoIP
+    /// lookup that the network server is started with.
+    #[serde(alias = "allowed-countries", default)]
+    pub allowed_countries: Vec<String>,
+    /// Numbers of the autonomous systems whose connections are accepted.
+    #[serde(alias = "allowed-asns", default)]
+    pub allowed_asns: Vec<u32>,
+    /// Number of connections above which unauthenticated connections are evicted. 0 disables
+    /// the eviction.
+    #[serde(alias = "connection-soft-limit", default)]
+    pub connection_soft_limit: usize,
+    /// Which unauthenticated connections are evicted first: "least-recently-active" or "newest".
+    #[serde(alias = "eviction-policy", default = "default_eviction_policy")]
+    pub eviction_policy: EvictionPolicy,
+    /// Phases in which packets are valid by opcode: "connected", "version-checked",
+    /// "authenticated" or "in-game". Overrides the phases the server derives for the opcode.
+    #[serde(alias = "opcode-phases", default)]
+    pub opcode_phases: HashMap<Opcode, Vec<ConnectionPhase>>,
+    /// What to do with packets that are send outside of their phases: "drop" or "disconnect".
+    #[serde(alias = "out-of-phase-policy", default = "default_out_of_phase_policy")]
+    pub out_of_phase_policy: OutOfPhasePolicy,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -39,10 +158,723 @@ pub struct DataConfiguration {
 #[derive(Clone, Debug, Deserialize)]
 pub struct GameConfiguration {
     pub pvp: bool,
+    /// Ticks per second of the global world.
+    #[serde(alias = "global-tick-rate-hz", default = "default_global_tick_rate_hz")]
+    pub global_tick_rate_hz: u64,
+    /// Number of users the global world starts to spawn per tick.
+    #[serde(
+        alias = "spawn-budget-per-tick",
+        default = "default_spawn_budget_per_tick"
+    )]
+    pub spawn_budget_per_tick: usize,
+    /// Maximal number of users in one page of the user list. Pages are also split if they would
+    /// exceed the maximal packet size.
+    #[serde(alias = "user-list-page-size", default = "default_user_list_page_size")]
+    pub user_list_page_size: usize,
+    /// Message of the day that is send to the client after the login.
+    #[serde(default)]
+    pub motd: String,
+    /// Seed of the random number generators of the worlds. Seeded from entropy if not set.
+    #[serde(alias = "rng-seed", default)]
+    pub rng_seed: Option<u64>,
+    /// Packets that are send in order after the login. Lets the sequence follow client patches.
+    #[serde(
+        alias = "post-login-sequence",
+        default = "PostLoginPacket::default_sequence"
+    )]
+    pub post_login_sequence: Vec<PostLoginPacket>,
+}
+
+fn default_spawn_budget_per_tick() -> usize {
+    16
+}
+
+fn default_user_list_page_size() -> usize {
+    5
+}
+
+fn default_listen_backlog() -> i32 {
+    1024
+}
+
+fn default_reuse_address() -> bool {
+    true
+}
+
+pub(crate) fn default_response_channel_capacity() -> usize {
+    128
+}
+
+pub(crate) fn default_channel_full_policy() -> ChannelFullPolicy {
+    ChannelFullPolicy::DropNewest
+}
+
+fn default_eviction_policy() -> EvictionPolicy {
+    EvictionPolicy::LeastRecentlyActive
+}
+
+pub(crate) fn default_out_of_phase_policy() -> OutOfPhasePolicy {
+    OutOfPhasePolicy::Disconnect
+}
+
+pub(crate) fn default_packet_log_level() -> PacketLogLevel {
+    PacketLogLevel::Debug
+}
+
+fn default_priority_opcodes() -> Vec<Opcode> {
+    vec![Opcode::C_PONG]
+}
+
+pub(crate) fn default_send_queue_warning_threshold() -> usize {
+    64
+}
+
+pub(crate) fn default_max_decode_failures() -> usize {
+    10
+}
+
+fn default_version_check_grace_secs() -> u64 {
+    5
+}
+
+fn default_ticket_ttl_secs() -> u64 {
+    300
+}
+
+const MAX_MOTD_LENGTH: usize = 512;
+const MIN_TICK_RATE_HZ: u64 = 1;
+const MAX_TICK_RATE_HZ: u64 = 100;
+
+fn default_global_tick_rate_hz() -> u64 {
+    10
 }
 
 pub fn read_configuration(path: &PathBuf) -> Result<Configuration> {
     let f = File::open(path)?;
-    let configuration = serde_yaml::from_reader(f)?;
+    let value = serde_yaml::from_reader(f)?;
+    let configuration = parse_configuration(value)?;
+    validate_configuration(&configuration)?;
     Ok(configuration)
 }
+
+/// Migrates the configuration to the current version and parses it. Fields that are missing
+/// in older versions get their default value.
+fn parse_configuration(mut value: Value) -> Result<Configuration> {
+    let root = match value.as_mapping_mut() {
+        Some(root) => root,
+        None => bail!(
+            "Configuration must be a mapping of the sections {:?}",
+            SECTIONS
+        ),
+    };
+    let version_key = Value::String("version".to_string());
+    let version = match root.get(&version_key) {
+        Some(version) => version
+            .as_u64()
+            .context("Configuration version must be a positive number")?,
+        None => 0,
+    };
+    ensure!(
+        version <= CONFIGURATION_VERSION,
+        "Configuration version {} is newer than the supported version {}",
+        version,
+        CONFIGURATION_VERSION
+    );
+
+    // The migration at index i migrates version i to version i + 1.
+    let migrations: [fn(&mut Mapping); CONFIGURATION_VERSION as usize] = [migrate_snake_case_keys];
+    for migration in migrations.iter().skip(version as usize) {
+        migration(root);
+    }
+    root.insert(version_key, Value::Number(CONFIGURATION_VERSION.into()));
+
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Version 0 accepted snake_case keys next to the kebab-case keys. They are renamed to
+/// kebab-case.
+fn migrate_snake_case_keys(root: &mut Mapping) {
+    for section in SECTIONS.iter() {
+        let section_value = root
+            .get_mut(&Value::String(section.to_string()))
+            .and_then(Value::as_mapping_mut);
+        let section_value = match section_value {
+            Some(section_value) => section_value,
+            None => continue,
+        };
+
+        let snake_case_keys: Vec<String> = section_value
+            .iter()
+            .filter_map(|(key, _)| key.as_str())
+            .filter(|key| key.contains('_'))
+            .map(|key| key.to_string())
+            .collect();
+        for key in snake_case_keys {
+            let kebab_case_key = key.replace('_', "-");
+            let kebab_case_value = Value::String(kebab_case_key.clone());
+            let value = section_value.remove(&Value::String(key.clone()));
+            if section_value.contains_key(&kebab_case_value) {
+                warn!(
+                    "Configuration key {}.{} is deprecated and ignored, since {}.{} is set",
+                    section, key, section, kebab_case_key
+                );
+            } else if let Some(value) = value {
+                warn!(
+                    "Configuration key {}.{} is deprecated, use {}.{} instead",
+                    section, key, section, kebab_case_key
+                );
+                section_value.insert(kebab_case_value, value);
+            }
+        }
+    }
+}
+
+fn validate_configuration(configuration: &Configuration) -> Result<()> {
+    let tick_rate = configuration.game.global_tick_rate_hz;
+    for (opcode, min_length) in configuration.server.min_body_lengths.iter() {
+        ensure!(
+            *min_length <= framing::MAX_BODY_LENGTH,
+            "Minimal body length of {:?} must be at most {} but is {}",
+            opcode,
+            framing::MAX_BODY_LENGTH,
+            min_length
+        );
+    }
+    for (opcode, phases) in configuration.server.opcode_phases.iter() {
+        ensure!(
+            !Message::requires_authentication(*opcode)
+                || phases.iter().all(|phase| {
+                    *phase == ConnectionPhase::Authenticated || *phase == ConnectionPhase::InGame
+                }),
+            "{:?} requires authentication and can't be valid before the login",
+            opcode
+        );
+    }
+    for country in configuration.server.allowed_countries.iter() {
+        ensure!(
+            country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()),
+            "Allowed country {} is not an ISO 3166-1 alpha-2 code",
+            country
+        );
+    }
+    ensure!(
+        configuration.server.listen_backlog > 0,
+        "Listen backlog must be greater than 0"
+    );
+    ensure!(
+        !configuration.server.insecure_skip_login_checks || cfg!(debug_assertions),
+        "Skipping the login checks is only allowed in debug builds"
+    );
+    ensure!(
+        configuration.server.response_channel_capacity > 0,
+        "Response channel capacity must be greater than 0"
+    );
+    ensure!(
+        configuration.server.ticket_ttl_secs > 0,
+        "Ticket TTL must be greater than 0"
+    );
+    ensure!(
+        configuration.server.version_check_grace_secs > 0,
+        "Version check grace period must be greater than 0"
+    );
+    ensure!(
+        configuration.server.max_decode_failures > 0,
+        "Max decode failures must be greater than 0"
+    );
+    ensure!(
+        tick_rate >= MIN_TICK_RATE_HZ && tick_rate <= MAX_TICK_RATE_HZ,
+        "Global tick rate must be between {} and {} but is {}",
+        MIN_TICK_RATE_HZ,
+        MAX_TICK_RATE_HZ,
+        tick_rate
+    );
+    ensure!(
+        configuration.game.spawn_budget_per_tick > 0,
+        "Spawn budget per tick must be greater than 0"
+    );
+    ensure!(
+        configuration.game.user_list_page_size > 0,
+        "User list page size must be greater than 0"
+    );
+    let motd_length = configuration.game.motd.chars().count();
+    ensure!(
+        motd_length <= MAX_MOTD_LENGTH,
+        "MOTD must be at most {} characters long but is {}",
+        MAX_MOTD_LENGTH,
+        motd_length
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::capture_logs;
+
+    const CONFIGURATION: &str = "
+server:
+    ip: 127.0.0.1
+    web-port: 8080
+    game-port: 10001
+database:
+    hostname: 127.0.0.1
+    port: 5432
+    username: almetica
+    password: almetica
+    database: almetica
+data:
+    path: /tmp
+game:
+    pvp: true
+";
+
+    #[test]
+    fn test_migrate_version_0() -> Result<()> {
+        let old_configuration =
+            CONFIGURATION.replace("    game-port: 10001\n", "    game_port: 10001\n");
+        let value = serde_yaml::from_str(&old_configuration)?;
+
+        let mut configuration = None;
+        let output = capture_logs(tracing::Level::TRACE, || {
+            configuration = Some(parse_configuration(value));
+        });
+        let configuration = configuration.unwrap()?;
+        validate_configuration(&configuration)?;
+
+        assert_eq!(configuration.version, CONFIGURATION_VERSION);
+        assert_eq!(configuration.server.game_port, 10001);
+        // Fields that are missing in the old file get their defaults.
+        assert_eq!(configuration.server.listen_backlog, 1024);
+        assert_eq!(configuration.server.connection_soft_limit, 0);
+        assert_eq!(configuration.game.global_tick_rate_hz, 10);
+
+        let line = output
+            .lines()
+            .find(|line| line.contains("server.game_port"))
+            .expect("Can't find the deprecation warning");
+        assert!(line.contains("WARN"));
+        assert!(line.contains("use server.game-port instead"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_keeps_kebab_case_key() -> Result<()> {
+        let old_configuration = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    game_port: 10002\n",
+        );
+        let configuration = parse_configuration(serde_yaml::from_str(&old_configuration)?)?;
+        assert_eq!(configuration.server.game_port, 10001);
+        Ok(())
+    }
+
+    #[test]
+    fn test_current_version() -> Result<()> {
+        let current = format!("version: {}\n{}", CONFIGURATION_VERSION, CONFIGURATION);
+        let configuration = parse_configuration(serde_yaml::from_str(&current)?)?;
+        assert_eq!(configuration.version, CONFIGURATION_VERSION);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_version() -> Result<()> {
+        let newer = format!("version: {}\n{}", CONFIGURATION_VERSION + 1, CONFIGURATION);
+        assert!(parse_configuration(serde_yaml::from_str(&newer)?).is_err());
+
+        let invalid = format!("version: first\n{}", CONFIGURATION);
+        assert!(parse_configuration(serde_yaml::from_str(&invalid)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_configuration() -> Result<()> {
+        assert!(parse_configuration(serde_yaml::from_str("- server")?).is_err());
+
+        let wrong_type = CONFIGURATION.replace("    game-port: 10001\n", "    game-port: many\n");
+        assert!(parse_configuration(serde_yaml::from_str(&wrong_type)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_tick_rate() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        validate_configuration(&configuration)?;
+        assert_eq!(configuration.game.global_tick_rate_hz, 10);
+        assert_eq!(configuration.game.spawn_budget_per_tick, 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_listener_options() -> Result<()> {
+        let mut configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.listen_backlog, 1024);
+        assert!(configuration.server.reuse_address);
+        assert!(!configuration.server.reuse_port);
+
+        configuration.server.listen_backlog = 0;
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ticket_ttl() -> Result<()> {
+        let mut configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.ticket_ttl_secs, 300);
+
+        configuration.server.ticket_ttl_secs = 0;
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_check_grace() -> Result<()> {
+        let mut configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.version_check_grace_secs, 5);
+
+        configuration.server.version_check_grace_secs = 0;
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_decode_failures() -> Result<()> {
+        let mut configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.max_decode_failures, 10);
+
+        configuration.server.max_decode_failures = 0;
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_body_lengths() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.min_body_lengths.is_empty());
+
+        let with_lengths = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    min-body-lengths:\n        S_CHECK_VERSION: 32\n",
+        );
+        let mut configuration: Configuration = serde_yaml::from_str(&with_lengths)?;
+        validate_configuration(&configuration)?;
+        assert_eq!(
+            configuration.server.min_body_lengths[&Opcode::S_CHECK_VERSION],
+            32
+        );
+
+        configuration
+            .server
+            .min_body_lengths
+            .insert(Opcode::S_CHECK_VERSION, framing::MAX_BODY_LENGTH + 1);
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_packet_log_levels() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.packet_log_levels.is_empty());
+        assert_eq!(
+            configuration.server.default_packet_log_level,
+            PacketLogLevel::Debug
+        );
+
+        let with_levels = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    packet-log-levels:\n        C_CHAT: trace\n        C_LOGIN_ARBITER: info\n    default-packet-log-level: warn\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_levels)?;
+        assert_eq!(
+            configuration.server.packet_log_levels[&Opcode::C_CHAT],
+            PacketLogLevel::Trace
+        );
+        assert_eq!(
+            configuration.server.packet_log_levels[&Opcode::C_LOGIN_ARBITER],
+            PacketLogLevel::Info
+        );
+        assert_eq!(
+            configuration.server.default_packet_log_level,
+            PacketLogLevel::Warn
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_priority_opcodes() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.priority_opcodes, vec![Opcode::C_PONG]);
+
+        let without_priorities = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    priority-opcodes: []\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&without_priorities)?;
+        assert!(configuration.server.priority_opcodes.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_channel() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.response_channel_capacity, 128);
+        assert_eq!(
+            configuration.server.channel_full_policy,
+            ChannelFullPolicy::DropNewest
+        );
+
+        let with_policy = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    response-channel-capacity: 1\n    channel-full-policy: disconnect\n",
+        );
+        let mut configuration: Configuration = serde_yaml::from_str(&with_policy)?;
+        validate_configuration(&configuration)?;
+        assert_eq!(configuration.server.response_channel_capacity, 1);
+        assert_eq!(
+            configuration.server.channel_full_policy,
+            ChannelFullPolicy::Disconnect
+        );
+
+        configuration.server.response_channel_capacity = 0;
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_login_sequence() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(
+            configuration.game.post_login_sequence,
+            PostLoginPacket::default_sequence()
+        );
+
+        let with_sequence = CONFIGURATION.replace(
+            "    pvp: true\n",
+            "    pvp: true\n    post-login-sequence: [login-arbiter, motd, check-version]\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_sequence)?;
+        assert_eq!(
+            configuration.game.post_login_sequence,
+            vec![
+                PostLoginPacket::LoginArbiter,
+                PostLoginPacket::Motd,
+                PostLoginPacket::CheckVersion
+            ]
+        );
+
+        let with_unknown = CONFIGURATION.replace(
+            "    pvp: true\n",
+            "    pvp: true\n    post-login-sequence: [login-arbiter, unknown-packet]\n",
+        );
+        assert!(serde_yaml::from_str::<Configuration>(&with_unknown).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_versions() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.allowed_versions.is_empty());
+
+        let with_versions = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    allowed-versions: [[366222, 365535]]\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_versions)?;
+        assert_eq!(
+            configuration.server.allowed_versions,
+            vec![(366_222, 365_535)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocked_ip_ranges() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.blocked_ip_ranges.is_empty());
+
+        let with_ranges = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    blocked-ip-ranges: [10.0.0.0/8, 192.168.1.7, \"fe80::/10\"]\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_ranges)?;
+        let ranges = &configuration.server.blocked_ip_ranges;
+        assert_eq!(ranges.len(), 3);
+        assert!(ranges[0].contains(&"10.1.2.3".parse()?));
+        assert!(ranges[1].contains(&"192.168.1.7".parse()?));
+        assert!(!ranges[1].contains(&"192.168.1.8".parse()?));
+        assert!(ranges[2].contains(&"fe80::1".parse()?));
+
+        for malformed in &[
+            "10.0.0.0/33",
+            "10.0.0.1/8",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "localhost",
+        ] {
+            let with_malformed = CONFIGURATION.replace(
+                "    game-port: 10001\n",
+                &format!(
+                    "    game-port: 10001\n    blocked-ip-ranges: [\"{}\"]\n",
+                    malformed
+                ),
+            );
+            assert!(serde_yaml::from_str::<Configuration>(&with_malformed).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_origins() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.allowed_countries.is_empty());
+        assert!(configuration.server.allowed_asns.is_empty());
+
+        let with_origins = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    allowed-countries: [DE, fr]\n    allowed-asns: [3320]\n",
+        );
+        let mut configuration: Configuration = serde_yaml::from_str(&with_origins)?;
+        assert_eq!(configuration.server.allowed_countries, vec!["DE", "fr"]);
+        assert_eq!(configuration.server.allowed_asns, vec![3320]);
+        validate_configuration(&configuration)?;
+
+        configuration.server.allowed_countries = vec!["Germany".to_string()];
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_eviction() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.server.connection_soft_limit, 0);
+        assert_eq!(
+            configuration.server.eviction_policy,
+            EvictionPolicy::LeastRecentlyActive
+        );
+
+        let with_eviction = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    connection-soft-limit: 16384\n    eviction-policy: newest\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_eviction)?;
+        validate_configuration(&configuration)?;
+        assert_eq!(configuration.server.connection_soft_limit, 16384);
+        assert_eq!(configuration.server.eviction_policy, EvictionPolicy::Newest);
+        Ok(())
+    }
+
+    #[test]
+    fn test_opcode_phases() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.opcode_phases.is_empty());
+        assert_eq!(
+            configuration.server.out_of_phase_policy,
+            OutOfPhasePolicy::Disconnect
+        );
+
+        let with_phases = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    opcode-phases:\n        C_GET_USER_LIST: [authenticated]\n        C_PONG: [version-checked, authenticated, in-game]\n    out-of-phase-policy: drop\n",
+        );
+        let mut configuration: Configuration = serde_yaml::from_str(&with_phases)?;
+        validate_configuration(&configuration)?;
+        assert_eq!(
+            configuration.server.opcode_phases[&Opcode::C_GET_USER_LIST],
+            vec![ConnectionPhase::Authenticated]
+        );
+        assert_eq!(configuration.server.opcode_phases[&Opcode::C_PONG].len(), 3);
+        assert_eq!(
+            configuration.server.out_of_phase_policy,
+            OutOfPhasePolicy::Drop
+        );
+
+        configuration
+            .server
+            .opcode_phases
+            .insert(Opcode::C_GET_USER_LIST, vec![ConnectionPhase::Connected]);
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_insecure_skip_login_checks() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(!configuration.server.insecure_skip_login_checks);
+
+        let with_skip = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    insecure-skip-login-checks: true\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_skip)?;
+        assert!(configuration.server.insecure_skip_login_checks);
+        // Tests are debug builds.
+        assert!(validate_configuration(&configuration).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_motd_length() -> Result<()> {
+        let mut configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.game.motd.is_empty());
+
+        configuration.game.motd = "a".repeat(MAX_MOTD_LENGTH);
+        assert!(validate_configuration(&configuration).is_ok());
+
+        configuration.game.motd = "a".repeat(MAX_MOTD_LENGTH + 1);
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_arbiter_fields() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert!(configuration.server.login_arbiter_fields.is_empty());
+
+        let with_fields = CONFIGURATION.replace(
+            "    game-port: 10001\n",
+            "    game-port: 10001\n    login-arbiter-fields:\n        366222: {status: 1, unk1: 2}\n",
+        );
+        let configuration: Configuration = serde_yaml::from_str(&with_fields)?;
+        assert_eq!(
+            configuration.server.login_arbiter_fields[&366_222],
+            LoginArbiterFields {
+                status: 1,
+                unk1: 2,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_list_page_size() -> Result<()> {
+        let configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+        assert_eq!(configuration.game.user_list_page_size, 5);
+
+        let with_page_size = CONFIGURATION.replace(
+            "    pvp: true\n",
+            "    pvp: true\n    user-list-page-size: 0\n",
+        );
+        let mut configuration: Configuration = serde_yaml::from_str(&with_page_size)?;
+        assert_eq!(configuration.game.user_list_page_size, 0);
+        assert!(validate_configuration(&configuration).is_err());
+
+        configuration.game.user_list_page_size = 20;
+        assert!(validate_configuration(&configuration).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_rate_bounds() -> Result<()> {
+        let mut configuration: Configuration = serde_yaml::from_str(CONFIGURATION)?;
+
+        configuration.game.global_tick_rate_hz = 30;
+        assert!(validate_configuration(&configuration).is_ok());
+
+        configuration.game.global_tick_rate_hz = 0;
+        assert!(validate_configuration(&configuration).is_err());
+
+        configuration.game.global_tick_rate_hz = 1000;
+        assert!(validate_configuration(&configuration).is_err());
+        Ok(())
+    }
+}
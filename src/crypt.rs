@@ -71,6 +71,18 @@ impl CryptSession {
     }
 }
 
+/// Obfuscation of the stream that is layered on top of the stream cipher once the keys are
+/// exchanged. The outgoing data is obfuscated after it was encrypted and the incoming data is
+/// deobfuscated before it is decrypted. Set on a session with `GameSession::set_obfuscation`.
+pub trait StreamObfuscation: Send {
+    /// Obfuscates the data that is sent to the peer and advances the state of the obfuscation.
+    fn obfuscate(&mut self, data: &mut [u8]);
+
+    /// Reverses the obfuscation of the data that was received from the peer and advances the
+    /// state of the obfuscation.
+    fn deobfuscate(&mut self, data: &mut [u8]);
+}
+
 fn shift_key(dst: &mut [u8], src: &[u8], n: i32) {
     dst.copy_from_slice(src);
     if n > 0 {
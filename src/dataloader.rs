@@ -78,6 +78,40 @@ pub fn calculate_reverse_map(opcode_mapping: &[Opcode]) -> HashMap<Opcode, u16>
     reverse_opcode_mapping
 }
 
+/// Checks that the opcode table and the reverse map describe the same mapping. Packets would be
+/// routed to the wrong opcode otherwise.
+pub fn validate_opcode_maps(map: &[Opcode], reverse_map: &HashMap<Opcode, u16>) -> Result<()> {
+    for (opcode, value) in reverse_map.iter() {
+        ensure!(
+            *opcode != Opcode::UNKNOWN,
+            "Reverse opcode map contains the UNKNOWN opcode"
+        );
+        let mapped = map.get(*value as usize);
+        ensure!(
+            mapped == Some(opcode),
+            "Opcode {:?} is reverse mapped to {}, but the opcode table maps {} to {:?}",
+            opcode,
+            value,
+            value,
+            mapped
+        );
+    }
+    for (value, opcode) in map.iter().enumerate() {
+        if *opcode == Opcode::UNKNOWN {
+            continue;
+        }
+        let reverse_value = reverse_map.get(opcode);
+        ensure!(
+            reverse_value == Some(&(value as u16)),
+            "Opcode table maps {} to {:?}, but the reverse map has {:?}",
+            value,
+            opcode,
+            reverse_value
+        );
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Write;
@@ -120,6 +154,38 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_validate_consistent_opcode_maps() -> Result<()> {
+        let mut table = vec![Opcode::UNKNOWN; 16];
+        table[1] = Opcode::C_CHECK_VERSION;
+        table[7] = Opcode::S_CHECK_VERSION;
+        let reverse_map = calculate_reverse_map(table.as_slice());
+
+        validate_opcode_maps(&table, &reverse_map)
+    }
+
+    #[test]
+    fn test_validate_inconsistent_opcode_maps() {
+        let mut table = vec![Opcode::UNKNOWN; 16];
+        table[1] = Opcode::C_CHECK_VERSION;
+        table[7] = Opcode::S_CHECK_VERSION;
+
+        // Wrong value for an opcode
+        let mut reverse_map = calculate_reverse_map(table.as_slice());
+        reverse_map.insert(Opcode::S_CHECK_VERSION, 8);
+        assert!(validate_opcode_maps(&table, &reverse_map).is_err());
+
+        // Opcode missing in the reverse map
+        let mut reverse_map = calculate_reverse_map(table.as_slice());
+        reverse_map.remove(&Opcode::C_CHECK_VERSION);
+        assert!(validate_opcode_maps(&table, &reverse_map).is_err());
+
+        // Value outside of the opcode table
+        let mut reverse_map = calculate_reverse_map(table.as_slice());
+        reverse_map.insert(Opcode::C_CHECK_USERNAME, 100);
+        assert!(validate_opcode_maps(&table, &reverse_map).is_err());
+    }
+
     #[test]
     fn test_read_datacenter_file() -> Result<()> {
         let size = 1024 * 1024;
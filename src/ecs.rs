@@ -2,6 +2,8 @@
 pub mod component;
 pub mod dto;
 pub mod message;
+#[cfg(test)]
+pub(crate) mod replay;
 pub mod resource;
 pub mod system;
 pub mod world;
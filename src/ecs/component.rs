@@ -1,11 +1,13 @@
 /// Module holds the components that the ECS use.
 use crate::ecs::message::EcsMessage;
-use crate::model::Region;
+use crate::model::{AccountId, Region, UserId};
+use crate::protocol::serde::SchemaVersion;
 use crate::Result;
 use async_std::sync::Sender;
 use async_std::task::JoinHandle;
 use shipyard::EntityId;
 use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::time::Instant;
 
 /// Tracks the connection and login information of a player for the global world.
@@ -16,6 +18,10 @@ pub struct GlobalConnection {
     pub is_authenticated: bool,
     pub last_pong: Instant,
     pub waiting_for_pong: bool,
+    pub peer_addr: SocketAddr,
+    pub connected_since: Instant,
+    /// Schema version the client negotiated with it's version check.
+    pub schema_version: Option<SchemaVersion>,
 }
 
 /// Tracks the connection of a player for a local world.
@@ -27,7 +33,7 @@ pub struct LocalConnection {
 /// Holds the account information attached to a connection entity once it's authenticated.
 #[derive(Clone, Copy, Debug)]
 pub struct Account {
-    pub id: i64,
+    pub id: AccountId,
     pub region: Region,
 }
 
@@ -40,8 +46,8 @@ pub struct Settings {
 /// Holds the global spawn information of an user.
 #[derive(Clone, Debug)]
 pub struct GlobalUserSpawn {
-    pub user_id: i32,
-    pub account_id: i64,
+    pub user_id: UserId,
+    pub account_id: AccountId,
     pub status: UserSpawnStatus,
     pub zone_id: i32,
     pub connection_local_world_id: Option<EntityId>,
@@ -54,8 +60,8 @@ pub struct GlobalUserSpawn {
 /// Holds the local spawn information of an user.
 #[derive(Clone, Debug)]
 pub struct LocalUserSpawn {
-    pub user_id: i32,
-    pub account_id: i64,
+    pub user_id: UserId,
+    pub account_id: AccountId,
     pub status: UserSpawnStatus,
     pub is_alive: bool,
 }
@@ -16,17 +16,73 @@
 /// Network connections and ECS have async ```mpmc``` channels to write messages into.
 ///
 use crate::ecs::dto::UserInitializer;
+use crate::metrics;
+use crate::model::{AccountId, UserId};
 use crate::protocol::opcode::Opcode;
 use crate::protocol::packet::*;
-use crate::protocol::serde::{from_vec, to_vec};
+use crate::protocol::serde::{
+    from_vec, from_vec_with_layout, pad_to_min_length, to_vec_into_with_schema_version,
+    Deserializer, FieldLayout, SchemaVersion,
+};
+use crate::protocol::{CloseKind, ConnectionPhase};
 use crate::{AlmeticaError, Result};
 use anyhow::bail;
 use async_std::sync::Sender;
+use serde::Deserialize;
 use shipyard::*;
+use std::cell::Cell;
 use std::fmt;
+use std::net::SocketAddr;
+use std::ops::Deref;
+
+/// The world a message was created in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageOrigin {
+    Global,
+    Local(EntityId),
+}
+
+thread_local! {
+    // A world owns its thread for its whole lifetime, so the origin can be tracked per thread.
+    static CURRENT_ORIGIN: Cell<Option<MessageOrigin>> = Cell::new(None);
+}
+
+/// Sets the origin of all messages that are created afterwards on the current thread.
+pub fn set_message_origin(origin: Option<MessageOrigin>) {
+    CURRENT_ORIGIN.with(|current| current.set(origin));
+}
 
 /// ECS messages. We use `Box` so that we don't need to copy the packet data around.
-pub type EcsMessage = Box<Message>;
+#[derive(Clone, Debug)]
+pub struct EcsMessage {
+    pub inner: Box<Message>,
+    /// The world the message was created in. Messages of connections have no origin.
+    pub origin: Option<MessageOrigin>,
+}
+
+impl EcsMessage {
+    /// Creates a new ECS message that is tagged with the world of the current thread.
+    pub fn new(message: Message) -> Self {
+        EcsMessage {
+            inner: Box::new(message),
+            origin: CURRENT_ORIGIN.with(|current| current.get()),
+        }
+    }
+}
+
+impl Deref for EcsMessage {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        &self.inner
+    }
+}
+
+impl fmt::Display for EcsMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
 
 /// The target of the message.
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -37,19 +93,33 @@ pub enum MessageTarget {
     Connection,
 }
 
+/// The category of a packet. Used to aggregate metrics and to prioritize packets by their
+/// purpose instead of by the single opcode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OpcodeCategory {
+    /// Version check, login and the packets of the login sequence.
+    Auth,
+    /// Creation, deletion and selection of the users of an account.
+    CharacterManagement,
+    /// Ping and pong.
+    Keepalive,
+    /// Everything once the user is spawned.
+    Gameplay,
+}
+
 macro_rules! assemble_message {
     (
     Local Packet Messages {
-        $($l_ty:ident{packet: $l_packet_type:ty}, $l_opcode:ident, $l_target:ident;)*
+        $($l_ty:ident{packet: $l_packet_type:ty}, $l_opcode:ident, $l_target:ident, $l_category:ident;)*
     }
     Global User Packet Messages {
-        $($u_ty:ident{packet: $u_packet_type:ty}, $u_opcode:ident, $u_target:ident;)*
+        $($u_ty:ident{packet: $u_packet_type:ty}, $u_opcode:ident, $u_target:ident, $u_category:ident;)*
     }
     Global Account Packet Messages {
-        $($a_ty:ident{packet: $a_packet_type:ty}, $a_opcode:ident, $a_target:ident;)*
+        $($a_ty:ident{packet: $a_packet_type:ty}, $a_opcode:ident, $a_target:ident, $a_category:ident;)*
     }
     Global Packet Messages {
-        $($p_ty:ident{packet: $p_packet_type:ty}, $p_opcode:ident, $p_target:ident;)*
+        $($p_ty:ident{packet: $p_packet_type:ty}, $p_opcode:ident, $p_target:ident, $p_category:ident;)*
     }
     Special Messages {
         $($s_ty:ident{$($s_arg_name:ident: $s_arg_type:ty),+}, $s_target:ident;)*
@@ -59,22 +129,43 @@ macro_rules! assemble_message {
         #[derive(Clone, Debug)]
         pub enum Message {
             $($l_ty {connection_global_world_id: EntityId, connection_local_world_id: EntityId, packet: $l_packet_type},)*
-            $($u_ty {connection_global_world_id: EntityId, account_id: i64, user_id: i32, packet: $u_packet_type},)*
-            $($a_ty {connection_global_world_id: EntityId, account_id: i64, packet: $a_packet_type},)*
+            $($u_ty {connection_global_world_id: EntityId, account_id: AccountId, user_id: UserId, packet: $u_packet_type},)*
+            $($a_ty {connection_global_world_id: EntityId, account_id: AccountId, packet: $a_packet_type},)*
             $($p_ty {connection_global_world_id: EntityId, packet: $p_packet_type},)*
             $($s_ty {$($s_arg_name: $s_arg_type),*},)*
         }
 
         impl Message {
             /// Creates a new packet message for the given opcode & packet data from a client.
-            pub fn new_from_packet(connection_global_world_id: EntityId, connection_local_world_id: Option<EntityId>, account_id: Option<i64>, user_id: Option<i32>, opcode: Opcode, packet_data: Vec<u8>) -> Result<Message> {
+            pub fn new_from_packet(connection_global_world_id: EntityId, connection_local_world_id: Option<EntityId>, account_id: Option<AccountId>, user_id: Option<UserId>, opcode: Opcode, packet_data: Vec<u8>) -> Result<Message> {
+                Message::new_from_deserializer(connection_global_world_id, connection_local_world_id, account_id, user_id, opcode, &mut Deserializer::from_vec(packet_data))
+            }
+
+            /// Creates a new packet message for the given opcode from the packet data of the deserializer.
+            /// Used with pooled deserializers, so that the packet buffer is re-used.
+            /// The outcome is counted in the decode statistics of the opcode.
+            pub fn new_from_deserializer(connection_global_world_id: EntityId, connection_local_world_id: Option<EntityId>, account_id: Option<AccountId>, user_id: Option<UserId>, opcode: Opcode, deserializer: &mut Deserializer) -> Result<Message> {
+                let body_length = deserializer.buffer().len();
+                let result = Message::decode_from_deserializer(connection_global_world_id, connection_local_world_id, account_id, user_id, opcode, deserializer);
+                match &result {
+                    Ok(..) => metrics::record_decode_success(opcode, body_length),
+                    Err(e) => match e.downcast_ref::<AlmeticaError>() {
+                        // The data of refused packets is never read.
+                        Some(AlmeticaError::UnauthorizedPacket) | Some(AlmeticaError::NoMessageMappingForPacket) => {},
+                        _ => metrics::record_decode_failure(opcode, e),
+                    },
+                }
+                result
+            }
+
+            fn decode_from_deserializer(connection_global_world_id: EntityId, connection_local_world_id: Option<EntityId>, account_id: Option<AccountId>, user_id: Option<UserId>, opcode: Opcode, deserializer: &mut Deserializer) -> Result<Message> {
                 match opcode {
                     $(Opcode::$l_opcode => {
                         if connection_local_world_id.is_none() {
                             bail!(AlmeticaError::UnauthorizedPacket);
                         }
 
-                        let packet = from_vec(packet_data)?;
+                        let packet = Deserialize::deserialize(&mut *deserializer)?;
                         Ok(Message::$l_ty{connection_global_world_id, connection_local_world_id: connection_local_world_id.unwrap(), packet})
                     },)*
                     $(Opcode::$u_opcode => {
@@ -82,7 +173,7 @@ macro_rules! assemble_message {
                             bail!(AlmeticaError::UnauthorizedPacket);
                         }
 
-                        let packet = from_vec(packet_data)?;
+                        let packet = Deserialize::deserialize(&mut *deserializer)?;
                         Ok(Message::$u_ty{connection_global_world_id, account_id: account_id.unwrap(), user_id: user_id.unwrap(), packet})
                     },)*
                     $(Opcode::$a_opcode => {
@@ -90,17 +181,92 @@ macro_rules! assemble_message {
                             bail!(AlmeticaError::UnauthorizedPacket);
                         }
 
-                        let packet = from_vec(packet_data)?;
+                        let packet = Deserialize::deserialize(&mut *deserializer)?;
                         Ok(Message::$a_ty{connection_global_world_id, account_id: account_id.unwrap(), packet})
                     },)*
                     $(Opcode::$p_opcode => {
-                        let packet = from_vec(packet_data)?;
+                        let packet = Deserialize::deserialize(&mut *deserializer)?;
                         Ok(Message::$p_ty{connection_global_world_id: connection_global_world_id, packet})
                     },)*
                     _ => bail!(AlmeticaError::NoMessageMappingForPacket),
                 }
             }
 
+            /// Decodes the packet data of the given opcode without creating a message.
+            /// Used to validate the packet definitions against captured packets.
+            pub fn validate_packet(opcode: Opcode, packet_data: Vec<u8>) -> Result<()> {
+                match opcode {
+                    $(Opcode::$l_opcode => { from_vec::<$l_packet_type>(packet_data)?; },)*
+                    $(Opcode::$u_opcode => { from_vec::<$u_packet_type>(packet_data)?; },)*
+                    $(Opcode::$a_opcode => { from_vec::<$a_packet_type>(packet_data)?; },)*
+                    $(Opcode::$p_opcode => { from_vec::<$p_packet_type>(packet_data)?; },)*
+                    _ => bail!(AlmeticaError::NoMessageMappingForPacket),
+                }
+                Ok(())
+            }
+
+            /// Decodes the packet data of the given opcode and returns where each field of the
+            /// packet is located inside the data.
+            pub fn packet_layout(opcode: Opcode, packet_data: Vec<u8>) -> Result<Vec<FieldLayout>> {
+                let layout = match opcode {
+                    $(Opcode::$l_opcode => from_vec_with_layout::<$l_packet_type>(packet_data)?.1,)*
+                    $(Opcode::$u_opcode => from_vec_with_layout::<$u_packet_type>(packet_data)?.1,)*
+                    $(Opcode::$a_opcode => from_vec_with_layout::<$a_packet_type>(packet_data)?.1,)*
+                    $(Opcode::$p_opcode => from_vec_with_layout::<$p_packet_type>(packet_data)?.1,)*
+                    _ => bail!(AlmeticaError::NoMessageMappingForPacket),
+                };
+                Ok(layout)
+            }
+
+            /// Returns true if the packet of the opcode can only be send by an authenticated
+            /// connection. Only the handshake packets of the global packet messages are allowed
+            /// before the authentication. Unmapped opcodes don't require it.
+            pub fn requires_authentication(opcode: Opcode) -> bool {
+                match opcode {
+                    $(Opcode::$l_opcode => true,)*
+                    $(Opcode::$u_opcode => true,)*
+                    $(Opcode::$a_opcode => true,)*
+                    _ => false,
+                }
+            }
+
+            /// Returns the phases of a connection in which the packet of the opcode is valid.
+            /// The handshake packets have their own phase, packets that require authentication
+            /// are valid once the client is logged in. Everything else is always valid.
+            pub fn allowed_phases(opcode: Opcode) -> &'static [ConnectionPhase] {
+                match opcode {
+                    Opcode::C_CHECK_VERSION => &[ConnectionPhase::Connected],
+                    Opcode::C_LOGIN_ARBITER => &[ConnectionPhase::VersionChecked],
+                    $(Opcode::$l_opcode => &[ConnectionPhase::InGame],)*
+                    $(Opcode::$u_opcode => &[ConnectionPhase::InGame],)*
+                    $(Opcode::$a_opcode => &[ConnectionPhase::Authenticated, ConnectionPhase::InGame],)*
+                    _ => &ConnectionPhase::ALL,
+                }
+            }
+
+            /// Returns the category of the packet of the opcode. Unmapped opcodes have no
+            /// category.
+            pub fn opcode_category(opcode: Opcode) -> Option<OpcodeCategory> {
+                match opcode {
+                    $(Opcode::$l_opcode => Some(OpcodeCategory::$l_category),)*
+                    $(Opcode::$u_opcode => Some(OpcodeCategory::$u_category),)*
+                    $(Opcode::$a_opcode => Some(OpcodeCategory::$a_category),)*
+                    $(Opcode::$p_opcode => Some(OpcodeCategory::$p_category),)*
+                    _ => None,
+                }
+            }
+
+            /// Returns all opcodes that have a message mapping. Packets with any other opcode
+            /// are rejected with `NoMessageMappingForPacket`.
+            pub fn handled_opcodes() -> Vec<Opcode> {
+                vec![
+                    $(Opcode::$l_opcode,)*
+                    $(Opcode::$u_opcode,)*
+                    $(Opcode::$a_opcode,)*
+                    $(Opcode::$p_opcode,)*
+                ]
+            }
+
             /// Get the connection_id of a packet message.
             pub fn connection_id(&self) -> Option<EntityId> {
                 match self {
@@ -112,27 +278,43 @@ macro_rules! assemble_message {
                 }
             }
 
-            /// Get the data from a packet message.
-            pub fn data(&self) -> Result<Option<Vec<u8>>> {
+            /// Get the data from a packet message. The packet body is padded with zeros to at
+            /// least `min_length` bytes.
+            pub fn data(&self, min_length: usize) -> Result<Option<Vec<u8>>> {
+                let mut data = Vec::with_capacity(1024);
+                if self.write_data_into(&mut data, min_length, None)? {
+                    Ok(Some(data))
+                } else {
+                    Ok(None)
+                }
+            }
+
+            /// Writes the data of a packet message into the given buffer like `data()`, with the
+            /// packet layout of the given schema version. The buffer is cleared first and keeps
+            /// it's capacity. Returns false if the message is not a packet message.
+            pub fn write_data_into(
+                &self,
+                buf: &mut Vec<u8>,
+                min_length: usize,
+                schema_version: Option<SchemaVersion>,
+            ) -> Result<bool> {
                 match self {
                     $(Message::$l_ty{packet, ..} => {
-                        let data = to_vec(packet)?;
-                        Ok(Some(data))
-                    },)*
+                        to_vec_into_with_schema_version(packet, buf, schema_version)?
+                    })*
                     $(Message::$u_ty{packet, ..} => {
-                        let data = to_vec(packet)?;
-                        Ok(Some(data))
-                    },)*
+                        to_vec_into_with_schema_version(packet, buf, schema_version)?
+                    })*
                     $(Message::$a_ty{packet, ..} => {
-                        let data = to_vec(packet)?;
-                        Ok(Some(data))
-                    },)*
+                        to_vec_into_with_schema_version(packet, buf, schema_version)?
+                    })*
                     $(Message::$p_ty{packet, ..} => {
-                        let data = to_vec(packet)?;
-                        Ok(Some(data))
-                    },)*
-                    _ => Ok(None),
+                        to_vec_into_with_schema_version(packet, buf, schema_version)?
+                    })*
+                    _ => return Ok(false),
                 }
+                pad_to_min_length(buf, min_length)?;
+                Ok(true)
             }
 
             /// Get the opcode from a packet message.
@@ -166,6 +348,113 @@ macro_rules! assemble_message {
             }
         }
 
+        impl Message {
+            /// Returns true if the message is a packet message that was send by a client.
+            pub fn is_request(&self) -> bool {
+                match self {
+                    $(Message::$l_ty{..} => MessageTarget::$l_target != MessageTarget::Connection,)*
+                    $(Message::$u_ty{..} => MessageTarget::$u_target != MessageTarget::Connection,)*
+                    $(Message::$a_ty{..} => MessageTarget::$a_target != MessageTarget::Connection,)*
+                    $(Message::$p_ty{..} => MessageTarget::$p_target != MessageTarget::Connection,)*
+                    $(Message::$s_ty{..} => false,)*
+                }
+            }
+
+            /// Returns true if the message is a packet message that will be send to a client.
+            pub fn is_response(&self) -> bool {
+                match self {
+                    $(Message::$l_ty{..} => MessageTarget::$l_target == MessageTarget::Connection,)*
+                    $(Message::$u_ty{..} => MessageTarget::$u_target == MessageTarget::Connection,)*
+                    $(Message::$a_ty{..} => MessageTarget::$a_target == MessageTarget::Connection,)*
+                    $(Message::$p_ty{..} => MessageTarget::$p_target == MessageTarget::Connection,)*
+                    $(Message::$s_ty{..} => false,)*
+                }
+            }
+        }
+
+        #[cfg(test)]
+        mod opcode_mapping_tests {
+            use super::*;
+            use crate::protocol::serde::to_vec;
+
+            /// Round-trips a default packet of every packet message through its opcode and
+            /// checks that the opcode is mapped to the same message.
+            #[test]
+            fn test_opcode_message_consistency() -> Result<()> {
+                let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+                let account_id = Some(AccountId(1));
+                let user_id = Some(UserId(1));
+                $(
+                    let data = to_vec(<$l_packet_type as Default>::default())?;
+                    match Message::new_from_packet(entity, Some(entity), account_id, user_id, Opcode::$l_opcode, data)? {
+                        Message::$l_ty{..} => {},
+                        m => panic!("{:?} is mapped to {} instead of Message::{}", Opcode::$l_opcode, m, stringify!($l_ty)),
+                    }
+                )*
+                $(
+                    let data = to_vec(<$u_packet_type as Default>::default())?;
+                    match Message::new_from_packet(entity, Some(entity), account_id, user_id, Opcode::$u_opcode, data)? {
+                        Message::$u_ty{..} => {},
+                        m => panic!("{:?} is mapped to {} instead of Message::{}", Opcode::$u_opcode, m, stringify!($u_ty)),
+                    }
+                )*
+                $(
+                    let data = to_vec(<$a_packet_type as Default>::default())?;
+                    match Message::new_from_packet(entity, Some(entity), account_id, user_id, Opcode::$a_opcode, data)? {
+                        Message::$a_ty{..} => {},
+                        m => panic!("{:?} is mapped to {} instead of Message::{}", Opcode::$a_opcode, m, stringify!($a_ty)),
+                    }
+                )*
+                $(
+                    let data = to_vec(<$p_packet_type as Default>::default())?;
+                    match Message::new_from_packet(entity, Some(entity), account_id, user_id, Opcode::$p_opcode, data)? {
+                        Message::$p_ty{..} => {},
+                        m => panic!("{:?} is mapped to {} instead of Message::{}", Opcode::$p_opcode, m, stringify!($p_ty)),
+                    }
+                )*
+                Ok(())
+            }
+
+            /// Every packet outside of the handshake is rejected from a connection that is not
+            /// authenticated.
+            #[test]
+            fn test_authentication_gate() -> Result<()> {
+                let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+                let assert_unauthorized = |opcode: Opcode, data: Vec<u8>| {
+                    assert!(Message::requires_authentication(opcode), "{:?} doesn't require authentication", opcode);
+                    match Message::new_from_packet(entity, None, None, None, opcode, data) {
+                        Err(e) => match e.downcast_ref::<AlmeticaError>() {
+                            Some(AlmeticaError::UnauthorizedPacket) => {},
+                            _ => panic!("{:?} was rejected with {:?}", opcode, e),
+                        },
+                        Ok(m) => panic!("{:?} was accepted as {} without authentication", opcode, m),
+                    }
+                };
+                $(assert_unauthorized(Opcode::$l_opcode, to_vec(<$l_packet_type as Default>::default())?);)*
+                $(assert_unauthorized(Opcode::$u_opcode, to_vec(<$u_packet_type as Default>::default())?);)*
+                $(assert_unauthorized(Opcode::$a_opcode, to_vec(<$a_packet_type as Default>::default())?);)*
+                $(assert!(!Message::requires_authentication(Opcode::$p_opcode));)*
+                assert!(!Message::requires_authentication(Opcode::UNKNOWN));
+                Ok(())
+            }
+
+            /// Packets that require authentication are never valid before the login.
+            #[test]
+            fn test_allowed_phases() {
+                let assert_after_login = |opcode: Opcode| {
+                    let phases = Message::allowed_phases(opcode);
+                    assert!(!phases.contains(&ConnectionPhase::Connected), "{:?} is valid before the version check", opcode);
+                    assert!(!phases.contains(&ConnectionPhase::VersionChecked), "{:?} is valid before the login", opcode);
+                };
+                $(assert_after_login(Opcode::$l_opcode);)*
+                $(assert_after_login(Opcode::$u_opcode);)*
+                $(assert_after_login(Opcode::$a_opcode);)*
+                assert_eq!(Message::allowed_phases(Opcode::C_CHECK_VERSION), &[ConnectionPhase::Connected]);
+                assert_eq!(Message::allowed_phases(Opcode::C_LOGIN_ARBITER), &[ConnectionPhase::VersionChecked]);
+                assert_eq!(Message::allowed_phases(Opcode::C_PONG), &ConnectionPhase::ALL);
+            }
+        }
+
         impl fmt::Display for Message {
             fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
                 match self {
@@ -183,42 +472,45 @@ macro_rules! assemble_message {
 assemble_message! {
     // Local packet messages (handled by the LOCAL_WORLD)
     Local Packet Messages {
-        RequestLoadTopoFin{packet: CLoadTopoFin}, C_LOAD_TOPO_FIN, Local;
-        ResponseSpawnMe{packet: SSpawnMe}, S_SPAWN_ME, Connection;
+        RequestLoadTopoFin{packet: CLoadTopoFin}, C_LOAD_TOPO_FIN, Local, Gameplay;
+        ResponseSpawnMe{packet: SSpawnMe}, S_SPAWN_ME, Connection, Gameplay;
     }
     // Global packets that need an account ID and the user ID attached.
     Global User Packet Messages {
-        ResponseLogin{packet: SLogin}, S_LOGIN, Connection;
+        ResponseLogin{packet: SLogin}, S_LOGIN, Connection, Gameplay;
     }
     // Global packets that need an account ID attached.
     Global Account Packet Messages {
-        RequestCanCreateUser{packet: CCanCreateUser}, C_CAN_CREATE_USER, Global;
-        RequestChangeUserLobbySlotId{packet: CChangeUserLobbySlotId}, C_CHANGE_USER_LOBBY_SLOT_ID, Global;
-        RequestCheckUserName{packet: CCheckUserName}, C_CHECK_USERNAME, Global;
-        RequestCreateUser{packet: CCreateUser}, C_CREATE_USER, Global;
-        RequestDeleteUser{packet: CDeleteUser}, C_DELETE_USER, Global;
-        RequestGetUserList{packet: CGetUserList}, C_GET_USER_LIST, Global;
-        RequestSetVisibleRange{packet: CSetVisibleRange}, C_SET_VISIBLE_RANGE, Global;
-        RequestSelectUser{packet: CSelectUser}, C_SELECT_USER, Global;
-        ResponseLoginArbiter{packet: SLoginArbiter}, S_LOGIN_ARBITER, Connection;
+        RequestCanCreateUser{packet: CCanCreateUser}, C_CAN_CREATE_USER, Global, CharacterManagement;
+        RequestChangeUserLobbySlotId{packet: CChangeUserLobbySlotId}, C_CHANGE_USER_LOBBY_SLOT_ID, Global, CharacterManagement;
+        RequestCheckUserName{packet: CCheckUserName}, C_CHECK_USERNAME, Global, CharacterManagement;
+        RequestCreateUser{packet: CCreateUser}, C_CREATE_USER, Global, CharacterManagement;
+        RequestDeleteUser{packet: CDeleteUser}, C_DELETE_USER, Global, CharacterManagement;
+        RequestGetUserList{packet: CGetUserList}, C_GET_USER_LIST, Global, CharacterManagement;
+        RequestSetVisibleRange{packet: CSetVisibleRange}, C_SET_VISIBLE_RANGE, Global, Gameplay;
+        RequestSelectUser{packet: CSelectUser}, C_SELECT_USER, Global, CharacterManagement;
+        ResponseLoginArbiter{packet: SLoginArbiter}, S_LOGIN_ARBITER, Connection, Auth;
     }
-    // Global packet messages (handled by the GLOBAL_WORLD)
+    // Global packet messages (handled by the GLOBAL_WORLD). The requests are the handshake and
+    // the only packets a connection can send before it's authenticated.
     Global Packet Messages {
-        RequestLoginArbiter{packet: CLoginArbiter}, C_LOGIN_ARBITER, Global;
-        RequestCheckVersion{packet: CCheckVersion}, C_CHECK_VERSION, Global;
-        RequestPong{packet: CPong}, C_PONG, Global;
-        ResponseCanCreateUser{packet: SCanCreateUser}, S_CAN_CREATE_USER, Connection;
-        ResponseCheckUserName{packet: SCheckUserName}, S_CHECK_USERNAME, Connection;
-        ResponseCheckVersion{packet: SCheckVersion}, S_CHECK_VERSION, Connection;
-        ResponseCreateUser{packet: SCreateUser}, S_CREATE_USER, Connection;
-        ResponseDeleteUser{packet: SDeleteUser}, S_DELETE_USER, Connection;
-        ResponseGetUserList{packet: SGetUserList}, S_GET_USER_LIST, Connection;
-        ResponseLoadHint{packet: SLoadHint}, S_LOAD_HINT, Connection;
-        ResponseLoadTopo{packet: SLoadTopo}, S_LOAD_TOPO, Connection;
-        ResponseLoadingScreenControlInfo{packet: SLoadingScreenControlInfo}, S_LOADING_SCREEN_CONTROL_INFO, Connection;
-        ResponseLoginAccountInfo{packet: SLoginAccountInfo}, S_LOGIN_ACCOUNT_INFO, Connection;
-        ResponsePing{packet: SPing}, S_PING, Connection;
-        ResponseRemainPlayTime{packet: SRemainPlayTime}, S_REMAIN_PLAY_TIME, Connection;
+        RequestLoginArbiter{packet: CLoginArbiter}, C_LOGIN_ARBITER, Global, Auth;
+        RequestCheckVersion{packet: CCheckVersion}, C_CHECK_VERSION, Global, Auth;
+        RequestPong{packet: CPong}, C_PONG, Global, Keepalive;
+        ResponseCanCreateUser{packet: SCanCreateUser}, S_CAN_CREATE_USER, Connection, CharacterManagement;
+        ResponseChat{packet: SChat}, S_CHAT, Connection, Gameplay;
+        ResponseCheckUserName{packet: SCheckUserName}, S_CHECK_USERNAME, Connection, CharacterManagement;
+        ResponseCheckVersion{packet: SCheckVersion}, S_CHECK_VERSION, Connection, Auth;
+        ResponseCreateUser{packet: SCreateUser}, S_CREATE_USER, Connection, CharacterManagement;
+        ResponseDeleteUser{packet: SDeleteUser}, S_DELETE_USER, Connection, CharacterManagement;
+        ResponseGetUserList{packet: SGetUserList}, S_GET_USER_LIST, Connection, CharacterManagement;
+        ResponseLoadHint{packet: SLoadHint}, S_LOAD_HINT, Connection, Gameplay;
+        ResponseLoadTopo{packet: SLoadTopo}, S_LOAD_TOPO, Connection, Gameplay;
+        ResponseLoadingScreenControlInfo{packet: SLoadingScreenControlInfo}, S_LOADING_SCREEN_CONTROL_INFO, Connection, Auth;
+        ResponseLoginAccountInfo{packet: SLoginAccountInfo}, S_LOGIN_ACCOUNT_INFO, Connection, Auth;
+        ResponsePing{packet: SPing}, S_PING, Connection, Keepalive;
+        ResponseRemainPlayTime{packet: SRemainPlayTime}, S_REMAIN_PLAY_TIME, Connection, Auth;
+        ResponseResumeToken{packet: SResumeToken}, S_RESUME_TOKEN, Connection, Auth;
     }
     // Special messages send between the global and local world and also the connections.
     Special Messages {
@@ -228,8 +520,18 @@ assemble_message! {
         // The connection will be dropped after it receives this message.
         DropConnection{connection_global_world_id: EntityId}, Connection;
 
+        // Packet messages of one connection that are written out back to back, so no other
+        // message of the connection can end up between them. Batches can't be nested.
+        ResponseBatch{connection_global_world_id: EntityId, messages: Vec<EcsMessage>}, Connection;
+
+        // The connection writes out all pending responses and closes after it receives this message.
+        ShutdownConnection{connection_global_world_id: EntityId}, Connection;
+
         // Registers the connection to the global world.
-        RegisterConnection{connection_channel: Sender<EcsMessage>}, Global;
+        RegisterConnection{connection_channel: Sender<EcsMessage>, peer_addr: SocketAddr}, Global;
+
+        // Informs the global world that the connection is closed and why it was closed.
+        RequestConnectionClosed{connection_global_world_id: EntityId, kind: CloseKind}, Global;
 
         // The connections get it's EntityId of the global world returned.
         RegisterConnectionFinished{connection_global_world_id: EntityId}, Connection;
@@ -285,6 +587,59 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_decode_statistics() {
+        let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        // Decode on a fresh thread, so that the shard only holds the packets of this test.
+        let statistics = std::thread::spawn(move || {
+            let decode = |opcode: Opcode, data: Vec<u8>| {
+                Message::new_from_packet(entity, None, None, None, opcode, data)
+            };
+            let check_version = vec![
+                0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0x14, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1d, 0x8a, 0x5, 0x0,
+                0x14, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xce, 0x7b, 0x5, 0x0,
+            ];
+
+            assert!(decode(Opcode::C_CHECK_VERSION, check_version.clone()).is_ok());
+            assert!(decode(Opcode::C_CHECK_VERSION, check_version).is_ok());
+            // Too short for the array header.
+            assert!(decode(Opcode::C_CHECK_VERSION, vec![0x2]).is_err());
+            // The offset of the array points behind the data.
+            assert!(decode(Opcode::C_CHECK_VERSION, vec![0x1, 0x0, 0xff, 0x0]).is_err());
+            assert!(decode(Opcode::C_PONG, vec![]).is_ok());
+            // Refused before the data is read.
+            assert!(decode(Opcode::C_CHECK_USERNAME, vec![0x0; 8]).is_err());
+
+            metrics::thread_decode_statistics()
+        })
+        .join()
+        .unwrap();
+
+        let check_version = &statistics[&Opcode::C_CHECK_VERSION];
+        assert_eq!(check_version.attempts, 4);
+        assert_eq!(check_version.successes, 2);
+        assert_eq!(check_version.failure_count(), 2);
+        assert_eq!(check_version.failures["UnexpectedEof"], 1);
+        assert_eq!(check_version.failures["OffsetOutsideData"], 1);
+        assert_eq!(check_version.average_decoded_size(), Some(28.0));
+
+        let pong = &statistics[&Opcode::C_PONG];
+        assert_eq!(pong.attempts, 1);
+        assert_eq!(pong.successes, 1);
+        assert_eq!(pong.failure_count(), 0);
+
+        assert!(!statistics.contains_key(&Opcode::C_CHECK_USERNAME));
+    }
+
+    #[test]
+    fn test_handled_opcodes() {
+        let opcodes = Message::handled_opcodes();
+        assert!(opcodes.contains(&Opcode::C_CHECK_VERSION));
+        assert!(opcodes.contains(&Opcode::C_LOGIN_ARBITER));
+        assert!(!opcodes.contains(&Opcode::UNKNOWN));
+        assert!(!opcodes.contains(&Opcode::C_ADD_FRIEND));
+    }
+
     #[test]
     fn test_unauthorized_packet_creation() -> Result<()> {
         let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
@@ -333,6 +688,101 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_request_classification() -> Result<()> {
+        let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        let local = Message::RequestLoadTopoFin {
+            connection_global_world_id: entity,
+            connection_local_world_id: entity,
+            packet: CLoadTopoFin {},
+        };
+        let account = Message::RequestGetUserList {
+            connection_global_world_id: entity,
+            account_id: AccountId(1),
+            packet: CGetUserList {},
+        };
+        let global = Message::RequestPong {
+            connection_global_world_id: entity,
+            packet: CPong {},
+        };
+
+        for message in &[local, account, global] {
+            assert!(message.is_request());
+            assert!(!message.is_response());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_classification() -> Result<()> {
+        let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        let global = Message::ResponseCheckVersion {
+            connection_global_world_id: entity,
+            packet: SCheckVersion { ok: true },
+        };
+        let account = Message::ResponseLoginArbiter {
+            connection_global_world_id: entity,
+            account_id: AccountId(1),
+            packet: SLoginArbiter {
+                success: true,
+                login_queue: false,
+                status: 0,
+                unk1: 0,
+                region: Region::Europe,
+                pvp_disabled: false,
+                unk2: 0,
+                unk3: 0,
+            },
+        };
+
+        for message in &[global, account] {
+            assert!(message.is_response());
+            assert!(!message.is_request());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_message_classification() -> Result<()> {
+        let (connection_channel, _) = channel(1);
+        let org = Message::RegisterConnection {
+            connection_channel,
+            peer_addr: "127.0.0.1:10001".parse().unwrap(),
+        };
+
+        assert!(!org.is_request());
+        assert!(!org.is_response());
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_write_data_into() -> Result<()> {
+        let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        let org = Message::ResponseLoginArbiter {
+            connection_global_world_id: entity,
+            account_id: AccountId(1),
+            packet: SLoginArbiter::accepted(Region::Europe),
+        };
+
+        let mut buf = Vec::with_capacity(1024);
+        let ptr = buf.as_ptr();
+        for min_length in &[0, 128] {
+            assert!(org.write_data_into(&mut buf, *min_length, None)?);
+            assert_eq!(Some(buf.clone()), org.data(*min_length)?);
+        }
+        assert_eq!(buf.len(), 128);
+        assert_eq!(buf.capacity(), 1024);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        let (connection_channel, _) = channel(1);
+        let special = Message::RegisterConnection {
+            connection_channel,
+            peer_addr: "127.0.0.1:10001".parse().unwrap(),
+        };
+        assert!(!special.write_data_into(&mut buf, 0, None)?);
+        Ok(())
+    }
+
     #[test]
     fn test_message_opcode_some() -> Result<()> {
         let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
@@ -347,7 +797,10 @@ mod tests {
     #[test]
     fn test_message_opcode_none() -> Result<()> {
         let (connection_channel, _) = channel(1);
-        let org = Message::RegisterConnection { connection_channel };
+        let org = Message::RegisterConnection {
+            connection_channel,
+            peer_addr: "127.0.0.1:10001".parse().unwrap(),
+        };
 
         assert_eq!(org.opcode(), None);
         Ok(())
@@ -367,8 +820,32 @@ mod tests {
     #[test]
     fn test_message_register_connection_connection_id_should_panic() {
         let (connection_channel, _) = channel(1);
-        let org = Message::RegisterConnection { connection_channel };
+        let org = Message::RegisterConnection {
+            connection_channel,
+            peer_addr: "127.0.0.1:10001".parse().unwrap(),
+        };
 
         assert_eq!(org.connection_id(), None);
     }
+
+    #[test]
+    fn test_opcode_category() {
+        assert_eq!(
+            Message::opcode_category(Opcode::C_CHECK_VERSION),
+            Some(OpcodeCategory::Auth)
+        );
+        assert_eq!(
+            Message::opcode_category(Opcode::C_GET_USER_LIST),
+            Some(OpcodeCategory::CharacterManagement)
+        );
+        assert_eq!(
+            Message::opcode_category(Opcode::C_PONG),
+            Some(OpcodeCategory::Keepalive)
+        );
+        assert_eq!(
+            Message::opcode_category(Opcode::C_LOAD_TOPO_FIN),
+            Some(OpcodeCategory::Gameplay)
+        );
+        assert_eq!(Message::opcode_category(Opcode::UNKNOWN), None);
+    }
 }
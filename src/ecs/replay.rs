@@ -0,0 +1,345 @@
+/// Replays transcripts of a client session against the current systems of the global world. The
+/// packets the systems respond with are compared to the responses of the transcript, so changes
+/// of the handshake behaviour show up as test failures.
+///
+/// A transcript is a YAML file with the packets in the order they were sent:
+///
+/// ```yaml
+/// volatile-fields:
+///   S_LOGIN_ACCOUNT_INFO: [account_id]
+/// entries:
+///   - direction: request
+///     opcode: C_CHECK_VERSION
+///     data: "0200080008001400..."
+///   - direction: response
+///     opcode: S_CHECK_VERSION
+///     data: "01"
+/// ```
+///
+/// `data` is the packet body (without the frame header) as a hex string. Volatile fields differ
+/// between two runs (like IDs or timestamps) and are not compared. They are named by their path
+/// inside the packet and need to be fixed size fields.
+use crate::ecs::component::GlobalConnection;
+use crate::ecs::message::{EcsMessage, Message};
+use crate::ecs::system::common::cleaner_system;
+use crate::ecs::system::global::{connection_manager_system, setup_world};
+use crate::model::repository::loginticket::TicketValidator;
+use crate::model::AccountId;
+use crate::protocol::opcode::Opcode;
+use crate::Result;
+use anyhow::{bail, ensure, Context};
+use async_std::sync::{channel, Receiver};
+use serde::Deserialize;
+use shipyard::*;
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+/// The packets of a client session.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Transcript {
+    /// Fields of the responses that are not compared, by the opcode of the response.
+    #[serde(alias = "volatile-fields", default)]
+    pub volatile_fields: HashMap<Opcode, Vec<String>>,
+    pub entries: Vec<TranscriptEntry>,
+}
+
+/// A packet of a transcript.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TranscriptEntry {
+    pub direction: Direction,
+    pub opcode: Opcode,
+    /// The packet body as a hex string.
+    pub data: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// Sent by the client.
+    Request,
+    /// Sent by the server.
+    Response,
+}
+
+impl Transcript {
+    pub fn from_yaml(yaml: &str) -> Result<Transcript> {
+        Ok(serde_yaml::from_str(yaml)?)
+    }
+}
+
+impl TranscriptEntry {
+    fn data(&self) -> Result<Vec<u8>> {
+        hex::decode(&self.data).context(format!("Data of {:?} is not valid hex", self.opcode))
+    }
+}
+
+/// Drives the connection manager of a global world with the requests of a transcript.
+pub struct Replay {
+    pub world: World,
+    connection_global_world_id: EntityId,
+    rx_channel: Receiver<EcsMessage>,
+    account_id: Option<AccountId>,
+}
+
+impl Replay {
+    /// Creates a global world with a new connection. The login tickets are validated by the
+    /// given validator.
+    pub fn new(ticket_validator: Box<dyn TicketValidator>) -> Self {
+        let world = setup_world(ticket_validator);
+
+        let (tx_channel, rx_channel) = channel(1024);
+        let connection_global_world_id = world.run(
+            |mut entities: EntitiesViewMut, mut connections: ViewMut<GlobalConnection>| {
+                entities.add_entity(
+                    &mut connections,
+                    GlobalConnection {
+                        channel: tx_channel,
+                        is_version_checked: false,
+                        is_authenticated: false,
+                        last_pong: Instant::now(),
+                        waiting_for_pong: false,
+                        peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                        connected_since: Instant::now(),
+                        schema_version: None,
+                    },
+                )
+            },
+        );
+
+        Replay {
+            world,
+            connection_global_world_id,
+            rx_channel,
+            account_id: None,
+        }
+    }
+
+    /// Sends the requests of the transcript in order and checks that the responses of the world
+    /// match the responses of the transcript. Every response has to be in the transcript.
+    pub fn run(&mut self, transcript: &Transcript) -> Result<()> {
+        let mut responses = VecDeque::new();
+        for (index, entry) in transcript.entries.iter().enumerate() {
+            match entry.direction {
+                Direction::Request => {
+                    self.send_request(entry)
+                        .context(format!("Can't replay entry {}", index))?;
+                    self.collect_responses(&mut responses)?;
+                }
+                Direction::Response => {
+                    let (opcode, data) = match responses.pop_front() {
+                        Some(response) => response,
+                        None => bail!(
+                            "Entry {}: expected {:?} but got nothing",
+                            index,
+                            entry.opcode
+                        ),
+                    };
+                    ensure!(
+                        opcode == entry.opcode,
+                        "Entry {}: expected {:?} but got {:?}",
+                        index,
+                        entry.opcode,
+                        opcode
+                    );
+                    let volatile_fields = transcript
+                        .volatile_fields
+                        .get(&opcode)
+                        .map(Vec::as_slice)
+                        .unwrap_or(&[]);
+                    compare_packet(opcode, &entry.data()?, &data, volatile_fields)
+                        .context(format!("Entry {} doesn't match", index))?;
+                }
+            }
+        }
+        if let Some((opcode, _)) = responses.front() {
+            bail!(
+                "{} responses are not in the transcript, starting with {:?}",
+                responses.len(),
+                opcode
+            );
+        }
+        Ok(())
+    }
+
+    fn send_request(&mut self, entry: &TranscriptEntry) -> Result<()> {
+        let message = Message::new_from_packet(
+            self.connection_global_world_id,
+            None,
+            self.account_id,
+            None,
+            entry.opcode,
+            entry.data()?,
+        )?;
+        self.world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(&mut messages, EcsMessage::new(message));
+            },
+        );
+        self.world.run(connection_manager_system);
+        self.world.run(cleaner_system);
+        Ok(())
+    }
+
+    /// Collects the packets the world sent to the connection like the game session would
+    /// write them.
+    fn collect_responses(&mut self, responses: &mut VecDeque<(Opcode, Vec<u8>)>) -> Result<()> {
+        while let Ok(message) = self.rx_channel.try_recv() {
+            let messages = match &*message {
+                Message::ResponseBatch { messages, .. } => messages.clone(),
+                _ => vec![message],
+            };
+            for message in messages {
+                if let Message::ResponseLoginArbiter { account_id, .. } = &*message {
+                    self.account_id = Some(*account_id);
+                }
+                if let (Some(opcode), Some(data)) = (message.opcode(), message.data(0)?) {
+                    responses.push_back((opcode, data));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares the data of two packets. The bytes of the volatile fields are skipped. The layout
+/// of the fields is taken from the expected packet.
+pub fn compare_packet(
+    opcode: Opcode,
+    expected: &[u8],
+    actual: &[u8],
+    volatile_fields: &[String],
+) -> Result<()> {
+    ensure!(
+        expected.len() == actual.len(),
+        "{:?} has {} bytes, but {} were expected",
+        opcode,
+        actual.len(),
+        expected.len()
+    );
+
+    let mut skipped = vec![false; expected.len()];
+    if !volatile_fields.is_empty() {
+        for field in Message::packet_layout(opcode, expected.to_vec())? {
+            let is_volatile = volatile_fields
+                .iter()
+                .any(|name| field.name == *name || field.name.starts_with(&format!("{}.", name)));
+            if is_volatile {
+                let end = (field.offset + field.size).min(skipped.len());
+                for byte in skipped[field.offset.min(end)..end].iter_mut() {
+                    *byte = true;
+                }
+            }
+        }
+    }
+
+    for (i, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        ensure!(
+            skipped[i] || e == a,
+            "{:?} differs at byte {}: expected {:#04x} but got {:#04x}",
+            opcode,
+            i,
+            e,
+            a
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::repository::loginticket::TicketValidation;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_compare_packet_skips_volatile_fields() -> Result<()> {
+        let expected =
+            hex::decode("120001000000000000000000000041006c006d0065007400690063006100000000")?;
+        let mut actual = expected.clone();
+        // account_id
+        actual[2] = 0x07;
+        assert!(compare_packet(Opcode::S_LOGIN_ACCOUNT_INFO, &expected, &actual, &[]).is_err());
+        compare_packet(
+            Opcode::S_LOGIN_ACCOUNT_INFO,
+            &expected,
+            &actual,
+            &["account_id".to_string()],
+        )?;
+
+        // integrity_iv
+        actual[10] = 0x01;
+        assert!(compare_packet(
+            Opcode::S_LOGIN_ACCOUNT_INFO,
+            &expected,
+            &actual,
+            &["account_id".to_string()],
+        )
+        .is_err());
+
+        assert!(
+            compare_packet(Opcode::S_LOGIN_ACCOUNT_INFO, &expected, &actual[1..], &[]).is_err()
+        );
+        Ok(())
+    }
+
+    /// Accepts one ticket of an account once, like the database does after the ticket was
+    /// issued.
+    struct SingleTicketValidator {
+        account_name: String,
+        ticket: Vec<u8>,
+        consumed: AtomicBool,
+    }
+
+    impl TicketValidator for SingleTicketValidator {
+        fn validate(
+            &self,
+            account_name: &str,
+            ticket: Option<&[u8]>,
+            is_in_use: &dyn Fn(AccountId) -> bool,
+        ) -> Result<TicketValidation> {
+            if account_name != self.account_name
+                || ticket != Some(self.ticket.as_slice())
+                || self.consumed.load(Ordering::SeqCst)
+            {
+                return Ok(TicketValidation::Invalid);
+            }
+            if is_in_use(AccountId(1)) {
+                return Ok(TicketValidation::AccountInUse);
+            }
+            self.consumed.store(true, Ordering::SeqCst);
+            Ok(TicketValidation::Valid(AccountId(1)))
+        }
+    }
+
+    /// Creates a replay that accepts the given ticket of the account of the transcript.
+    fn ticket_replay(ticket: &[u8]) -> Replay {
+        Replay::new(Box::new(SingleTicketValidator {
+            account_name: "royalBush5915".to_string(),
+            ticket: ticket.to_vec(),
+            consumed: AtomicBool::new(false),
+        }))
+    }
+
+    const TICKET: &[u8] = b"OScGKtmr3sngb418rFnHEDWMTrYSbHa280jveZtCeG7T7pXv7H";
+
+    #[test]
+    fn test_handshake_transcript() -> Result<()> {
+        let transcript = Transcript::from_yaml(include_str!("replay/handshake.yaml"))?;
+        ticket_replay(TICKET).run(&transcript)
+    }
+
+    #[test]
+    fn test_handshake_transcript_with_invalid_ticket() -> Result<()> {
+        let transcript = Transcript::from_yaml(include_str!("replay/handshake.yaml"))?;
+        assert!(ticket_replay(b"another-ticket").run(&transcript).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_missing_in_transcript() -> Result<()> {
+        let mut transcript = Transcript::from_yaml(include_str!("replay/handshake.yaml"))?;
+        transcript.entries.pop();
+        assert!(ticket_replay(TICKET).run(&transcript).is_err());
+        Ok(())
+    }
+}
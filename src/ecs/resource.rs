@@ -1,7 +1,41 @@
 /// Module that hold the definitions for Resources used by the ECS.
 use crate::ecs::message::EcsMessage;
+use crate::ecs::system::HandlerOutcome;
+use crate::model::{AccountId, Region};
+use crate::protocol::framing;
+use crate::protocol::opcode::Opcode;
+use crate::protocol::packet::SLoginArbiter;
+use crate::protocol::serde::SchemaVersion;
 use async_std::sync::{Receiver, Sender};
+use rand::rngs::{OsRng, StdRng};
+use rand::{RngCore, SeedableRng};
+use serde::Deserialize;
 use shipyard::EntityId;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Source of the time of a world. The tests replace it, so they don't depend on the real time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+    /// Blocks the world for the given duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// Clock that uses the time of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
 
 /// Holds the Receiver channel of a world.
 pub struct InputChannel {
@@ -13,17 +47,507 @@ pub struct GlobalMessageChannel {
     pub channel: Sender<EcsMessage>,
 }
 
+/// Holds the opcodes of the request messages that are processed before all other messages of a
+/// tick. Keeps control packets like pongs responsive while a world is flooded with requests.
+#[derive(Clone, Debug, Default)]
+pub struct PriorityOpcodes(pub HashSet<Opcode>);
+
+impl PriorityOpcodes {
+    /// Returns true if the message needs to be processed first.
+    pub fn is_prioritized(&self, message: &EcsMessage) -> bool {
+        message
+            .opcode()
+            .map_or(false, |opcode| self.0.contains(&opcode))
+    }
+}
+
 /// Holds a list with EntityIds marked for deletion.
 #[derive(Clone)]
 pub struct DeletionList(pub Vec<EntityId>);
 
+/// Holds the resume tokens of the logged in accounts. A token is issued and sent to the client
+/// on the login. Once the connection of the account is gone, the client can present it in place
+/// of its login ticket inside the lifetime of the token to skip the ticket verification.
+#[derive(Clone, Debug, Default)]
+pub struct ResumeTokens {
+    pub tokens: HashMap<Vec<u8>, ResumeToken>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResumeToken {
+    pub account_id: AccountId,
+    /// Not set while the account is still connected.
+    pub valid_until: Option<Instant>,
+}
+
+impl ResumeTokens {
+    /// Issues a new random resume token for the given account and returns it. The older tokens
+    /// of the account are invalidated.
+    pub fn issue(&mut self, account_id: AccountId) -> [u8; 32] {
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        self.tokens.retain(|_, t| t.account_id != account_id);
+        self.tokens.insert(
+            token.to_vec(),
+            ResumeToken {
+                account_id,
+                valid_until: None,
+            },
+        );
+        token
+    }
+
+    /// Starts the lifetime of the tokens whose account is no longer connected.
+    pub fn start_lifetime(
+        &mut self,
+        is_connected: impl Fn(AccountId) -> bool,
+        lifetime: Duration,
+        now: Instant,
+    ) {
+        for token in self.tokens.values_mut() {
+            if token.valid_until.is_none() && !is_connected(token.account_id) {
+                token.valid_until = Some(now + lifetime);
+            }
+        }
+    }
+
+    /// Returns the account ID the token was issued for. Expired tokens are removed.
+    pub fn account_id(&mut self, token: &[u8], now: Instant) -> Option<AccountId> {
+        self.tokens
+            .retain(|_, t| t.valid_until.map_or(true, |valid_until| valid_until > now));
+        self.tokens.get(token).map(|t| t.account_id)
+    }
+
+    /// Invalidates a token, so it can only be redeemed once.
+    pub fn invalidate(&mut self, token: &[u8]) {
+        self.tokens.remove(token);
+    }
+}
+
+/// The client versions that pass the version check. A clone shares the set with the original, so
+/// the set can be replaced at runtime (for example when a new client patch is allowed). Every
+/// version is allowed if the set is empty.
+#[derive(Clone, Debug, Default)]
+pub struct AllowedVersions(Arc<RwLock<HashSet<(i32, i32)>>>);
+
+impl AllowedVersions {
+    pub fn new(versions: impl IntoIterator<Item = (i32, i32)>) -> Self {
+        AllowedVersions(Arc::new(RwLock::new(versions.into_iter().collect())))
+    }
+
+    /// Returns true if the version with the values of index 0 and 1 is allowed.
+    pub fn is_allowed(&self, version: (i32, i32)) -> bool {
+        let versions = self.0.read().unwrap_or_else(|e| e.into_inner());
+        versions.is_empty() || versions.contains(&version)
+    }
+
+    /// Replaces the allowed versions. Version checks that run afterwards use the new set.
+    pub fn replace(&self, versions: impl IntoIterator<Item = (i32, i32)>) {
+        let versions = versions.into_iter().collect();
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = versions;
+    }
+}
+
+/// Defines which versions and tickets are accepted and what is sent to the client after the
+/// login.
+#[derive(Clone, Debug)]
+pub struct LoginTicketPolicy {
+    /// Message of the day that is send after the login. An empty message isn't send.
+    pub motd: String,
+    /// How long a new connection has to check it's version before it's dropped.
+    pub version_check_grace: Duration,
+    /// Packets that are send in order after the login.
+    pub post_login_sequence: Vec<PostLoginPacket>,
+    /// Accepts every version and login ticket. Only used for local development.
+    pub skip_login_checks: bool,
+    /// Client versions that pass the version check.
+    pub allowed_versions: AllowedVersions,
+    /// Unknown fields of the accepted login arbiter by the schema version they apply from.
+    pub login_arbiter_fields: BTreeMap<SchemaVersion, LoginArbiterFields>,
+}
+
+impl LoginTicketPolicy {
+    /// Unknown fields of the accepted login arbiter for a client with the given schema version.
+    /// The entry of the newest version up to the version of the client is used. Like the packet
+    /// layouts, a client without a negotiated schema version gets the newest entry.
+    pub fn login_arbiter_fields(
+        &self,
+        schema_version: Option<SchemaVersion>,
+    ) -> LoginArbiterFields {
+        let entry = match schema_version {
+            Some(schema_version) => self
+                .login_arbiter_fields
+                .range(..=schema_version)
+                .next_back(),
+            None => self.login_arbiter_fields.iter().next_back(),
+        };
+        entry.map(|(_, fields)| *fields).unwrap_or_default()
+    }
+}
+
+impl Default for LoginTicketPolicy {
+    fn default() -> Self {
+        LoginTicketPolicy {
+            motd: String::new(),
+            version_check_grace: Duration::from_secs(5),
+            post_login_sequence: PostLoginPacket::default_sequence(),
+            skip_login_checks: false,
+            allowed_versions: AllowedVersions::default(),
+            login_arbiter_fields: BTreeMap::new(),
+        }
+    }
+}
+
+/// Fields of an accepted `S_LOGIN_ARBITER` whose meaning is unknown. Client patches expect
+/// different values in them.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LoginArbiterFields {
+    pub status: i32,
+    pub unk1: u32,
+    pub unk2: u16,
+    pub unk3: u16,
+}
+
+impl Default for LoginArbiterFields {
+    /// The fields of `SLoginArbiter::accepted`.
+    fn default() -> Self {
+        let packet = SLoginArbiter::accepted(Region::default());
+        LoginArbiterFields {
+            status: packet.status,
+            unk1: packet.unk1,
+            unk2: packet.unk2,
+            unk3: packet.unk3,
+        }
+    }
+}
+
+/// A packet of the post login sequence. The payload is taken from the account of the connection
+/// and the login ticket policy.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostLoginPacket {
+    CheckVersion,
+    LoadingScreenControlInfo,
+    RemainPlayTime,
+    LoginArbiter,
+    LoginAccountInfo,
+    /// Only send if a message of the day is configured.
+    Motd,
+    /// Only received by clients whose opcode mapping knows the packet.
+    ResumeToken,
+}
+
+impl PostLoginPacket {
+    /// The sequence the client expects after the login.
+    pub fn default_sequence() -> Vec<PostLoginPacket> {
+        vec![
+            PostLoginPacket::CheckVersion,
+            PostLoginPacket::LoadingScreenControlInfo,
+            PostLoginPacket::RemainPlayTime,
+            PostLoginPacket::LoginArbiter,
+            PostLoginPacket::LoginAccountInfo,
+            PostLoginPacket::Motd,
+            PostLoginPacket::ResumeToken,
+        ]
+    }
+}
+
+/// Settings of the paged user list. A page is closed once it holds `page_size` users or the
+/// next user would push the packet over `max_body_length`.
+#[derive(Clone, Debug)]
+pub struct UserListSettings {
+    /// Maximal number of users in one `S_GET_USER_LIST` packet.
+    pub page_size: usize,
+    /// Maximal body length of one `S_GET_USER_LIST` packet in bytes.
+    pub max_body_length: usize,
+}
+
+impl Default for UserListSettings {
+    fn default() -> Self {
+        UserListSettings {
+            page_size: 5,
+            max_body_length: framing::MAX_BODY_LENGTH,
+        }
+    }
+}
+
+/// Queues the users that can be spawned. Only the budget of spawns is processed per tick, so
+/// that many users entering a zone at once don't cause a latency spike.
+#[derive(Clone, Debug)]
+pub struct SpawnQueue {
+    pub budget: usize,
+    queue: VecDeque<EntityId>,
+}
+
+impl SpawnQueue {
+    pub fn new(budget: usize) -> Self {
+        SpawnQueue {
+            budget,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Queues a spawn. A spawn that is already queued keeps it's position.
+    pub fn push(&mut self, connection_global_world_id: EntityId) {
+        if !self.queue.contains(&connection_global_world_id) {
+            self.queue.push_back(connection_global_world_id);
+        }
+    }
+
+    /// Takes the spawns that are processed in this tick in the order they were queued.
+    pub fn next_batch(&mut self) -> Vec<EntityId> {
+        let count = self.budget.min(self.queue.len());
+        self.queue.drain(..count).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl Default for SpawnQueue {
+    fn default() -> Self {
+        SpawnQueue::new(16)
+    }
+}
+
+/// Order in which the unauthenticated connections are evicted once the number of connections
+/// exceeds the soft limit.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Connections that made no progress in the handshake for the longest time go first.
+    LeastRecentlyActive,
+    /// The newest connections go first, so a flood of new connections can't push out clients
+    /// that are already in the handshake.
+    Newest,
+}
+
+/// Settings of the connection eviction. Authenticated connections are never evicted.
+#[derive(Clone, Debug)]
+pub struct EvictionSettings {
+    /// Number of connections above which connections are evicted. 0 disables the eviction.
+    pub connection_soft_limit: usize,
+    pub policy: EvictionPolicy,
+}
+
+impl Default for EvictionSettings {
+    fn default() -> Self {
+        EvictionSettings {
+            connection_soft_limit: 0,
+            policy: EvictionPolicy::LeastRecentlyActive,
+        }
+    }
+}
+
+/// Upper bounds of the latency histogram buckets in microseconds. The last bucket holds
+/// everything that took longer.
+pub const LATENCY_BUCKET_BOUNDS_US: [u64; 9] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+/// Holds the latency histograms and the outcome counts of the request handlers keyed by the
+/// opcode of the request. Measuring is skipped if the histograms are not enabled. Outcomes are
+/// always counted.
+#[derive(Clone, Debug, Default)]
+pub struct HandlerLatencies {
+    pub enabled: bool,
+    pub histograms: HashMap<Opcode, LatencyHistogram>,
+    pub outcomes: HashMap<Opcode, OutcomeCounts>,
+}
+
+/// Number of requests per handler outcome.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutcomeCounts {
+    pub handled: u64,
+    pub rejected: u64,
+    pub deferred: u64,
+    /// Requests that failed because the storage backend was unavailable.
+    pub storage_failures: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; 10], // One bucket per bound and one overflow bucket
+    pub count: u64,
+    pub total: Duration,
+}
+
+impl HandlerLatencies {
+    pub fn new(enabled: bool) -> Self {
+        HandlerLatencies {
+            enabled,
+            histograms: HashMap::new(),
+            outcomes: HashMap::new(),
+        }
+    }
+
+    /// Runs the handler and records how long it took under the given opcode.
+    pub fn time<F, R>(&mut self, opcode: Opcode, handler: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        if !self.enabled {
+            return handler();
+        }
+        let start = Instant::now();
+        let result = handler();
+        self.record(opcode, start.elapsed());
+        result
+    }
+
+    pub fn record(&mut self, opcode: Opcode, latency: Duration) {
+        self.histograms
+            .entry(opcode)
+            .or_insert_with(LatencyHistogram::default)
+            .record(latency);
+    }
+
+    pub fn count(&mut self, opcode: Opcode, outcome: &HandlerOutcome) {
+        let counts = self.outcomes.entry(opcode).or_default();
+        match outcome {
+            HandlerOutcome::Handled => counts.handled += 1,
+            HandlerOutcome::Rejected(..) => counts.rejected += 1,
+            HandlerOutcome::Deferred => counts.deferred += 1,
+            HandlerOutcome::StorageUnavailable => counts.storage_failures += 1,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = LATENCY_BUCKET_BOUNDS_US
+            .iter()
+            .position(|bound| micros <= *bound as u128)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS_US.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+    }
+}
+
+/// Random number generator of a world. Systems draw their randomness from it instead of
+/// `thread_rng()`, so that tests can fix the seed and get reproducible results.
+pub struct WorldRng(StdRng);
+
+impl WorldRng {
+    /// Creates a generator with the given seed. Without a seed the generator is seeded from entropy.
+    pub fn new(seed: Option<u64>) -> Self {
+        match seed {
+            Some(seed) => WorldRng(StdRng::seed_from_u64(seed)),
+            None => WorldRng(StdRng::from_entropy()),
+        }
+    }
+}
+
+impl RngCore for WorldRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
 pub struct ShutdownSignal {
     pub status: ShutdownSignalStatus,
 }
 
+/// Keeps the network server accepting new connections. The channel is closed once the shutdown
+/// started, which stops the accept loop. Worlds without a network server have no channel.
+pub struct AcceptSignal {
+    pub channel: Option<Sender<()>>,
+}
+
 #[derive(PartialEq)]
 pub enum ShutdownSignalStatus {
     Operational,
     ShutdownInProgress,
     Shutdown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use shipyard::{EntitiesViewMut, UniqueViewMut, World};
+    use std::thread;
+
+    #[test]
+    fn test_spawn_queue_budget() {
+        let world = World::new();
+        let ids: Vec<EntityId> = (0..5)
+            .map(|_| world.borrow::<EntitiesViewMut>().add_entity((), ()))
+            .collect();
+
+        let mut queue = SpawnQueue::new(2);
+        for id in &ids {
+            queue.push(*id);
+        }
+        // Queuing a spawn twice doesn't change it's position.
+        queue.push(ids[0]);
+        assert_eq!(queue.len(), 5);
+
+        assert_eq!(queue.next_batch(), ids[0..2].to_vec());
+        assert_eq!(queue.next_batch(), ids[2..4].to_vec());
+        assert_eq!(queue.next_batch(), ids[4..5].to_vec());
+        assert!(queue.next_batch().is_empty());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_world_rng_is_reproducible() {
+        let draw = |seed: Option<u64>| {
+            let world = World::new();
+            world.add_unique(WorldRng::new(seed));
+            world.run(|mut rng: UniqueViewMut<WorldRng>| {
+                (0..16)
+                    .map(|_| rng.gen_range(0, 1000))
+                    .collect::<Vec<u32>>()
+            })
+        };
+
+        assert_eq!(draw(Some(42)), draw(Some(42)));
+        assert_ne!(draw(Some(42)), draw(Some(43)));
+    }
+
+    #[test]
+    fn test_slow_handler_lands_in_histogram() {
+        let mut latencies = HandlerLatencies::new(true);
+
+        let value = latencies.time(Opcode::C_LOGIN_ARBITER, || {
+            thread::sleep(Duration::from_millis(20));
+            42
+        });
+        assert_eq!(value, 42);
+
+        let histogram = &latencies.histograms[&Opcode::C_LOGIN_ARBITER];
+        assert_eq!(histogram.count, 1);
+        assert!(histogram.total >= Duration::from_millis(20));
+        // 20ms is above the 10ms bound, so the sample can't be in one of the first five buckets.
+        assert_eq!(histogram.buckets[..5].iter().sum::<u64>(), 0);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn test_disabled_latencies_are_not_recorded() {
+        let mut latencies = HandlerLatencies::new(false);
+        latencies.time(Opcode::C_LOGIN_ARBITER, || {});
+        assert!(latencies.histograms.is_empty());
+    }
+}
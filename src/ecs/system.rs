@@ -11,16 +11,35 @@ macro_rules! id_span {
         let span = info_span!("id", $v = ?$v);
         let _enter = span.enter();
     );
+    ($v:ident, $a:ident) => (
+        let span = info_span!("id", $v = ?$v, $a = %$a);
+        let _enter = span.enter();
+    );
 }
 
 pub mod common;
 pub mod global;
 pub mod local;
 
+/// Outcome of a request handler. The system that dispatched the request acts on it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HandlerOutcome {
+    /// The request was processed.
+    Handled,
+    /// The request was refused. Holds the reason.
+    Rejected(String),
+    /// The request can't be processed right now, but the client may retry it later.
+    Deferred,
+    /// The storage backend failed, so the request couldn't be processed. The client may retry it
+    /// later.
+    StorageUnavailable,
+}
+
 /// Send a message using the given channel.
 pub fn send_message(message: EcsMessage, channel: &Sender<EcsMessage>) {
     debug!("Sending outgoing {}", message);
-    trace!("Message data: {:?}", message);
+    trace!("Message origin: {:?}", message.origin);
+    trace!("Message data: {:?}", message.inner);
     match channel.try_send(message) {
         Ok(..) => {}
         Err(TrySendError::Full(..)) => {
@@ -57,7 +57,7 @@ mod tests {
                 for _i in 0..10 {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestPong {
+                        EcsMessage::new(Message::RequestPong {
                             connection_global_world_id,
                             packet: CPong {},
                         }),
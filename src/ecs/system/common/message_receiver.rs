@@ -1,5 +1,5 @@
 use crate::ecs::message::{EcsMessage, Message};
-use crate::ecs::resource::{InputChannel, ShutdownSignal, ShutdownSignalStatus};
+use crate::ecs::resource::{InputChannel, PriorityOpcodes, ShutdownSignal, ShutdownSignalStatus};
 use async_std::sync::TryRecvError;
 use shipyard::*;
 use tracing::{debug, info, trace};
@@ -7,24 +7,23 @@ use tracing::{debug, info, trace};
 // TODO test the setting of ShutdownSignalStatus::ShutdownInProgress
 
 /// Message receiver dispatches the messages from the request channel into the ECS.
+/// Messages with a priority opcode are dispatched first, so the systems process them first.
 pub fn message_receiver_system(
     mut incoming_messages: ViewMut<EcsMessage>,
     mut entities: EntitiesViewMut,
     message_channel: UniqueView<InputChannel>,
+    priority_opcodes: UniqueView<PriorityOpcodes>,
     mut shutdown: UniqueViewMut<ShutdownSignal>,
 ) {
+    let mut received = Vec::new();
     loop {
         match message_channel.channel.try_recv() {
-            Ok(message) => match *message {
+            Ok(message) => match *message.inner {
                 Message::ShutdownSignal { .. } => {
                     info!("Setting shutdown signal to status ShutdownSignalStatus::ShutdownInProgress");
                     shutdown.status = ShutdownSignalStatus::ShutdownInProgress;
                 }
-                _ => {
-                    debug!("Created incoming {}", message);
-                    trace!("Message data: {:?}", message);
-                    entities.add_entity(&mut incoming_messages, message);
-                }
+                _ => received.push(message),
             },
             Err(TryRecvError::Empty) => {
                 break;
@@ -32,12 +31,23 @@ pub fn message_receiver_system(
             Err(TryRecvError::Disconnected) => panic!("Message channel was disconnected"),
         };
     }
+
+    // The partition keeps the order of the messages inside of both groups.
+    let (prioritized, others): (Vec<_>, Vec<_>) = received
+        .into_iter()
+        .partition(|message| priority_opcodes.is_prioritized(message));
+    for message in prioritized.into_iter().chain(others) {
+        debug!("Created incoming {}", message);
+        trace!("Message origin: {:?}", message.origin);
+        trace!("Message data: {:?}", message.inner);
+        entities.add_entity(&mut incoming_messages, message);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ecs::message::Message;
+    use crate::ecs::message::{set_message_origin, Message, MessageOrigin};
     use crate::ecs::resource::InputChannel;
     use crate::protocol::packet::CCheckVersion;
     use crate::Result;
@@ -56,14 +66,15 @@ mod tests {
         world.add_unique(ShutdownSignal {
             status: ShutdownSignalStatus::Operational,
         });
+        world.add_unique(PriorityOpcodes::default());
 
         let entity = world.borrow::<EntitiesViewMut>().add_entity((), ());
 
-        tx_channel.try_send(Box::new(Message::RequestCheckVersion {
+        tx_channel.try_send(EcsMessage::new(Message::RequestCheckVersion {
             connection_global_world_id: entity,
             packet: CCheckVersion { version: vec![] },
         }))?;
-        tx_channel.try_send(Box::new(Message::RequestCheckVersion {
+        tx_channel.try_send(EcsMessage::new(Message::RequestCheckVersion {
             connection_global_world_id: entity,
             packet: CCheckVersion { version: vec![] },
         }))?;
@@ -75,4 +86,46 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_message_origin() -> Result<()> {
+        let world = World::new();
+
+        let (tx_channel, rx_channel) = channel(10);
+
+        world.add_unique(InputChannel {
+            channel: rx_channel,
+        });
+
+        world.add_unique(ShutdownSignal {
+            status: ShutdownSignalStatus::Operational,
+        });
+        world.add_unique(PriorityOpcodes::default());
+
+        let local_world_id = world.borrow::<EntitiesViewMut>().add_entity((), ());
+
+        // Messages are created on the thread of the local world.
+        std::thread::spawn(move || {
+            set_message_origin(Some(MessageOrigin::Local(local_world_id)));
+            tx_channel
+                .try_send(EcsMessage::new(Message::UserSpawned {
+                    connection_global_world_id: local_world_id,
+                }))
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+
+        // Messages created outside of a world have no origin.
+        let message = EcsMessage::new(Message::ShutdownSignal { forced: false });
+        assert_eq!(message.origin, None);
+
+        world.run(message_receiver_system);
+
+        let messages = world.borrow::<View<EcsMessage>>();
+        let origins: Vec<_> = messages.iter().map(|message| message.origin).collect();
+        assert_eq!(origins, vec![Some(MessageOrigin::Local(local_world_id))]);
+
+        Ok(())
+    }
 }
@@ -1,13 +1,61 @@
-use crate::ecs::resource::{ShutdownSignal, ShutdownSignalStatus};
+use crate::ecs::resource::{AcceptSignal, ShutdownSignal, ShutdownSignalStatus};
 use shipyard::*;
 use tracing::info;
 
-// TODO test the shutdown system
-
 /// Simple system that sets the finished the shutdown signal. Later ECS iteration may make this switch once they know a shutdown has been properly handled.
-pub fn shutdown_system(mut shutdown: UniqueViewMut<ShutdownSignal>) {
+/// The network server stops accepting new connections once the shutdown started.
+pub fn shutdown_system(
+    mut shutdown: UniqueViewMut<ShutdownSignal>,
+    mut accept_signal: UniqueViewMut<AcceptSignal>,
+) {
     if shutdown.status == ShutdownSignalStatus::ShutdownInProgress {
+        if accept_signal.channel.take().is_some() {
+            info!("Stopping to accept new connections");
+        }
         info!("Setting shutdown signal to status ShutdownSignalStatus::Shutdown");
         shutdown.status = ShutdownSignalStatus::Shutdown;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::{channel, Receiver};
+    use async_std::task;
+
+    fn setup(status: ShutdownSignalStatus) -> (World, Receiver<()>) {
+        let world = World::new();
+        let (tx_channel, rx_channel) = channel(1);
+        world.add_unique(ShutdownSignal { status });
+        world.add_unique(AcceptSignal {
+            channel: Some(tx_channel),
+        });
+        (world, rx_channel)
+    }
+
+    #[test]
+    fn test_operational() {
+        let (world, rx_channel) = setup(ShutdownSignalStatus::Operational);
+        world.run(shutdown_system);
+
+        assert!(
+            world.borrow::<UniqueView<ShutdownSignal>>().status
+                == ShutdownSignalStatus::Operational
+        );
+        assert!(world.borrow::<UniqueView<AcceptSignal>>().channel.is_some());
+        assert!(rx_channel.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_shutdown_stops_accepting() {
+        let (world, rx_channel) = setup(ShutdownSignalStatus::ShutdownInProgress);
+        world.run(shutdown_system);
+
+        assert!(
+            world.borrow::<UniqueView<ShutdownSignal>>().status == ShutdownSignalStatus::Shutdown
+        );
+        assert!(world.borrow::<UniqueView<AcceptSignal>>().channel.is_none());
+        // The accept loop sees the closed channel.
+        assert!(task::block_on(rx_channel.recv()).is_err());
+    }
+}
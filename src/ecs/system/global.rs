@@ -1,35 +1,87 @@
 /// All systems used by the global world
+mod admin;
+mod connection_eviction;
 mod connection_manager;
 mod local_world_manager;
 mod settings_manager;
 mod user_manager;
 mod user_spawner;
 
+pub use admin::{active_connections, kick_account, ConnectionSnapshot};
+pub use connection_eviction::connection_eviction_system;
 pub use connection_manager::connection_manager_system;
+#[cfg(test)]
+pub(crate) use connection_manager::setup_world;
 pub use local_world_manager::local_world_manager_system;
 pub use settings_manager::settings_manager_system;
 pub use user_manager::user_manager_system;
 pub use user_spawner::user_spawner_system;
 
 use crate::ecs::component::GlobalConnection;
-use crate::ecs::message::EcsMessage;
+use crate::ecs::message::{EcsMessage, Message};
 use crate::ecs::system::send_message;
+use shipyard::EntityId;
 use tracing::{debug, error};
 
 // FIXME refactor this and the local version with traits if possible. Maybe merge local and global Connection and refactor some global Connection variables into it's own Component
 
 /// Send an outgoing packet message. This function can't be used by "Special Messages".
+/// The connection keeps it's response channel when it's moved into a local world, so responses
+/// of the global world still reach it.
 pub fn send_message_to_connection<'a, T>(message: EcsMessage, connections: T)
 where
     T: shipyard::Get<Out = &'a GlobalConnection>,
 {
     if let Some(connection_id) = message.connection_id() {
-        if let Ok(connection) = connections.try_get(connection_id) {
-            send_message(message, &connection.channel);
-        } else {
-            debug!("Couldn't find user spawn: {:?}", connection_id);
-        }
+        send_message_to_connection_id(message, connection_id, connections);
     } else {
         error!("Message didn't had a global world ID attached");
     }
 }
+
+fn send_message_to_connection_id<'a, T>(
+    message: EcsMessage,
+    connection_id: EntityId,
+    connections: T,
+) where
+    T: shipyard::Get<Out = &'a GlobalConnection>,
+{
+    if let Ok(connection) = connections.try_get(connection_id) {
+        send_message(message, &connection.channel);
+    } else {
+        debug!("Couldn't find user spawn: {:?}", connection_id);
+    }
+}
+
+/// Send outgoing packet messages of the same connection as one unit. The connection writes them
+/// out back to back, so they can't be interleaved with messages from other systems or the local
+/// world. This function can't be used by "Special Messages".
+pub fn send_messages_to_connection<'a, T>(messages: Vec<EcsMessage>, connections: T)
+where
+    T: shipyard::Get<Out = &'a GlobalConnection>,
+{
+    let connection_id = match messages.first().map(|message| message.connection_id()) {
+        Some(Some(connection_id)) => connection_id,
+        Some(None) => {
+            error!("Message didn't had a global world ID attached");
+            return;
+        }
+        None => return,
+    };
+    if messages
+        .iter()
+        .any(|message| message.connection_id() != Some(connection_id))
+    {
+        error!("Messages of a batch need to have the same global world ID");
+        return;
+    }
+
+    send_message_to_connection_id(
+        EcsMessage::new(Message::ResponseBatch {
+            connection_global_world_id: connection_id,
+            messages,
+        }),
+        connection_id,
+        connections,
+    );
+}
@@ -0,0 +1,401 @@
+/// Queries and operations for the admin tooling.
+use super::connection_manager::{assemble_notice, drop_connection};
+use crate::ecs::component::{Account, GlobalConnection, GlobalUserSpawn, UserSpawnStatus};
+use crate::ecs::message::EcsMessage;
+use crate::ecs::system::send_message;
+use crate::model::{AccountId, Region};
+use crate::protocol::serde::SchemaVersion;
+use shipyard::*;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{debug, info};
+
+/// Snapshot of an active connection of the global world.
+#[derive(Clone, Debug)]
+pub struct ConnectionSnapshot {
+    pub connection_global_world_id: EntityId,
+    /// Only set once the connection is authenticated.
+    pub account_id: Option<AccountId>,
+    pub peer_addr: SocketAddr,
+    pub connected_since: Instant,
+    /// Number of responses that wait to be written to the client.
+    pub queue_depth: usize,
+}
+
+/// Returns a snapshot of all active connections. Run it with `world.run(active_connections)`.
+pub fn active_connections(
+    connections: View<GlobalConnection>,
+    accounts: View<Account>,
+) -> Vec<ConnectionSnapshot> {
+    connections
+        .iter()
+        .with_id()
+        .map(
+            |(connection_global_world_id, connection)| ConnectionSnapshot {
+                connection_global_world_id,
+                account_id: accounts
+                    .try_get(connection_global_world_id)
+                    .ok()
+                    .map(|account| account.id),
+                peer_addr: connection.peer_addr,
+                connected_since: connection.connected_since,
+                queue_depth: connection.channel.len(),
+            },
+        )
+        .collect()
+}
+
+/// Everything the global world knows about a single connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionReport {
+    pub connection_global_world_id: EntityId,
+    pub peer_addr: SocketAddr,
+    pub connected_since: Instant,
+    pub is_version_checked: bool,
+    pub is_authenticated: bool,
+    /// Only set once the connection is authenticated.
+    pub account_id: Option<AccountId>,
+    pub region: Option<Region>,
+    pub schema_version: Option<SchemaVersion>,
+    /// Only set once the user of the connection requested to be spawned.
+    pub user_spawn_status: Option<UserSpawnStatus>,
+    /// Number of responses that wait to be written to the client.
+    pub queue_depth: usize,
+    pub last_pong: Instant,
+    pub waiting_for_pong: bool,
+    /// Number of messages of the connection that the global world didn't process yet.
+    pub pending_messages: usize,
+}
+
+/// Gathers the state of the given connection. Returns `None` if the connection doesn't exist
+/// (anymore).
+pub fn describe_connection(
+    connection_global_world_id: EntityId,
+    connections: &View<GlobalConnection>,
+    accounts: &View<Account>,
+    user_spawns: &View<GlobalUserSpawn>,
+    messages: &View<EcsMessage>,
+) -> Option<ConnectionReport> {
+    let connection = connections.try_get(connection_global_world_id).ok()?;
+    let account = accounts.try_get(connection_global_world_id).ok();
+
+    Some(ConnectionReport {
+        connection_global_world_id,
+        peer_addr: connection.peer_addr,
+        connected_since: connection.connected_since,
+        is_version_checked: connection.is_version_checked,
+        is_authenticated: connection.is_authenticated,
+        account_id: account.map(|account| account.id),
+        region: account.map(|account| account.region),
+        schema_version: connection.schema_version,
+        user_spawn_status: user_spawns
+            .try_get(connection_global_world_id)
+            .ok()
+            .map(|user_spawn| user_spawn.status.clone()),
+        queue_depth: connection.channel.len(),
+        last_pong: connection.last_pong,
+        waiting_for_pong: connection.waiting_for_pong,
+        pending_messages: messages
+            .iter()
+            .filter(|message| message.connection_id() == Some(connection_global_world_id))
+            .count(),
+    })
+}
+
+/// Shows the reason to the client, then drops the connection of the given account and removes
+/// its components. Returns the ID of the dropped connection or `None` if the account isn't
+/// connected.
+pub fn kick_account(
+    account_id: AccountId,
+    reason: &str,
+    accounts: &mut ViewMut<Account>,
+    connections: &mut ViewMut<GlobalConnection>,
+    user_spawns: &mut ViewMut<GlobalUserSpawn>,
+) -> Option<EntityId> {
+    let connection_global_world_id = (&*accounts)
+        .iter()
+        .with_id()
+        .find(|(_, account)| account.id == account_id)
+        .map(|(connection_global_world_id, _)| connection_global_world_id);
+
+    match connection_global_world_id {
+        Some(connection_global_world_id) => {
+            info!(
+                "Kicking account {} from connection {:?}: {}",
+                account_id, connection_global_world_id, reason
+            );
+            // The connection writes the notice before it handles the drop.
+            if let Ok(connection) = (&*connections).try_get(connection_global_world_id) {
+                send_message(
+                    assemble_notice(connection_global_world_id, reason.to_string()),
+                    &connection.channel,
+                );
+            }
+            drop_connection(
+                connection_global_world_id,
+                accounts,
+                connections,
+                user_spawns,
+            );
+            Some(connection_global_world_id)
+        }
+        None => {
+            debug!("Can't kick account {}. It's not connected", account_id);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::message::Message;
+    use crate::model::UserId;
+    use crate::protocol::packet::CPong;
+    use async_std::sync::{channel, Receiver};
+
+    fn add_connection(world: &World, peer_addr: &str) -> (EntityId, Receiver<EcsMessage>) {
+        let (tx_channel, rx_channel) = channel(1024);
+        let connection_global_world_id = world.run(
+            |mut entities: EntitiesViewMut, mut connections: ViewMut<GlobalConnection>| {
+                entities.add_entity(
+                    &mut connections,
+                    GlobalConnection {
+                        channel: tx_channel,
+                        is_version_checked: true,
+                        is_authenticated: false,
+                        last_pong: Instant::now(),
+                        waiting_for_pong: false,
+                        peer_addr: peer_addr.parse().unwrap(),
+                        connected_since: Instant::now(),
+                        schema_version: None,
+                    },
+                )
+            },
+        );
+        (connection_global_world_id, rx_channel)
+    }
+
+    #[test]
+    fn test_active_connections() {
+        let world = World::new();
+        let (first_id, _first_rx) = add_connection(&world, "127.0.0.1:40001");
+        let (second_id, _second_rx) = add_connection(&world, "127.0.0.2:40002");
+
+        world.run(|entities: EntitiesView, mut accounts: ViewMut<Account>| {
+            entities.add_component(
+                &mut accounts,
+                Account {
+                    id: AccountId(7),
+                    region: Region::Germany,
+                },
+                second_id,
+            );
+        });
+
+        let mut snapshot = world.run(active_connections);
+        snapshot.sort_by_key(|connection| connection.peer_addr);
+
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].connection_global_world_id, first_id);
+        assert_eq!(snapshot[0].account_id, None);
+        assert_eq!(
+            snapshot[0].peer_addr,
+            "127.0.0.1:40001".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(snapshot[0].queue_depth, 0);
+        assert_eq!(snapshot[1].connection_global_world_id, second_id);
+        assert_eq!(snapshot[1].account_id, Some(AccountId(7)));
+        assert_eq!(
+            snapshot[1].peer_addr,
+            "127.0.0.2:40002".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    fn describe(world: &World, connection_global_world_id: EntityId) -> Option<ConnectionReport> {
+        world.run(
+            |connections: View<GlobalConnection>,
+             accounts: View<Account>,
+             user_spawns: View<GlobalUserSpawn>,
+             messages: View<EcsMessage>| {
+                describe_connection(
+                    connection_global_world_id,
+                    &connections,
+                    &accounts,
+                    &user_spawns,
+                    &messages,
+                )
+            },
+        )
+    }
+
+    #[test]
+    fn test_describe_connection() {
+        let world = World::new();
+        let (connection_global_world_id, _rx_channel) = add_connection(&world, "127.0.0.1:40001");
+        let (other_id, _other_rx_channel) = add_connection(&world, "127.0.0.2:40002");
+
+        let report = describe(&world, connection_global_world_id).unwrap();
+        assert!(report.is_version_checked);
+        assert!(!report.is_authenticated);
+        assert_eq!(report.account_id, None);
+        assert_eq!(report.user_spawn_status, None);
+        assert_eq!(report.pending_messages, 0);
+
+        world.run(
+            |mut entities: EntitiesViewMut,
+             mut connections: ViewMut<GlobalConnection>,
+             mut accounts: ViewMut<Account>,
+             mut user_spawns: ViewMut<GlobalUserSpawn>,
+             mut messages: ViewMut<EcsMessage>| {
+                let connection = &mut connections[connection_global_world_id];
+                connection.is_authenticated = true;
+                connection.waiting_for_pong = true;
+                connection.schema_version = Some(SchemaVersion(366_222));
+                connection
+                    .channel
+                    .try_send(EcsMessage::new(Message::DropConnection {
+                        connection_global_world_id,
+                    }))
+                    .unwrap();
+
+                entities.add_component(
+                    &mut accounts,
+                    Account {
+                        id: AccountId(7),
+                        region: Region::Germany,
+                    },
+                    connection_global_world_id,
+                );
+                entities.add_component(
+                    &mut user_spawns,
+                    GlobalUserSpawn {
+                        user_id: UserId(1),
+                        account_id: AccountId(7),
+                        status: UserSpawnStatus::Waiting,
+                        zone_id: 0,
+                        connection_local_world_id: None,
+                        local_world_id: None,
+                        local_world_channel: None,
+                        marked_for_deletion: false,
+                        is_alive: true,
+                    },
+                    connection_global_world_id,
+                );
+                for id in &[
+                    connection_global_world_id,
+                    connection_global_world_id,
+                    other_id,
+                ] {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestPong {
+                            connection_global_world_id: *id,
+                            packet: CPong {},
+                        }),
+                    );
+                }
+            },
+        );
+
+        let report = describe(&world, connection_global_world_id).unwrap();
+        assert_eq!(
+            report.connection_global_world_id,
+            connection_global_world_id
+        );
+        assert_eq!(
+            report.peer_addr,
+            "127.0.0.1:40001".parse::<SocketAddr>().unwrap()
+        );
+        assert!(report.is_authenticated);
+        assert!(report.waiting_for_pong);
+        assert_eq!(report.account_id, Some(AccountId(7)));
+        assert_eq!(report.region, Some(Region::Germany));
+        assert_eq!(report.schema_version, Some(SchemaVersion(366_222)));
+        assert_eq!(report.user_spawn_status, Some(UserSpawnStatus::Waiting));
+        assert_eq!(report.queue_depth, 1);
+        assert_eq!(report.pending_messages, 2);
+
+        world.run(|mut all_storages: AllStoragesViewMut| {
+            all_storages.delete(connection_global_world_id);
+        });
+        assert!(describe(&world, connection_global_world_id).is_none());
+        assert!(describe(&world, other_id).is_some());
+    }
+
+    fn kick(world: &World, account_id: AccountId) -> Option<EntityId> {
+        world.run(
+            |mut accounts: ViewMut<Account>,
+             mut connections: ViewMut<GlobalConnection>,
+             mut user_spawns: ViewMut<GlobalUserSpawn>| {
+                kick_account(
+                    account_id,
+                    "Testing",
+                    &mut accounts,
+                    &mut connections,
+                    &mut user_spawns,
+                )
+            },
+        )
+    }
+
+    #[test]
+    fn test_kick_account() {
+        let world = World::new();
+        let (connection_global_world_id, rx_channel) = add_connection(&world, "127.0.0.1:40001");
+        let (other_id, other_rx_channel) = add_connection(&world, "127.0.0.2:40002");
+
+        world.run(|entities: EntitiesView, mut accounts: ViewMut<Account>| {
+            for (id, account_id) in &[(connection_global_world_id, 1), (other_id, 2)] {
+                entities.add_component(
+                    &mut accounts,
+                    Account {
+                        id: AccountId(*account_id),
+                        region: Region::Germany,
+                    },
+                    *id,
+                );
+            }
+        });
+
+        assert_eq!(kick(&world, AccountId(1)), Some(connection_global_world_id));
+
+        match rx_channel.try_recv() {
+            Ok(message) => match &*message {
+                Message::ResponseChat {
+                    connection_global_world_id: id,
+                    packet,
+                } => {
+                    assert_eq!(*id, connection_global_world_id);
+                    assert_eq!(packet.message, "Testing");
+                }
+                m => panic!("Expected the kick reason, got {}", m),
+            },
+            Err(_) => panic!("Couldn't find the kick reason"),
+        }
+        match rx_channel.try_recv() {
+            Ok(message) => match &*message {
+                Message::DropConnection {
+                    connection_global_world_id: id,
+                } => assert_eq!(*id, connection_global_world_id),
+                m => panic!("Expected a drop connection message, got {}", m),
+            },
+            Err(_) => panic!("Couldn't find drop connection message"),
+        }
+        assert!(other_rx_channel.try_recv().is_err());
+
+        let snapshot = world.run(active_connections);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].account_id, Some(AccountId(2)));
+    }
+
+    #[test]
+    fn test_kick_account_not_connected() {
+        let world = World::new();
+        let (_, rx_channel) = add_connection(&world, "127.0.0.1:40001");
+
+        assert_eq!(kick(&world, AccountId(1)), None);
+        assert!(rx_channel.try_recv().is_err());
+        assert_eq!(world.run(active_connections).len(), 1);
+    }
+}
@@ -0,0 +1,196 @@
+/// Evicts connections when their number exceeds the configured soft limit.
+use super::connection_manager::drop_connection;
+use crate::ecs::component::{Account, GlobalConnection, GlobalUserSpawn};
+use crate::ecs::resource::{EvictionPolicy, EvictionSettings};
+use shipyard::*;
+use std::cmp::Reverse;
+use tracing::{info_span, warn};
+
+/// Evicts unauthenticated connections while the number of all connections is above the soft
+/// limit. Keeps a flood of connections from running the server out of memory.
+pub fn connection_eviction_system(
+    mut accounts: ViewMut<Account>,
+    mut user_spawns: ViewMut<GlobalUserSpawn>,
+    mut connections: ViewMut<GlobalConnection>,
+    settings: UniqueView<EvictionSettings>,
+) {
+    let limit = settings.connection_soft_limit;
+    if limit == 0 {
+        return;
+    }
+    let count = connections.iter().count();
+    if count <= limit {
+        return;
+    }
+
+    let to_evict = select_evictions(&connections, settings.policy, count - limit);
+    warn!(
+        "{} connections exceed the soft limit of {} connections. Evicting {} unauthenticated connections",
+        count,
+        limit,
+        to_evict.len()
+    );
+    for connection_global_world_id in to_evict {
+        id_span!(connection_global_world_id);
+        drop_connection(
+            connection_global_world_id,
+            &mut accounts,
+            &mut connections,
+            &mut user_spawns,
+        );
+    }
+}
+
+/// Selects at most `count` unauthenticated connections in the order of the policy.
+fn select_evictions(
+    connections: &ViewMut<GlobalConnection>,
+    policy: EvictionPolicy,
+    count: usize,
+) -> Vec<EntityId> {
+    let mut candidates: Vec<(EntityId, &GlobalConnection)> = connections
+        .iter()
+        .with_id()
+        .filter(|(_, connection)| !connection.is_authenticated)
+        .collect();
+    match policy {
+        // The version check and the pongs reset the last pong.
+        EvictionPolicy::LeastRecentlyActive => {
+            candidates.sort_by_key(|(_, connection)| connection.last_pong)
+        }
+        EvictionPolicy::Newest => {
+            candidates.sort_by_key(|(_, connection)| Reverse(connection.connected_since))
+        }
+    }
+    candidates
+        .into_iter()
+        .take(count)
+        .map(|(connection_global_world_id, _)| connection_global_world_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::channel;
+    use std::time::{Duration, Instant};
+
+    fn add_connection(
+        world: &World,
+        is_authenticated: bool,
+        idle: Duration,
+        age: Duration,
+    ) -> EntityId {
+        let (tx_channel, _rx_channel) = channel(16);
+        let now = Instant::now();
+        world.run(
+            |mut entities: EntitiesViewMut, mut connections: ViewMut<GlobalConnection>| {
+                entities.add_entity(
+                    &mut connections,
+                    GlobalConnection {
+                        channel: tx_channel,
+                        is_version_checked: is_authenticated,
+                        is_authenticated,
+                        last_pong: now - idle,
+                        waiting_for_pong: false,
+                        peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                        connected_since: now - age,
+                        schema_version: None,
+                    },
+                )
+            },
+        )
+    }
+
+    /// Adds 3 authenticated and 20 idle unauthenticated connections. The connection with the
+    /// index i was idle for i seconds and connected i + 1 seconds ago.
+    fn setup(
+        policy: EvictionPolicy,
+        connection_soft_limit: usize,
+    ) -> (World, Vec<EntityId>, Vec<EntityId>) {
+        let world = World::new();
+        world.add_unique(EvictionSettings {
+            connection_soft_limit,
+            policy,
+        });
+        let authenticated = (0..3)
+            .map(|_| {
+                add_connection(
+                    &world,
+                    true,
+                    Duration::from_secs(60),
+                    Duration::from_secs(60),
+                )
+            })
+            .collect();
+        let unauthenticated = (0..20)
+            .map(|i| {
+                add_connection(
+                    &world,
+                    false,
+                    Duration::from_secs(i),
+                    Duration::from_secs(i + 1),
+                )
+            })
+            .collect();
+        (world, authenticated, unauthenticated)
+    }
+
+    fn is_connected(world: &World, connection_global_world_id: EntityId) -> bool {
+        (&world.borrow::<View<GlobalConnection>>())
+            .try_get(connection_global_world_id)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_evict_least_recently_active() {
+        let (world, authenticated, unauthenticated) =
+            setup(EvictionPolicy::LeastRecentlyActive, 13);
+        world.run(connection_eviction_system);
+
+        assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 13);
+        assert!(authenticated.iter().all(|id| is_connected(&world, *id)));
+        assert!(unauthenticated[..10]
+            .iter()
+            .all(|id| is_connected(&world, *id)));
+        assert!(unauthenticated[10..]
+            .iter()
+            .all(|id| !is_connected(&world, *id)));
+    }
+
+    #[test]
+    fn test_evict_newest() {
+        let (world, authenticated, unauthenticated) = setup(EvictionPolicy::Newest, 13);
+        world.run(connection_eviction_system);
+
+        assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 13);
+        assert!(authenticated.iter().all(|id| is_connected(&world, *id)));
+        assert!(unauthenticated[..10]
+            .iter()
+            .all(|id| !is_connected(&world, *id)));
+        assert!(unauthenticated[10..]
+            .iter()
+            .all(|id| is_connected(&world, *id)));
+    }
+
+    #[test]
+    fn test_never_evict_authenticated_connections() {
+        let (world, authenticated, _) = setup(EvictionPolicy::LeastRecentlyActive, 1);
+        world.run(connection_eviction_system);
+
+        assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 3);
+        assert!(authenticated.iter().all(|id| is_connected(&world, *id)));
+    }
+
+    #[test]
+    fn test_eviction_below_soft_limit() {
+        let (world, _, _) = setup(EvictionPolicy::LeastRecentlyActive, 23);
+        world.run(connection_eviction_system);
+        assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 23);
+
+        world
+            .borrow::<UniqueViewMut<EvictionSettings>>()
+            .connection_soft_limit = 0;
+        world.run(connection_eviction_system);
+        assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 23);
+    }
+}
@@ -1,22 +1,30 @@
 use crate::ecs::component::{Account, GlobalConnection, GlobalUserSpawn};
 use crate::ecs::message::{EcsMessage, Message};
+use crate::ecs::resource::{
+    Clock, HandlerLatencies, LoginArbiterFields, LoginTicketPolicy, PostLoginPacket, ResumeTokens,
+    ShutdownSignal, ShutdownSignalStatus,
+};
 use crate::ecs::system::global::send_message_to_connection;
-use crate::ecs::system::send_message;
+use crate::ecs::system::{send_message, HandlerOutcome};
 use crate::model;
-use crate::model::repository::{account, loginticket};
+use crate::model::repository::loginticket::{TicketValidation, TicketValidator};
+use crate::model::AccountId;
+use crate::protocol::opcode::Opcode;
 use crate::protocol::packet::*;
+use crate::protocol::CloseKind;
 use crate::Result;
-use anyhow::{bail, ensure, Context};
+use anyhow::Context;
 use async_std::sync::Sender;
-use async_std::task;
 use shipyard::*;
-use sqlx::PgPool;
-use std::time::Instant;
-use tracing::{debug, error, info, info_span, trace};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, trace, warn};
 
 const MAX_UNAUTHENTICATED_LIFETIME: u64 = 5;
 const PING_INTERVAL: u64 = 15;
 const PONG_DEADLINE: u64 = 30;
+const RESUME_TOKEN_LIFETIME: u64 = 60;
 
 /// Connection manager handles the connection components.
 pub fn connection_manager_system(
@@ -25,17 +33,33 @@ pub fn connection_manager_system(
     mut user_spawns: ViewMut<GlobalUserSpawn>,
     mut connections: ViewMut<GlobalConnection>,
     mut entities: EntitiesViewMut,
-    pool: UniqueView<PgPool>,
+    mut resume_tokens: UniqueViewMut<ResumeTokens>,
+    mut latencies: UniqueViewMut<HandlerLatencies>,
+    // A system takes at most 10 parameters.
+    (ticket_policy, clock): (UniqueView<LoginTicketPolicy>, UniqueView<Box<dyn Clock>>),
+    shutdown: UniqueView<ShutdownSignal>,
+    ticket_validator: UniqueView<Box<dyn TicketValidator>>,
 ) {
+    // On shutdown the connections flush their pending responses and close themselves.
+    if shutdown.status == ShutdownSignalStatus::ShutdownInProgress {
+        shutdown_connections(&connections);
+        return;
+    }
+
+    let now = clock.now();
+
     // Incoming messages
     (&incoming_messages)
         .iter()
         .for_each(|message| match &**message {
             Message::RegisterConnection {
-                connection_channel, ..
+                connection_channel,
+                peer_addr,
             } => {
                 handle_connection_registration(
                     connection_channel.clone(),
+                    *peer_addr,
+                    now,
                     &mut connections,
                     &mut entities,
                 );
@@ -45,21 +69,36 @@ pub fn connection_manager_system(
                 packet,
             } => {
                 id_span!(connection_global_world_id);
-                if let Err(e) = handle_request_check_version(
-                    *connection_global_world_id,
-                    &packet,
-                    &mut connections,
-                ) {
-                    error!("Rejecting Message::RequestCheckVersion: {:?}", e);
-                    send_message_to_connection(
-                        reject_check_version(*connection_global_world_id),
-                        &connections,
-                    );
-                    drop_connection(
+                if is_dropped_connection(*connection_global_world_id, &connections, &entities) {
+                    debug!("Ignoring Message::RequestCheckVersion of a dropped connection");
+                    return;
+                }
+                let result = latencies.time(Opcode::C_CHECK_VERSION, || {
+                    handle_request_check_version(
                         *connection_global_world_id,
+                        &packet,
                         &mut connections,
-                        &mut user_spawns,
-                    );
+                        &ticket_policy,
+                        now,
+                    )
+                });
+                match resolve_outcome(Opcode::C_CHECK_VERSION, result, &mut latencies) {
+                    HandlerOutcome::Handled
+                    | HandlerOutcome::Deferred
+                    | HandlerOutcome::StorageUnavailable => {}
+                    HandlerOutcome::Rejected(reason) => {
+                        warn!("Rejecting Message::RequestCheckVersion: {}", reason);
+                        send_message_to_connection(
+                            reject_check_version(*connection_global_world_id),
+                            &connections,
+                        );
+                        drop_connection(
+                            *connection_global_world_id,
+                            &mut accounts,
+                            &mut connections,
+                            &mut user_spawns,
+                        );
+                    }
                 }
             }
             Message::RequestLoginArbiter {
@@ -67,24 +106,56 @@ pub fn connection_manager_system(
                 packet,
             } => {
                 id_span!(connection_global_world_id);
-                if let Err(e) = handle_request_login_arbiter(
-                    *connection_global_world_id,
-                    &packet,
-                    &mut accounts,
-                    &mut connections,
-                    &mut entities,
-                    &pool,
-                ) {
-                    error!("Rejecting Message::RequestLoginArbiter: {:?}", e);
-                    send_message_to_connection(
-                        reject_login_arbiter(*connection_global_world_id, -1, packet.region),
-                        &connections,
-                    );
-                    drop_connection(
+                if is_dropped_connection(*connection_global_world_id, &connections, &entities) {
+                    debug!("Ignoring Message::RequestLoginArbiter of a dropped connection");
+                    return;
+                }
+                let result = latencies.time(Opcode::C_LOGIN_ARBITER, || {
+                    handle_request_login_arbiter(
                         *connection_global_world_id,
+                        &packet,
+                        &mut accounts,
                         &mut connections,
-                        &mut user_spawns,
-                    );
+                        &mut entities,
+                        &mut resume_tokens,
+                        &ticket_policy,
+                        ticket_validator.as_ref(),
+                        now,
+                    )
+                });
+                match resolve_outcome(Opcode::C_LOGIN_ARBITER, result, &mut latencies) {
+                    HandlerOutcome::Handled => {}
+                    HandlerOutcome::Deferred => {
+                        info!("Deferring Message::RequestLoginArbiter of an account in use");
+                        send_message_to_connection(
+                            soft_reject_login_arbiter(*connection_global_world_id, packet.region),
+                            &connections,
+                        );
+                    }
+                    // The client is asked to try again later. It keeps the connection.
+                    HandlerOutcome::StorageUnavailable => {
+                        send_message_to_connection(
+                            soft_reject_login_arbiter(*connection_global_world_id, packet.region),
+                            &connections,
+                        );
+                    }
+                    HandlerOutcome::Rejected(reason) => {
+                        warn!("Rejecting Message::RequestLoginArbiter: {}", reason);
+                        send_message_to_connection(
+                            reject_login_arbiter(
+                                *connection_global_world_id,
+                                AccountId(-1),
+                                packet.region,
+                            ),
+                            &connections,
+                        );
+                        drop_connection(
+                            *connection_global_world_id,
+                            &mut accounts,
+                            &mut connections,
+                            &mut user_spawns,
+                        );
+                    }
                 }
             }
             Message::RequestPong {
@@ -92,14 +163,32 @@ pub fn connection_manager_system(
                 ..
             } => {
                 id_span!(connection_global_world_id);
-                handle_pong(*connection_global_world_id, &mut connections);
+                let result = latencies.time(Opcode::C_PONG, || {
+                    handle_pong(*connection_global_world_id, &mut connections, now)
+                });
+                if let HandlerOutcome::Rejected(reason) =
+                    resolve_outcome(Opcode::C_PONG, result, &mut latencies)
+                {
+                    warn!("Ignoring Message::RequestPong: {}", reason);
+                }
+            }
+            Message::RequestConnectionClosed {
+                connection_global_world_id,
+                kind,
+            } => {
+                id_span!(connection_global_world_id);
+                handle_connection_closed(
+                    *connection_global_world_id,
+                    *kind,
+                    &mut accounts,
+                    &mut connections,
+                    &mut user_spawns,
+                );
             }
             _ => { /* Ignore all other packets */ }
         });
 
     // Check the status of the existing connections and drop inactive connections
-    let now = Instant::now();
-
     // Ping/Pong test for authenticated connections
     let mut to_drop = Vec::new();
     (&mut connections)
@@ -114,14 +203,19 @@ pub fn connection_manager_system(
             }
         });
 
-    // Unauthenticated connections only live for 5 seconds
+    // Unauthenticated connections need to check their version inside the grace period and
+    // then only live for 5 seconds.
     (&mut connections)
         .iter()
         .with_id()
         .filter(|(_, connection)| !connection.is_authenticated)
         .for_each(|(connection_global_world_id, connection)| {
-            let last_pong_duration = now.duration_since(connection.last_pong).as_secs();
-            if last_pong_duration >= MAX_UNAUTHENTICATED_LIFETIME {
+            let lifetime = if connection.is_version_checked {
+                Duration::from_secs(MAX_UNAUTHENTICATED_LIFETIME)
+            } else {
+                ticket_policy.version_check_grace
+            };
+            if now.duration_since(connection.last_pong) >= lifetime {
                 to_drop.push(connection_global_world_id);
             }
         });
@@ -130,14 +224,94 @@ pub fn connection_manager_system(
         id_span!(connection_global_world_id);
         drop_connection(
             connection_global_world_id,
+            &mut accounts,
             &mut connections,
             &mut user_spawns,
         );
     }
+
+    // The lifetime of a resume token starts once the connection of its account is gone.
+    let connected_accounts: HashSet<AccountId> = (&accounts).iter().map(|a| a.id).collect();
+    resume_tokens.start_lifetime(
+        |account_id| connected_accounts.contains(&account_id),
+        Duration::from_secs(RESUME_TOKEN_LIFETIME),
+        now,
+    );
+}
+
+fn shutdown_connections(connections: &ViewMut<GlobalConnection>) {
+    info!("Signaling all connections to shut down");
+    connections
+        .iter()
+        .with_id()
+        .for_each(|(connection_global_world_id, connection)| {
+            send_message(
+                assemble_shutdown_connection(connection_global_world_id),
+                &connection.channel,
+            );
+        });
+}
+
+/// Counts the outcome of a request handler. A failed handler rejects the request, unless the
+/// storage backend is unavailable.
+fn resolve_outcome(
+    opcode: Opcode,
+    result: Result<HandlerOutcome>,
+    latencies: &mut HandlerLatencies,
+) -> HandlerOutcome {
+    let outcome = result.unwrap_or_else(|e| {
+        if model::is_storage_unavailable(&e) {
+            warn!(
+                "Storage is unavailable for the handler of {:?}: {:?}",
+                opcode, e
+            );
+            HandlerOutcome::StorageUnavailable
+        } else {
+            error!("Handler of {:?} failed: {:?}", opcode, e);
+            HandlerOutcome::Rejected(format!("{:#}", e))
+        }
+    });
+    latencies.count(opcode, &outcome);
+    outcome
+}
+
+/// Returns true if the entity of the connection was already deleted. A client can still have
+/// requests in flight when it's connection is dropped, so these requests are no error.
+fn is_dropped_connection(
+    connection_global_world_id: EntityId,
+    connections: &ViewMut<GlobalConnection>,
+    entities: &EntitiesViewMut,
+) -> bool {
+    connections.try_get(connection_global_world_id).is_err()
+        && !entities.is_alive(connection_global_world_id)
+}
+
+/// Removes the components of a closed connection. Connections that were dropped by the server
+/// are already cleaned up.
+fn handle_connection_closed(
+    connection_global_world_id: EntityId,
+    kind: CloseKind,
+    accounts: &mut ViewMut<Account>,
+    connections: &mut ViewMut<GlobalConnection>,
+    user_spawns: &mut ViewMut<GlobalUserSpawn>,
+) {
+    if connections.try_get(connection_global_world_id).is_err() {
+        debug!("Closed connection was already dropped ({:?})", kind);
+        return;
+    }
+    info!("Connection was closed ({:?})", kind);
+    drop_connection(
+        connection_global_world_id,
+        accounts,
+        connections,
+        user_spawns,
+    );
 }
 
 fn handle_connection_registration(
     connection_channel: Sender<EcsMessage>,
+    peer_addr: SocketAddr,
+    now: Instant,
     connections: &mut ViewMut<GlobalConnection>,
     entities: &mut EntitiesViewMut,
 ) {
@@ -150,8 +324,11 @@ fn handle_connection_registration(
             channel: connection_channel,
             is_authenticated: false,
             is_version_checked: false,
-            last_pong: Instant::now(),
+            last_pong: now,
             waiting_for_pong: false,
+            peer_addr,
+            connected_since: now,
+            schema_version: None,
         },
     );
 
@@ -169,92 +346,154 @@ fn handle_request_check_version(
     connection_global_world_id: EntityId,
     packet: &CCheckVersion,
     mut connections: &mut ViewMut<GlobalConnection>,
-) -> Result<()> {
+    ticket_policy: &LoginTicketPolicy,
+    now: Instant,
+) -> Result<HandlerOutcome> {
     debug!("Message::RequestCheckVersion incoming");
 
-    ensure!(
-        packet.version.len() == 2,
-        format!(
-            "Expected version array to be of length 2 but is {}",
-            packet.version.len()
-        )
-    );
+    if ticket_policy.skip_login_checks {
+        warn!("Skipping the version check");
+    } else {
+        if !packet.has_expected_indices() {
+            return Ok(HandlerOutcome::Rejected(format!(
+                "Expected version entries with the indices 0 and 1 but got {:?}",
+                packet.version
+            )));
+        }
+        let version = (
+            packet.value(0).unwrap_or_default(),
+            packet.value(1).unwrap_or_default(),
+        );
+        if !ticket_policy.allowed_versions.is_allowed(version) {
+            return Ok(HandlerOutcome::Rejected(format!(
+                "Version {:?} is not allowed",
+                version
+            )));
+        }
+    }
 
     debug!(
         "Version 1: {} version 2: {}",
-        packet.version[0].value, packet.version[1].value
+        packet.value(0).unwrap_or_default(),
+        packet.value(1).unwrap_or_default()
     );
 
     let mut connection = (&mut connections)
         .try_get(connection_global_world_id)
         .context("Could not find connection component for entity")?;
     connection.is_version_checked = true;
+    connection.schema_version = packet.schema_version();
+    // The time to authenticate starts after the version check.
+    connection.last_pong = now;
 
-    Ok(())
+    Ok(HandlerOutcome::Handled)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_request_login_arbiter(
     connection_global_world_id: EntityId,
     packet: &CLoginArbiter,
     accounts: &mut ViewMut<Account>,
     mut connections: &mut ViewMut<GlobalConnection>,
     entities: &mut EntitiesViewMut,
-    pool: &PgPool,
-) -> Result<()> {
+    resume_tokens: &mut ResumeTokens,
+    ticket_policy: &LoginTicketPolicy,
+    ticket_validator: &dyn TicketValidator,
+    now: Instant,
+) -> Result<HandlerOutcome> {
     debug!(
         "Message::RequestLoginArbiter incoming for account: {}",
         packet.master_account_name
     );
 
-    Ok(task::block_on(async {
-        let mut connection = (&mut connections)
-            .try_get(connection_global_world_id)
-            .context("Could not find connection component for entity")?;
-
-        trace!("Ticket value: {}", base64::encode(&packet.ticket));
+    let mut connection = (&mut connections)
+        .try_get(connection_global_world_id)
+        .context("Could not find connection component for entity")?;
 
-        if packet.ticket.is_empty() {
-            bail!("Ticket was empty");
-        }
+    // The client always checks the version first. An arbiter request without a checked
+    // version would leave a half initialized connection, so we reject it.
+    if !connection.is_version_checked {
+        return Ok(HandlerOutcome::Rejected(
+            "Login arbiter received before the version was checked".to_string(),
+        ));
+    }
 
-        let mut conn = pool
-            .acquire()
-            .await
-            .context("Couldn't acquire connection from pool")?;
+    trace!("Ticket value: {}", base64::encode(&packet.ticket));
 
-        if !loginticket::is_ticket_valid(&mut conn, &packet.master_account_name, &packet.ticket)
-            .await
-            .context("Error while executing query for account")?
-        {
-            bail!("Ticket not valid");
-        }
+    if packet.ticket.is_empty() && !ticket_policy.skip_login_checks {
+        return Ok(HandlerOutcome::Rejected("Ticket was empty".to_string()));
+    }
 
-        info!(
-            "Account {} provided a valid ticket",
-            packet.master_account_name
-        );
+    // A connection logs in only once.
+    if (&*accounts).try_get(connection_global_world_id).is_ok() {
+        return Ok(HandlerOutcome::Rejected(
+            "Account is already logged in".to_string(),
+        ));
+    }
 
-        let account = account::get_by_name(&mut conn, &packet.master_account_name)
-            .await
-            .context("Can't find the account for the given master account name")?;
+    // The ticket or resume token is only consumed once the account is not in use on another
+    // connection. That connection could be about to time out, so the client may retry with it.
+    let is_in_use =
+        |account_id: AccountId| (&*accounts).iter().any(|account| account.id == account_id);
+
+    // A client that recently lost its connection presents the resume token of its last login
+    // in place of its ticket. The token can only be used once.
+    let account_id = match resume_tokens.account_id(&packet.ticket, now) {
+        Some(account_id) if is_in_use(account_id) => return Ok(HandlerOutcome::Deferred),
+        Some(account_id) => {
+            resume_tokens.invalidate(&packet.ticket);
+            info!(
+                "Account {} provided a valid resume token",
+                packet.master_account_name
+            );
+            account_id
+        }
+        None => {
+            let ticket = if ticket_policy.skip_login_checks {
+                warn!(
+                    "Skipping the ticket check of account {}",
+                    packet.master_account_name
+                );
+                None
+            } else {
+                Some(packet.ticket.as_slice())
+            };
+            match ticket_validator.validate(&packet.master_account_name, ticket, &is_in_use)? {
+                TicketValidation::Valid(account_id) => {
+                    info!(
+                        "Account {} provided a valid ticket",
+                        packet.master_account_name
+                    );
+                    account_id
+                }
+                TicketValidation::AccountInUse => return Ok(HandlerOutcome::Deferred),
+                TicketValidation::Invalid => {
+                    return Ok(HandlerOutcome::Rejected("Ticket not valid".to_string()))
+                }
+            }
+        }
+    };
 
-        ensure!(
-            accounts.iter().find(|id| id.id == account.id).is_none(),
-            "Account is already logged in"
-        );
+    connection.is_authenticated = true;
 
-        connection.is_authenticated = true;
+    let account = Account {
+        id: account_id,
+        region: packet.region,
+    };
+    entities.add_component(accounts, account, connection_global_world_id);
 
-        let account = Account {
-            id: account.id,
-            region: packet.region,
-        };
-        entities.add_component(accounts, account, connection_global_world_id);
+    let resume_token = resume_tokens.issue(account_id);
+    debug!("Issued a resume token for account {}", account_id);
 
-        check_and_handle_post_initialization(connection_global_world_id, account, connection);
+    check_and_handle_post_initialization(
+        connection_global_world_id,
+        account,
+        connection,
+        ticket_policy,
+        resume_token,
+    );
 
-        Ok(())
-    })?)
+    Ok(HandlerOutcome::Handled)
 }
 
 // Returns true if connection didn't return a ping in time.
@@ -286,22 +525,29 @@ fn handle_ping(
 fn handle_pong(
     connection_global_world_id: EntityId,
     mut connections: &mut ViewMut<GlobalConnection>,
-) {
+    now: Instant,
+) -> Result<HandlerOutcome> {
     debug!("Message::RequestPong incoming");
 
     let span = info_span!("id", connection_global_world_id = ?connection_global_world_id);
     let _enter = span.enter();
 
-    if let Ok(mut connection) = (&mut connections).try_get(connection_global_world_id) {
-        connection.last_pong = Instant::now();
-        connection.waiting_for_pong = false;
-    } else {
-        error!("Could not find connection component for entity");
+    let mut connection = (&mut connections)
+        .try_get(connection_global_world_id)
+        .context("Could not find connection component for entity")?;
+    // Ping and pong carry no sequence number, so there is only one ping in flight and a pong
+    // without an open ping can't be matched to any ping.
+    if !connection.waiting_for_pong {
+        return Ok(HandlerOutcome::Rejected("Unsolicited pong".to_string()));
     }
+    connection.last_pong = now;
+    connection.waiting_for_pong = false;
+    Ok(HandlerOutcome::Handled)
 }
 
-fn drop_connection(
+pub(super) fn drop_connection(
     connection_global_world_id: EntityId,
+    accounts: &mut ViewMut<Account>,
     connections: &mut ViewMut<GlobalConnection>,
     user_spawns: &mut ViewMut<GlobalUserSpawn>,
 ) {
@@ -311,6 +557,7 @@ fn drop_connection(
             &connection.channel,
         );
         connections.delete(connection_global_world_id);
+        accounts.delete(connection_global_world_id);
 
         // TODO test the "marked_for_deletion" on spawned users
         if let Ok(spawn) = user_spawns.try_get(connection_global_world_id) {
@@ -328,39 +575,49 @@ fn check_and_handle_post_initialization(
     connection_global_world_id: EntityId,
     account: Account,
     connection: &GlobalConnection,
+    ticket_policy: &LoginTicketPolicy,
+    resume_token: [u8; 32],
 ) {
     // Now that the client is vetted, we need to send him some specific packets in order for him to progress.
     debug!("Sending connection post initialization commands");
 
     // FIXME get from configuration (server name and PVP setting)!
-    send_message(
-        accept_check_version(connection_global_world_id),
-        &connection.channel,
-    );
-    send_message(
-        assemble_loading_screen_info(connection_global_world_id),
-        &connection.channel,
-    );
-    send_message(
-        assemble_remain_play_time(connection_global_world_id),
-        &connection.channel,
-    );
-    send_message(
-        accept_login_arbiter(connection_global_world_id, account.id, account.region),
-        &connection.channel,
-    );
-    send_message(
-        assemble_login_account_info(
-            connection_global_world_id,
-            "Almetica".to_string(),
-            account.id,
-        ),
-        &connection.channel,
-    );
+    for post_login_packet in ticket_policy.post_login_sequence.iter() {
+        let message = match post_login_packet {
+            PostLoginPacket::CheckVersion => accept_check_version(connection_global_world_id),
+            PostLoginPacket::LoadingScreenControlInfo => {
+                assemble_loading_screen_info(connection_global_world_id)
+            }
+            PostLoginPacket::RemainPlayTime => {
+                assemble_remain_play_time(connection_global_world_id)
+            }
+            PostLoginPacket::LoginArbiter => accept_login_arbiter(
+                connection_global_world_id,
+                account.id,
+                account.region,
+                ticket_policy.login_arbiter_fields(connection.schema_version),
+            ),
+            PostLoginPacket::LoginAccountInfo => assemble_login_account_info(
+                connection_global_world_id,
+                "Almetica".to_string(),
+                account.id,
+            ),
+            PostLoginPacket::Motd => {
+                if ticket_policy.motd.is_empty() {
+                    continue;
+                }
+                assemble_notice(connection_global_world_id, ticket_policy.motd.clone())
+            }
+            PostLoginPacket::ResumeToken => {
+                assemble_resume_token(connection_global_world_id, resume_token)
+            }
+        };
+        send_message(message, &connection.channel);
+    }
 }
 
 fn assemble_loading_screen_info(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::ResponseLoadingScreenControlInfo {
+    EcsMessage::new(Message::ResponseLoadingScreenControlInfo {
         connection_global_world_id,
         packet: SLoadingScreenControlInfo {
             custom_screen_enabled: false,
@@ -368,8 +625,16 @@ fn assemble_loading_screen_info(connection_global_world_id: EntityId) -> EcsMess
     })
 }
 
+/// Assembles a message that the client shows as a notice.
+pub(super) fn assemble_notice(connection_global_world_id: EntityId, message: String) -> EcsMessage {
+    EcsMessage::new(Message::ResponseChat {
+        connection_global_world_id,
+        packet: SChat::notice(message),
+    })
+}
+
 fn assemble_remain_play_time(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::ResponseRemainPlayTime {
+    EcsMessage::new(Message::ResponseRemainPlayTime {
         connection_global_world_id,
         packet: SRemainPlayTime {
             account_type: 6,
@@ -381,9 +646,9 @@ fn assemble_remain_play_time(connection_global_world_id: EntityId) -> EcsMessage
 fn assemble_login_account_info(
     connection_global_world_id: EntityId,
     server_name: String,
-    account_id: i64,
+    account_id: AccountId,
 ) -> EcsMessage {
-    Box::new(Message::ResponseLoginAccountInfo {
+    EcsMessage::new(Message::ResponseLoginAccountInfo {
         connection_global_world_id,
         packet: SLoginAccountInfo {
             server_name,
@@ -393,57 +658,68 @@ fn assemble_login_account_info(
     })
 }
 
+fn assemble_resume_token(connection_global_world_id: EntityId, token: [u8; 32]) -> EcsMessage {
+    EcsMessage::new(Message::ResponseResumeToken {
+        connection_global_world_id,
+        packet: SResumeToken { token },
+    })
+}
+
 fn assemble_ping(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::ResponsePing {
+    EcsMessage::new(Message::ResponsePing {
         connection_global_world_id,
         packet: SPing {},
     })
 }
 
+fn assemble_shutdown_connection(connection_global_world_id: EntityId) -> EcsMessage {
+    EcsMessage::new(Message::ShutdownConnection {
+        connection_global_world_id,
+    })
+}
+
 fn assemble_drop_connection(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::DropConnection {
+    EcsMessage::new(Message::DropConnection {
         connection_global_world_id,
     })
 }
 
 fn assemble_connection_registration_finished(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::RegisterConnectionFinished {
+    EcsMessage::new(Message::RegisterConnectionFinished {
         connection_global_world_id,
     })
 }
 
 fn accept_check_version(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::ResponseCheckVersion {
+    EcsMessage::new(Message::ResponseCheckVersion {
         connection_global_world_id,
-        packet: SCheckVersion { ok: true },
+        packet: SCheckVersion::accepted(),
     })
 }
 
 fn reject_check_version(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::ResponseCheckVersion {
+    EcsMessage::new(Message::ResponseCheckVersion {
         connection_global_world_id,
-        packet: SCheckVersion { ok: false },
+        packet: SCheckVersion::rejected(),
     })
 }
 
 // TODO read PVP option out of configuration
 fn accept_login_arbiter(
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     region: model::Region,
+    fields: LoginArbiterFields,
 ) -> EcsMessage {
-    Box::new(Message::ResponseLoginArbiter {
+    EcsMessage::new(Message::ResponseLoginArbiter {
         connection_global_world_id,
         account_id,
         packet: SLoginArbiter {
-            success: true,
-            login_queue: false,
-            status: 65538,
-            unk1: 0,
-            region,
-            pvp_disabled: false,
-            unk2: 0,
-            unk3: 0,
+            status: fields.status,
+            unk1: fields.unk1,
+            unk2: fields.unk2,
+            unk3: fields.unk3,
+            ..SLoginArbiter::accepted(region)
         },
     })
 }
@@ -451,60 +727,102 @@ fn accept_login_arbiter(
 // TODO read PVP option out of configuration
 fn reject_login_arbiter(
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     region: model::Region,
 ) -> EcsMessage {
-    Box::new(Message::ResponseLoginArbiter {
+    EcsMessage::new(Message::ResponseLoginArbiter {
         connection_global_world_id,
         account_id,
-        packet: SLoginArbiter {
-            success: false,
-            login_queue: false,
-            status: 0,
-            unk1: 0,
-            region,
-            pvp_disabled: false,
-            unk2: 0,
-            unk3: 0,
-        },
+        packet: SLoginArbiter::rejected(region),
+    })
+}
+
+// Keeps the connection open, so that the client can retry the login.
+fn soft_reject_login_arbiter(
+    connection_global_world_id: EntityId,
+    region: model::Region,
+) -> EcsMessage {
+    EcsMessage::new(Message::ResponseLoginArbiter {
+        connection_global_world_id,
+        account_id: AccountId(-1),
+        packet: SLoginArbiter::queued(region),
     })
 }
 
+/// Creates a global world with the resources the connection manager system needs.
+#[cfg(test)]
+pub(crate) fn setup_world(ticket_validator: Box<dyn TicketValidator>) -> World {
+    let world = World::new();
+    world.add_unique(crate::ecs::resource::DeletionList(vec![]));
+    world.add_unique(ResumeTokens::default());
+    world.add_unique(HandlerLatencies::default());
+    world.add_unique(LoginTicketPolicy::default());
+    world.add_unique(ShutdownSignal {
+        status: ShutdownSignalStatus::Operational,
+    });
+    world.add_unique(ticket_validator);
+    world.add_unique(Box::new(crate::ecs::resource::SystemClock) as Box<dyn Clock>);
+    world
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ecs::component;
     use crate::ecs::message::Message;
-    use crate::ecs::resource::DeletionList;
-    use crate::ecs::system::common::cleaner_system;
+    use crate::ecs::resource::{AllowedVersions, InputChannel, OutcomeCounts, PriorityOpcodes};
+    use crate::ecs::system::common::{cleaner_system, message_receiver_system};
     use crate::model::entity;
     use crate::model::repository::account;
-    use crate::model::repository::loginticket;
+    use crate::model::repository::loginticket::{self, PgTicketValidator};
     use crate::model::tests::db_test;
     use crate::model::{PasswordHashAlgorithm, Region};
     use crate::protocol::packet::CCheckVersion;
+    use crate::protocol::serde::SchemaVersion;
+    use crate::test_support::ManualClock;
     use crate::Result;
     use async_std::prelude::*;
     use async_std::sync::{channel, Receiver};
+    use async_std::task;
     use chrono::{TimeZone, Utc};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
     use sqlx::pool::PoolConnection;
     use sqlx::{PgConnection, PgPool};
-    use std::time::Duration;
+    use std::panic::{self, AssertUnwindSafe};
 
     fn setup(pool: PgPool) -> World {
-        let world = World::new();
-        world.add_unique(DeletionList(vec![]));
-        world.add_unique(pool);
-        world
+        setup_world(Box::new(PgTicketValidator::new(
+            pool,
+            Duration::from_secs(300),
+        )))
+    }
+
+    /// Fails like the validator of an unreachable database.
+    struct UnavailableTicketValidator;
+
+    impl TicketValidator for UnavailableTicketValidator {
+        fn validate(
+            &self,
+            _account_name: &str,
+            _ticket: Option<&[u8]>,
+            _is_in_use: &dyn Fn(AccountId) -> bool,
+        ) -> Result<TicketValidation> {
+            Err(sqlx::Error::PoolClosed).context("Couldn't acquire connection from pool")
+        }
     }
 
     fn setup_with_connection(
         pool: PgPool,
         is_authenticated: bool,
     ) -> (World, EntityId, Receiver<EcsMessage>) {
-        let world = World::new();
-        world.add_unique(pool);
+        let world = setup(pool);
+        let (connection_global_world_id, rx_channel) = add_connection(&world, is_authenticated);
+        (world, connection_global_world_id, rx_channel)
+    }
 
+    fn add_connection(world: &World, is_authenticated: bool) -> (EntityId, Receiver<EcsMessage>) {
         let (tx_channel, rx_channel) = channel(1024);
 
         let connection_global_world_id = world.run(
@@ -517,12 +835,15 @@ mod tests {
                         is_version_checked: is_authenticated,
                         last_pong: Instant::now(),
                         waiting_for_pong: false,
+                        peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                        connected_since: Instant::now(),
+                        schema_version: None,
                     },
                 )
             },
         );
 
-        (world, connection_global_world_id, rx_channel)
+        (connection_global_world_id, rx_channel)
     }
 
     async fn create_login(conn: &mut PgConnection) -> Result<(entity::Account, Vec<u8>)> {
@@ -555,8 +876,9 @@ mod tests {
                         for _i in 0..5 {
                             entities.add_entity(
                                 &mut messages,
-                                Box::new(Message::RegisterConnection {
+                                EcsMessage::new(Message::RegisterConnection {
                                     connection_channel: tx_channel.clone(),
+                                    peer_addr: "127.0.0.1:10001".parse().unwrap(),
                                 }),
                             );
                         }
@@ -568,7 +890,7 @@ mod tests {
                 let mut count = 0;
                 loop {
                     if let Ok(message) = rx_channel.try_recv() {
-                        match *message {
+                        match *message.inner {
                             Message::RegisterConnectionFinished { .. } => count += 1,
                             _ => {}
                         }
@@ -595,7 +917,7 @@ mod tests {
                     |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                         entities.add_entity(
                             &mut messages,
-                            Box::new(Message::RequestCheckVersion {
+                            EcsMessage::new(Message::RequestCheckVersion {
                                 connection_global_world_id,
                                 packet: CCheckVersion {
                                     version: vec![
@@ -623,113 +945,513 @@ mod tests {
                     .count();
                 assert_eq!(valid_count, 1);
 
+                let schema_version = world
+                    .borrow::<View<GlobalConnection>>()
+                    .try_get(connection_global_world_id)
+                    .map(|connection| connection.schema_version);
+                assert_eq!(schema_version.ok(), Some(Some(SchemaVersion(366_222))));
+
                 Ok(())
             })
         })
     }
 
     #[test]
-    fn test_check_version_invalid() -> Result<()> {
+    fn test_check_version_after_allowed_versions_update() -> Result<()> {
         db_test(|db_string| {
             task::block_on(async {
                 let pool = PgPool::new(db_string).await?;
-                let (world, connection_global_world_id, mut rx_channel) =
-                    setup_with_connection(pool, true);
-
-                world.run(
-                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
-                        entities.add_entity(
-                            &mut messages,
-                            Box::new(Message::RequestCheckVersion {
-                                connection_global_world_id,
-                                packet: CCheckVersion {
-                                    version: vec![CCheckVersionEntry {
-                                        index: 0,
-                                        value: 366_222,
-                                    }],
-                                },
-                            }),
-                        )
-                    },
-                );
+                let (world, connection_global_world_id, _rx_channel) =
+                    setup_with_connection(pool, false);
+                let allowed_versions = AllowedVersions::new(vec![(1, 1)]);
+                world
+                    .borrow::<UniqueViewMut<LoginTicketPolicy>>()
+                    .allowed_versions = allowed_versions.clone();
+
+                let send_check_version = |connection_global_world_id: EntityId| {
+                    world.run(
+                        |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                            entities.add_entity(
+                                &mut messages,
+                                EcsMessage::new(Message::RequestCheckVersion {
+                                    connection_global_world_id,
+                                    packet: CCheckVersion {
+                                        version: vec![
+                                            CCheckVersionEntry {
+                                                index: 0,
+                                                value: 366_222,
+                                            },
+                                            CCheckVersionEntry {
+                                                index: 1,
+                                                value: 365_535,
+                                            },
+                                        ],
+                                    },
+                                }),
+                            )
+                        },
+                    );
+                    world.run(connection_manager_system);
+                    world
+                        .borrow::<View<GlobalConnection>>()
+                        .try_get(connection_global_world_id)
+                        .map(|connection| connection.is_version_checked)
+                        .ok()
+                };
 
-                world.run(connection_manager_system);
+                // Not allowed yet, so the connection is dropped.
+                assert_eq!(send_check_version(connection_global_world_id), None);
 
-                assert!(
-                    rx_channel
-                        .all(|message| match *message {
-                            Message::ResponseCheckVersion { packet, .. } => !packet.ok,
-                            Message::DropConnection { .. } => true,
-                            _ => false,
-                        })
-                        .await,
-                );
+                allowed_versions.replace(vec![(1, 1), (366_222, 365_535)]);
 
-                // The connection should be dropped.
-                let count = world.borrow::<View<GlobalConnection>>().iter().count();
-                assert_eq!(count, 0);
+                let (connection_global_world_id, _rx_channel) = add_connection(&world, false);
+                assert_eq!(send_check_version(connection_global_world_id), Some(true));
 
                 Ok(())
             })
         })
     }
 
-    #[test]
-    fn test_login_arbiter_valid() -> Result<()> {
-        db_test(|db_string| {
-            let (_conn, _rx_channel, world, connection_global_world_id, account, ticket) =
-                task::block_on(async {
-                    let pool = PgPool::new(db_string).await?;
-                    let mut conn = pool.acquire().await?;
-                    let (world, connection_global_world_id, rx_channel) =
-                        setup_with_connection(pool, true);
-                    let (account, ticket) = create_login(&mut conn).await?;
-
-                    Ok::<
-                        (
-                            PoolConnection<PgConnection>,
-                            Receiver<EcsMessage>,
-                            World,
-                            EntityId,
-                            entity::Account,
-                            Vec<u8>,
-                        ),
-                        anyhow::Error,
-                    >((
-                        conn,
-                        rx_channel,
-                        world,
+    fn check_version_outcome(world: &World, version: Vec<CCheckVersionEntry>) -> HandlerOutcome {
+        let connection_global_world_id = add_connection(world, false).0;
+        world
+            .run(
+                |mut connections: ViewMut<GlobalConnection>,
+                 ticket_policy: UniqueView<LoginTicketPolicy>| {
+                    handle_request_check_version(
                         connection_global_world_id,
-                        account,
-                        ticket,
-                    ))
-                })?;
-
-            world.run(
-                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
-                    entities.add_entity(
-                        &mut messages,
-                        Box::new(Message::RequestLoginArbiter {
-                            connection_global_world_id,
-                            packet: CLoginArbiter {
-                                master_account_name: account.name.clone(),
-                                ticket,
-                                unk1: 0,
-                                unk2: 0,
-                                region: Region::Europe,
-                                patch_version: 9002,
-                            },
-                        }),
+                        &CCheckVersion { version },
+                        &mut connections,
+                        &ticket_policy,
+                        Instant::now(),
                     )
                 },
-            );
-
-            world.run(connection_manager_system);
+            )
+            .unwrap()
+    }
 
-            let valid_count = world
-                .borrow::<View<component::Account>>()
+    /// Negotiates the schema version with a version check and returns the login arbiter of the
+    /// post login sequence.
+    fn negotiated_login_arbiter(world: &World, schema_version: i32) -> SLoginArbiter {
+        let (connection_global_world_id, rx_channel) = add_connection(world, false);
+        world.run(
+            |mut connections: ViewMut<GlobalConnection>,
+             ticket_policy: UniqueView<LoginTicketPolicy>| {
+                let version = vec![
+                    CCheckVersionEntry {
+                        index: 0,
+                        value: schema_version,
+                    },
+                    CCheckVersionEntry {
+                        index: 1,
+                        value: 365_535,
+                    },
+                ];
+                handle_request_check_version(
+                    connection_global_world_id,
+                    &CCheckVersion { version },
+                    &mut connections,
+                    &ticket_policy,
+                    Instant::now(),
+                )
+                .unwrap();
+                check_and_handle_post_initialization(
+                    connection_global_world_id,
+                    Account {
+                        id: AccountId(1),
+                        region: Region::Europe,
+                    },
+                    (&connections).try_get(connection_global_world_id).unwrap(),
+                    &ticket_policy,
+                    [0; 32],
+                );
+            },
+        );
+        match *rx_channel.try_recv().unwrap().inner {
+            Message::ResponseLoginArbiter { packet, .. } => packet,
+            m => panic!("Unexpected message {}", m),
+        }
+    }
+
+    #[test]
+    fn test_login_arbiter_fields_by_schema_version() {
+        let world = World::new();
+        let mut ticket_policy = LoginTicketPolicy {
+            post_login_sequence: vec![PostLoginPacket::LoginArbiter],
+            ..LoginTicketPolicy::default()
+        };
+        world.add_unique(ticket_policy.clone());
+
+        // Without a table the values of all patches are used.
+        let packet = negotiated_login_arbiter(&world, 366_222);
+        assert_eq!(packet, SLoginArbiter::accepted(Region::Europe));
+
+        let old_patch = LoginArbiterFields {
+            status: 65538,
+            unk1: 1,
+            unk2: 2,
+            unk3: 3,
+        };
+        let new_patch = LoginArbiterFields {
+            status: 2,
+            unk1: 4,
+            unk2: 5,
+            unk3: 6,
+        };
+        ticket_policy
+            .login_arbiter_fields
+            .insert(SchemaVersion(366_222), old_patch);
+        ticket_policy
+            .login_arbiter_fields
+            .insert(SchemaVersion(380_000), new_patch);
+        *world.borrow::<UniqueViewMut<LoginTicketPolicy>>() = ticket_policy;
+
+        let fields = |packet: SLoginArbiter| LoginArbiterFields {
+            status: packet.status,
+            unk1: packet.unk1,
+            unk2: packet.unk2,
+            unk3: packet.unk3,
+        };
+        assert_eq!(fields(negotiated_login_arbiter(&world, 366_222)), old_patch);
+        // Versions between two entries use the older entry.
+        assert_eq!(fields(negotiated_login_arbiter(&world, 370_000)), old_patch);
+        assert_eq!(fields(negotiated_login_arbiter(&world, 380_000)), new_patch);
+        assert_eq!(
+            fields(negotiated_login_arbiter(&world, 300_000)),
+            LoginArbiterFields::default()
+        );
+
+        let packet = negotiated_login_arbiter(&world, 380_000);
+        assert!(packet.success);
+        assert_eq!(packet.region, Region::Europe);
+    }
+
+    #[test]
+    fn test_check_version_outcomes() {
+        let world = World::new();
+        world.add_unique(LoginTicketPolicy {
+            allowed_versions: AllowedVersions::new(vec![(366_222, 365_535)]),
+            ..LoginTicketPolicy::default()
+        });
+        let entry = |index, value| CCheckVersionEntry { index, value };
+
+        assert_eq!(
+            check_version_outcome(&world, vec![entry(0, 366_222), entry(1, 365_535)]),
+            HandlerOutcome::Handled
+        );
+        match check_version_outcome(&world, vec![entry(0, 366_222)]) {
+            HandlerOutcome::Rejected(reason) => assert!(reason.contains("indices")),
+            outcome => panic!("Expected a rejection, got {:?}", outcome),
+        }
+        match check_version_outcome(&world, vec![entry(0, 1), entry(1, 1)]) {
+            HandlerOutcome::Rejected(reason) => assert!(reason.contains("not allowed")),
+            outcome => panic!("Expected a rejection, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_pong_outcomes() {
+        let world = World::new();
+        let (connection_global_world_id, _rx_channel) = add_connection(&world, true);
+
+        let pong = |connection_global_world_id: EntityId| {
+            world.run(|mut connections: ViewMut<GlobalConnection>| {
+                handle_pong(connection_global_world_id, &mut connections, Instant::now())
+            })
+        };
+
+        match pong(connection_global_world_id).unwrap() {
+            HandlerOutcome::Rejected(..) => { /* Expected result */ }
+            outcome => panic!(
+                "Expected a rejection of the unsolicited pong, got {:?}",
+                outcome
+            ),
+        }
+
+        world.run(|mut connections: ViewMut<GlobalConnection>| {
+            connections[connection_global_world_id].waiting_for_pong = true;
+        });
+        assert_eq!(
+            pong(connection_global_world_id).unwrap(),
+            HandlerOutcome::Handled
+        );
+
+        // A missing connection is a failure of the handler.
+        world.run(|mut all_storages: AllStoragesViewMut| {
+            all_storages.delete(connection_global_world_id);
+        });
+        assert!(pong(connection_global_world_id).is_err());
+    }
+
+    #[test]
+    fn test_resolve_outcome_counts() {
+        let mut latencies = HandlerLatencies::default();
+        assert_eq!(
+            resolve_outcome(Opcode::C_PONG, Ok(HandlerOutcome::Handled), &mut latencies),
+            HandlerOutcome::Handled
+        );
+        match resolve_outcome(
+            Opcode::C_PONG,
+            Err(anyhow::anyhow!("Handler failed")),
+            &mut latencies,
+        ) {
+            HandlerOutcome::Rejected(reason) => assert_eq!(reason, "Handler failed"),
+            outcome => panic!("Expected a rejection, got {:?}", outcome),
+        }
+        resolve_outcome(Opcode::C_PONG, Ok(HandlerOutcome::Deferred), &mut latencies);
+        assert_eq!(
+            resolve_outcome(
+                Opcode::C_PONG,
+                Err(anyhow::Error::new(sqlx::Error::PoolClosed).context("Can't query account")),
+                &mut latencies,
+            ),
+            HandlerOutcome::StorageUnavailable
+        );
+
+        assert_eq!(
+            latencies.outcomes[&Opcode::C_PONG],
+            OutcomeCounts {
+                handled: 1,
+                rejected: 1,
+                deferred: 1,
+                storage_failures: 1,
+            }
+        );
+    }
+
+    /// Sends a pong right after the connection was closed and returns the outcomes of the pong.
+    fn pong_after_close_outcomes(priority_opcodes: PriorityOpcodes) -> OutcomeCounts {
+        let world = setup_world(Box::new(UnavailableTicketValidator));
+        let (tx_channel, rx_channel) = channel(16);
+        world.add_unique(InputChannel {
+            channel: rx_channel,
+        });
+        world.add_unique(priority_opcodes);
+
+        let (connection_global_world_id, _rx_channel) = add_connection(&world, true);
+        world.run(|mut connections: ViewMut<GlobalConnection>| {
+            connections[connection_global_world_id].waiting_for_pong = true;
+        });
+
+        tx_channel
+            .try_send(EcsMessage::new(Message::RequestConnectionClosed {
+                connection_global_world_id,
+                kind: CloseKind::Client,
+            }))
+            .unwrap();
+        tx_channel
+            .try_send(EcsMessage::new(Message::RequestPong {
+                connection_global_world_id,
+                packet: CPong {},
+            }))
+            .unwrap();
+
+        world.run(message_receiver_system);
+        world.run(connection_manager_system);
+
+        let latencies = world.borrow::<UniqueView<HandlerLatencies>>();
+        latencies.outcomes[&Opcode::C_PONG].clone()
+    }
+
+    #[test]
+    fn test_priority_opcodes_are_handled_first() {
+        // The pong is handled while the connection is still open.
+        let outcomes =
+            pong_after_close_outcomes(PriorityOpcodes(vec![Opcode::C_PONG].into_iter().collect()));
+        assert_eq!(
+            outcomes,
+            OutcomeCounts {
+                handled: 1,
+                ..Default::default()
+            }
+        );
+
+        // Without a priority the connection is already gone.
+        let outcomes = pong_after_close_outcomes(PriorityOpcodes::default());
+        assert_eq!(
+            outcomes,
+            OutcomeCounts {
+                rejected: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_login_arbiter_outcomes() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, unchecked_id, _rx_channel) = setup_with_connection(pool, false);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            let login = |connection_global_world_id: EntityId, ticket: &[u8]| {
+                let packet = CLoginArbiter {
+                    master_account_name: account.name.clone(),
+                    ticket: ticket.to_vec(),
+                    unk1: 0,
+                    unk2: 0,
+                    region: Region::Europe,
+                    patch_version: 9002,
+                };
+                world.run(
+                    |mut accounts: ViewMut<Account>,
+                     mut connections: ViewMut<GlobalConnection>,
+                     mut entities: EntitiesViewMut,
+                     mut resume_tokens: UniqueViewMut<ResumeTokens>,
+                     ticket_policy: UniqueView<LoginTicketPolicy>,
+                     ticket_validator: UniqueView<Box<dyn TicketValidator>>| {
+                        handle_request_login_arbiter(
+                            connection_global_world_id,
+                            &packet,
+                            &mut accounts,
+                            &mut connections,
+                            &mut entities,
+                            &mut resume_tokens,
+                            &ticket_policy,
+                            ticket_validator.as_ref(),
+                            Instant::now(),
+                        )
+                    },
+                )
+            };
+            let is_rejected = |outcome: HandlerOutcome| match outcome {
+                HandlerOutcome::Rejected(..) => true,
+                _ => false,
+            };
+
+            // The version of the connection isn't checked.
+            assert!(is_rejected(login(unchecked_id, &ticket)?));
+
+            let (first_id, _first_rx_channel) = add_connection(&world, true);
+            assert!(is_rejected(login(first_id, &[])?));
+            assert_eq!(login(first_id, &ticket)?, HandlerOutcome::Handled);
+            assert!(is_rejected(login(first_id, &ticket)?));
+
+            // The account is in use, so the ticket of the second connection isn't consumed and
+            // the client can retry with it once the first connection is gone.
+            let retry_ticket =
+                task::block_on(async { loginticket::upsert_ticket(&mut conn, account.id).await })?
+                    .ticket;
+            let (second_id, _second_rx_channel) = add_connection(&world, true);
+            assert_eq!(login(second_id, &retry_ticket)?, HandlerOutcome::Deferred);
+            assert_eq!(login(second_id, &retry_ticket)?, HandlerOutcome::Deferred);
+
+            world.run(
+                |mut accounts: ViewMut<Account>,
+                 mut connections: ViewMut<GlobalConnection>,
+                 mut user_spawns: ViewMut<GlobalUserSpawn>| {
+                    drop_connection(first_id, &mut accounts, &mut connections, &mut user_spawns);
+                },
+            );
+            assert_eq!(login(second_id, &retry_ticket)?, HandlerOutcome::Handled);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_check_version_invalid() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let (world, connection_global_world_id, mut rx_channel) =
+                    setup_with_connection(pool, true);
+
+                world.run(
+                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                        entities.add_entity(
+                            &mut messages,
+                            EcsMessage::new(Message::RequestCheckVersion {
+                                connection_global_world_id,
+                                packet: CCheckVersion {
+                                    version: vec![CCheckVersionEntry {
+                                        index: 0,
+                                        value: 366_222,
+                                    }],
+                                },
+                            }),
+                        )
+                    },
+                );
+
+                world.run(connection_manager_system);
+
+                assert!(
+                    rx_channel
+                        .all(|message| match *message.inner {
+                            Message::ResponseCheckVersion { packet, .. } => !packet.ok,
+                            Message::DropConnection { .. } => true,
+                            _ => false,
+                        })
+                        .await,
+                );
+
+                // The connection should be dropped.
+                let count = world.borrow::<View<GlobalConnection>>().iter().count();
+                assert_eq!(count, 0);
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_login_arbiter_valid() -> Result<()> {
+        db_test(|db_string| {
+            let (_conn, _rx_channel, world, connection_global_world_id, account, ticket) =
+                task::block_on(async {
+                    let pool = PgPool::new(db_string).await?;
+                    let mut conn = pool.acquire().await?;
+                    let (world, connection_global_world_id, rx_channel) =
+                        setup_with_connection(pool, true);
+                    let (account, ticket) = create_login(&mut conn).await?;
+
+                    Ok::<
+                        (
+                            PoolConnection<PgConnection>,
+                            Receiver<EcsMessage>,
+                            World,
+                            EntityId,
+                            entity::Account,
+                            Vec<u8>,
+                        ),
+                        anyhow::Error,
+                    >((
+                        conn,
+                        rx_channel,
+                        world,
+                        connection_global_world_id,
+                        account,
+                        ticket,
+                    ))
+                })?;
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name.clone(),
+                                ticket,
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    )
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let valid_count = world
+                .borrow::<View<component::Account>>()
                 .iter()
-                .filter(|acc| acc.id == account.id && acc.region == Region::Europe)
+                .filter(|acc| acc.id == AccountId(account.id) && acc.region == Region::Europe)
                 .count();
             assert_eq!(valid_count, 1);
 
@@ -738,21 +1460,243 @@ mod tests {
     }
 
     #[test]
-    fn test_login_arbiter_invalid() -> Result<()> {
+    fn test_login_arbiter_invalid() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
+            let (account, mut ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            // Make ticket invalid
+            ticket.make_ascii_uppercase();
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name,
+                                ticket,
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    )
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let mut count = 0;
+            loop {
+                if let Ok(message) = rx_channel.try_recv() {
+                    match *message.inner {
+                        Message::ResponseLoginArbiter { packet, .. } => {
+                            if !packet.success {
+                                count += 1;
+                            }
+                        }
+                        Message::DropConnection { .. } => {
+                            count += 1;
+                        }
+                        _ => {}
+                    }
+                } else {
+                    break;
+                }
+            }
+            assert_eq!(count, 2);
+
+            // The connection should be dropped.
+            let count = world.borrow::<View<GlobalConnection>>().iter().count();
+            assert_eq!(count, 0);
+
+            Ok(())
+        })
+    }
+
+    fn send_invalid_check_version(world: &World, connection_global_world_id: EntityId) {
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestCheckVersion {
+                        connection_global_world_id,
+                        packet: CCheckVersion {
+                            version: vec![CCheckVersionEntry {
+                                index: 0,
+                                value: 366_222,
+                            }],
+                        },
+                    }),
+                )
+            },
+        );
+        world.run(connection_manager_system);
+    }
+
+    #[test]
+    fn test_skip_login_checks_version() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let (world, connection_global_world_id, _rx_channel) =
+                    setup_with_connection(pool.clone(), false);
+                world
+                    .borrow::<UniqueViewMut<LoginTicketPolicy>>()
+                    .skip_login_checks = true;
+
+                send_invalid_check_version(&world, connection_global_world_id);
+                let connection_checked = world
+                    .borrow::<View<GlobalConnection>>()
+                    .try_get(connection_global_world_id)
+                    .map(|connection| connection.is_version_checked);
+                assert_eq!(connection_checked.ok(), Some(true));
+
+                // The same version is rejected without the flag.
+                let (world, connection_global_world_id, _rx_channel) =
+                    setup_with_connection(pool, false);
+
+                send_invalid_check_version(&world, connection_global_world_id);
+                let count = world.borrow::<View<GlobalConnection>>().iter().count();
+                assert_eq!(count, 0);
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_skip_login_checks_ticket() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
+            let (account, _) = task::block_on(async { create_login(&mut conn).await })?;
+            let account_id = AccountId(account.id);
+            world
+                .borrow::<UniqueViewMut<LoginTicketPolicy>>()
+                .skip_login_checks = true;
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name,
+                                ticket: b"not-a-valid-ticket".to_vec(),
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    )
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let mut accepted = false;
+            while let Ok(message) = rx_channel.try_recv() {
+                match *message.inner {
+                    Message::ResponseLoginArbiter { packet, .. } => accepted = packet.success,
+                    Message::DropConnection { .. } => panic!("Connection was dropped"),
+                    _ => {}
+                }
+            }
+            assert!(accepted);
+
+            let logged_in = world
+                .borrow::<View<Account>>()
+                .try_get(connection_global_world_id)
+                .map(|account| account.id);
+            assert_eq!(logged_in.ok(), Some(account_id));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_arbiter_invalid_utf8_ticket() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
+            let (account, _ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            // Tickets are compared as raw bytes, so bytes that aren't valid UTF-8 are rejected
+            // like any other wrong ticket.
+            let ticket = vec![0xff, 0xfe, 0xc3, 0x28, 0xa0, 0xa1, 0xe2, 0x28, 0xa1];
+            assert!(std::str::from_utf8(&ticket).is_err());
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name,
+                                ticket,
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    )
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let mut count = 0;
+            loop {
+                if let Ok(message) = rx_channel.try_recv() {
+                    match *message.inner {
+                        Message::ResponseLoginArbiter { packet, .. } => {
+                            assert!(!packet.success);
+                            count += 1;
+                        }
+                        Message::DropConnection { .. } => {
+                            count += 1;
+                        }
+                        _ => panic!("Received unexpected message"),
+                    }
+                } else {
+                    break;
+                }
+            }
+            assert_eq!(count, 2);
+
+            let count = world.borrow::<View<Account>>().iter().count();
+            assert_eq!(count, 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_arbiter_before_check_version() -> Result<()> {
         db_test(|db_string| {
             let pool = task::block_on(async { PgPool::new(db_string).await })?;
             let mut conn = task::block_on(async { pool.acquire().await })?;
-            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
-            let (account, mut ticket) = task::block_on(async { create_login(&mut conn).await })?;
-
-            // Make ticket invalid
-            ticket.make_ascii_uppercase();
+            let (world, connection_global_world_id, rx_channel) =
+                setup_with_connection(pool, false);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
 
             world.run(
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestLoginArbiter {
+                        EcsMessage::new(Message::RequestLoginArbiter {
                             connection_global_world_id,
                             packet: CLoginArbiter {
                                 master_account_name: account.name,
@@ -772,16 +1716,15 @@ mod tests {
             let mut count = 0;
             loop {
                 if let Ok(message) = rx_channel.try_recv() {
-                    match *message {
+                    match *message.inner {
                         Message::ResponseLoginArbiter { packet, .. } => {
-                            if !packet.success {
-                                count += 1;
-                            }
+                            assert!(!packet.success);
+                            count += 1;
                         }
                         Message::DropConnection { .. } => {
                             count += 1;
                         }
-                        _ => {}
+                        _ => panic!("Received unexpected message"),
                     }
                 } else {
                     break;
@@ -789,9 +1732,86 @@ mod tests {
             }
             assert_eq!(count, 2);
 
-            // The connection should be dropped.
+            // The connection should be dropped without creating an account component.
             let count = world.borrow::<View<GlobalConnection>>().iter().count();
             assert_eq!(count, 0);
+            let count = world.borrow::<View<Account>>().iter().count();
+            assert_eq!(count, 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_arbiter_after_check_version() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) =
+                setup_with_connection(pool, false);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestCheckVersion {
+                            connection_global_world_id,
+                            packet: CCheckVersion {
+                                version: vec![
+                                    CCheckVersionEntry {
+                                        index: 0,
+                                        value: 366_222,
+                                    },
+                                    CCheckVersionEntry {
+                                        index: 1,
+                                        value: 365_535,
+                                    },
+                                ],
+                            },
+                        }),
+                    );
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name,
+                                ticket,
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    );
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let mut check_version = None;
+            let mut login_arbiter = None;
+            while let Ok(message) = rx_channel.try_recv() {
+                match *message.inner {
+                    Message::ResponseCheckVersion { packet, .. } => check_version = Some(packet),
+                    Message::ResponseLoginArbiter { packet, .. } => login_arbiter = Some(packet),
+                    Message::DropConnection { .. } => panic!("Connection was dropped"),
+                    _ => { /* Post initialization */ }
+                }
+            }
+            assert!(check_version.unwrap().ok);
+            assert!(login_arbiter.unwrap().success);
+
+            // The connection is fully initialized.
+            let connections = world.borrow::<View<GlobalConnection>>();
+            let connection = connections.try_get(connection_global_world_id).unwrap();
+            assert!(connection.is_version_checked);
+            assert!(connection.is_authenticated);
+            assert!(world
+                .borrow::<View<Account>>()
+                .try_get(connection_global_world_id)
+                .is_ok());
 
             Ok(())
         })
@@ -811,7 +1831,7 @@ mod tests {
                     entities.add_component(
                         &mut accounts,
                         Account {
-                            id: account.id,
+                            id: AccountId(account.id),
                             region: Region::Europe,
                         },
                         connection_global_world_id,
@@ -823,7 +1843,7 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestLoginArbiter {
+                        EcsMessage::new(Message::RequestLoginArbiter {
                             connection_global_world_id,
                             packet: CLoginArbiter {
                                 master_account_name: account.name,
@@ -843,7 +1863,7 @@ mod tests {
             let mut count = 0;
             loop {
                 if let Ok(message) = rx_channel.try_recv() {
-                    match *message {
+                    match *message.inner {
                         Message::ResponseLoginArbiter { packet, .. } => {
                             if !packet.success {
                                 count += 1;
@@ -868,6 +1888,183 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_login_arbiter_soft_reject_account_in_use() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            // The account is logged in on another connection.
+            let (other_channel, _other_rx_channel) = channel(1024);
+            let other_id = world.run(
+                |mut entities: EntitiesViewMut,
+                 mut connections: ViewMut<GlobalConnection>,
+                 mut accounts: ViewMut<Account>| {
+                    entities.add_entity(
+                        (&mut connections, &mut accounts),
+                        (
+                            GlobalConnection {
+                                channel: other_channel,
+                                is_authenticated: true,
+                                is_version_checked: true,
+                                last_pong: Instant::now(),
+                                waiting_for_pong: false,
+                                peer_addr: "127.0.0.1:10002".parse().unwrap(),
+                                connected_since: Instant::now(),
+                                schema_version: None,
+                            },
+                            Account {
+                                id: AccountId(account.id),
+                                region: Region::Europe,
+                            },
+                        ),
+                    )
+                },
+            );
+
+            let login = || {
+                world.run(
+                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                        entities.add_entity(
+                            &mut messages,
+                            EcsMessage::new(Message::RequestLoginArbiter {
+                                connection_global_world_id,
+                                packet: CLoginArbiter {
+                                    master_account_name: account.name.clone(),
+                                    ticket: ticket.clone(),
+                                    unk1: 0,
+                                    unk2: 0,
+                                    region: Region::Europe,
+                                    patch_version: 9002,
+                                },
+                            }),
+                        )
+                    },
+                );
+                world.run(connection_manager_system);
+                world.run(cleaner_system);
+            };
+
+            login();
+
+            let mut soft_rejects = 0;
+            while let Ok(message) = rx_channel.try_recv() {
+                match *message.inner {
+                    Message::ResponseLoginArbiter { packet, .. } => {
+                        assert!(!packet.success);
+                        assert!(packet.login_queue);
+                        assert_eq!(packet.region, Region::Europe);
+                        soft_rejects += 1;
+                    }
+                    Message::DropConnection { .. } => panic!("Connection was dropped"),
+                    _ => {}
+                }
+            }
+            assert_eq!(soft_rejects, 1);
+            assert_eq!(
+                world.borrow::<UniqueView<HandlerLatencies>>().outcomes[&Opcode::C_LOGIN_ARBITER]
+                    .deferred,
+                1
+            );
+
+            // Both connections stay open and only the other connection is logged in.
+            let connection = world
+                .borrow::<View<GlobalConnection>>()
+                .try_get(connection_global_world_id)
+                .map(|connection| connection.is_authenticated);
+            assert_eq!(connection.ok(), Some(false));
+            assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 2);
+            assert!(world
+                .borrow::<View<Account>>()
+                .try_get(connection_global_world_id)
+                .is_err());
+
+            // The client retries with the same ticket once the other connection is closed.
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestConnectionClosed {
+                            connection_global_world_id: other_id,
+                            kind: CloseKind::Client,
+                        }),
+                    )
+                },
+            );
+            world.run(connection_manager_system);
+            world.run(cleaner_system);
+            login();
+
+            let mut accepted = false;
+            while let Ok(message) = rx_channel.try_recv() {
+                match *message.inner {
+                    Message::ResponseLoginArbiter { packet, .. } => accepted = packet.success,
+                    Message::DropConnection { .. } => panic!("Connection was dropped"),
+                    _ => {}
+                }
+            }
+            assert!(accepted);
+            let logged_in = world
+                .borrow::<View<Account>>()
+                .try_get(connection_global_world_id)
+                .map(|account| account.id);
+            assert_eq!(logged_in.ok(), Some(AccountId(account.id)));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_arbiter_storage_unavailable() -> Result<()> {
+        let world = setup_world(Box::new(UnavailableTicketValidator));
+        let (connection_global_world_id, rx_channel) = add_connection(&world, true);
+
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestLoginArbiter {
+                        connection_global_world_id,
+                        packet: CLoginArbiter {
+                            master_account_name: "testaccount".to_string(),
+                            ticket: b"0000000000000000".to_vec(),
+                            unk1: 0,
+                            unk2: 0,
+                            region: Region::Europe,
+                            patch_version: 9002,
+                        },
+                    }),
+                )
+            },
+        );
+
+        world.run(connection_manager_system);
+
+        let mut soft_rejects = 0;
+        while let Ok(message) = rx_channel.try_recv() {
+            match *message.inner {
+                Message::ResponseLoginArbiter { packet, .. } => {
+                    assert!(!packet.success);
+                    assert!(packet.login_queue);
+                    soft_rejects += 1;
+                }
+                Message::DropConnection { .. } => panic!("Connection was dropped"),
+                _ => {}
+            }
+        }
+        assert_eq!(soft_rejects, 1);
+
+        let latencies = world.borrow::<UniqueView<HandlerLatencies>>();
+        let counts = &latencies.outcomes[&Opcode::C_LOGIN_ARBITER];
+        assert_eq!(counts.storage_failures, 1);
+        assert_eq!(counts.rejected, 0);
+        assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 1);
+
+        Ok(())
+    }
+
     #[test]
     fn test_login_sequence() -> Result<()> {
         db_test(|db_string| {
@@ -881,8 +2078,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RegisterConnection {
+                        EcsMessage::new(Message::RegisterConnection {
                             connection_channel: tx_channel.clone(),
+                            peer_addr: "127.0.0.1:10001".parse().unwrap(),
                         }),
                     )
                 },
@@ -891,7 +2089,7 @@ mod tests {
             world.run(connection_manager_system);
 
             let con = match rx_channel.try_recv() {
-                Ok(message) => match *message {
+                Ok(message) => match *message.inner {
                     Message::RegisterConnectionFinished {
                         connection_global_world_id,
                     } => connection_global_world_id,
@@ -907,7 +2105,7 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestCheckVersion {
+                        EcsMessage::new(Message::RequestCheckVersion {
                             connection_global_world_id: con,
                             packet: CCheckVersion {
                                 version: vec![
@@ -925,7 +2123,7 @@ mod tests {
                     );
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestLoginArbiter {
+                        EcsMessage::new(Message::RequestLoginArbiter {
                             connection_global_world_id: con,
                             packet: CLoginArbiter {
                                 master_account_name: account.name.clone(),
@@ -991,7 +2189,7 @@ mod tests {
             } = &*list[3]
             {
                 assert_eq!(*connection_global_world_id, con);
-                assert_eq!(*account_id, account.id);
+                assert_eq!(*account_id, AccountId(account.id));
                 assert_eq!(packet.success, true);
                 assert_eq!(packet.status, 65538);
             } else {
@@ -1004,13 +2202,125 @@ mod tests {
             } = &*list[4]
             {
                 assert_eq!(*connection_global_world_id, con);
-                assert_eq!(packet.account_id, account.id);
+                assert_eq!(packet.account_id, AccountId(account.id));
                 assert_eq!(packet.server_name, "Almetica".to_string());
                 assert!(!packet.server_name.trim().is_empty());
             } else {
                 panic!("Received packets in wrong order");
             }
 
+            // No MOTD is configured, so it's not send.
+            match *rx_channel.try_recv().unwrap().inner {
+                Message::ResponseResumeToken {
+                    connection_global_world_id,
+                    ..
+                } => assert_eq!(connection_global_world_id, con),
+                _ => panic!("Received packets in wrong order"),
+            }
+            assert!(rx_channel.try_recv().is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_sends_post_login_sequence() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+            {
+                let mut ticket_policy = world.borrow::<UniqueViewMut<LoginTicketPolicy>>();
+                ticket_policy.motd = "Welcome to Almetica".to_string();
+                ticket_policy.post_login_sequence = vec![
+                    PostLoginPacket::LoginAccountInfo,
+                    PostLoginPacket::Motd,
+                    PostLoginPacket::LoginArbiter,
+                    PostLoginPacket::CheckVersion,
+                ];
+            }
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name,
+                                ticket,
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    )
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let mut opcodes = Vec::new();
+            while let Ok(message) = rx_channel.try_recv() {
+                opcodes.push(message.opcode());
+            }
+            assert_eq!(
+                opcodes,
+                vec![
+                    Some(Opcode::S_LOGIN_ACCOUNT_INFO),
+                    Some(Opcode::S_CHAT),
+                    Some(Opcode::S_LOGIN_ARBITER),
+                    Some(Opcode::S_CHECK_VERSION),
+                ]
+            );
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_sends_motd() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel) = setup_with_connection(pool, true);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+            world.borrow::<UniqueViewMut<LoginTicketPolicy>>().motd =
+                "Welcome to Almetica".to_string();
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestLoginArbiter {
+                            connection_global_world_id,
+                            packet: CLoginArbiter {
+                                master_account_name: account.name,
+                                ticket,
+                                unk1: 0,
+                                unk2: 0,
+                                region: Region::Europe,
+                                patch_version: 9002,
+                            },
+                        }),
+                    )
+                },
+            );
+
+            world.run(connection_manager_system);
+
+            let mut motds = Vec::new();
+            while let Ok(message) = rx_channel.try_recv() {
+                if let Message::ResponseChat { packet, .. } = *message.inner {
+                    motds.push(packet);
+                }
+            }
+            assert_eq!(motds.len(), 1);
+            assert_eq!(motds[0].message, "Welcome to Almetica");
+            assert_eq!(motds[0].channel, NOTICE_CHAT_CHANNEL);
+
             Ok(())
         })
     }
@@ -1050,23 +2360,64 @@ mod tests {
                     panic!("Couldn't find ping message");
                 }
 
-                // Check if waiting_for_pong is updated
-                world.run(|connections: View<GlobalConnection>| {
-                    if let Ok(connection) = (&connections).try_get(connection_global_world_id) {
-                        if !connection.waiting_for_pong {
-                            panic!("Waiting_for_pong was not set after ping");
-                        }
-                    } else {
-                        panic!("Couldn't find connection component");
-                    }
+                // Check if waiting_for_pong is updated
+                world.run(|connections: View<GlobalConnection>| {
+                    if let Ok(connection) = (&connections).try_get(connection_global_world_id) {
+                        if !connection.waiting_for_pong {
+                            panic!("Waiting_for_pong was not set after ping");
+                        }
+                    } else {
+                        panic!("Couldn't find connection component");
+                    }
+                });
+
+                // Send pong
+                world.run(
+                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                        entities.add_entity(
+                            &mut messages,
+                            EcsMessage::new(Message::RequestPong {
+                                connection_global_world_id,
+                                packet: CPong {},
+                            }),
+                        )
+                    },
+                );
+
+                world.run(connection_manager_system);
+
+                // Check if last_pong is updated
+                world.run(|connections: View<GlobalConnection>| {
+                    let component = &connections[connection_global_world_id];
+                    assert_eq!(component.last_pong > old_pong, true);
+                });
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_unsolicited_pong() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let (world, connection_global_world_id, _rx_channel) =
+                    setup_with_connection(pool, true);
+
+                let old_pong = Instant::now()
+                    .checked_sub(Duration::from_secs(PING_INTERVAL - 1))
+                    .unwrap();
+                world.run(|mut connections: ViewMut<GlobalConnection>| {
+                    connections[connection_global_world_id].last_pong = old_pong;
                 });
 
-                // Send pong
+                // Send pong without a ping
                 world.run(
                     |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                         entities.add_entity(
                             &mut messages,
-                            Box::new(Message::RequestPong {
+                            EcsMessage::new(Message::RequestPong {
                                 connection_global_world_id,
                                 packet: CPong {},
                             }),
@@ -1076,10 +2427,11 @@ mod tests {
 
                 world.run(connection_manager_system);
 
-                // Check if last_pong is updated
+                // The pong doesn't count as an answer
                 world.run(|connections: View<GlobalConnection>| {
                     let component = &connections[connection_global_world_id];
-                    assert_eq!(component.last_pong > old_pong, true);
+                    assert_eq!(component.last_pong, old_pong);
+                    assert!(!component.waiting_for_pong);
                 });
 
                 Ok(())
@@ -1190,6 +2542,56 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_version_check_grace_period() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+
+                let (world, connection_global_world_id, rx_channel) =
+                    setup_with_connection(pool, false);
+                let grace = MAX_UNAUTHENTICATED_LIFETIME * 2;
+                world
+                    .borrow::<UniqueViewMut<LoginTicketPolicy>>()
+                    .version_check_grace = Duration::from_secs(grace);
+
+                let clock = ManualClock::default();
+                *world.borrow::<UniqueViewMut<Box<dyn Clock>>>() = Box::new(clock.clone());
+                world.run(|mut connections: ViewMut<GlobalConnection>| {
+                    connections[connection_global_world_id].last_pong = clock.now();
+                });
+
+                // Slow clients are tolerated inside the grace period
+                clock.advance(Duration::from_secs(grace - 1));
+                world.run(connection_manager_system);
+
+                assert!(world
+                    .borrow::<View<GlobalConnection>>()
+                    .try_get(connection_global_world_id)
+                    .is_ok());
+                assert!(rx_channel.try_recv().is_err());
+
+                // A connection that never checks its version is dropped after the grace period
+                clock.advance(Duration::from_secs(2));
+                world.run(connection_manager_system);
+
+                match rx_channel.try_recv() {
+                    Ok(message) => match &*message {
+                        Message::DropConnection { .. } => { /* Ok */ }
+                        m => panic!("Expected a drop connection message, got {}", m),
+                    },
+                    Err(_) => panic!("Couldn't find drop connection message"),
+                }
+                assert!(world
+                    .borrow::<View<GlobalConnection>>()
+                    .try_get(connection_global_world_id)
+                    .is_err());
+
+                Ok(())
+            })
+        })
+    }
+
     #[test]
     fn test_dont_drop_authenticated_connection_without_ping_pong() -> Result<()> {
         db_test(|db_string| {
@@ -1220,4 +2622,409 @@ mod tests {
             })
         })
     }
+
+    fn add_login_arbiter_message(
+        world: &World,
+        connection_global_world_id: EntityId,
+        ticket: Vec<u8>,
+    ) {
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestLoginArbiter {
+                        connection_global_world_id,
+                        packet: CLoginArbiter {
+                            master_account_name: "testaccount".to_string(),
+                            ticket,
+                            unk1: 0,
+                            unk2: 0,
+                            region: Region::Europe,
+                            patch_version: 9002,
+                        },
+                    }),
+                )
+            },
+        );
+    }
+
+    /// Returns the resume token of the last login the connection received.
+    fn received_resume_token(rx_channel: &Receiver<EcsMessage>) -> Option<Vec<u8>> {
+        let mut token = None;
+        while let Ok(message) = rx_channel.try_recv() {
+            if let Message::ResponseResumeToken { packet, .. } = &*message {
+                token = Some(packet.token.to_vec());
+            }
+        }
+        token
+    }
+
+    fn close_connection(world: &World, connection_global_world_id: EntityId) {
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestConnectionClosed {
+                        connection_global_world_id,
+                        kind: CloseKind::Client,
+                    }),
+                )
+            },
+        );
+        world.run(connection_manager_system);
+        world.run(cleaner_system);
+    }
+
+    fn logged_in_account(world: &World, connection_global_world_id: EntityId) -> Option<AccountId> {
+        world
+            .borrow::<View<Account>>()
+            .try_get(connection_global_world_id)
+            .map(|account| account.id)
+            .ok()
+    }
+
+    #[test]
+    fn test_login_arbiter_resume_token() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, first_id, first_rx_channel) = setup_with_connection(pool, true);
+            let (account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            add_login_arbiter_message(&world, first_id, ticket);
+            world.run(connection_manager_system);
+            let token = received_resume_token(&first_rx_channel).expect("No resume token received");
+
+            // The token can't be used while the account is still connected.
+            let (second_id, second_rx_channel) = add_connection(&world, true);
+            add_login_arbiter_message(&world, second_id, token.clone());
+            world.run(connection_manager_system);
+            assert_eq!(logged_in_account(&world, second_id), None);
+
+            close_connection(&world, first_id);
+
+            add_login_arbiter_message(&world, second_id, token.clone());
+            world.run(connection_manager_system);
+            assert_eq!(
+                logged_in_account(&world, second_id),
+                Some(AccountId(account.id))
+            );
+
+            // The resumed login receives a new token.
+            let new_token =
+                received_resume_token(&second_rx_channel).expect("No resume token received");
+            assert_ne!(new_token, token);
+
+            // The token is invalidated once it's used.
+            close_connection(&world, second_id);
+            let (third_id, _third_rx_channel) = add_connection(&world, true);
+            add_login_arbiter_message(&world, third_id, token);
+            world.run(connection_manager_system);
+            assert_eq!(logged_in_account(&world, third_id), None);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_login_arbiter_expired_resume_token() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, first_id, first_rx_channel) = setup_with_connection(pool, true);
+            let (_account, ticket) = task::block_on(async { create_login(&mut conn).await })?;
+
+            let clock = ManualClock::default();
+            *world.borrow::<UniqueViewMut<Box<dyn Clock>>>() = Box::new(clock.clone());
+
+            add_login_arbiter_message(&world, first_id, ticket);
+            world.run(connection_manager_system);
+            let token = received_resume_token(&first_rx_channel).expect("No resume token received");
+            close_connection(&world, first_id);
+
+            clock.advance(Duration::from_secs(RESUME_TOKEN_LIFETIME));
+            let (second_id, second_rx_channel) = add_connection(&world, true);
+            world.run(|mut connections: ViewMut<GlobalConnection>| {
+                connections[second_id].last_pong = clock.now();
+            });
+            add_login_arbiter_message(&world, second_id, token);
+            world.run(connection_manager_system);
+
+            // Falls back to the ticket verification, which fails.
+            assert_eq!(world.borrow::<View<component::Account>>().iter().count(), 0);
+            assert!(world.borrow::<UniqueView<ResumeTokens>>().tokens.is_empty());
+
+            let mut dropped = false;
+            while let Ok(message) = second_rx_channel.try_recv() {
+                if let Message::DropConnection { .. } = *message.inner {
+                    dropped = true;
+                }
+            }
+            assert!(dropped);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_check_version_of_dropped_connection() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let (world, connection_global_world_id, rx_channel) =
+                    setup_with_connection(pool, false);
+
+                world.run(
+                    |connections: ViewMut<GlobalConnection>, entities: EntitiesViewMut| {
+                        assert!(!is_dropped_connection(
+                            connection_global_world_id,
+                            &connections,
+                            &entities
+                        ));
+                    },
+                );
+
+                world.run(|mut all_storages: AllStoragesViewMut| {
+                    all_storages.delete(connection_global_world_id);
+                });
+
+                world.run(
+                    |connections: ViewMut<GlobalConnection>, entities: EntitiesViewMut| {
+                        assert!(is_dropped_connection(
+                            connection_global_world_id,
+                            &connections,
+                            &entities
+                        ));
+                    },
+                );
+
+                world.run(
+                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                        entities.add_entity(
+                            &mut messages,
+                            EcsMessage::new(Message::RequestCheckVersion {
+                                connection_global_world_id,
+                                packet: CCheckVersion {
+                                    version: vec![
+                                        CCheckVersionEntry {
+                                            index: 0,
+                                            value: 366_222,
+                                        },
+                                        CCheckVersionEntry {
+                                            index: 1,
+                                            value: 365_535,
+                                        },
+                                    ],
+                                },
+                            }),
+                        )
+                    },
+                );
+
+                world.run(connection_manager_system);
+
+                // Neither a rejection nor a drop is send for the already dropped connection.
+                assert!(rx_channel.try_recv().is_err());
+                assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 0);
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_connection_closed_by_client() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let (world, connection_global_world_id, _rx_channel) =
+                    setup_with_connection(pool, true);
+
+                world.run(|entities: EntitiesView, mut accounts: ViewMut<Account>| {
+                    entities.add_component(
+                        &mut accounts,
+                        Account {
+                            id: AccountId(1),
+                            region: Region::Europe,
+                        },
+                        connection_global_world_id,
+                    );
+                });
+
+                world.run(
+                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                        entities.add_entity(
+                            &mut messages,
+                            EcsMessage::new(Message::RequestConnectionClosed {
+                                connection_global_world_id,
+                                kind: CloseKind::Client,
+                            }),
+                        )
+                    },
+                );
+
+                world.run(connection_manager_system);
+
+                assert_eq!(world.borrow::<View<GlobalConnection>>().iter().count(), 0);
+                assert_eq!(world.borrow::<View<Account>>().iter().count(), 0);
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_missing_connection_component_is_no_dropped_connection() {
+        let world = World::new();
+        let connection_global_world_id =
+            world.run(|mut entities: EntitiesViewMut| entities.add_entity((), ()));
+
+        world.run(
+            |connections: ViewMut<GlobalConnection>, entities: EntitiesViewMut| {
+                assert!(!is_dropped_connection(
+                    connection_global_world_id,
+                    &connections,
+                    &entities
+                ));
+            },
+        );
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum HandshakeEvent {
+        Register,
+        CheckVersion,
+        LoginArbiter,
+        Pong,
+        Close,
+    }
+
+    const HANDSHAKE_EVENTS: [HandshakeEvent; 5] = [
+        HandshakeEvent::Register,
+        HandshakeEvent::CheckVersion,
+        HandshakeEvent::LoginArbiter,
+        HandshakeEvent::Pong,
+        HandshakeEvent::Close,
+    ];
+
+    fn handshake_message(
+        event: HandshakeEvent,
+        connection_global_world_id: EntityId,
+        account_name: &str,
+        ticket: &[u8],
+    ) -> Message {
+        match event {
+            HandshakeEvent::Register => {
+                let (tx_channel, _) = channel(1024);
+                Message::RegisterConnection {
+                    connection_channel: tx_channel,
+                    peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                }
+            }
+            HandshakeEvent::CheckVersion => Message::RequestCheckVersion {
+                connection_global_world_id,
+                packet: CCheckVersion {
+                    version: vec![
+                        CCheckVersionEntry {
+                            index: 0,
+                            value: 366_222,
+                        },
+                        CCheckVersionEntry {
+                            index: 1,
+                            value: 365_535,
+                        },
+                    ],
+                },
+            },
+            HandshakeEvent::LoginArbiter => Message::RequestLoginArbiter {
+                connection_global_world_id,
+                packet: CLoginArbiter {
+                    master_account_name: account_name.to_string(),
+                    ticket: ticket.to_vec(),
+                    unk1: 0,
+                    unk2: 0,
+                    region: Region::Europe,
+                    patch_version: 9002,
+                },
+            },
+            HandshakeEvent::Pong => Message::RequestPong {
+                connection_global_world_id,
+                packet: CPong {},
+            },
+            HandshakeEvent::Close => Message::RequestConnectionClosed {
+                connection_global_world_id,
+                kind: CloseKind::Client,
+            },
+        }
+    }
+
+    fn assert_consistent_state(world: &World, events: &[HandshakeEvent]) {
+        let connections = world.borrow::<View<GlobalConnection>>();
+        let accounts = world.borrow::<View<Account>>();
+
+        for connection in connections.iter() {
+            assert!(
+                !connection.is_authenticated || connection.is_version_checked,
+                "Connection is authenticated without a version check after {:?}",
+                events
+            );
+        }
+        for (id, _) in accounts.iter().with_id() {
+            match (&connections).try_get(id) {
+                Ok(connection) => assert!(
+                    connection.is_authenticated,
+                    "Account of an unauthenticated connection after {:?}",
+                    events
+                ),
+                Err(_) => panic!("Account without a connection after {:?}", events),
+            }
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_handshake_order() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (account, _) = task::block_on(async { create_login(&mut conn).await })?;
+            // A fixed seed keeps failures reproducible.
+            let mut rng = StdRng::seed_from_u64(434);
+
+            for _ in 0..64 {
+                let events: Vec<HandshakeEvent> = (0..8)
+                    .map(|_| *HANDSHAKE_EVENTS.choose(&mut rng).unwrap())
+                    .collect();
+                let ticket = task::block_on(async {
+                    loginticket::upsert_ticket(&mut conn, account.id).await
+                })?
+                .ticket;
+                let world = setup(pool.clone());
+                let (connection_global_world_id, _rx_channel) = add_connection(&world, false);
+
+                for event in &events {
+                    let message = handshake_message(
+                        *event,
+                        connection_global_world_id,
+                        &account.name,
+                        &ticket,
+                    );
+                    world.run(
+                        |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                            entities.add_entity(&mut messages, EcsMessage::new(message));
+                        },
+                    );
+
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        world.run(connection_manager_system)
+                    }));
+                    assert!(result.is_ok(), "System panicked on {:?}", events);
+
+                    world.run(cleaner_system);
+                    assert_consistent_state(&world, &events);
+                }
+            }
+
+            Ok(())
+        })
+    }
 }
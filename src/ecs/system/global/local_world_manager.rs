@@ -8,6 +8,7 @@ use crate::ecs::resource::{DeletionList, GlobalMessageChannel};
 use crate::ecs::system::send_message;
 use crate::{ecs, Result};
 use anyhow::{ensure, Context};
+use async_std::sync::Sender;
 use async_std::task;
 use shipyard::*;
 use sqlx::PgPool;
@@ -17,7 +18,6 @@ use tracing::{debug, error, info, info_span};
 
 const LOCAL_WORLD_IDLE_LIFETIME_SEC: u64 = 15;
 
-// TODO write tests for the local_world_manager_system
 pub fn local_world_manager_system(
     incoming_messages: View<EcsMessage>,
     _connections: View<GlobalConnection>,
@@ -99,55 +99,43 @@ fn handle_user_requesting_spawn(
     global_world_channel: &UniqueView<GlobalMessageChannel>,
     pool: &UniqueView<PgPool>,
 ) -> Result<()> {
-    // TODO once we implement parties / dungeons / pvp arenas, this code needs to be extended
-    let (world_id, channel) = if let Some((world_id, world)) = local_worlds
-        .iter()
-        .with_id()
-        .filter(|(_id, world)| world.zone_id == spawn.zone_id)
-        .next()
-    {
-        info!(
-            "Spawning user {:?} in local world {:?}",
-            connection_global_world_id, world_id
-        );
-        world.users.insert(connection_global_world_id);
-        world.deadline = None;
-
-        (world_id, world.channel.clone())
-    } else {
-        // TODO once we have implemented the datacenter parser, we need to extend this part
-        let world_id = entities.add_entity((), ());
-        let mut local_world = ecs::world::LocalWorld::new(
-            &**config.clone(),
-            &**pool.clone(),
-            world_id,
-            global_world_channel.channel.clone(),
-        );
-        let local_world_channel = local_world.channel.clone();
-        let join_handle = task::spawn_blocking(move || {
-            local_world.run();
-            Ok(())
-        });
+    let (world_id, channel) =
+        if let Some(joined) = change_local_world(spawn, connection_global_world_id, local_worlds) {
+            joined
+        } else {
+            // TODO once we have implemented the datacenter parser, we need to extend this part
+            let world_id = entities.add_entity((), ());
+            let mut local_world = ecs::world::LocalWorld::new(
+                &**config.clone(),
+                &**pool.clone(),
+                world_id,
+                global_world_channel.channel.clone(),
+            );
+            let local_world_channel = local_world.channel.clone();
+            let join_handle = task::spawn_blocking(move || {
+                local_world.run();
+                Ok(())
+            });
 
-        let mut users = HashSet::new();
-        users.insert(connection_global_world_id);
-
-        entities.add_component(
-            local_worlds,
-            LocalWorld {
-                instance_type: LocalWorldType::Field,
-                channel_num: None,
-                zone_id: spawn.zone_id,
-                channel: local_world_channel.clone(),
-                join_handle,
-                users,
-                deadline: None,
-            },
-            world_id,
-        );
+            let mut users = HashSet::new();
+            users.insert(connection_global_world_id);
+
+            entities.add_component(
+                local_worlds,
+                LocalWorld {
+                    instance_type: LocalWorldType::Field,
+                    channel_num: None,
+                    zone_id: spawn.zone_id,
+                    channel: local_world_channel.clone(),
+                    join_handle,
+                    users,
+                    deadline: None,
+                },
+                world_id,
+            );
 
-        (world_id, local_world_channel)
-    };
+            (world_id, local_world_channel)
+        };
 
     spawn.local_world_id = Some(world_id);
     spawn.local_world_channel = Some(channel);
@@ -183,13 +171,72 @@ fn handle_user_despawn(
         &spawn.local_world_channel.clone().unwrap(),
     );
 
-    // Remove user from the local world users list and set the deadline if there are no users left on the local world
+    leave_local_world(
+        connection_global_world_id,
+        spawn.local_world_id.unwrap(),
+        local_worlds,
+    )
+}
+
+/// Moves the user out of its previous local world and into the local world of its zone. Returns
+/// `None` if there is no local world for the zone yet.
+fn change_local_world(
+    spawn: &mut GlobalUserSpawn,
+    connection_global_world_id: EntityId,
+    local_worlds: &mut ViewMut<LocalWorld>,
+) -> Option<(EntityId, Sender<EcsMessage>)> {
+    // A user that changes the zone leaves the local world of the old zone.
+    if let Some(local_world_id) = spawn.local_world_id.take() {
+        if let (Some(connection_local_world_id), Some(channel)) = (
+            spawn.connection_local_world_id.take(),
+            spawn.local_world_channel.take(),
+        ) {
+            send_message(assemble_user_despawn(connection_local_world_id), &channel);
+        }
+        if let Err(e) = leave_local_world(connection_global_world_id, local_world_id, local_worlds)
+        {
+            debug!("Can't leave the previous local world: {:?}", e);
+        }
+    }
+
+    join_local_world(spawn.zone_id, connection_global_world_id, local_worlds)
+}
+
+/// Adds the user to the local world of the given zone. Returns the ID and the channel of the
+/// local world, which the connection uses to route it's local messages, or `None` if there is
+/// no local world for the zone yet.
+fn join_local_world(
+    zone_id: i32,
+    connection_global_world_id: EntityId,
+    local_worlds: &mut ViewMut<LocalWorld>,
+) -> Option<(EntityId, Sender<EcsMessage>)> {
+    // TODO once we implement parties / dungeons / pvp arenas, this code needs to be extended
+    let (world_id, world) = local_worlds
+        .iter()
+        .with_id()
+        .find(|(_id, world)| world.zone_id == zone_id)?;
+
+    info!(
+        "Spawning user {:?} in local world {:?}",
+        connection_global_world_id, world_id
+    );
+    world.users.insert(connection_global_world_id);
+    world.deadline = None;
+
+    Some((world_id, world.channel.clone()))
+}
+
+/// Removes the user from the users list of the local world and sets the deadline if there are no
+/// users left on the local world.
+fn leave_local_world(
+    connection_global_world_id: EntityId,
+    local_world_id: EntityId,
+    local_worlds: &mut ViewMut<LocalWorld>,
+) -> Result<()> {
     let mut local_world = local_worlds
-        .try_get(spawn.local_world_id.unwrap())
+        .try_get(local_world_id)
         .context("Can't find the local world")?;
-    local_world
-        .users
-        .remove(&spawn.connection_local_world_id.unwrap());
+    local_world.users.remove(&connection_global_world_id);
 
     if local_world.users.is_empty() {
         let deadline = Instant::now()
@@ -236,11 +283,122 @@ fn handle_local_world_loaded(
 }
 
 fn assemble_shutdown_message() -> EcsMessage {
-    Box::new(Message::ShutdownSignal { forced: false })
+    EcsMessage::new(Message::ShutdownSignal { forced: false })
 }
 
 fn assemble_user_despawn(connection_local_world_id: EntityId) -> EcsMessage {
-    Box::new(Message::UserDespawn {
+    EcsMessage::new(Message::UserDespawn {
         connection_local_world_id,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{AccountId, UserId};
+    use async_std::sync::{channel, Receiver};
+
+    fn add_local_world(world: &World, zone_id: i32) -> (EntityId, Receiver<EcsMessage>) {
+        let (tx_channel, rx_channel) = channel(1024);
+        let local_world_id = world.run(
+            |mut entities: EntitiesViewMut, mut local_worlds: ViewMut<LocalWorld>| {
+                entities.add_entity(
+                    &mut local_worlds,
+                    LocalWorld {
+                        instance_type: LocalWorldType::Field,
+                        channel_num: None,
+                        zone_id,
+                        channel: tx_channel,
+                        join_handle: task::spawn(async { Ok(()) }),
+                        users: HashSet::new(),
+                        deadline: None,
+                    },
+                )
+            },
+        );
+        (local_world_id, rx_channel)
+    }
+
+    #[test]
+    fn test_zone_change_despawns_from_old_local_world() {
+        let world = World::new();
+        let (zone_a_id, zone_a_rx) = add_local_world(&world, 1);
+        let (zone_b_id, zone_b_rx) = add_local_world(&world, 2);
+        let connection_local_world_id = world.borrow::<EntitiesViewMut>().add_entity((), ());
+        let zone_b_channel = world
+            .borrow::<View<LocalWorld>>()
+            .try_get(zone_b_id)
+            .unwrap()
+            .channel
+            .clone();
+
+        // The user is spawned in zone B and requests to spawn in zone A.
+        let connection_global_world_id = world.run(
+            |mut entities: EntitiesViewMut,
+             mut user_spawns: ViewMut<GlobalUserSpawn>,
+             mut local_worlds: ViewMut<LocalWorld>| {
+                let connection_global_world_id = entities.add_entity(
+                    &mut user_spawns,
+                    GlobalUserSpawn {
+                        user_id: UserId(1),
+                        account_id: AccountId(1),
+                        status: UserSpawnStatus::Requesting,
+                        zone_id: 1,
+                        connection_local_world_id: Some(connection_local_world_id),
+                        local_world_id: Some(zone_b_id),
+                        local_world_channel: Some(zone_b_channel),
+                        marked_for_deletion: false,
+                        is_alive: true,
+                    },
+                );
+                (&mut local_worlds)
+                    .try_get(zone_b_id)
+                    .unwrap()
+                    .users
+                    .insert(connection_global_world_id);
+                connection_global_world_id
+            },
+        );
+
+        let joined = world.run(
+            |mut user_spawns: ViewMut<GlobalUserSpawn>, mut local_worlds: ViewMut<LocalWorld>| {
+                let spawn = (&mut user_spawns)
+                    .try_get(connection_global_world_id)
+                    .unwrap();
+                change_local_world(spawn, connection_global_world_id, &mut local_worlds)
+            },
+        );
+        assert_eq!(joined.map(|(world_id, _channel)| world_id), Some(zone_a_id));
+
+        match &*zone_b_rx.try_recv().unwrap() {
+            Message::UserDespawn {
+                connection_local_world_id: id,
+            } => assert_eq!(*id, connection_local_world_id),
+            message => panic!("Expected Message::UserDespawn, got {:?}", message),
+        }
+        assert!(zone_a_rx.try_recv().is_err());
+
+        let user_spawns = world.borrow::<View<GlobalUserSpawn>>();
+        let spawn = user_spawns.try_get(connection_global_world_id).unwrap();
+        assert!(spawn.connection_local_world_id.is_none());
+        assert!(spawn.local_world_channel.is_none());
+
+        let local_worlds = world.borrow::<View<LocalWorld>>();
+        let zone_a = local_worlds.try_get(zone_a_id).unwrap();
+        let zone_b = local_worlds.try_get(zone_b_id).unwrap();
+        assert!(zone_a.users.contains(&connection_global_world_id));
+        assert!(zone_b.users.is_empty());
+    }
+
+    #[test]
+    fn test_no_local_world_for_zone() {
+        let world = World::new();
+        add_local_world(&world, 1);
+        let connection_global_world_id = world.borrow::<EntitiesViewMut>().add_entity((), ());
+
+        let joined = world.run(|mut local_worlds: ViewMut<LocalWorld>| {
+            join_local_world(3, connection_global_world_id, &mut local_worlds)
+        });
+        assert!(joined.is_none());
+    }
+}
@@ -14,10 +14,10 @@ pub fn settings_manager_system(
         match &**message {
             Message::RequestSetVisibleRange {
                 connection_global_world_id,
+                account_id,
                 packet,
-                ..
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 handle_set_visible_range(
                     *connection_global_world_id,
                     &packet,
@@ -54,6 +54,7 @@ mod tests {
     use super::*;
     use crate::ecs::component::GlobalConnection;
     use crate::ecs::message::Message;
+    use crate::test_support::capture_logs;
     use async_std::sync::{channel, Receiver};
     use std::time::Instant;
 
@@ -72,6 +73,9 @@ mod tests {
                         is_authenticated: false,
                         last_pong: Instant::now(),
                         waiting_for_pong: false,
+                        peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                        connected_since: Instant::now(),
+                        schema_version: None,
                     },
                 )
             },
@@ -88,9 +92,9 @@ mod tests {
             |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                 entities.add_entity(
                     &mut messages,
-                    Box::new(Message::RequestSetVisibleRange {
+                    EcsMessage::new(Message::RequestSetVisibleRange {
                         connection_global_world_id,
-                        account_id: -1,
+                        account_id: AccountId(-1),
                         packet: CSetVisibleRange { range: 4234 },
                     }),
                 );
@@ -107,4 +111,31 @@ mod tests {
 
         assert_eq!(valid_component_count, 1);
     }
+
+    #[test]
+    fn test_set_visible_range_logs_account() {
+        let (world, connection_global_world_id, _rx_channel) = setup_with_connection();
+
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestSetVisibleRange {
+                        connection_global_world_id,
+                        account_id: AccountId(1337),
+                        packet: CSetVisibleRange { range: 4234 },
+                    }),
+                );
+            },
+        );
+
+        let output = capture_logs(tracing::Level::TRACE, || {
+            world.run(settings_manager_system);
+        });
+        let line = output
+            .lines()
+            .find(|line| line.contains("Message::RequestSetVisibleRange incoming"))
+            .expect("Can't find the log line of the handler");
+        assert!(line.contains("account_id=1337"));
+    }
 }
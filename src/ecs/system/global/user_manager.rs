@@ -1,11 +1,13 @@
 use crate::ecs::component::GlobalConnection;
 use crate::ecs::message::Message::ResponseGetUserList;
 use crate::ecs::message::{EcsMessage, Message};
+use crate::ecs::resource::UserListSettings;
 use crate::ecs::system::global::send_message_to_connection;
 use crate::model::entity::User;
 use crate::model::repository::user;
-use crate::model::{Vec3, Vec3a};
+use crate::model::{AccountId, UserId, Vec3, Vec3a};
 use crate::protocol::packet::*;
+use crate::protocol::serde::to_vec_with_max_length;
 use crate::Result;
 use anyhow::{ensure, Context};
 use async_std::task;
@@ -14,17 +16,17 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use shipyard::*;
 use sqlx::{PgConnection, PgPool};
-use std::cmp::min;
-use tracing::{debug, error, info, info_span};
+use std::cmp::{max, min};
+use tracing::{debug, error, info, info_span, warn};
 
 const MAX_USERS_PER_ACCOUNT: usize = 20;
-const CHUNK_SIZE: usize = 5;
 
 /// Handles the users of an account. Users in TERA terminology are the player characters of an account.
 pub fn user_manager_system(
     incoming_messages: View<EcsMessage>,
     connections: View<GlobalConnection>,
     pool: UniqueView<PgPool>,
+    user_list_settings: UniqueView<UserListSettings>,
 ) {
     // TODO Look for users without a connection component. Set their "deletion time" and persist them ones reached.
     (&incoming_messages)
@@ -35,7 +37,7 @@ pub fn user_manager_system(
                 account_id,
                 ..
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_can_create_user(
                     *connection_global_world_id,
                     *account_id,
@@ -54,7 +56,7 @@ pub fn user_manager_system(
                 account_id,
                 packet,
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_change_user_lobby_slot_id(&packet, *account_id, &pool) {
                     error!("Ignoring change user lobby slot id request: {:?}", e);
                 }
@@ -64,12 +66,13 @@ pub fn user_manager_system(
                 account_id,
                 ..
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_user_list(
                     *connection_global_world_id,
                     *account_id,
                     &connections,
                     &pool,
+                    &user_list_settings,
                 ) {
                     error!("Rejecting get user list request: {:?}", e);
                     send_message_to_connection(
@@ -85,10 +88,10 @@ pub fn user_manager_system(
             }
             Message::RequestCheckUserName {
                 connection_global_world_id,
+                account_id,
                 packet,
-                ..
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_check_user_name(
                     &packet,
                     *connection_global_world_id,
@@ -107,7 +110,7 @@ pub fn user_manager_system(
                 account_id,
                 packet,
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_create_user(
                     &packet,
                     *connection_global_world_id,
@@ -127,7 +130,7 @@ pub fn user_manager_system(
                 account_id,
                 packet,
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_delete_user(
                     &packet,
                     *connection_global_world_id,
@@ -148,9 +151,10 @@ pub fn user_manager_system(
 
 fn handle_user_list(
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     connections: &View<GlobalConnection>,
     pool: &UniqueView<PgPool>,
+    settings: &UserListSettings,
 ) -> Result<()> {
     debug!("Get user list message incoming");
 
@@ -160,40 +164,10 @@ fn handle_user_list(
             .await
             .context("Couldn't acquire connection from pool")?;
 
-        // Send the user list paged, since we can only send 16kiB of data in one packet
-        let mut is_first_page = true;
-
-        let users = user::list(&mut conn, account_id).await?;
+        let users = user::list(&mut conn, account_id.0).await?;
 
-        if users.len() == 0 {
-            send_message_to_connection(
-                assemble_user_list_response(connection_global_world_id, &Vec::new(), true, true),
-                connections,
-            );
-        } else {
-            let chunk_count = users.chunks(CHUNK_SIZE).count();
-            let mut current_chunk = 1;
-
-            for chunk in users.chunks(CHUNK_SIZE) {
-                let is_last_page = if current_chunk == chunk_count {
-                    true
-                } else {
-                    false
-                };
-
-                send_message_to_connection(
-                    assemble_user_list_response(
-                        connection_global_world_id,
-                        chunk,
-                        is_first_page,
-                        is_last_page,
-                    ),
-                    connections,
-                );
-
-                is_first_page = false;
-                current_chunk += 1;
-            }
+        for message in assemble_user_list_pages(connection_global_world_id, &users, settings) {
+            send_message_to_connection(message, connections);
         }
 
         Ok::<(), anyhow::Error>(())
@@ -202,7 +176,7 @@ fn handle_user_list(
 
 fn handle_can_create_user(
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     connections: &View<GlobalConnection>,
     pool: &UniqueView<PgPool>,
 ) -> Result<()> {
@@ -214,17 +188,16 @@ fn handle_can_create_user(
             .await
             .context("Couldn't acquire connection from pool")?;
 
-        if can_create_user(&mut conn, account_id).await? {
-            send_message_to_connection(
-                assemble_can_create_user_response(connection_global_world_id, true),
-                connections,
-            );
-        } else {
-            send_message_to_connection(
-                assemble_can_create_user_response(connection_global_world_id, false),
-                connections,
-            );
-        }
+        let remaining_slots = remaining_user_slots(&mut conn, account_id).await?;
+        // The client packet has no field for the remaining slots.
+        debug!(
+            "Account {} has {} remaining user slots",
+            account_id, remaining_slots
+        );
+        send_message_to_connection(
+            assemble_can_create_user_response(connection_global_world_id, remaining_slots > 0),
+            connections,
+        );
 
         Ok::<(), anyhow::Error>(())
     })?)
@@ -232,7 +205,7 @@ fn handle_can_create_user(
 
 fn handle_change_user_lobby_slot_id(
     packet: &CChangeUserLobbySlotId,
-    account_id: i64,
+    account_id: AccountId,
     pool: &UniqueView<PgPool>,
 ) -> Result<()> {
     debug!("Message::RequestChangeUserLobbySlotId incoming");
@@ -247,11 +220,11 @@ fn handle_change_user_lobby_slot_id(
         user_list.sort_by(|a, b| a.lobby_slot.partial_cmp(&b.lobby_slot).unwrap());
 
         for (pos, entry) in user_list.iter().enumerate() {
-            let db_user = user::get_by_id(&mut conn, entry.database_id)
+            let db_user = user::get_by_id(&mut conn, entry.database_id.0)
                 .await
                 .context(format!("Can't find user {}", entry.database_id))?;
             ensure!(
-                db_user.account_id == account_id,
+                db_user.account_id == account_id.0,
                 "User {} doesn't belong to account {}",
                 entry.database_id,
                 account_id
@@ -262,7 +235,7 @@ fn handle_change_user_lobby_slot_id(
                 db_user.id,
                 pos + 1
             );
-            user::update_lobby_slot(&mut conn, entry.database_id, (pos + 1) as i32)
+            user::update_lobby_slot(&mut conn, entry.database_id.0, (pos + 1) as i32)
                 .await
                 .context("Can't update the lobby slot of  user")?;
         }
@@ -276,7 +249,7 @@ fn handle_change_user_lobby_slot_id(
 fn handle_create_user(
     packet: &CCreateUser,
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     connections: &View<GlobalConnection>,
     pool: &UniqueView<PgPool>,
 ) -> Result<()> {
@@ -290,11 +263,11 @@ fn handle_create_user(
 
         // TODO validate the character even more
 
-        if can_create_user(&mut conn, account_id).await?
+        if remaining_user_slots(&mut conn, account_id).await? > 0
             && check_username(&mut conn, &packet.name).await?
         {
             // Client starts the position at 1
-            let next_position = 1 + user::get_user_count(&mut conn, account_id).await?;
+            let next_position = 1 + user::get_user_count(&mut conn, account_id.0).await?;
             create_new_user(&mut conn, account_id, next_position as i32, packet).await?;
             send_message_to_connection(
                 assemble_create_user_response(connection_global_world_id, true),
@@ -316,7 +289,7 @@ fn handle_create_user(
 fn handle_delete_user(
     packet: &CDeleteUser,
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     connections: &View<GlobalConnection>,
     pool: &UniqueView<PgPool>,
 ) -> Result<()> {
@@ -332,15 +305,17 @@ fn handle_delete_user(
             .context("Couldn't acquire connection from pool")?;
 
         ensure!(
-            user::get_by_id(&mut conn, packet.database_id).await.is_ok(),
+            user::get_by_id(&mut conn, packet.database_id.0)
+                .await
+                .is_ok(),
             format!("Can't find user ID {} in the database", packet.database_id)
         );
 
-        let db_user = user::get_by_id(&mut conn, packet.database_id)
+        let db_user = user::get_by_id(&mut conn, packet.database_id.0)
             .await
             .context("Can't query user")?;
         ensure!(
-            db_user.account_id == account_id,
+            db_user.account_id == account_id.0,
             "User {} doesn't belong to account {}",
             db_user.id,
             account_id
@@ -351,7 +326,7 @@ fn handle_delete_user(
             .context("Can't delete user")?;
         info!("Deleted user with ID {}", db_user.id);
 
-        let users = user::list(&mut conn, account_id).await?;
+        let users = user::list(&mut conn, account_id.0).await?;
         for (pos, user) in users.iter().enumerate() {
             if user.lobby_slot != pos as i32 {
                 // Client starts the lobby slot at 1
@@ -417,19 +392,17 @@ async fn check_username(mut conn: &mut PgConnection, name: &str) -> Result<bool>
     }
 }
 
-// Returns true if the account has free character slots.
-async fn can_create_user(mut conn: &mut PgConnection, account_id: i64) -> Result<bool> {
-    if MAX_USERS_PER_ACCOUNT as i64 > user::get_user_count(&mut conn, account_id).await? {
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+// Returns the number of free character slots of the account.
+async fn remaining_user_slots(mut conn: &mut PgConnection, account_id: AccountId) -> Result<i64> {
+    let slots = user::get_user_slots(&mut conn, account_id.0, MAX_USERS_PER_ACCOUNT as i64).await?;
+    let count = user::get_user_count(&mut conn, account_id.0).await?;
+    Ok(max(slots - count, 0))
 }
 
 // Creates a new user with default values
 async fn create_new_user(
     mut conn: &mut PgConnection,
-    account_id: i64,
+    account_id: AccountId,
     lobby_slot: i32,
     packet: &CCreateUser,
 ) -> Result<()> {
@@ -438,7 +411,7 @@ async fn create_new_user(
         &mut conn,
         &User {
             id: -1,
-            account_id,
+            account_id: account_id.0,
             name: packet.name.clone(),
             gender: packet.gender,
             race: packet.race,
@@ -478,171 +451,237 @@ fn is_valid_user_name(text: &str) -> bool {
 }
 
 fn assemble_can_create_user_response(connection_global_world_id: EntityId, ok: bool) -> EcsMessage {
-    Box::new(Message::ResponseCanCreateUser {
+    EcsMessage::new(Message::ResponseCanCreateUser {
         connection_global_world_id,
         packet: SCanCreateUser { ok },
     })
 }
 
 fn assemble_create_user_response(connection_global_world_id: EntityId, ok: bool) -> EcsMessage {
-    Box::new(Message::ResponseCreateUser {
+    EcsMessage::new(Message::ResponseCreateUser {
         connection_global_world_id,
         packet: SCreateUser { ok },
     })
 }
 
 fn assemble_check_user_name_response(connection_global_world_id: EntityId, ok: bool) -> EcsMessage {
-    Box::new(Message::ResponseCheckUserName {
+    EcsMessage::new(Message::ResponseCheckUserName {
         connection_global_world_id,
         packet: SCheckUserName { ok },
     })
 }
 
 fn assemble_delete_user_response(connection_global_world_id: EntityId, ok: bool) -> EcsMessage {
-    Box::new(Message::ResponseDeleteUser {
+    EcsMessage::new(Message::ResponseDeleteUser {
         connection_global_world_id,
         packet: SDeleteUser { ok },
     })
 }
 
+/// Splits the user list into the pages of `S_GET_USER_LIST` packets. The client can only take
+/// a limited number of bytes in one packet, so a page is closed once it's full or the next user
+/// wouldn't fit anymore. A user that doesn't even fit into an empty page is left out.
+///
+/// The users are ordered by their lobby slot, so the client shows them in the same slots on
+/// every request. Users in the same slot are ordered by their creation.
+fn assemble_user_list_pages(
+    connection_global_world_id: EntityId,
+    users: &[User],
+    settings: &UserListSettings,
+) -> Vec<EcsMessage> {
+    let mut pages: Vec<Vec<SGetUserListCharacter>> = Vec::new();
+    let mut page: Vec<SGetUserListCharacter> = Vec::with_capacity(settings.page_size);
+
+    let mut users = users.to_vec();
+    users.sort_by_key(|user| (user.lobby_slot, user.created_at, user.id));
+
+    for user in users {
+        let character = assemble_user_list_character(user);
+        if !user_list_page_fits(std::slice::from_ref(&character), settings) {
+            warn!(
+                "User {} is too big for the user list. Leaving it out",
+                character.name
+            );
+            continue;
+        }
+
+        page.push(character);
+        if page.len() > settings.page_size || !user_list_page_fits(&page, settings) {
+            let character = page.pop().unwrap();
+            pages.push(std::mem::replace(&mut page, vec![character]));
+        }
+    }
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(page);
+    }
+
+    let page_count = pages.len();
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, characters)| {
+            EcsMessage::new(ResponseGetUserList {
+                connection_global_world_id,
+                packet: assemble_user_list_packet(characters, i == 0, i + 1 == page_count),
+            })
+        })
+        .collect()
+}
+
+/// Returns true if the packet of the page is inside of the maximal body length.
+fn user_list_page_fits(characters: &[SGetUserListCharacter], settings: &UserListSettings) -> bool {
+    let packet = assemble_user_list_packet(characters.to_vec(), false, false);
+    to_vec_with_max_length(&packet, settings.max_body_length).is_ok()
+}
+
 fn assemble_user_list_response(
     connection_global_world_id: EntityId,
     users: &[User],
     is_first_page: bool,
     is_last_page: bool,
 ) -> EcsMessage {
-    // TODO calculate hp/mp/max_rest_bonus/world_id/guard_id/section_id and also return the equip / styles / custom strings / guild / has_broker_sales from db
     let characters = users
-        .into_iter()
+        .iter()
         .cloned()
-        .map(move |user| {
-            let delete_time = match user.delete_at {
-                Some(t) => t.timestamp(),
-                None => 0,
-            };
-
-            // FIXME Something is wrong with the custom_strings field! It needs to be set with zero values?!
-            // FIXME test the deletion time stamps!
-            SGetUserListCharacter {
-                custom_strings: vec![SGetUserListCharacterCustomString {
-                    string: "".to_string(),
-                    id: 0,
-                }],
-                name: user.name,
-                details: user.details,
-                shape: user.shape,
-                guild_name: "".to_string(),
-                db_id: user.id,
-                gender: user.gender,
-                race: user.race,
-                class: user.class,
-                level: user.level,
-                hp: 200,
-                mp: 100,
-                world_id: 0,
-                guard_id: 0,
-                section_id: 0,
-                last_logout_time: user.last_logout_at.timestamp(),
-                is_deleting: user.is_deleting,
-                delete_time: 86400,
-                delete_remain_sec: min(delete_time - Utc::now().timestamp(), -1_585_902_611) as i32,
-                weapon: 0,
-                earring1: 0,
-                earring2: 0,
-                body: 0,
-                hand: 0,
-                feet: 0,
-                unk_item7: 0,
-                ring1: 0,
-                ring2: 0,
-                underwear: 0,
-                head: 0,
-                face: 0,
-                appearance: user.appearance,
-                is_second_character: false,
-                admin_level: 0,
-                is_banned: false,
-                ban_end_time: 0,
-                ban_remain_sec: -1_585_989_011,
-                rename_needed: 0,
-                weapon_model: 0,
-                unk_model2: 0,
-                unk_model3: 0,
-                body_model: 0,
-                hand_model: 0,
-                feet_model: 0,
-                unk_model7: 0,
-                unk_model8: 0,
-                unk_model9: 0,
-                unk_model10: 0,
-                unk_dye1: 0,
-                unk_dye2: 0,
-                weapon_dye: 0,
-                body_dye: 0,
-                hand_dye: 0,
-                feet_dye: 0,
-                unk_dye7: 0,
-                unk_dye8: 0,
-                unk_dye9: 0,
-                underwear_dye: 0,
-                style_back_dye: 0,
-                style_head_dye: 0,
-                style_face_dye: 0,
-                style_head: 0,
-                style_face: 0,
-                style_back: 0,
-                style_weapon: 0,
-                style_body: 0,
-                style_footprint: 0,
-                style_body_dye: 0,
-                weapon_enchant: 0,
-                rest_bonus_xp: user.rest_bonus_xp,
-                max_rest_bonus_xp: 1,
-                show_face: user.show_face,
-                style_head_scale: 1.0,
-                style_head_rotation: Vec3a::default(),
-                style_head_translation: Vec3::default(),
-                style_head_translation_debug: Vec3::default(),
-                style_faces_scale: 1.0,
-                style_face_rotation: Vec3a::default(),
-                style_face_translation: Vec3::default(),
-                style_face_translation_debug: Vec3::default(),
-                style_back_scale: 1.0,
-                style_back_rotation: Vec3a::default(),
-                style_back_translation: Vec3::default(),
-                style_back_translation_debug: Vec3::default(),
-                used_style_head_transform: false,
-                is_new_character: user.is_new_character,
-                tutorial_state: user.tutorial_state,
-                show_style: user.show_style,
-                appearance2: user.appearance2,
-                achievement_points: user.achievement_points,
-                laurel: user.laurel,
-                lobby_slot: user.lobby_slot,
-                guild_logo_id: 0,
-                awakening_level: user.awakening_level,
-                has_broker_sales: false,
-            }
-        })
+        .map(assemble_user_list_character)
         .collect();
 
-    Box::new(ResponseGetUserList {
+    EcsMessage::new(ResponseGetUserList {
         connection_global_world_id,
-        packet: SGetUserList {
-            characters,
-            veteran: false,
-            bonus_buf_sec: 0,
-            max_characters: MAX_USERS_PER_ACCOUNT as i32,
-            first: is_first_page,
-            more: !is_last_page,
-            left_del_time_account_over: 0,
-            deletion_section_classify_level: 40,
-            delete_character_expire_hour1: 0,
-            delete_character_expire_hour2: 24,
-        },
+        packet: assemble_user_list_packet(characters, is_first_page, is_last_page),
     })
 }
 
+fn assemble_user_list_packet(
+    characters: Vec<SGetUserListCharacter>,
+    is_first_page: bool,
+    is_last_page: bool,
+) -> SGetUserList {
+    SGetUserList {
+        characters,
+        veteran: false,
+        bonus_buf_sec: 0,
+        max_characters: MAX_USERS_PER_ACCOUNT as i32,
+        first: is_first_page,
+        more: !is_last_page,
+        left_del_time_account_over: 0,
+        deletion_section_classify_level: 40,
+        delete_character_expire_hour1: 0,
+        delete_character_expire_hour2: 24,
+    }
+}
+
+fn assemble_user_list_character(user: User) -> SGetUserListCharacter {
+    // TODO calculate hp/mp/max_rest_bonus/world_id/guard_id/section_id and also return the equip / styles / custom strings / guild / has_broker_sales from db
+    let delete_time = match user.delete_at {
+        Some(t) => t.timestamp(),
+        None => 0,
+    };
+
+    // FIXME Something is wrong with the custom_strings field! It needs to be set with zero values?!
+    // FIXME test the deletion time stamps!
+    SGetUserListCharacter {
+        custom_strings: vec![SGetUserListCharacterCustomString {
+            string: "".to_string(),
+            id: 0,
+        }],
+        name: user.name,
+        details: user.details,
+        shape: user.shape,
+        guild_name: "".to_string(),
+        db_id: UserId(user.id),
+        gender: user.gender,
+        race: user.race,
+        class: user.class,
+        level: user.level,
+        hp: 200,
+        mp: 100,
+        world_id: 0,
+        guard_id: 0,
+        section_id: 0,
+        last_logout_time: user.last_logout_at.timestamp(),
+        is_deleting: user.is_deleting,
+        delete_time: 86400,
+        delete_remain_sec: min(delete_time - Utc::now().timestamp(), -1_585_902_611) as i32,
+        weapon: 0,
+        earring1: 0,
+        earring2: 0,
+        body: 0,
+        hand: 0,
+        feet: 0,
+        unk_item7: 0,
+        ring1: 0,
+        ring2: 0,
+        underwear: 0,
+        head: 0,
+        face: 0,
+        appearance: user.appearance,
+        is_second_character: false,
+        admin_level: 0,
+        is_banned: false,
+        ban_end_time: 0,
+        ban_remain_sec: -1_585_989_011,
+        rename_needed: 0,
+        weapon_model: 0,
+        unk_model2: 0,
+        unk_model3: 0,
+        body_model: 0,
+        hand_model: 0,
+        feet_model: 0,
+        unk_model7: 0,
+        unk_model8: 0,
+        unk_model9: 0,
+        unk_model10: 0,
+        unk_dye1: 0,
+        unk_dye2: 0,
+        weapon_dye: 0,
+        body_dye: 0,
+        hand_dye: 0,
+        feet_dye: 0,
+        unk_dye7: 0,
+        unk_dye8: 0,
+        unk_dye9: 0,
+        underwear_dye: 0,
+        style_back_dye: 0,
+        style_head_dye: 0,
+        style_face_dye: 0,
+        style_head: 0,
+        style_face: 0,
+        style_back: 0,
+        style_weapon: 0,
+        style_body: 0,
+        style_footprint: 0,
+        style_body_dye: 0,
+        weapon_enchant: 0,
+        rest_bonus_xp: user.rest_bonus_xp,
+        max_rest_bonus_xp: 1,
+        show_face: user.show_face,
+        style_head_scale: 1.0,
+        style_head_rotation: Vec3a::default(),
+        style_head_translation: Vec3::default(),
+        style_head_translation_debug: Vec3::default(),
+        style_faces_scale: 1.0,
+        style_face_rotation: Vec3a::default(),
+        style_face_translation: Vec3::default(),
+        style_face_translation_debug: Vec3::default(),
+        style_back_scale: 1.0,
+        style_back_rotation: Vec3a::default(),
+        style_back_translation: Vec3::default(),
+        style_back_translation_debug: Vec3::default(),
+        used_style_head_transform: false,
+        is_new_character: user.is_new_character,
+        tutorial_state: user.tutorial_state,
+        show_style: user.show_style,
+        appearance2: user.appearance2,
+        achievement_points: user.achievement_points,
+        laurel: user.laurel,
+        lobby_slot: user.lobby_slot,
+        guild_logo_id: 0,
+        awakening_level: user.awakening_level,
+        has_broker_sales: false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -652,6 +691,8 @@ mod tests {
     use crate::model::repository::account;
     use crate::model::tests::db_test;
     use crate::model::{Class, Customization, Gender, PasswordHashAlgorithm, Race};
+    use crate::protocol::serde::to_vec;
+    use crate::test_support::new_user;
     use crate::Result;
     use async_std::sync::{channel, Receiver};
     use chrono::TimeZone;
@@ -665,6 +706,7 @@ mod tests {
 
         let world = World::new();
         world.add_unique(pool);
+        world.add_unique(UserListSettings::default());
 
         let account = account::create(
             &mut conn,
@@ -691,6 +733,9 @@ mod tests {
                         is_authenticated: false,
                         last_pong: Instant::now(),
                         waiting_for_pong: false,
+                        peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                        connected_since: Instant::now(),
+                        schema_version: None,
                     },
                 )
             },
@@ -721,37 +766,7 @@ mod tests {
     }
 
     async fn create_user(conn: &mut PgConnection, account_id: i64, num: i32) -> Result<User> {
-        Ok(user::create(
-            conn,
-            &User {
-                id: -1,
-                account_id,
-                name: format!("name-{}", num),
-                gender: Gender::Male,
-                race: Race::Human,
-                class: Class::Warrior,
-                shape: vec![],
-                details: vec![],
-                appearance: Default::default(),
-                appearance2: 0,
-                level: 0,
-                awakening_level: 0,
-                laurel: 0,
-                achievement_points: 0,
-                playtime: 0,
-                rest_bonus_xp: 0,
-                show_face: false,
-                show_style: false,
-                lobby_slot: num,
-                is_new_character: false,
-                tutorial_state: 0,
-                is_deleting: false,
-                delete_at: None,
-                last_logout_at: Utc.ymd(2007, 7, 8).and_hms(9, 10, 11),
-                created_at: Utc.ymd(2009, 7, 8).and_hms(9, 10, 11),
-            },
-        )
-        .await?)
+        Ok(user::create(conn, &new_user(account_id, num)).await?)
     }
 
     #[test]
@@ -765,9 +780,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestCanCreateUser {
+                        EcsMessage::new(Message::RequestCanCreateUser {
                             connection_global_world_id,
-                            account_id: -1,
+                            account_id: AccountId(-1),
                             packet: CCanCreateUser {},
                         }),
                     );
@@ -777,7 +792,7 @@ mod tests {
             world.run(user_manager_system);
 
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCanCreateUser { packet, .. } => {
                         assert!(packet.ok);
                     }
@@ -807,9 +822,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestCanCreateUser {
+                        EcsMessage::new(Message::RequestCanCreateUser {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: CCanCreateUser {},
                         }),
                     );
@@ -819,7 +834,7 @@ mod tests {
             world.run(user_manager_system);
 
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCanCreateUser { packet, .. } => {
                         assert!(!packet.ok);
                     }
@@ -833,6 +848,114 @@ mod tests {
         })
     }
 
+    fn request_can_create_user(
+        world: &World,
+        connection_global_world_id: EntityId,
+        account_id: AccountId,
+        rx_channel: &Receiver<EcsMessage>,
+    ) -> SCanCreateUser {
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestCanCreateUser {
+                        connection_global_world_id,
+                        account_id,
+                        packet: CCanCreateUser {},
+                    }),
+                );
+            },
+        );
+
+        world.run(user_manager_system);
+
+        match rx_channel.try_recv() {
+            Ok(message) => match *message.inner {
+                Message::ResponseCanCreateUser { packet, .. } => packet,
+                _ => panic!("Message is not a ResponseCanCreateUser message"),
+            },
+            Err(..) => panic!("Can't find any message"),
+        }
+    }
+
+    fn request_create_user(
+        world: &World,
+        connection_global_world_id: EntityId,
+        account_id: AccountId,
+        rx_channel: &Receiver<EcsMessage>,
+    ) -> SCreateUser {
+        world.run(
+            |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                entities.add_entity(
+                    &mut messages,
+                    EcsMessage::new(Message::RequestCreateUser {
+                        connection_global_world_id,
+                        account_id,
+                        packet: assemble_create_user_packet(),
+                    }),
+                );
+            },
+        );
+
+        world.run(user_manager_system);
+
+        match rx_channel.try_recv() {
+            Ok(message) => match *message.inner {
+                Message::ResponseCreateUser { packet, .. } => packet,
+                _ => panic!("Message is not a ResponseCreateUser message"),
+            },
+            Err(..) => panic!("Can't find any message"),
+        }
+    }
+
+    #[test]
+    fn test_account_slots() -> Result<()> {
+        // (account slots, existing users, can create another user)
+        let cases = [(2, 2, false), (3, 2, true), (1, 0, true), (1, 1, false)];
+
+        for &(slots, existing, expected) in cases.iter() {
+            db_test(|db_string| {
+                let pool = task::block_on(async { PgPool::new(db_string).await })?;
+                let mut conn = task::block_on(async { pool.acquire().await })?;
+                let (world, connection_global_world_id, rx_channel, account) =
+                    task::block_on(async { setup_with_connection(pool).await })?;
+
+                task::block_on(async {
+                    user::set_user_slots(&mut conn, account.id, slots).await?;
+                    for i in 0..existing {
+                        create_user(&mut conn, account.id, i).await?;
+                    }
+                    Ok::<(), anyhow::Error>(())
+                })?;
+
+                let packet = request_can_create_user(
+                    &world,
+                    connection_global_world_id,
+                    AccountId(account.id),
+                    &rx_channel,
+                );
+                assert_eq!(packet.ok, expected, "slots {} users {}", slots, existing);
+
+                let packet = request_create_user(
+                    &world,
+                    connection_global_world_id,
+                    AccountId(account.id),
+                    &rx_channel,
+                );
+                assert_eq!(packet.ok, expected, "slots {} users {}", slots, existing);
+
+                let count =
+                    task::block_on(async { user::get_user_count(&mut conn, account.id).await })?;
+                let expected_count = if expected { existing + 1 } else { existing };
+                assert_eq!(count, expected_count as i64);
+
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_is_valid_user_name() {
         // Valid user names
@@ -868,9 +991,9 @@ mod tests {
                     for i in 0..5 {
                         entities.add_entity(
                             &mut messages,
-                            Box::new(Message::RequestCheckUserName {
+                            EcsMessage::new(Message::RequestCheckUserName {
                                 connection_global_world_id,
-                                account_id: account.id,
+                                account_id: AccountId(account.id),
                                 packet: CCheckUserName {
                                     name: format!("NotTakenUserName{}", i),
                                 },
@@ -885,7 +1008,7 @@ mod tests {
             let mut count = 0;
             loop {
                 if let Ok(message) = rx_channel.try_recv() {
-                    match *message {
+                    match *message.inner {
                         Message::ResponseCheckUserName { packet, .. } => {
                             if packet.ok {
                                 count += 1
@@ -914,9 +1037,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestCheckUserName {
+                        EcsMessage::new(Message::RequestCheckUserName {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: CCheckUserName {
                                 name: "H!x?or{}".to_string(),
                             },
@@ -928,7 +1051,7 @@ mod tests {
             world.run(user_manager_system);
 
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCheckUserName { packet, .. } => {
                         assert!(!packet.ok);
                     }
@@ -958,9 +1081,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestGetUserList {
+                        EcsMessage::new(Message::RequestGetUserList {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: CGetUserList {},
                         }),
                     );
@@ -969,10 +1092,11 @@ mod tests {
 
             world.run(user_manager_system);
 
-            let expected_packet_count = if MAX_USERS_PER_ACCOUNT % CHUNK_SIZE != 0 {
-                (MAX_USERS_PER_ACCOUNT / CHUNK_SIZE) + 1
+            let page_size = UserListSettings::default().page_size;
+            let expected_packet_count = if MAX_USERS_PER_ACCOUNT % page_size != 0 {
+                (MAX_USERS_PER_ACCOUNT / page_size) + 1
             } else {
-                MAX_USERS_PER_ACCOUNT / CHUNK_SIZE
+                MAX_USERS_PER_ACCOUNT / page_size
             };
 
             let mut char_count = 0;
@@ -980,7 +1104,7 @@ mod tests {
             loop {
                 if let Ok(message) = rx_channel.try_recv() {
                     packet_count += 1;
-                    match *message {
+                    match *message.inner {
                         Message::ResponseGetUserList { packet, .. } => {
                             char_count += packet.characters.len();
 
@@ -1012,6 +1136,122 @@ mod tests {
         })
     }
 
+    fn user_list_pages(users: &[User], settings: &UserListSettings) -> Vec<SGetUserList> {
+        let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        assemble_user_list_pages(entity, users, settings)
+            .into_iter()
+            .map(|message| match &*message {
+                Message::ResponseGetUserList { packet, .. } => packet.clone(),
+                m => panic!("Unexpected message {}", m),
+            })
+            .collect()
+    }
+
+    fn page_names(pages: &[SGetUserList]) -> Vec<Vec<String>> {
+        pages
+            .iter()
+            .map(|page| page.characters.iter().map(|c| c.name.clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_user_list_pages_respect_page_size() {
+        let users: Vec<User> = (0..7).map(|i| new_user(1, i)).collect();
+        let settings = UserListSettings {
+            page_size: 3,
+            ..Default::default()
+        };
+
+        let pages = user_list_pages(&users, &settings);
+        assert_eq!(
+            page_names(&pages),
+            vec![
+                vec!["name-0", "name-1", "name-2"],
+                vec!["name-3", "name-4", "name-5"],
+                vec!["name-6"],
+            ]
+        );
+        assert_eq!(
+            pages.iter().map(|p| (p.first, p.more)).collect::<Vec<_>>(),
+            vec![(true, true), (false, true), (false, false)]
+        );
+    }
+
+    #[test]
+    fn test_user_list_pages_respect_body_length() -> Result<()> {
+        let mut users: Vec<User> = (0..5).map(|i| new_user(1, i)).collect();
+        let two_users: Vec<SGetUserListCharacter> = users[..2]
+            .iter()
+            .cloned()
+            .map(assemble_user_list_character)
+            .collect();
+        let settings = UserListSettings {
+            page_size: 5,
+            max_body_length: to_vec(assemble_user_list_packet(two_users, true, true))?.len(),
+        };
+
+        // Doesn't fit into a page on it's own.
+        let mut big_user = new_user(1, 9);
+        big_user.details = vec![0; settings.max_body_length];
+        users.insert(3, big_user);
+
+        let pages = user_list_pages(&users, &settings);
+        assert_eq!(
+            page_names(&pages),
+            vec![
+                vec!["name-0", "name-1"],
+                vec!["name-2", "name-3"],
+                vec!["name-4"],
+            ]
+        );
+        for page in pages.iter() {
+            assert!(to_vec(page)?.len() <= settings.max_body_length);
+        }
+        assert_eq!(pages.last().unwrap().more, false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_list_pages_are_ordered_by_slot() {
+        let mut users: Vec<User> = vec![3, 0, 2, 1]
+            .into_iter()
+            .map(|i| new_user(1, i))
+            .collect();
+        // Two users in one slot are ordered by their creation.
+        let mut older_user = new_user(1, 4);
+        older_user.lobby_slot = 2;
+        older_user.created_at = Utc.ymd(2008, 7, 8).and_hms(9, 10, 11);
+        users.push(older_user);
+
+        let settings = UserListSettings {
+            page_size: 2,
+            ..Default::default()
+        };
+        let pages = user_list_pages(&users, &settings);
+        assert_eq!(
+            page_names(&pages),
+            vec![
+                vec!["name-0", "name-1"],
+                vec!["name-4", "name-2"],
+                vec!["name-3"],
+            ]
+        );
+
+        users.reverse();
+        assert_eq!(
+            page_names(&user_list_pages(&users, &settings)),
+            page_names(&pages)
+        );
+    }
+
+    #[test]
+    fn test_user_list_pages_without_users() {
+        let pages = user_list_pages(&[], &UserListSettings::default());
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].characters.is_empty());
+        assert_eq!((pages[0].first, pages[0].more), (true, false));
+    }
+
     #[test]
     fn test_get_empty_user_list() -> Result<()> {
         db_test(|db_string| {
@@ -1023,9 +1263,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestGetUserList {
+                        EcsMessage::new(Message::RequestGetUserList {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: CGetUserList {},
                         }),
                     );
@@ -1039,7 +1279,7 @@ mod tests {
             loop {
                 if let Ok(message) = rx_channel.try_recv() {
                     packet_count += 1;
-                    match *message {
+                    match *message.inner {
                         Message::ResponseGetUserList { packet, .. } => {
                             char_count = packet.characters.len()
                         }
@@ -1071,9 +1311,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestCreateUser {
+                        EcsMessage::new(Message::RequestCreateUser {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: org_packet.clone(),
                         }),
                     );
@@ -1083,7 +1323,7 @@ mod tests {
             world.run(user_manager_system);
 
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCreateUser { packet, .. } => {
                         assert!(packet.ok);
                     }
@@ -1130,9 +1370,9 @@ mod tests {
                     for _i in 0..2 {
                         entities.add_entity(
                             &mut messages,
-                            Box::new(Message::RequestCreateUser {
+                            EcsMessage::new(Message::RequestCreateUser {
                                 connection_global_world_id,
-                                account_id: account.id,
+                                account_id: AccountId(account.id),
                                 packet: org_packet.clone(),
                             }),
                         );
@@ -1144,7 +1384,7 @@ mod tests {
 
             // First user could be created
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCreateUser { packet, .. } => {
                         assert!(packet.ok);
                     }
@@ -1156,7 +1396,7 @@ mod tests {
 
             // Second user failed because the name was already taken
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCreateUser { packet, .. } => {
                         assert!(!packet.ok);
                     }
@@ -1193,9 +1433,9 @@ mod tests {
                     for _i in 0..2 {
                         entities.add_entity(
                             &mut messages,
-                            Box::new(Message::RequestCreateUser {
+                            EcsMessage::new(Message::RequestCreateUser {
                                 connection_global_world_id,
-                                account_id: account.id,
+                                account_id: AccountId(account.id),
                                 packet: org_packet.clone(),
                             }),
                         );
@@ -1206,7 +1446,7 @@ mod tests {
             world.run(user_manager_system);
 
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseCreateUser { packet, .. } => {
                         assert!(!packet.ok);
                     }
@@ -1224,6 +1464,55 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_create_user_unsuccessful_account_slots_exhausted() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let mut conn = task::block_on(async { pool.acquire().await })?;
+            let (world, connection_global_world_id, rx_channel, account) =
+                task::block_on(async { setup_with_connection(pool).await })?;
+
+            task::block_on(async {
+                user::set_user_slots(&mut conn, account.id, 1).await?;
+                create_user(&mut conn, account.id, 0).await
+            })?;
+
+            let org_packet = assemble_create_user_packet();
+
+            world.run(
+                |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                    entities.add_entity(
+                        &mut messages,
+                        EcsMessage::new(Message::RequestCreateUser {
+                            connection_global_world_id,
+                            account_id: AccountId(account.id),
+                            packet: org_packet.clone(),
+                        }),
+                    );
+                },
+            );
+
+            world.run(user_manager_system);
+
+            if let Ok(message) = rx_channel.try_recv() {
+                match *message.inner {
+                    Message::ResponseCreateUser { packet, .. } => {
+                        assert!(!packet.ok);
+                    }
+                    _ => panic!("Message is not a ResponseCreateUser message"),
+                }
+            } else {
+                panic!("Can't find any message");
+            }
+
+            let count =
+                task::block_on(async { user::get_user_count(&mut conn, account.id).await })?;
+            assert_eq!(count, 1);
+
+            Ok(())
+        })
+    }
+
     #[test]
     fn test_delete_user() -> Result<()> {
         db_test(|db_string| {
@@ -1244,11 +1533,11 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestDeleteUser {
+                        EcsMessage::new(Message::RequestDeleteUser {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: CDeleteUser {
-                                database_id: users[0].id,
+                                database_id: UserId(users[0].id),
                             },
                         }),
                     );
@@ -1258,7 +1547,7 @@ mod tests {
             world.run(user_manager_system);
 
             if let Ok(message) = rx_channel.try_recv() {
-                match *message {
+                match *message.inner {
                     Message::ResponseDeleteUser { packet, .. } => {
                         assert!(packet.ok);
                     }
@@ -1306,7 +1595,7 @@ mod tests {
             let user_positions: Vec<CChangeUserLobbySlotIdEntry> = users
                 .iter()
                 .map(|u| CChangeUserLobbySlotIdEntry {
-                    database_id: u.id,
+                    database_id: UserId(u.id),
                     lobby_slot: (MAX_USERS_PER_ACCOUNT as i32 - u.lobby_slot + 1),
                 })
                 .collect();
@@ -1315,9 +1604,9 @@ mod tests {
                 |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
                     entities.add_entity(
                         &mut messages,
-                        Box::new(Message::RequestChangeUserLobbySlotId {
+                        EcsMessage::new(Message::RequestChangeUserLobbySlotId {
                             connection_global_world_id,
-                            account_id: account.id,
+                            account_id: AccountId(account.id),
                             packet: CChangeUserLobbySlotId { user_positions },
                         }),
                     );
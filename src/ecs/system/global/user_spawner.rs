@@ -6,10 +6,11 @@ use crate::ecs::message::Message::{
     UserReadyToConnect,
 };
 use crate::ecs::message::{EcsMessage, Message};
-use crate::ecs::system::global::send_message_to_connection;
+use crate::ecs::resource::SpawnQueue;
+use crate::ecs::system::global::send_messages_to_connection;
 use crate::ecs::system::send_message;
 use crate::model::repository::user;
-use crate::model::{entity, TemplateID, Vec3};
+use crate::model::{entity, AccountId, TemplateID, UserId, Vec3};
 use crate::protocol::packet::*;
 use crate::Result;
 use anyhow::{bail, ensure, Context};
@@ -25,6 +26,7 @@ pub fn user_spawner_system(
     connections: View<GlobalConnection>,
     mut spawns: ViewMut<GlobalUserSpawn>,
     entities: EntitiesView,
+    mut spawn_queue: UniqueViewMut<SpawnQueue>,
     pool: UniqueView<PgPool>,
 ) {
     (&incoming_messages)
@@ -35,7 +37,7 @@ pub fn user_spawner_system(
                 account_id,
                 packet,
             } => {
-                id_span!(connection_global_world_id);
+                id_span!(connection_global_world_id, account_id);
                 if let Err(e) = handle_select_user(
                     packet,
                     *connection_global_world_id,
@@ -73,16 +75,9 @@ pub fn user_spawner_system(
             _ => { /* Ignore all other messages */ }
         });
 
-    for (connection_global_world_id, spawn) in spawns.iter().with_id().filter(|(_id, spawn)| {
-        spawn.status == UserSpawnStatus::CanSpawn || spawn.status == UserSpawnStatus::SpawnFailed
-    }) {
+    for (connection_global_world_id, spawn) in spawns.iter().with_id() {
         if spawn.status == UserSpawnStatus::CanSpawn {
-            id_span!(connection_global_world_id);
-            if let Err(e) =
-                prepare_local_spawn(spawn, connection_global_world_id, &connections, &pool)
-            {
-                error!("Can't prepare local spawn: {:?}", e);
-            }
+            spawn_queue.push(connection_global_world_id);
         } else if spawn.status == UserSpawnStatus::SpawnFailed {
             // FIXME we don't want to panic here, but right now I don't know how to properly handle this error
             id_span!(connection_global_world_id);
@@ -90,6 +85,28 @@ pub fn user_spawner_system(
             panic!("SPAWN FAILED");
         }
     }
+
+    // Only the spawn budget is processed per tick. The rest stays queued for the next ticks.
+    for connection_global_world_id in spawn_queue.next_batch() {
+        id_span!(connection_global_world_id);
+        let spawn = match (&mut spawns).try_get(connection_global_world_id) {
+            Ok(spawn) => spawn,
+            Err(_) => {
+                debug!("Queued user spawn doesn't exist anymore");
+                continue;
+            }
+        };
+        if spawn.status != UserSpawnStatus::CanSpawn {
+            continue;
+        }
+
+        if let Err(e) = prepare_local_spawn(spawn, connection_global_world_id, &connections, &pool)
+        {
+            error!("Can't prepare local spawn: {:?}", e);
+        } else {
+            spawn.status = UserSpawnStatus::Spawning;
+        }
+    }
 }
 
 fn prepare_local_spawn(
@@ -113,7 +130,7 @@ fn prepare_local_spawn(
             .await
             .context("Couldn't acquire connection from pool")?;
 
-        let user = user::get_by_id(&mut conn, spawn.user_id).await?;
+        let user = user::get_by_id(&mut conn, spawn.user_id.0).await?;
         send_message(
             assemble_prepare_user_spawn(
                 connection_global_world_id,
@@ -144,7 +161,7 @@ fn handle_user_spawned(
 fn handle_select_user(
     packet: &CSelectUser,
     connection_global_world_id: EntityId,
-    account_id: i64,
+    account_id: AccountId,
     spawns: &mut ViewMut<GlobalUserSpawn>,
     entities: &EntitiesView,
     pool: &UniqueView<PgPool>,
@@ -157,9 +174,9 @@ fn handle_select_user(
             .await
             .context("Couldn't acquire connection from pool")?;
 
-        let user = user::get_by_id(&mut conn, packet.database_id).await?;
+        let user = user::get_by_id(&mut conn, packet.database_id.0).await?;
         ensure!(
-            user.account_id == account_id,
+            user.account_id == account_id.0,
             "User {:?} doesn't belongs to account {:?}",
             user,
             account_id
@@ -175,7 +192,7 @@ fn handle_select_user(
             spawns,
             GlobalUserSpawn {
                 connection_local_world_id: None,
-                user_id: user.id,
+                user_id: UserId(user.id),
                 account_id,
                 status: UserSpawnStatus::Requesting,
                 zone_id: 0,
@@ -234,24 +251,19 @@ fn handle_user_spawn_prepared(
             .await
             .context("Couldn't acquire connection from pool")?;
 
-        let user = user::get_by_id(&mut conn, spawn.user_id)
+        let user = user::get_by_id(&mut conn, spawn.user_id.0)
             .await
             .context(format!("Can't query user {}", spawn.user_id))?;
 
-        send_message_to_connection(
-            assemble_response_login(connection_global_world_id, user),
-            connections,
-        );
-
         // TODO Send all other persisted date
 
         // TODO use the user_location entity once implemented
-        send_message_to_connection(
-            assemble_response_load_topo(connection_global_world_id),
-            connections,
-        );
-        send_message_to_connection(
-            assemble_response_load_hint(connection_global_world_id),
+        send_messages_to_connection(
+            vec![
+                assemble_response_login(connection_global_world_id, user),
+                assemble_response_load_topo(connection_global_world_id),
+                assemble_response_load_hint(connection_global_world_id),
+            ],
             connections,
         );
 
@@ -269,17 +281,17 @@ fn assemble_register_local_world(
     connection_local_world_id: EntityId,
     local_world_channel: Sender<EcsMessage>,
 ) -> EcsMessage {
-    Box::new(RegisterLocalWorld {
+    EcsMessage::new(RegisterLocalWorld {
         connection_local_world_id,
         local_world_channel,
     })
 }
 
 fn assemble_response_login(connection_global_world_id: EntityId, user: entity::User) -> EcsMessage {
-    Box::new(ResponseLogin {
+    EcsMessage::new(ResponseLogin {
         connection_global_world_id,
-        account_id: user.account_id,
-        user_id: user.id,
+        account_id: AccountId(user.account_id),
+        user_id: UserId(user.id),
         packet: SLogin {
             servants: vec![],
             name: user.name,
@@ -364,7 +376,7 @@ fn assemble_response_login(connection_global_world_id: EntityId, user: entity::U
 }
 
 fn assemble_response_load_topo(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(ResponseLoadTopo {
+    EcsMessage::new(ResponseLoadTopo {
         connection_global_world_id,
         packet: SLoadTopo {
             zone: 5,
@@ -379,14 +391,14 @@ fn assemble_response_load_topo(connection_global_world_id: EntityId) -> EcsMessa
 }
 
 fn assemble_response_load_hint(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(ResponseLoadHint {
+    EcsMessage::new(ResponseLoadHint {
         connection_global_world_id,
         packet: SLoadHint { unk1: 0 },
     })
 }
 
 fn assemble_user_ready_to_connect(connection_local_world_id: EntityId) -> EcsMessage {
-    Box::new(UserReadyToConnect {
+    EcsMessage::new(UserReadyToConnect {
         connection_local_world_id,
     })
 }
@@ -397,7 +409,7 @@ fn assemble_prepare_user_spawn(
     connection_channel: Sender<EcsMessage>,
     user: entity::User,
 ) -> EcsMessage {
-    Box::new(PrepareUserSpawn {
+    EcsMessage::new(PrepareUserSpawn {
         user_initializer: UserInitializer {
             connection_global_world_id,
             connection_channel,
@@ -405,3 +417,208 @@ fn assemble_prepare_user_spawn(
         },
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::entity::{Account, User};
+    use crate::model::repository::account;
+    use crate::model::tests::db_test;
+    use crate::model::PasswordHashAlgorithm;
+    use crate::test_support::new_user;
+    use async_std::sync::{channel, Receiver};
+    use chrono::{TimeZone, Utc};
+    use std::time::Instant;
+
+    /// Adds a connection whose user can be spawned in the local world of the given channel.
+    fn add_spawnable_user(
+        world: &World,
+        user: &User,
+        local_world_channel: Sender<EcsMessage>,
+    ) -> (EntityId, Receiver<EcsMessage>) {
+        let (tx_channel, rx_channel) = channel(1024);
+        let connection_global_world_id = world.run(
+            |mut entities: EntitiesViewMut,
+             mut connections: ViewMut<GlobalConnection>,
+             mut spawns: ViewMut<GlobalUserSpawn>| {
+                entities.add_entity(
+                    (&mut connections, &mut spawns),
+                    (
+                        GlobalConnection {
+                            channel: tx_channel,
+                            is_version_checked: true,
+                            is_authenticated: true,
+                            last_pong: Instant::now(),
+                            waiting_for_pong: false,
+                            peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                            connected_since: Instant::now(),
+                            schema_version: None,
+                        },
+                        GlobalUserSpawn {
+                            user_id: UserId(user.id),
+                            account_id: AccountId(user.account_id),
+                            status: UserSpawnStatus::CanSpawn,
+                            zone_id: 0,
+                            connection_local_world_id: None,
+                            local_world_id: None,
+                            local_world_channel: Some(local_world_channel),
+                            marked_for_deletion: false,
+                            is_alive: true,
+                        },
+                    ),
+                )
+            },
+        );
+        (connection_global_world_id, rx_channel)
+    }
+
+    /// Returns the connections of the users the local world was asked to spawn.
+    fn prepared_spawns(rx_channel: &Receiver<EcsMessage>) -> Vec<EntityId> {
+        let mut prepared = Vec::new();
+        while let Ok(message) = rx_channel.try_recv() {
+            match &*message {
+                Message::PrepareUserSpawn { user_initializer } => {
+                    prepared.push(user_initializer.connection_global_world_id)
+                }
+                message => panic!("Expected Message::PrepareUserSpawn, got {}", message),
+            }
+        }
+        prepared
+    }
+
+    #[test]
+    fn test_spawn_budget_per_tick() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let mut conn = pool.acquire().await?;
+
+                let account = account::create(&mut conn, &new_account()).await?;
+
+                let world = World::new();
+                world.add_unique(pool);
+                world.add_unique(SpawnQueue::new(2));
+
+                let (local_tx_channel, local_rx_channel) = channel(1024);
+                let mut queued = Vec::new();
+                for num in 0..5 {
+                    let user = user::create(&mut conn, &new_user(account.id, num)).await?;
+                    let (connection_global_world_id, _rx_channel) =
+                        add_spawnable_user(&world, &user, local_tx_channel.clone());
+                    queued.push(connection_global_world_id);
+                }
+
+                // Every tick spawns at most the budget in the order the spawns were queued.
+                world.run(user_spawner_system);
+                assert_eq!(prepared_spawns(&local_rx_channel), queued[..2].to_vec());
+                assert_eq!(world.borrow::<UniqueView<SpawnQueue>>().len(), 3);
+
+                world.run(user_spawner_system);
+                assert_eq!(prepared_spawns(&local_rx_channel), queued[2..4].to_vec());
+
+                world.run(user_spawner_system);
+                assert_eq!(prepared_spawns(&local_rx_channel), queued[4..].to_vec());
+                assert!(world.borrow::<UniqueView<SpawnQueue>>().is_empty());
+
+                // No spawn is dropped or spawned twice.
+                world.run(user_spawner_system);
+                assert!(prepared_spawns(&local_rx_channel).is_empty());
+                let spawns = world.borrow::<View<GlobalUserSpawn>>();
+                assert!(spawns
+                    .iter()
+                    .all(|spawn| spawn.status == UserSpawnStatus::Spawning));
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_global_responses_after_local_world_registration() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let pool = PgPool::new(db_string).await?;
+                let mut conn = pool.acquire().await?;
+
+                let account = account::create(&mut conn, &new_account()).await?;
+                let user = user::create(&mut conn, &new_user(account.id, 0)).await?;
+
+                let world = World::new();
+                world.add_unique(pool);
+                world.add_unique(SpawnQueue::new(1));
+
+                let (local_tx_channel, local_rx_channel) = channel(1024);
+                let (connection_global_world_id, rx_channel) =
+                    add_spawnable_user(&world, &user, local_tx_channel);
+
+                world.run(user_spawner_system);
+                assert_eq!(
+                    prepared_spawns(&local_rx_channel),
+                    vec![connection_global_world_id]
+                );
+
+                // The local world prepared the spawn. The connection is moved into the local world
+                // and the global world answers with the login afterwards.
+                let connection_local_world_id =
+                    World::new().run(|mut entities: EntitiesViewMut| entities.add_entity((), ()));
+                world.run(
+                    |mut entities: EntitiesViewMut, mut messages: ViewMut<EcsMessage>| {
+                        entities.add_entity(
+                            &mut messages,
+                            EcsMessage::new(Message::UserSpawnPrepared {
+                                connection_global_world_id,
+                                connection_local_world_id,
+                            }),
+                        );
+                    },
+                );
+                world.run(user_spawner_system);
+
+                match &*rx_channel.try_recv().unwrap() {
+                    Message::RegisterLocalWorld {
+                        connection_local_world_id: registered_id,
+                        ..
+                    } => assert_eq!(*registered_id, connection_local_world_id),
+                    message => panic!("Expected Message::RegisterLocalWorld, got {}", message),
+                }
+                match &*rx_channel.try_recv().unwrap() {
+                    Message::ResponseBatch {
+                        connection_global_world_id: batch_id,
+                        messages,
+                    } => {
+                        assert_eq!(*batch_id, connection_global_world_id);
+                        assert_eq!(messages.len(), 3);
+                        match &*messages[0] {
+                            Message::ResponseLogin { packet, .. } => {
+                                assert_eq!(packet.db_id, user.id)
+                            }
+                            message => panic!("Expected Message::ResponseLogin, got {}", message),
+                        }
+                    }
+                    message => panic!("Expected Message::ResponseBatch, got {}", message),
+                }
+                assert!(rx_channel.try_recv().is_err());
+
+                match &*local_rx_channel.try_recv().unwrap() {
+                    Message::UserReadyToConnect {
+                        connection_local_world_id: ready_id,
+                    } => assert_eq!(*ready_id, connection_local_world_id),
+                    message => panic!("Expected Message::UserReadyToConnect, got {}", message),
+                }
+
+                Ok(())
+            })
+        })
+    }
+
+    fn new_account() -> Account {
+        Account {
+            id: -1,
+            name: "testaccount".to_string(),
+            password: "not-a-real-password-hash".to_string(),
+            algorithm: PasswordHashAlgorithm::Argon2,
+            created_at: Utc.ymd(1995, 7, 8).and_hms(9, 10, 11),
+            updated_at: Utc.ymd(1995, 7, 8).and_hms(9, 10, 11),
+        }
+    }
+}
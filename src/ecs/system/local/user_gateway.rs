@@ -4,7 +4,7 @@ use crate::ecs::message::Message::{ResponseSpawnMe, UserSpawnPrepared, UserSpawn
 use crate::ecs::message::{EcsMessage, Message};
 use crate::ecs::resource::{DeletionList, GlobalMessageChannel};
 use crate::ecs::system::send_message;
-use crate::model::{Angle, Vec3};
+use crate::model::{AccountId, Angle, UserId, Vec3};
 use crate::protocol::packet::*;
 use crate::Result;
 use anyhow::{ensure, Context};
@@ -94,8 +94,8 @@ fn handle_prepare_user_spawn(
     let connection_local_world_id = entities.add_entity(
         user_spawns,
         LocalUserSpawn {
-            user_id: user_initializer.user.id,
-            account_id: user_initializer.user.account_id,
+            user_id: UserId(user_initializer.user.id),
+            account_id: AccountId(user_initializer.user.account_id),
             status: UserSpawnStatus::Waiting,
             is_alive: true,
         },
@@ -211,7 +211,7 @@ fn assemble_response_spawn_me(
     connection_global_world_id: EntityId,
     connection_local_world_id: EntityId,
 ) -> EcsMessage {
-    Box::new(ResponseSpawnMe {
+    EcsMessage::new(ResponseSpawnMe {
         connection_global_world_id,
         connection_local_world_id,
         packet: SSpawnMe {
@@ -229,7 +229,7 @@ fn assemble_response_spawn_me(
 }
 
 fn assemble_user_spawned(connection_global_world_id: EntityId) -> EcsMessage {
-    Box::new(UserSpawned {
+    EcsMessage::new(UserSpawned {
         connection_global_world_id,
     })
 }
@@ -238,7 +238,7 @@ fn assemble_user_spawn_prepared(
     connection_global_world_id: EntityId,
     connection_local_world_id: EntityId,
 ) -> EcsMessage {
-    Box::new(UserSpawnPrepared {
+    EcsMessage::new(UserSpawnPrepared {
         connection_global_world_id,
         connection_local_world_id,
     })
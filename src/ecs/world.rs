@@ -1,22 +1,27 @@
 /// Module that handles the world generation and handling
 use crate::config::Configuration;
-use crate::ecs::message::{EcsMessage, Message};
+use crate::ecs::message::{set_message_origin, EcsMessage, Message, MessageOrigin};
 use crate::ecs::resource::*;
 use crate::ecs::system::{common, global, local};
-use async_std::sync::{channel, Sender};
+use crate::metrics;
+use crate::model::repository::loginticket::{PgTicketValidator, TicketValidator};
+use crate::protocol::serde::SchemaVersion;
+use async_std::sync::{channel, Receiver, Sender};
 use shipyard::*;
 use sqlx::PgPool;
 use std::time::Duration;
-use std::{thread, time};
-use tracing::{error, info, info_span};
+use tracing::{error, info, info_span, warn};
 
-const GLOBAL_WORLD_TICK_RATE: u64 = 10;
 const LOCAL_WORLD_TICK_RATE: u64 = 30;
 
 /// The global world handles all general messages and the persistence layer.
 pub struct GlobalWorld {
     pub channel: Sender<EcsMessage>,
+    /// Closed once the shutdown started. The network server stops accepting connections then.
+    pub accept_channel: Receiver<()>,
     pub world: World,
+    /// Shares the allowed client versions with the world, so they can be updated while it runs.
+    pub allowed_versions: AllowedVersions,
 }
 
 impl GlobalWorld {
@@ -37,6 +42,46 @@ impl GlobalWorld {
         world.add_unique(ShutdownSignal {
             status: ShutdownSignalStatus::Operational,
         });
+        let (accept_tx_channel, accept_rx_channel) = channel(1);
+        world.add_unique(AcceptSignal {
+            channel: Some(accept_tx_channel),
+        });
+        world.add_unique(PriorityOpcodes(
+            config.server.priority_opcodes.iter().cloned().collect(),
+        ));
+        world.add_unique(ResumeTokens::default());
+        world.add_unique(HandlerLatencies::new(
+            config.server.handler_latency_histograms,
+        ));
+        world.add_unique(SpawnQueue::new(config.game.spawn_budget_per_tick));
+        world.add_unique(WorldRng::new(config.game.rng_seed));
+        world.add_unique(Box::new(SystemClock) as Box<dyn Clock>);
+        let allowed_versions = AllowedVersions::new(config.server.allowed_versions.clone());
+        world.add_unique(LoginTicketPolicy {
+            motd: config.game.motd.clone(),
+            version_check_grace: Duration::from_secs(config.server.version_check_grace_secs),
+            post_login_sequence: config.game.post_login_sequence.clone(),
+            skip_login_checks: config.server.insecure_skip_login_checks,
+            allowed_versions: allowed_versions.clone(),
+            login_arbiter_fields: config
+                .server
+                .login_arbiter_fields
+                .iter()
+                .map(|(version, fields)| (SchemaVersion(*version), *fields))
+                .collect(),
+        });
+        world.add_unique(UserListSettings {
+            page_size: config.game.user_list_page_size,
+            ..Default::default()
+        });
+        world.add_unique(EvictionSettings {
+            connection_soft_limit: config.server.connection_soft_limit,
+            policy: config.server.eviction_policy,
+        });
+        world.add_unique(Box::new(PgTicketValidator::new(
+            pool.clone(),
+            Duration::from_secs(config.server.ticket_ttl_secs),
+        )) as Box<dyn TicketValidator>);
         world.add_unique(config.clone());
         world.add_unique(pool.clone());
 
@@ -45,7 +90,9 @@ impl GlobalWorld {
 
         Self {
             channel: tx_channel,
+            accept_channel: accept_rx_channel,
             world,
+            allowed_versions,
         }
     }
 
@@ -53,6 +100,7 @@ impl GlobalWorld {
     pub fn run(&mut self) {
         let span = info_span!("world", world_id = "global");
         let _enter = span.enter();
+        set_message_origin(Some(MessageOrigin::Global));
 
         let world = &mut self.world;
 
@@ -62,14 +110,20 @@ impl GlobalWorld {
             .add_workload(GLOBAL_WORLD_TICK)
             .with_system(system!(common::message_receiver_system))
             .with_system(system!(global::connection_manager_system))
+            .with_system(system!(global::connection_eviction_system))
             .with_system(system!(global::settings_manager_system))
             .with_system(system!(global::user_manager_system))
             .with_system(system!(global::user_spawner_system))
             .with_system(system!(global::local_world_manager_system))
             .with_system(system!(common::cleaner_system))
+            .with_system(system!(common::shutdown_system))
             .build();
 
-        let min_tick_duration = time::Duration::from_millis(1000 / GLOBAL_WORLD_TICK_RATE);
+        let tick_rate = world
+            .borrow::<UniqueView<Configuration>>()
+            .game
+            .global_tick_rate_hz;
+        let min_tick_duration = Duration::from_millis(1000 / tick_rate);
         loop {
             let shutdown_signal = world.borrow::<UniqueView<ShutdownSignal>>();
             if shutdown_signal.status == ShutdownSignalStatus::Shutdown {
@@ -79,6 +133,7 @@ impl GlobalWorld {
             drop(shutdown_signal);
 
             run_workload_tick(&world, GLOBAL_WORLD_TICK, min_tick_duration);
+            metrics::publish_handler_latencies(&world.borrow::<UniqueView<HandlerLatencies>>());
         }
     }
 
@@ -118,6 +173,16 @@ impl LocalWorld {
         world.add_unique(ShutdownSignal {
             status: ShutdownSignalStatus::Operational,
         });
+        world.add_unique(AcceptSignal { channel: None });
+        world.add_unique(PriorityOpcodes(
+            config.server.priority_opcodes.iter().cloned().collect(),
+        ));
+        world.add_unique(WorldRng::new(config.game.rng_seed));
+        world.add_unique(Box::new(SystemClock) as Box<dyn Clock>);
+        world.add_unique(Box::new(PgTicketValidator::new(
+            pool.clone(),
+            Duration::from_secs(config.server.ticket_ttl_secs),
+        )) as Box<dyn TicketValidator>);
         world.add_unique(config.clone());
         world.add_unique(pool.clone());
 
@@ -135,6 +200,7 @@ impl LocalWorld {
     pub fn run(&mut self) {
         let span = info_span!("world", world_id = ?self.id);
         let _enter = span.enter();
+        set_message_origin(Some(MessageOrigin::Local(self.id)));
 
         let world = &mut self.world;
 
@@ -156,7 +222,7 @@ impl LocalWorld {
         let global_message_channel = world.borrow::<UniqueView<GlobalMessageChannel>>();
         match global_message_channel
             .channel
-            .try_send(Box::new(Message::LocalWorldLoaded {
+            .try_send(EcsMessage::new(Message::LocalWorldLoaded {
                 successful: true,
                 global_world_id: self.id,
             })) {
@@ -171,7 +237,7 @@ impl LocalWorld {
         }
         drop(global_message_channel);
 
-        let min_tick_duration = time::Duration::from_millis(1000 / LOCAL_WORLD_TICK_RATE);
+        let min_tick_duration = Duration::from_millis(1000 / LOCAL_WORLD_TICK_RATE);
         loop {
             let shutdown_signal = world.borrow::<UniqueView<ShutdownSignal>>();
             if shutdown_signal.status == ShutdownSignalStatus::Shutdown {
@@ -187,12 +253,69 @@ impl LocalWorld {
 
 #[inline]
 fn run_workload_tick(world: &World, workload_name: &str, min_tick_duration: Duration) {
-    let start = time::Instant::now();
+    let start = world.borrow::<UniqueView<Box<dyn Clock>>>().now();
 
     world.run_workload(workload_name);
 
-    let elapsed = start.elapsed();
+    let clock = world.borrow::<UniqueView<Box<dyn Clock>>>();
+    let elapsed = clock.now().duration_since(start);
     if elapsed < min_tick_duration {
-        thread::sleep(min_tick_duration - elapsed);
+        clock.sleep(min_tick_duration - elapsed);
+    } else {
+        warn!(
+            "Tick of workload {} overran it's budget of {:?} by {:?}",
+            workload_name,
+            min_tick_duration,
+            elapsed - min_tick_duration
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ManualClock;
+
+    const WORK_DURATION: Duration = Duration::from_millis(5);
+
+    // Takes the same time every tick.
+    fn work_system(clock: UniqueView<ManualClock>) {
+        clock.advance(WORK_DURATION);
+    }
+
+    fn setup() -> (World, ManualClock) {
+        let clock = ManualClock::default();
+        let world = World::new();
+        world.add_unique(Box::new(clock.clone()) as Box<dyn Clock>);
+        world.add_unique(clock.clone());
+        world
+            .add_workload("TEST_TICK")
+            .with_system(system!(work_system))
+            .build();
+        (world, clock)
+    }
+
+    #[test]
+    fn test_run_workload_tick_respects_interval() {
+        let (world, clock) = setup();
+
+        let min_tick_duration = Duration::from_millis(20);
+        let start = clock.now();
+        for i in 1..=5 {
+            run_workload_tick(&world, "TEST_TICK", min_tick_duration);
+            assert_eq!(clock.now().duration_since(start), min_tick_duration * i);
+        }
+    }
+
+    #[test]
+    fn test_run_workload_tick_doesnt_sleep_after_overrun() {
+        let (world, clock) = setup();
+
+        let min_tick_duration = Duration::from_millis(2);
+        let start = clock.now();
+        for i in 1..=5 {
+            run_workload_tick(&world, "TEST_TICK", min_tick_duration);
+            assert_eq!(clock.now().duration_since(start), WORK_DURATION * i);
+        }
     }
 }
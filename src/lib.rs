@@ -4,9 +4,12 @@ pub mod config;
 pub mod crypt;
 pub mod dataloader;
 pub mod ecs;
+pub mod metrics;
 pub mod model;
 pub mod networkserver;
 pub mod protocol;
+#[cfg(test)]
+pub(crate) mod test_support;
 pub mod webserver;
 use thiserror::Error;
 
@@ -32,4 +35,7 @@ pub enum AlmeticaError {
 
     #[error("invalid login provided")]
     InvalidLogin,
+
+    #[error("world channel closed")]
+    WorldChannelClosed,
 }
@@ -0,0 +1,245 @@
+/// Process wide metrics of the server.
+///
+/// The decode statistics count how the packets of each opcode decode. A definition that fails
+/// for every client is likely wrong, while failures of single clients point to malformed data.
+/// The send queue statistics show how many responses wait for slow clients and the handler
+/// latencies how long the global world takes to handle the requests.
+use crate::ecs::resource::HandlerLatencies;
+use crate::protocol::opcode::Opcode;
+use crate::protocol::serde::Error;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Decode statistics by opcode.
+pub type DecodeStatisticsMap = HashMap<Opcode, DecodeStatistics>;
+
+/// Decode statistics of the packets of one opcode.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DecodeStatistics {
+    pub attempts: u64,
+    pub successes: u64,
+    /// Failed attempts by the kind of the error.
+    pub failures: HashMap<&'static str, u64>,
+    /// Sum of the body lengths of the decoded packets.
+    pub decoded_bytes: u64,
+}
+
+impl DecodeStatistics {
+    /// Number of failed attempts.
+    pub fn failure_count(&self) -> u64 {
+        self.failures.values().sum()
+    }
+
+    /// Average body length of the decoded packets.
+    pub fn average_decoded_size(&self) -> Option<f64> {
+        if self.successes == 0 {
+            None
+        } else {
+            Some(self.decoded_bytes as f64 / self.successes as f64)
+        }
+    }
+
+    fn merge(&mut self, other: &DecodeStatistics) {
+        self.attempts += other.attempts;
+        self.successes += other.successes;
+        self.decoded_bytes += other.decoded_bytes;
+        for (kind, count) in other.failures.iter() {
+            *self.failures.entry(*kind).or_insert(0) += count;
+        }
+    }
+}
+
+/// Depth of the send queues of all sessions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SendQueueStatistics {
+    /// Responses that are currently queued for all sessions.
+    pub queued: usize,
+    /// Deepest queue of a single session since the server started.
+    pub max_depth: usize,
+}
+
+lazy_static! {
+    // The statistics of every thread that decoded a packet. The shards of finished threads are
+    // kept, so their counts are not lost.
+    static ref SHARDS: Mutex<Vec<Arc<Mutex<DecodeStatisticsMap>>>> = Mutex::new(Vec::new());
+    // Copy of the handler latencies of the global world as of it's last tick.
+    static ref HANDLER_LATENCIES: Mutex<HandlerLatencies> = Mutex::new(HandlerLatencies::default());
+}
+
+static QUEUED_RESPONSES: AtomicUsize = AtomicUsize::new(0);
+static MAX_SEND_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Every thread counts into it's own shard, so the sessions don't contend on one lock.
+    static LOCAL_SHARD: Arc<Mutex<DecodeStatisticsMap>> = {
+        let shard = Arc::new(Mutex::new(HashMap::new()));
+        SHARDS.lock().unwrap().push(shard.clone());
+        shard
+    };
+}
+
+/// Records a successful decode of a packet with the given body length.
+pub fn record_decode_success(opcode: Opcode, body_length: usize) {
+    LOCAL_SHARD.with(|shard| {
+        let mut shard = shard.lock().unwrap();
+        let statistics = shard.entry(opcode).or_default();
+        statistics.attempts += 1;
+        statistics.successes += 1;
+        statistics.decoded_bytes += body_length as u64;
+    });
+}
+
+/// Records a failed decode of a packet.
+pub fn record_decode_failure(opcode: Opcode, error: &anyhow::Error) {
+    let kind = error_kind(error);
+    LOCAL_SHARD.with(|shard| {
+        let mut shard = shard.lock().unwrap();
+        let statistics = shard.entry(opcode).or_default();
+        statistics.attempts += 1;
+        *statistics.failures.entry(kind).or_insert(0) += 1;
+    });
+}
+
+/// Returns the decode statistics of all threads.
+pub fn decode_statistics() -> DecodeStatisticsMap {
+    let mut merged = DecodeStatisticsMap::new();
+    for shard in SHARDS.lock().unwrap().iter() {
+        for (opcode, statistics) in shard.lock().unwrap().iter() {
+            merged.entry(*opcode).or_default().merge(statistics);
+        }
+    }
+    merged
+}
+
+/// Records that the send queue of a session changed it's depth from `previous` to `depth`.
+pub fn record_send_queue_depth(previous: usize, depth: usize) {
+    if depth >= previous {
+        QUEUED_RESPONSES.fetch_add(depth - previous, Ordering::Relaxed);
+    } else {
+        QUEUED_RESPONSES.fetch_sub(previous - depth, Ordering::Relaxed);
+    }
+
+    let mut max_depth = MAX_SEND_QUEUE_DEPTH.load(Ordering::Relaxed);
+    while depth > max_depth {
+        match MAX_SEND_QUEUE_DEPTH.compare_exchange_weak(
+            max_depth,
+            depth,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(current) => max_depth = current,
+        }
+    }
+}
+
+/// Returns the depth of the send queues of all sessions.
+pub fn send_queue_statistics() -> SendQueueStatistics {
+    SendQueueStatistics {
+        queued: QUEUED_RESPONSES.load(Ordering::Relaxed),
+        max_depth: MAX_SEND_QUEUE_DEPTH.load(Ordering::Relaxed),
+    }
+}
+
+/// Publishes the handler latencies of the global world. Called after every tick.
+pub fn publish_handler_latencies(latencies: &HandlerLatencies) {
+    *HANDLER_LATENCIES.lock().unwrap() = latencies.clone();
+}
+
+/// Returns the latency histograms and outcome counts of the request handlers of the global world.
+pub fn handler_latencies() -> HandlerLatencies {
+    HANDLER_LATENCIES.lock().unwrap().clone()
+}
+
+/// Returns the decode statistics of the current thread. Tests run on their own thread, so they
+/// only see the packets they decoded themselves.
+#[cfg(test)]
+pub(crate) fn thread_decode_statistics() -> DecodeStatisticsMap {
+    LOCAL_SHARD.with(|shard| shard.lock().unwrap().clone())
+}
+
+fn error_kind(error: &anyhow::Error) -> &'static str {
+    match error.downcast_ref::<Error>() {
+        Some(e) => e.kind(),
+        None => "Other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::system::HandlerOutcome;
+    use anyhow::anyhow;
+    use std::time::Duration;
+
+    #[test]
+    fn test_merge_threads() {
+        record_decode_success(Opcode::C_SAVE_CLIENT_USER_SETTING, 10);
+        std::thread::spawn(|| {
+            record_decode_success(Opcode::C_SAVE_CLIENT_USER_SETTING, 20);
+            record_decode_failure(
+                Opcode::C_SAVE_CLIENT_USER_SETTING,
+                &anyhow!(Error::StringTooLong(4)),
+            );
+        })
+        .join()
+        .unwrap();
+
+        let local = &thread_decode_statistics()[&Opcode::C_SAVE_CLIENT_USER_SETTING];
+        assert_eq!(local.attempts, 1);
+
+        let merged = &decode_statistics()[&Opcode::C_SAVE_CLIENT_USER_SETTING];
+        assert!(merged.attempts >= 3);
+        assert!(merged.successes >= 2);
+        assert!(merged.failures["StringTooLong"] >= 1);
+    }
+
+    #[test]
+    fn test_average_decoded_size() {
+        let mut statistics = DecodeStatistics::default();
+        assert_eq!(statistics.average_decoded_size(), None);
+
+        statistics.successes = 4;
+        statistics.decoded_bytes = 10;
+        assert_eq!(statistics.average_decoded_size(), Some(2.5));
+    }
+
+    #[test]
+    fn test_send_queue_statistics() {
+        // Other tests run sessions at the same time, so only the own share is known.
+        record_send_queue_depth(0, 1_000_000);
+        let statistics = send_queue_statistics();
+        assert!(statistics.queued >= 1_000_000);
+        assert!(statistics.max_depth >= 1_000_000);
+
+        record_send_queue_depth(1_000_000, 10);
+        record_send_queue_depth(10, 0);
+        assert!(send_queue_statistics().max_depth >= 1_000_000);
+    }
+
+    #[test]
+    fn test_handler_latencies() {
+        let mut latencies = HandlerLatencies::new(true);
+        latencies.record(Opcode::C_LOGIN_ARBITER, Duration::from_millis(2));
+        latencies.count(Opcode::C_LOGIN_ARBITER, &HandlerOutcome::Handled);
+        publish_handler_latencies(&latencies);
+
+        let published = handler_latencies();
+        assert_eq!(
+            published.histograms[&Opcode::C_LOGIN_ARBITER],
+            latencies.histograms[&Opcode::C_LOGIN_ARBITER]
+        );
+        assert_eq!(published.outcomes[&Opcode::C_LOGIN_ARBITER].handled, 1);
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(
+            error_kind(&anyhow!(Error::UnexpectedEof("Test", 2))),
+            "UnexpectedEof"
+        );
+        assert_eq!(error_kind(&anyhow!("something else")), "Other");
+    }
+}
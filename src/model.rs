@@ -5,10 +5,12 @@ pub mod repository;
 
 use byteorder::{ByteOrder, LittleEndian};
 use serde::de::{self, Visitor};
+use serde::ser;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+/// The region is transmitted as the u32 value the client uses, not the index of the variant.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Region {
     International = 0,
     Korea = 1,
@@ -21,6 +23,41 @@ pub enum Region {
     Russia = 8,
 }
 
+impl Default for Region {
+    fn default() -> Self {
+        Region::International
+    }
+}
+
+impl Serialize for Region {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u32(*self as u32)
+    }
+}
+
+impl<'de> Deserialize<'de> for Region {
+    fn deserialize<D>(deserializer: D) -> Result<Region, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match deserializer.deserialize_u32(U32Visitor)? {
+            0 => Ok(Region::International),
+            1 => Ok(Region::Korea),
+            2 => Ok(Region::Usa),
+            3 => Ok(Region::Japan),
+            4 => Ok(Region::Germany),
+            5 => Ok(Region::France),
+            6 => Ok(Region::Europe),
+            7 => Ok(Region::Taiwan),
+            8 => Ok(Region::Russia),
+            value => Err(de::Error::custom(format!("unknown region {}", value))),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, sqlx::Type, PartialEq)]
 #[sqlx(rename = "gender")]
 pub enum Gender {
@@ -30,6 +67,12 @@ pub enum Gender {
     Female = 1,
 }
 
+impl Default for Gender {
+    fn default() -> Self {
+        Gender::Male
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, sqlx::Type, PartialEq)]
 #[sqlx(rename = "race")]
 pub enum Race {
@@ -47,6 +90,12 @@ pub enum Race {
     Baraka = 5,
 }
 
+impl Default for Race {
+    fn default() -> Self {
+        Race::Human
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, sqlx::Type, PartialEq)]
 #[sqlx(rename = "user_class")]
 pub enum Class {
@@ -78,6 +127,12 @@ pub enum Class {
     Valkyrie = 12,
 }
 
+impl Default for Class {
+    fn default() -> Self {
+        Class::Warrior
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, sqlx::Type, PartialEq)]
 #[sqlx(rename = "servant_type")]
 pub enum ServantType {
@@ -87,6 +142,34 @@ pub enum ServantType {
     Partner = 1,
 }
 
+impl Default for ServantType {
+    fn default() -> Self {
+        ServantType::Pet
+    }
+}
+
+/// ID of an account. Is serialized exactly like the raw ID.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct AccountId(pub i64);
+
+impl fmt::Display for AccountId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// ID of an user (TERA calls a character an user). Is serialized exactly like the raw ID.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct UserId(pub i32);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// TERA transmits the rotation of objects as a u16 value. It's a fraction value of a full rotation.
 /// 0x0 = 0°, 0xFFFF = 360°
 #[derive(Clone, Copy, Debug, sqlx::Type, PartialEq)]
@@ -194,6 +277,12 @@ impl Serialize for Customization {
     where
         S: Serializer,
     {
+        if self.0.len() != 8 {
+            return Err(ser::Error::custom(format!(
+                "customization must be 8 bytes long but is {}",
+                self.0.len()
+            )));
+        }
         serializer.serialize_u64(LittleEndian::read_u64(&self.0))
     }
 }
@@ -305,6 +394,18 @@ pub enum PasswordHashAlgorithm {
     Argon2,
 }
 
+/// Returns true if the error was caused by an unreachable or overloaded storage backend. These
+/// errors are transient, unlike errors of the queries themselves.
+pub fn is_storage_unavailable(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| match cause.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::Io { .. })
+            | Some(sqlx::Error::PoolTimedOut { .. })
+            | Some(sqlx::Error::PoolClosed { .. }) => true,
+            _ => false,
+        })
+}
+
 struct U16Visitor;
 
 impl<'de> Visitor<'de> for U16Visitor {
@@ -322,6 +423,23 @@ impl<'de> Visitor<'de> for U16Visitor {
     }
 }
 
+struct U32Visitor;
+
+impl<'de> Visitor<'de> for U32Visitor {
+    type Value = u32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("4 bytes")
+    }
+
+    fn visit_u32<E>(self, value: u32) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(value)
+    }
+}
+
 struct I32Visitor;
 
 impl<'de> Visitor<'de> for I32Visitor {
@@ -448,6 +566,12 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_customization_wrong_length() {
+        assert!(to_vec(&Customization(vec![])).is_err());
+        assert!(to_vec(&Customization(vec![0u8; 9])).is_err());
+    }
+
     #[test]
     fn test_customization_deserialization() -> Result<()> {
         let data = vec![1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8];
@@ -502,6 +626,32 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_region_serialization() -> Result<()> {
+        let regions = vec![
+            (Region::International, 0),
+            (Region::Korea, 1),
+            (Region::Usa, 2),
+            (Region::Japan, 3),
+            (Region::Germany, 4),
+            (Region::France, 5),
+            (Region::Europe, 6),
+            (Region::Taiwan, 7),
+            (Region::Russia, 8),
+        ];
+        for (region, client_value) in regions {
+            let data = to_vec(&region)?;
+            assert_eq!(LittleEndian::read_u32(&data), client_value);
+            let value: Region = from_vec(data)?;
+            assert_eq!(value, region);
+        }
+
+        let mut data = vec![0u8; 4];
+        LittleEndian::write_u32(&mut data, 9);
+        assert!(from_vec::<Region>(data).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_angle_basic() {
         for i in 0..360 {
@@ -526,4 +676,22 @@ pub mod tests {
         assert_eq!(value.deg(), 180.0);
         Ok(())
     }
+
+    #[test]
+    fn test_account_id_serializes_like_raw_id() -> Result<()> {
+        let data = to_vec(&AccountId(482_558))?;
+        assert_eq!(data, to_vec(&482_558i64)?);
+        let value: AccountId = from_vec(data)?;
+        assert_eq!(value, AccountId(482_558));
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_id_serializes_like_raw_id() -> Result<()> {
+        let data = to_vec(&UserId(2_000_131))?;
+        assert_eq!(data, to_vec(&2_000_131i32)?);
+        let value: UserId = from_vec(data)?;
+        assert_eq!(value, UserId(2_000_131));
+        Ok(())
+    }
 }
@@ -0,0 +1,5 @@
+CREATE TABLE "account_user_slots"
+(
+    "account_id" BIGINT NOT NULL UNIQUE REFERENCES "account" ON DELETE CASCADE,
+    "slots"      INT    NOT NULL
+);
@@ -1,59 +1,89 @@
 /// Handles the login ticket of client connections.
 use crate::model::entity::LoginTicket;
+use crate::model::repository::account;
+use crate::model::AccountId;
 use crate::Result;
+use anyhow::Context;
+use async_std::task;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use sqlx::prelude::*;
-use sqlx::PgConnection;
-
-/// Upserts a ticket (randomly generated 128 bytes). Tickets are valid for 5 minutes and can only be used once.
-pub async fn upsert_ticket(conn: &mut PgConnection, account_id: i64) -> Result<LoginTicket> {
-    let mut ticket = vec![0u8; 128];
-    OsRng.fill_bytes(&mut ticket);
-
-    Ok(sqlx::query_as::<_, LoginTicket>(
-        r#"INSERT INTO "login_ticket" VALUES ($1, $2, DEFAULT, DEFAULT)
-        ON CONFLICT ("account_id") DO UPDATE SET "ticket" = $2, "used" = DEFAULT, "created_at" = DEFAULT
-        RETURNING *"#,
-    )
-    .bind(account_id)
-    .bind(ticket)
-    .fetch_one(conn)
-    .await?)
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+
+/// Result of a ticket validation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TicketValidation {
+    /// The ticket was valid and is now consumed.
+    Valid(AccountId),
+    /// The account is in use. The ticket wasn't consumed, so the client can retry with it.
+    AccountInUse,
+    /// The ticket is unknown, expired or already used.
+    Invalid,
+}
+
+/// Validates the login tickets that clients present with the login arbiter.
+pub trait TicketValidator: Send + Sync {
+    /// Validates the ticket of an account and marks it as consumed. `is_in_use` is asked before
+    /// the ticket is consumed. Without a ticket only the account is looked up.
+    fn validate(
+        &self,
+        account_name: &str,
+        ticket: Option<&[u8]>,
+        is_in_use: &dyn Fn(AccountId) -> bool,
+    ) -> Result<TicketValidation>;
 }
 
-/// Tests if the given ticket is valid. A ticket can only be used one time. Should be called in a transaction.
-pub async fn is_ticket_valid(conn: &mut PgConnection, name: &str, ticket: &[u8]) -> Result<bool> {
-    // We have to manually re-borrow the transaction. &mut *conn will take a &mut PgConnection and
-    // produce a &mut PgConnection that is held for the lifetime required by the function.
-    // This is normally done implicitly by Rust. It's not in this case due to fetch_*() being
-    // generic over its parameter (allowing both connection, a pool or a transaction to be passed in).
-
-    let account_id: i64 = match sqlx::query_as(
-        r#"SELECT l."account_id"
-               FROM "login_ticket" l
-               INNER JOIN "account" a
-               ON l."account_id" = a."id"
-               WHERE a."name" = $1
-               AND l."ticket" = $2
-               AND l."used" = 'FALSE'
-               AND age(CURRENT_TIMESTAMP, l."created_at") < INTERVAL '5 minutes'"#,
-    )
-    .bind(name)
-    .bind(ticket)
-    .fetch_optional(&mut *conn)
-    .await?
-    {
-        Some((id,)) => id,
-        None => return Ok(false),
-    };
-
-    sqlx::query(r#"UPDATE "login_ticket" SET "used" = 'true' WHERE "account_id" = $1"#)
-        .bind(account_id)
-        .execute(&mut *conn)
-        .await?;
-
-    Ok(true)
+/// Validates the tickets that are stored in the database.
+#[derive(Clone)]
+pub struct PgTicketValidator {
+    pool: PgPool,
+    ttl: Duration,
+}
+
+impl PgTicketValidator {
+    /// Tickets are accepted until they are older than the TTL.
+    pub fn new(pool: PgPool, ttl: Duration) -> Self {
+        PgTicketValidator { pool, ttl }
+    }
+}
+
+impl TicketValidator for PgTicketValidator {
+    fn validate(
+        &self,
+        account_name: &str,
+        ticket: Option<&[u8]>,
+        is_in_use: &dyn Fn(AccountId) -> bool,
+    ) -> Result<TicketValidation> {
+        task::block_on(async {
+            // A failure rolls the transaction back, so the ticket isn't consumed.
+            let mut conn = self
+                .pool
+                .begin()
+                .await
+                .context("Couldn't acquire connection from pool")?;
+
+            let account = account::get_by_name(&mut conn, account_name)
+                .await
+                .context("Can't find the account for the given master account name")?;
+            let account_id = AccountId(account.id);
+            if is_in_use(account_id) {
+                return Ok(TicketValidation::AccountInUse);
+            }
+
+            if let Some(ticket) = ticket {
+                if !is_ticket_valid(&mut conn, account_name, ticket, self.ttl)
+                    .await
+                    .context("Error while executing query for account")?
+                {
+                    return Ok(TicketValidation::Invalid);
+                }
+            }
+
+            conn.commit().await?;
+            Ok(TicketValidation::Valid(account_id))
+        })
+    }
 }
 
 #[cfg(test)]
@@ -66,7 +96,9 @@ pub mod tests {
     use crate::Result;
     use async_std::task;
     use chrono::prelude::*;
-    use sqlx::PgConnection;
+    use sqlx::{PgConnection, PgPool};
+
+    const TTL: Duration = Duration::from_secs(300);
 
     #[test]
     fn test_upsert_login_ticket() -> Result<()> {
@@ -120,9 +152,9 @@ pub mod tests {
 
                 let ticket = upsert_ticket(&mut conn, account.id).await?;
                 assert!(!ticket.ticket.is_empty());
-                assert!(is_ticket_valid(&mut conn, &account.name, &ticket.ticket).await?);
+                assert!(is_ticket_valid(&mut conn, &account.name, &ticket.ticket, TTL).await?);
                 // Ticket can only be used one time
-                assert!(!is_ticket_valid(&mut conn, &account.name, &ticket.ticket).await?);
+                assert!(!is_ticket_valid(&mut conn, &account.name, &ticket.ticket, TTL).await?);
 
                 Ok(())
             })
@@ -149,7 +181,9 @@ pub mod tests {
                 .await?;
 
                 upsert_ticket(&mut conn, account.id).await?;
-                assert!(!is_ticket_valid(&mut conn, &account.name, "123456789".as_bytes()).await?);
+                assert!(
+                    !is_ticket_valid(&mut conn, &account.name, "123456789".as_bytes(), TTL).await?
+                );
 
                 Ok(())
             })
@@ -176,10 +210,113 @@ pub mod tests {
                 .await?;
 
                 let ticket = upsert_ticket(&mut conn, account.id).await?;
-                assert!(!is_ticket_valid(&mut conn, &"not-a-user", &ticket.ticket).await?);
+                assert!(!is_ticket_valid(&mut conn, &"not-a-user", &ticket.ticket, TTL).await?);
 
                 Ok(())
             })
         })
     }
+
+    #[test]
+    fn test_validate_expired_ticket() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let mut conn = PgConnection::connect(db_string).await?;
+
+                let account = account::create(
+                    &mut conn,
+                    &Account {
+                        id: -1,
+                        name: "testuser".to_string(),
+                        password: "not-a-real-password-hash".to_string(),
+                        algorithm: PasswordHashAlgorithm::Argon2,
+                        created_at: Utc.ymd(1995, 7, 8).and_hms(9, 10, 11),
+                        updated_at: Utc.ymd(1995, 7, 8).and_hms(9, 10, 11),
+                    },
+                )
+                .await?;
+
+                let ticket = upsert_ticket(&mut conn, account.id).await?;
+                sqlx::query(
+                    r#"UPDATE "login_ticket" SET "created_at" = CURRENT_TIMESTAMP - INTERVAL '2 minutes' WHERE "account_id" = $1"#,
+                )
+                .bind(account.id)
+                .execute(&mut conn)
+                .await?;
+
+                // The ticket is older than the TTL and must be rejected.
+                assert!(
+                    !is_ticket_valid(
+                        &mut conn,
+                        &account.name,
+                        &ticket.ticket,
+                        Duration::from_secs(60)
+                    )
+                    .await?
+                );
+                // An expired ticket isn't consumed, so it's still accepted with a longer TTL.
+                assert!(is_ticket_valid(&mut conn, &account.name, &ticket.ticket, TTL).await?);
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn test_ticket_validator() -> Result<()> {
+        db_test(|db_string| {
+            let pool = task::block_on(async { PgPool::new(db_string).await })?;
+            let (account, ticket) = task::block_on(async {
+                let mut conn = pool.acquire().await?;
+                let account = account::create(
+                    &mut conn,
+                    &Account {
+                        id: -1,
+                        name: "testuser".to_string(),
+                        password: "not-a-real-password-hash".to_string(),
+                        algorithm: PasswordHashAlgorithm::Argon2,
+                        created_at: Utc.ymd(1995, 7, 8).and_hms(9, 10, 11),
+                        updated_at: Utc.ymd(1995, 7, 8).and_hms(9, 10, 11),
+                    },
+                )
+                .await?;
+                let ticket = upsert_ticket(&mut conn, account.id).await?;
+                Ok::<_, anyhow::Error>((account, ticket.ticket))
+            })?;
+
+            let not_in_use = |_: AccountId| false;
+            let in_use = |_: AccountId| true;
+
+            // A TTL of zero expires every ticket, without consuming it.
+            let expired = PgTicketValidator::new(pool.clone(), Duration::from_secs(0));
+            assert_eq!(
+                expired.validate(&account.name, Some(&ticket), &not_in_use)?,
+                TicketValidation::Invalid
+            );
+
+            // The ticket of an account in use isn't consumed.
+            let validator = PgTicketValidator::new(pool, TTL);
+            assert_eq!(
+                validator.validate(&account.name, Some(&ticket), &in_use)?,
+                TicketValidation::AccountInUse
+            );
+
+            assert_eq!(
+                validator.validate(&account.name, Some(&ticket), &not_in_use)?,
+                TicketValidation::Valid(AccountId(account.id))
+            );
+            // The ticket was consumed, so a replay is rejected.
+            assert_eq!(
+                validator.validate(&account.name, Some(&ticket), &not_in_use)?,
+                TicketValidation::Invalid
+            );
+            // Without a ticket only the account is looked up.
+            assert_eq!(
+                validator.validate(&account.name, None, &not_in_use)?,
+                TicketValidation::Valid(AccountId(account.id))
+            );
+
+            Ok(())
+        })
+    }
 }
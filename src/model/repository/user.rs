@@ -122,14 +122,37 @@ pub async fn get_user_count(conn: &mut PgConnection, account_id: i64) -> Result<
     Ok(count)
 }
 
+/// Get the user slot limit of an account. Returns the given default if the account has no override.
+pub async fn get_user_slots(conn: &mut PgConnection, account_id: i64, default: i64) -> Result<i64> {
+    let slots: Option<(i32,)> =
+        sqlx::query_as(r#"SELECT "slots" FROM "account_user_slots" WHERE "account_id" = $1"#)
+            .bind(account_id)
+            .fetch_optional(conn)
+            .await?;
+    Ok(slots.map_or(default, |(slots,)| slots as i64))
+}
+
+/// Overrides the user slot limit of an account.
+pub async fn set_user_slots(conn: &mut PgConnection, account_id: i64, slots: i32) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO "account_user_slots" VALUES ($1, $2)
+        ON CONFLICT ("account_id") DO UPDATE SET "slots" = $2"#,
+    )
+    .bind(account_id)
+    .bind(slots)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
 /// Get all users of an account.
 pub async fn list(conn: &mut PgConnection, account_id: i64) -> Result<Vec<User>> {
-    Ok(
-        sqlx::query_as(r#"SELECT * FROM "user" WHERE "account_id" = $1 ORDER BY "lobby_slot""#)
-            .bind(account_id)
-            .fetch_all(conn)
-            .await?,
+    Ok(sqlx::query_as(
+        r#"SELECT * FROM "user" WHERE "account_id" = $1 ORDER BY "lobby_slot", "created_at", "id""#,
     )
+    .bind(account_id)
+    .fetch_all(conn)
+    .await?)
 }
 
 /// Checks if an user with the given name already exists.
@@ -397,4 +420,24 @@ pub mod tests {
             })
         })
     }
+
+    #[test]
+    fn test_user_slots() -> Result<()> {
+        db_test(|db_string| {
+            task::block_on(async {
+                let mut conn = PgConnection::connect(db_string).await?;
+                let account = create_account(&mut conn).await?;
+
+                assert_eq!(get_user_slots(&mut conn, account.id, 20).await?, 20);
+
+                set_user_slots(&mut conn, account.id, 4).await?;
+                assert_eq!(get_user_slots(&mut conn, account.id, 20).await?, 4);
+
+                set_user_slots(&mut conn, account.id, 30).await?;
+                assert_eq!(get_user_slots(&mut conn, account.id, 20).await?, 30);
+
+                Ok(())
+            })
+        })
+    }
 }
@@ -1,37 +1,320 @@
 /// The module of the network server that handles the TCP connections to the clients.
 use crate::config::Configuration;
+use crate::dataloader::validate_opcode_maps;
 use crate::ecs::message::EcsMessage;
 use crate::protocol::opcode::Opcode;
-use crate::protocol::GameSession;
+use crate::protocol::{GameSession, SessionSettings};
 use crate::{AlmeticaError, Result};
-use async_std::net::TcpListener;
-use async_std::sync::Sender;
+use anyhow::{ensure, Context};
+use async_macros::select;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::sync::{Receiver, Sender};
 use async_std::task;
-use std::collections::HashMap;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tracing::{error, info, info_span, warn};
+use std::time::{Duration, Instant};
+use tracing::{error, field, info, info_span, warn};
 use tracing_futures::Instrument;
 
-/// Main loop for the network server
+// Refused connections are logged at most once in this interval.
+const REFUSED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A range of IP addresses in CIDR notation (`10.0.0.0/8`). A plain address is a range of one.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl IpRange {
+    /// Returns true if the address is inside the range. IPv4-mapped IPv6 addresses (as reported
+    /// by a dual-stack listener) are matched against the IPv4 ranges.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = unmap_ipv4(*addr);
+        self.network.is_ipv4() == addr.is_ipv4()
+            && mask_address(addr, self.prefix_length) == self.network
+    }
+}
+
+/// Turns an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) into the IPv4 address.
+fn unmap_ipv4(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            v6.to_ipv4().map(IpAddr::V4).unwrap_or(addr)
+        }
+        _ => addr,
+    }
+}
+
+/// Clears all bits of the address behind the prefix.
+fn mask_address(addr: IpAddr, prefix_length: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = (!0u32)
+                .checked_shl(32 - u32::from(prefix_length))
+                .unwrap_or(0);
+            IpAddr::from((u32::from(addr) & mask).to_be_bytes())
+        }
+        IpAddr::V6(addr) => {
+            let mask = (!0u128)
+                .checked_shl(128 - u32::from(prefix_length))
+                .unwrap_or(0);
+            IpAddr::from((u128::from(addr) & mask).to_be_bytes())
+        }
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (network, prefix_length) = match value.find('/') {
+            Some(pos) => (&value[..pos], Some(&value[pos + 1..])),
+            None => (value.as_str(), None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .with_context(|| format!("Invalid address in IP range {}", value))?;
+        let max_prefix_length = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse::<u8>()
+                .with_context(|| format!("Invalid prefix length in IP range {}", value))?,
+            None => max_prefix_length,
+        };
+        ensure!(
+            prefix_length <= max_prefix_length,
+            "Prefix length of IP range {} must be at most {}",
+            value,
+            max_prefix_length
+        );
+        // Host bits are most likely a typo in the prefix length.
+        ensure!(
+            mask_address(network, prefix_length) == network,
+            "IP range {} has host bits set",
+            value
+        );
+        Ok(IpRange {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+/// Origin of an IP address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeoOrigin {
+    /// ISO 3166-1 alpha-2 code of the country.
+    pub country: Option<String>,
+    /// Number of the autonomous system that announces the address.
+    pub asn: Option<u32>,
+}
+
+/// Looks up the origin of IP addresses, for example in a GeoIP database.
+pub trait GeoLookup: Send + Sync {
+    /// Returns `None` if nothing is known about the address.
+    fn lookup(&self, addr: &IpAddr) -> Option<GeoOrigin>;
+
+    /// Returns false if the lookup can't tell the origin of any address.
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// Lookup that knows nothing about any address.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoGeoLookup;
+
+impl GeoLookup for NoGeoLookup {
+    fn lookup(&self, _addr: &IpAddr) -> Option<GeoOrigin> {
+        None
+    }
+
+    fn is_available(&self) -> bool {
+        false
+    }
+}
+
+/// Only lets connections through whose country or ASN is allowed. The gate is open if neither
+/// countries nor ASNs are configured. Addresses the lookup knows nothing about are let through,
+/// so that the server stays reachable without a GeoIP database.
+pub struct GeoGate {
+    allowed_countries: HashSet<String>,
+    allowed_asns: HashSet<u32>,
+    lookup: Box<dyn GeoLookup>,
+}
+
+impl GeoGate {
+    /// Fails if countries or ASNs are allowed, but the lookup isn't available.
+    pub fn new(
+        allowed_countries: &[String],
+        allowed_asns: &[u32],
+        lookup: Box<dyn GeoLookup>,
+    ) -> Result<Self> {
+        let gate = GeoGate {
+            allowed_countries: allowed_countries
+                .iter()
+                .map(|country| country.to_uppercase())
+                .collect(),
+            allowed_asns: allowed_asns.iter().copied().collect(),
+            lookup,
+        };
+        ensure!(
+            !gate.is_active() || gate.lookup.is_available(),
+            "Allowed countries and ASNs need a GeoIP lookup"
+        );
+        Ok(gate)
+    }
+
+    /// Returns true if the gate checks the origin of connections.
+    pub fn is_active(&self) -> bool {
+        !self.allowed_countries.is_empty() || !self.allowed_asns.is_empty()
+    }
+
+    /// Returns the reason why a connection from the address is refused or `None` if it's allowed.
+    fn refusal_reason(&self, addr: &IpAddr) -> Option<String> {
+        if !self.is_active() {
+            return None;
+        }
+        let origin = self.lookup.lookup(addr)?;
+
+        let country = origin.country.map(|country| country.to_uppercase());
+        let is_allowed = country
+            .as_ref()
+            .map_or(false, |country| self.allowed_countries.contains(country))
+            || origin
+                .asn
+                .map_or(false, |asn| self.allowed_asns.contains(&asn));
+        if is_allowed {
+            None
+        } else {
+            Some(format!(
+                "origin (country {}, ASN {}) is not allowed",
+                country.as_deref().unwrap_or("unknown"),
+                origin
+                    .asn
+                    .map(|asn| asn.to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            ))
+        }
+    }
+}
+
+impl Default for GeoGate {
+    fn default() -> Self {
+        GeoGate {
+            allowed_countries: HashSet::new(),
+            allowed_asns: HashSet::new(),
+            lookup: Box::new(NoGeoLookup),
+        }
+    }
+}
+
+/// Rate limits the log of refused connections, so a flood from a blocked range doesn't flood
+/// the log too.
+#[derive(Debug, Default)]
+struct RefusedConnectionLog {
+    last_log: Option<Instant>,
+    suppressed: usize,
+}
+
+impl RefusedConnectionLog {
+    fn record(&mut self, addr: SocketAddr, reason: &str, now: Instant) {
+        match self.last_log {
+            Some(last_log) if now.duration_since(last_log) < REFUSED_LOG_INTERVAL => {
+                self.suppressed += 1;
+            }
+            _ => {
+                warn!(
+                    "Refused connection from {}: {} ({} more refused since the last report)",
+                    addr, reason, self.suppressed
+                );
+                self.last_log = Some(now);
+                self.suppressed = 0;
+            }
+        }
+    }
+}
+
+/// Main loop for the network server. Accepts new connections until the accept channel is closed.
+/// The origins of the connections are looked up with the given GeoIP lookup.
 pub async fn run(
     global_channel: Sender<EcsMessage>,
+    accept_channel: Receiver<()>,
     map: Vec<Opcode>,
     reverse_map: HashMap<Opcode, u16>,
     config: Configuration,
+    geo_lookup: Box<dyn GeoLookup>,
 ) -> Result<()> {
-    let listen_string = format!("{}:{}", config.server.ip, config.server.game_port);
-    info!("listening on tcp://{}", listen_string);
-    let listener = TcpListener::bind(listen_string).await?;
+    validate_opcode_maps(&map, &reverse_map)?;
+    let geo_gate = GeoGate::new(
+        &config.server.allowed_countries,
+        &config.server.allowed_asns,
+        geo_lookup,
+    )?;
+
+    let addr = SocketAddr::from((config.server.ip, config.server.game_port));
+    info!("listening on tcp://{}", addr);
+    let listener = bind_listener(
+        addr,
+        config.server.listen_backlog,
+        config.server.reuse_address,
+        config.server.reuse_port,
+    )?;
 
     let arc_map = Arc::new(map);
     let arc_reverse_map = Arc::new(reverse_map);
+    let arc_settings = Arc::new(SessionSettings {
+        traced_opcodes: config.server.trace_packets.iter().cloned().collect(),
+        max_decode_failures: config.server.max_decode_failures,
+        log_bad_frames: config.server.log_bad_frames,
+        send_queue_warning_threshold: config.server.send_queue_warning_threshold,
+        min_body_lengths: config.server.min_body_lengths.clone(),
+        response_channel_capacity: config.server.response_channel_capacity,
+        channel_full_policy: config.server.channel_full_policy,
+        packet_log_levels: config.server.packet_log_levels.clone(),
+        default_packet_log_level: config.server.default_packet_log_level,
+        opcode_phases: config.server.opcode_phases.clone(),
+        out_of_phase_policy: config.server.out_of_phase_policy,
+    });
 
+    let mut refused_log = RefusedConnectionLog::default();
     loop {
-        match listener.accept().await {
-            Ok((mut socket, addr)) => {
+        let accepted = async {
+            Some(
+                accept_connection(
+                    &listener,
+                    &config.server.blocked_ip_ranges,
+                    &geo_gate,
+                    &mut refused_log,
+                )
+                .await,
+            )
+        };
+        let stopped = async {
+            let _ = accept_channel.recv().await;
+            None
+        };
+        let accepted = match select!(accepted, stopped).await {
+            Some(accepted) => accepted,
+            None => {
+                info!("Stopped accepting new connections");
+                return Ok(());
+            }
+        };
+
+        match accepted {
+            Ok(None) => {}
+            Ok(Some((mut socket, addr))) => {
                 let thread_channel = global_channel.clone();
                 let thread_opcode_map = arc_map.clone();
                 let thread_reverse_map = arc_reverse_map.clone();
+                let thread_settings = arc_settings.clone();
 
                 task::spawn(
                     async move {
@@ -41,6 +324,7 @@ pub async fn run(
                             thread_channel,
                             thread_opcode_map,
                             thread_reverse_map,
+                            thread_settings,
                         )
                         .await
                         {
@@ -49,7 +333,7 @@ pub async fn run(
                                 match session
                                     .handle_connection()
                                     .instrument(
-                                        info_span!("connection_global_world_id", connection_global_world_id = ?connection_global_world_id),
+                                        info_span!("connection_global_world_id", connection_global_world_id = ?connection_global_world_id, account_id = field::Empty),
                                     )
                                     .await
                                 {
@@ -74,3 +358,302 @@ pub async fn run(
         }
     }
 }
+
+/// Accepts the next connection. Connections from a blocked IP range or a disallowed origin are
+/// closed right away and `None` is returned for them.
+async fn accept_connection(
+    listener: &TcpListener,
+    blocked_ip_ranges: &[IpRange],
+    geo_gate: &GeoGate,
+    refused_log: &mut RefusedConnectionLog,
+) -> Result<Option<(TcpStream, SocketAddr)>> {
+    let (socket, addr) = listener.accept().await?;
+    let reason = if blocked_ip_ranges
+        .iter()
+        .any(|range| range.contains(&addr.ip()))
+    {
+        Some("address is blocked".to_string())
+    } else {
+        geo_gate.refusal_reason(&addr.ip())
+    };
+
+    if let Some(reason) = reason {
+        refused_log.record(addr, &reason, Instant::now());
+        drop(socket);
+        return Ok(None);
+    }
+    Ok(Some((socket, addr)))
+}
+
+/// Binds the listener of the game port. `TcpListener::bind` can't set the socket options and
+/// the backlog, so the socket is build by hand.
+fn bind_listener(
+    addr: SocketAddr,
+    backlog: i32,
+    reuse_address: bool,
+    reuse_port: bool,
+) -> Result<TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(reuse_address)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        warn!("SO_REUSEPORT is not supported on this platform");
+    }
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(backlog)?;
+
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from(listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::channel;
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    fn parse_ranges(ranges: &[&str]) -> Result<Vec<IpRange>> {
+        ranges
+            .iter()
+            .map(|range| IpRange::try_from(range.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_ip_range() -> Result<()> {
+        let range = IpRange::try_from("172.16.0.0/12".to_string())?;
+        assert!(range.contains(&"172.16.0.1".parse()?));
+        assert!(range.contains(&"172.31.255.255".parse()?));
+        assert!(!range.contains(&"172.32.0.0".parse()?));
+        // A dual-stack listener reports IPv4 peers as mapped IPv6 addresses.
+        assert!(range.contains(&"::ffff:172.16.0.1".parse()?));
+        assert!(!range.contains(&"::ffff:172.32.0.0".parse()?));
+        assert!(!IpRange::try_from("0.0.0.0/0".to_string())?.contains(&"::1".parse()?));
+
+        let everything = IpRange::try_from("0.0.0.0/0".to_string())?;
+        assert!(everything.contains(&"8.8.8.8".parse()?));
+
+        assert!(IpRange::try_from("172.16.0.0/12/1".to_string()).is_err());
+        assert!(IpRange::try_from("::1/129".to_string()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_blocked_ip_range() -> Result<()> {
+        task::block_on(async {
+            let listener = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), 16, true, false)?;
+            let addr = listener.local_addr()?;
+            let mut refused_log = RefusedConnectionLog::default();
+
+            // The blocked connection is closed before anything is send.
+            let blocked = parse_ranges(&["127.0.0.1/32"])?;
+            let mut client = TcpStream::connect(addr)?;
+            assert!(
+                accept_connection(&listener, &blocked, &GeoGate::default(), &mut refused_log)
+                    .await?
+                    .is_none()
+            );
+            let mut buf = [0u8; 1];
+            assert_eq!(client.read(&mut buf)?, 0);
+
+            let allowed = parse_ranges(&["10.0.0.0/8", "::1/128"])?;
+            let client = TcpStream::connect(addr)?;
+            let (_, peer_addr) =
+                accept_connection(&listener, &allowed, &GeoGate::default(), &mut refused_log)
+                    .await?
+                    .expect("Connection from an allowed range was refused");
+            assert_eq!(peer_addr, client.local_addr()?);
+            Ok(())
+        })
+    }
+
+    struct MockGeoLookup(HashMap<IpAddr, GeoOrigin>);
+
+    impl GeoLookup for MockGeoLookup {
+        fn lookup(&self, addr: &IpAddr) -> Option<GeoOrigin> {
+            self.0.get(addr).cloned()
+        }
+    }
+
+    fn mock_geo_gate(allowed_countries: &[&str], allowed_asns: &[u32]) -> Result<GeoGate> {
+        let origins = vec![
+            (
+                "127.0.0.1".parse()?,
+                GeoOrigin {
+                    country: Some("DE".to_string()),
+                    asn: Some(3320),
+                },
+            ),
+            (
+                "127.0.0.2".parse()?,
+                GeoOrigin {
+                    country: Some("US".to_string()),
+                    asn: Some(7018),
+                },
+            ),
+        ];
+        let countries: Vec<String> = allowed_countries.iter().map(|c| c.to_string()).collect();
+        GeoGate::new(
+            &countries,
+            allowed_asns,
+            Box::new(MockGeoLookup(origins.into_iter().collect())),
+        )
+    }
+
+    #[test]
+    fn test_geo_gate() -> Result<()> {
+        let gate = mock_geo_gate(&["de"], &[])?;
+        assert!(gate.refusal_reason(&"127.0.0.1".parse()?).is_none());
+        let reason = gate.refusal_reason(&"127.0.0.2".parse()?).unwrap();
+        assert_eq!(reason, "origin (country US, ASN 7018) is not allowed");
+        // Nothing is known about the address.
+        assert!(gate.refusal_reason(&"127.0.0.3".parse()?).is_none());
+
+        let gate = mock_geo_gate(&[], &[7018])?;
+        assert!(gate.refusal_reason(&"127.0.0.1".parse()?).is_some());
+        assert!(gate.refusal_reason(&"127.0.0.2".parse()?).is_none());
+
+        let gate = mock_geo_gate(&[], &[])?;
+        assert!(!gate.is_active());
+        assert!(gate.refusal_reason(&"127.0.0.2".parse()?).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_gate_needs_lookup() {
+        assert!(GeoGate::new(&["DE".to_string()], &[], Box::new(NoGeoLookup)).is_err());
+        assert!(GeoGate::new(&[], &[3320], Box::new(NoGeoLookup)).is_err());
+        assert!(!GeoGate::new(&[], &[], Box::new(NoGeoLookup))
+            .unwrap()
+            .is_active());
+    }
+
+    #[test]
+    fn test_refuse_disallowed_origin() -> Result<()> {
+        task::block_on(async {
+            let listener = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), 16, true, false)?;
+            let addr = listener.local_addr()?;
+            let gate = mock_geo_gate(&["DE"], &[])?;
+            let mut refused_log = RefusedConnectionLog::default();
+
+            // The client connects from 127.0.0.2, which the mock places in a blocked country.
+            let socket = Socket::new(Domain::ipv4(), Type::stream(), Some(Protocol::tcp()))?;
+            socket.bind(&SockAddr::from(SocketAddr::from(([127, 0, 0, 2], 0))))?;
+            socket.connect(&SockAddr::from(addr))?;
+            let mut client = socket.into_tcp_stream();
+            assert!(accept_connection(&listener, &[], &gate, &mut refused_log)
+                .await?
+                .is_none());
+            let mut buf = [0u8; 1];
+            assert_eq!(client.read(&mut buf)?, 0);
+            assert!(refused_log.last_log.is_some());
+
+            let client = TcpStream::connect(addr)?;
+            let (_, peer_addr) = accept_connection(&listener, &[], &gate, &mut refused_log)
+                .await?
+                .expect("Connection from an allowed country was refused");
+            assert_eq!(peer_addr, client.local_addr()?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_refused_connection_log_is_rate_limited() {
+        let mut refused_log = RefusedConnectionLog::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 40001));
+        let now = Instant::now();
+
+        refused_log.record(addr, "Testing", now);
+        refused_log.record(addr, "Testing", now + Duration::from_secs(1));
+        refused_log.record(addr, "Testing", now + Duration::from_secs(2));
+        assert_eq!(refused_log.last_log, Some(now));
+        assert_eq!(refused_log.suppressed, 2);
+
+        refused_log.record(addr, "Testing", now + REFUSED_LOG_INTERVAL);
+        assert_eq!(refused_log.last_log, Some(now + REFUSED_LOG_INTERVAL));
+        assert_eq!(refused_log.suppressed, 0);
+    }
+
+    #[test]
+    fn test_rebind_with_reuse_address() -> Result<()> {
+        task::block_on(async {
+            let listener = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), 16, true, false)?;
+            let addr = listener.local_addr()?;
+
+            // The server closes the connection first, which leaves the port in TIME_WAIT.
+            let mut client = TcpStream::connect(addr)?;
+            let (socket, _) = listener.accept().await?;
+            drop(socket);
+            let mut buf = [0u8; 1];
+            assert_eq!(client.read(&mut buf)?, 0);
+            drop(listener);
+
+            let listener = bind_listener(addr, 16, true, false)?;
+            assert_eq!(listener.local_addr()?, addr);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_refuse_connections_after_shutdown() -> Result<()> {
+        task::block_on(async {
+            // The configuration needs the game port upfront, so a free one is looked up first.
+            let port = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), 16, true, false)?
+                .local_addr()?
+                .port();
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let config: Configuration = serde_yaml::from_str(&format!(
+                "
+server:
+    ip: 127.0.0.1
+    web-port: 8080
+    game-port: {}
+database:
+    hostname: 127.0.0.1
+    port: 5432
+    username: almetica
+    password: almetica
+    database: almetica
+data:
+    path: /tmp
+game:
+    pvp: true
+",
+                port
+            ))?;
+
+            let (global_channel, _global_rx_channel) = channel(16);
+            let (accept_tx_channel, accept_rx_channel) = channel(1);
+            let server = task::spawn(run(
+                global_channel,
+                accept_rx_channel,
+                Vec::new(),
+                HashMap::new(),
+                config,
+            ));
+
+            // Connections are accepted while the server is running.
+            let mut attempts = 0;
+            while TcpStream::connect(addr).is_err() {
+                attempts += 1;
+                assert!(attempts < 100, "The server doesn't accept connections");
+                task::sleep(Duration::from_millis(10)).await;
+            }
+
+            // The global world closes the channel once the shutdown started.
+            drop(accept_tx_channel);
+            server.await?;
+            assert!(TcpStream::connect(addr).is_err());
+            Ok(())
+        })
+    }
+}
@@ -1,26 +1,239 @@
 /// Module that implements the network protocol used by TERA.
+pub mod framing;
 pub mod opcode;
 pub mod packet;
 pub mod serde;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod validation;
+
+pub use framing::peek_frame_header;
 
-use crate::crypt::CryptSession;
+use crate::config;
+use crate::crypt::{CryptSession, StreamObfuscation};
 use crate::ecs::message::{EcsMessage, Message, MessageTarget};
+use crate::metrics;
+use crate::model::{AccountId, UserId};
+use crate::protocol::framing::{FrameBuffer, FrameHeader, HEADER_LENGTH};
 use crate::protocol::opcode::Opcode;
+use crate::protocol::packet::SChat;
+use crate::protocol::serde::{
+    to_vec_with_min_length, Deserializer, DeserializerPool, SchemaVersion,
+};
 use crate::{AlmeticaError, Result};
+use ::serde::Deserialize;
 use anyhow::{bail, Context};
 use async_macros::select;
 use async_std::io::timeout;
 use async_std::net::TcpStream;
 use async_std::prelude::*;
-use async_std::sync::{channel, Receiver, Sender};
-use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use async_std::sync::{channel, Receiver, Sender, TrySendError};
 use rand::rngs::OsRng;
 use rand_core::RngCore;
 use shipyard::EntityId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{debug, error, info, trace, warn};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn, Span};
+
+/// Number of bytes that are read from the stream at once.
+const READ_BUFFER_LENGTH: usize = 4096;
+
+/// Notice that a client gets in place of a packet it sent outside of the valid phases.
+const OUT_OF_PHASE_NOTICE: &str = "The server ignored a request that was sent at the wrong time";
+
+/// Settings that are shared by all game sessions.
+#[derive(Clone, Debug)]
+pub struct SessionSettings {
+    /// Opcodes of the packets that are dumped with their decoded content at trace level.
+    pub traced_opcodes: HashSet<Opcode>,
+    /// Number of packets in a row that can't be decoded before the connection is dropped.
+    pub max_decode_failures: usize,
+    /// Logs the data of packets that can't be decoded as hex at debug level.
+    pub log_bad_frames: bool,
+    /// Number of queued responses above which a warning for a slow client is logged.
+    pub send_queue_warning_threshold: usize,
+    /// Minimal body length of packets. Shorter packets are padded with zeros.
+    pub min_body_lengths: HashMap<Opcode, usize>,
+    /// Number of responses the channel of a connection can hold.
+    pub response_channel_capacity: usize,
+    /// Decides which message is dropped if the channel of a connection is full.
+    pub channel_full_policy: ChannelFullPolicy,
+    /// Level at which the sent and received packets are logged, by opcode.
+    pub packet_log_levels: HashMap<Opcode, PacketLogLevel>,
+    /// Level at which packets without an entry in `packet_log_levels` are logged.
+    pub default_packet_log_level: PacketLogLevel,
+    /// Phases in which the packets are valid, by opcode. Overrides `Message::allowed_phases`.
+    pub opcode_phases: HashMap<Opcode, Vec<ConnectionPhase>>,
+    /// Decides what happens to a client that sends a packet outside of its phases.
+    pub out_of_phase_policy: OutOfPhasePolicy,
+}
+
+/// Phase of the protocol a connection is in. Every packet is only valid in some phases.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionPhase {
+    /// The client has to check its version.
+    Connected,
+    /// The client has to log in.
+    VersionChecked,
+    /// The client is logged in and manages its users.
+    Authenticated,
+    /// The client has selected a user.
+    InGame,
+}
+
+impl ConnectionPhase {
+    pub const ALL: [ConnectionPhase; 4] = [
+        ConnectionPhase::Connected,
+        ConnectionPhase::VersionChecked,
+        ConnectionPhase::Authenticated,
+        ConnectionPhase::InGame,
+    ];
+}
+
+/// Policy for packets that are sent outside of the phases they are valid in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutOfPhasePolicy {
+    /// The packet is logged and dropped.
+    Drop,
+    /// The packet is logged and the client is disconnected.
+    Disconnect,
+}
+
+/// Log level of a packet. Used to log chatty packets at a lower level than rare ones.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PacketLogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Policy for a connection that has more responses queued than the response channel capacity.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelFullPolicy {
+    /// The global world drops the new messages.
+    DropNewest,
+    /// The session drops the oldest queued packet responses. Control messages are kept.
+    DropOldest,
+    /// The session closes the connection.
+    Disconnect,
+}
+
+/// Cause of a closed connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseKind {
+    /// The client closed the socket.
+    Client,
+    /// The server dropped or shut down the connection.
+    Server,
+    /// The connection was closed because of an error.
+    Error,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        SessionSettings {
+            traced_opcodes: HashSet::new(),
+            max_decode_failures: config::default_max_decode_failures(),
+            log_bad_frames: false,
+            send_queue_warning_threshold: config::default_send_queue_warning_threshold(),
+            min_body_lengths: HashMap::new(),
+            response_channel_capacity: config::default_response_channel_capacity(),
+            channel_full_policy: config::default_channel_full_policy(),
+            packet_log_levels: HashMap::new(),
+            default_packet_log_level: config::default_packet_log_level(),
+            opcode_phases: HashMap::new(),
+            out_of_phase_policy: config::default_out_of_phase_policy(),
+        }
+    }
+}
+
+impl SessionSettings {
+    /// Log level of the packet with the given opcode.
+    fn packet_log_level(&self, opcode: Opcode) -> PacketLogLevel {
+        self.packet_log_levels
+            .get(&opcode)
+            .copied()
+            .unwrap_or(self.default_packet_log_level)
+    }
+
+    /// Number of messages the response channel of a connection is created with. The channel of
+    /// the policies that are applied by the session has room above the capacity, so the global
+    /// world doesn't drop the new messages before the session sees them.
+    fn response_channel_bound(&self) -> usize {
+        match self.channel_full_policy {
+            ChannelFullPolicy::DropNewest => self.response_channel_capacity,
+            ChannelFullPolicy::DropOldest | ChannelFullPolicy::Disconnect => {
+                self.response_channel_capacity * 2
+            }
+        }
+    }
+
+    /// Phases in which the packet with the given opcode is valid.
+    fn allowed_phases(&self, opcode: Opcode) -> &[ConnectionPhase] {
+        self.opcode_phases
+            .get(&opcode)
+            .map(Vec::as_slice)
+            .unwrap_or_else(|| Message::allowed_phases(opcode))
+    }
+}
+
+/// Tracks the number of responses that wait to be written to the client. A growing queue shows
+/// a slow client before the write timeout drops it. The depth is added to the send queue metrics.
+#[derive(Debug)]
+struct SendQueueMonitor {
+    threshold: usize,
+    /// Depth of the queue at the last response.
+    depth: usize,
+    /// Deepest queue since the session started.
+    max_depth: usize,
+    above_threshold: bool,
+}
+
+impl SendQueueMonitor {
+    fn new(threshold: usize) -> Self {
+        SendQueueMonitor {
+            threshold,
+            depth: 0,
+            max_depth: 0,
+            above_threshold: false,
+        }
+    }
+
+    /// Records the current depth. Warns once each time the depth crosses the threshold.
+    fn record(&mut self, depth: usize) {
+        metrics::record_send_queue_depth(self.depth, depth);
+        self.depth = depth;
+        self.max_depth = self.max_depth.max(depth);
+        trace!("Send queue depth: {}", depth);
+
+        if depth > self.threshold {
+            if !self.above_threshold {
+                warn!(
+                    "Client is slow. {} responses are queued (threshold {})",
+                    depth, self.threshold
+                );
+            }
+            self.above_threshold = true;
+        } else {
+            self.above_threshold = false;
+        }
+    }
+}
+
+impl Drop for SendQueueMonitor {
+    fn drop(&mut self) {
+        // The responses of a closed session are not queued anymore.
+        metrics::record_send_queue_depth(self.depth, 0);
+    }
+}
 
 enum ConnectionHandleMessage {
     Rx(usize),
@@ -31,12 +244,23 @@ enum ConnectionHandleMessage {
 pub struct GameSession<'a> {
     pub connection_global_world_id: EntityId,
     connection_local_world_id: Option<EntityId>,
-    account_id: Option<i64>,
-    user_id: Option<i32>,
+    account_id: Option<AccountId>,
+    user_id: Option<UserId>,
     stream: &'a mut TcpStream,
     cipher: CryptSession,
+    // Optional obfuscation on top of the stream cipher. Passes the data through if not set.
+    obfuscation: Option<Box<dyn StreamObfuscation>>,
     opcode_table: Arc<Vec<Opcode>>,
     reverse_opcode_table: Arc<HashMap<Opcode, u16>>,
+    settings: Arc<SessionSettings>,
+    phase: ConnectionPhase,
+    // Schema version of the packet layouts. Known after the version check of the client passed.
+    schema_version: Option<SchemaVersion>,
+    // Schema version the client sent with it's version check. Used once the check passed.
+    requested_schema_version: Option<SchemaVersion>,
+    // Number of packets in a row that couldn't be decoded
+    decode_failures: usize,
+    send_queue: SendQueueMonitor,
     // Receiving channel for the connection
     response_channel: Receiver<EcsMessage>,
     // Sending channel to the global world
@@ -45,7 +269,11 @@ pub struct GameSession<'a> {
     local_request_channel: Option<Sender<EcsMessage>>,
     write_timeout_dur: Duration,
     read_timeout_dur: Duration,
-    peek_timeout_dur: Duration,
+    idle_timeout_dur: Duration,
+    shutdown_timeout_dur: Duration,
+    deserializer_pool: DeserializerPool,
+    // Reused buffer for the data of the packets that are sent.
+    packet_buffer: Vec<u8>,
 }
 
 impl<'a> GameSession<'a> {
@@ -55,17 +283,21 @@ impl<'a> GameSession<'a> {
         global_request_channel: Sender<EcsMessage>,
         opcode_table: Arc<Vec<Opcode>>,
         reverse_opcode_table: Arc<HashMap<Opcode, u16>>,
+        settings: Arc<SessionSettings>,
     ) -> Result<GameSession<'a>> {
         // Initialize the stream cipher with the client.
         let cipher = GameSession::init_crypto(stream).await?;
 
         // Channel to receive response messages from the global world ECS.
-        let (tx_response_channel, rx_response_channel) = channel(128);
-        global_request_channel
-            .send(Box::new(Message::RegisterConnection {
+        let (tx_response_channel, rx_response_channel) = channel(settings.response_channel_bound());
+        send_to_world(
+            &global_request_channel,
+            EcsMessage::new(Message::RegisterConnection {
                 connection_channel: tx_response_channel,
-            }))
-            .await;
+                peer_addr: stream.peer_addr()?,
+            }),
+        )
+        .await?;
 
         // Wait for the global ECS to return an ID for the connection.
         let message = rx_response_channel.recv().await?;
@@ -77,6 +309,7 @@ impl<'a> GameSession<'a> {
             connection_global_world_id
         );
 
+        let send_queue = SendQueueMonitor::new(settings.send_queue_warning_threshold);
         Ok(GameSession {
             connection_global_world_id,
             connection_local_world_id: None,
@@ -84,17 +317,37 @@ impl<'a> GameSession<'a> {
             user_id: None,
             stream,
             cipher,
+            obfuscation: None,
             opcode_table,
             reverse_opcode_table,
+            settings,
+            phase: ConnectionPhase::Connected,
+            schema_version: None,
+            requested_schema_version: None,
+            decode_failures: 0,
+            send_queue,
             response_channel: rx_response_channel,
             global_request_channel,
             local_request_channel: None,
             write_timeout_dur: Duration::from_secs(15),
             read_timeout_dur: Duration::from_secs(15),
-            peek_timeout_dur: Duration::from_secs(120),
+            idle_timeout_dur: Duration::from_secs(120),
+            shutdown_timeout_dur: Duration::from_secs(5),
+            deserializer_pool: DeserializerPool::default(),
+            packet_buffer: Vec::with_capacity(1024),
         })
     }
 
+    /// Sets the obfuscation that is applied to all data that is sent and received after the key
+    /// exchange. Has to be called before `handle_connection`.
+    ///
+    /// This is an extension point for clients that add their own obfuscation. No obfuscation is
+    /// shipped, so `networkserver::run` doesn't set one and the sessions only use the stream
+    /// cipher.
+    pub fn set_obfuscation(&mut self, obfuscation: Box<dyn StreamObfuscation>) {
+        self.obfuscation = Some(obfuscation);
+    }
+
     async fn init_crypto(stream: &mut TcpStream) -> Result<CryptSession> {
         let timeout_dur = Duration::from_secs(5);
 
@@ -147,16 +400,51 @@ impl<'a> GameSession<'a> {
         }
     }
 
-    /// Handles the writing / sending on the TCP stream.
+    /// Handles the writing / sending on the TCP stream. The global world is informed with a
+    /// `RequestConnectionClosed` message once the connection is closed.
     pub async fn handle_connection(&mut self) -> Result<()> {
-        let mut header_buf = vec![0u8; 4];
-        let mut peek_buf = vec![0u8; 4];
+        let result = self.handle_stream().await;
+        let kind = match &result {
+            Ok(kind) => *kind,
+            Err(..) => CloseKind::Error,
+        };
+        debug!("Connection closed by {:?}", kind);
+        let closed = EcsMessage::new(Message::RequestConnectionClosed {
+            connection_global_world_id: self.connection_global_world_id,
+            kind,
+        });
+        // A global world that already shut down doesn't need to know.
+        if send_to_world(&self.global_request_channel, closed)
+            .await
+            .is_err()
+        {
+            debug!("Global world channel is closed. Not reporting the closed connection");
+        }
+        result.map(|_| ())
+    }
+
+    async fn handle_stream(&mut self) -> Result<CloseKind> {
+        let mut read_buf = vec![0u8; READ_BUFFER_LENGTH];
+        let mut frames = FrameBuffer::default();
 
         loop {
+            // An idle client only has to answer the pings. A started frame has to be completed
+            // in time.
+            let read_timeout_dur = if frames.is_empty() {
+                self.idle_timeout_dur
+            } else {
+                self.read_timeout_dur
+            };
+            // The pending frame data never grows above the maximal frame length.
+            let read_length = read_buf.len().min(frames.free_capacity());
+
             let rx = async {
-                let read = timeout(self.peek_timeout_dur, self.stream.peek(&mut peek_buf))
-                    .await
-                    .context("Could not peek into TCP stream")?;
+                let read = timeout(
+                    read_timeout_dur,
+                    (&*self.stream).read(&mut read_buf[..read_length]),
+                )
+                .await
+                .context("Could not read from TCP stream")?;
                 Ok::<_, anyhow::Error>(ConnectionHandleMessage::Rx(read))
             };
 
@@ -169,46 +457,146 @@ impl<'a> GameSession<'a> {
                 ConnectionHandleMessage::Rx(read) => {
                     if read == 0 {
                         // Connection was closed
-                        return Ok(());
+                        return Ok(CloseKind::Client);
                     }
-                    if read == 4 {
-                        timeout(
-                            self.read_timeout_dur,
-                            self.stream.read_exact(&mut header_buf),
-                        )
-                        .await?;
-                        self.cipher.crypt_client_data(&mut header_buf);
-                        let packet_length = LittleEndian::read_u16(&header_buf[0..2]) as usize - 4;
-                        let opcode = LittleEndian::read_u16(&header_buf[2..4]) as usize;
+                    // The stream cipher doesn't care about the frame boundaries.
+                    let data = &mut read_buf[..read];
+                    if let Some(obfuscation) = &mut self.obfuscation {
+                        obfuscation.deobfuscate(data);
+                    }
+                    self.cipher.crypt_client_data(data);
+                    frames.extend(data)?;
+
+                    while let Some(header) = frames.peek_frame()? {
+                        let opcode = header.opcode as usize;
 
                         // TODO handle the integrity bytes on some client packets (implement once need). Ignore the value, since it's broken anyhow.
                         // The header for a packet with an integrity check has 8 extra bytes. One i32 count and one i32 hash value.
 
-                        let mut data_buf = vec![0u8; packet_length];
-                        if packet_length != 0 {
-                            timeout(self.read_timeout_dur, self.stream.read_exact(&mut data_buf))
-                                .await?;
-                            self.cipher.crypt_client_data(&mut data_buf);
+                        let mut deserializer = self.deserializer_pool.acquire(header.body_length());
+                        let data_buf = deserializer.buffer_mut();
+                        frames.consume_frame(data_buf);
+                        if !data_buf.is_empty() {
                             trace!(
                                 "Received packet with opcode value {}: {:?}",
                                 opcode,
                                 data_buf
                             );
                         }
-                        if let Err(e) = self.handle_packet(opcode, data_buf).await {
+                        let result = self.handle_packet(opcode, &mut deserializer).await;
+                        self.deserializer_pool.release(deserializer);
+                        if let Err(e) = result {
+                            if let Some(AlmeticaError::WorldChannelClosed) =
+                                e.downcast_ref::<AlmeticaError>()
+                            {
+                                info!("World stopped receiving requests. Closing connection");
+                                return Ok(CloseKind::Server);
+                            }
                             self.handle_error(e)?;
                         }
                     }
                 }
                 ConnectionHandleMessage::Tx(message) => {
-                    if let Err(e) = self.handle_message(message).await {
-                        self.handle_error(e)?;
+                    // Include the message that was just taken from the queue.
+                    let depth = self.response_channel.len() + 1;
+                    self.send_queue.record(depth);
+                    let mut messages = vec![message];
+                    let overflow = depth.saturating_sub(self.settings.response_channel_capacity);
+                    if overflow > 0 {
+                        match self.settings.channel_full_policy {
+                            ChannelFullPolicy::DropNewest => {}
+                            ChannelFullPolicy::DropOldest => {
+                                self.evict_oldest_responses(&mut messages, overflow)
+                            }
+                            ChannelFullPolicy::Disconnect => {
+                                warn!(
+                                    "Response channel is full with {} messages. Closing connection",
+                                    depth
+                                );
+                                return Ok(CloseKind::Server);
+                            }
+                        }
+                    }
+                    let mut messages = messages.into_iter();
+                    while let Some(message) = messages.next() {
+                        if let Message::ShutdownConnection { .. } = &*message {
+                            debug!("Received shutdown connection message");
+                            self.flush_pending_messages(messages.collect()).await?;
+                            return Ok(CloseKind::Server);
+                        }
+                        if let Err(e) = self.handle_message(message).await {
+                            if let Some(AlmeticaError::ConnectionClosed) =
+                                e.downcast_ref::<AlmeticaError>()
+                            {
+                                return Ok(CloseKind::Server);
+                            }
+                            bail!(e);
+                        }
                     }
                 }
             };
         }
     }
 
+    /// Takes all queued messages out of the response channel and drops the `count` oldest
+    /// packet responses among them and the given messages. The kept messages are appended to the
+    /// given messages in order.
+    fn evict_oldest_responses(&self, messages: &mut Vec<EcsMessage>, count: usize) {
+        while let Ok(message) = self.response_channel.try_recv() {
+            messages.push(message);
+        }
+        let mut evicted = 0;
+        messages.retain(|message| {
+            if evicted < count && is_evictable(message) {
+                evicted += 1;
+                false
+            } else {
+                true
+            }
+        });
+        warn!(
+            "Response channel is full. Dropped the {} oldest packet responses",
+            evicted
+        );
+    }
+
+    /// Writes out the given messages and all messages that are still queued for the connection.
+    /// No more packets are read from the client at this point. If the messages can't be written
+    /// before the shutdown deadline, the connection is closed anyhow.
+    async fn flush_pending_messages(&mut self, pending: Vec<EcsMessage>) -> Result<()> {
+        let deadline = Instant::now() + self.shutdown_timeout_dur;
+        let mut pending = pending.into_iter();
+        loop {
+            let message = match pending.next() {
+                Some(message) => message,
+                None => match self.response_channel.try_recv() {
+                    Ok(message) => message,
+                    Err(..) => break,
+                },
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(
+                    "Couldn't flush pending messages in {:?}. Force closing connection",
+                    self.shutdown_timeout_dur
+                );
+                return Ok(());
+            }
+            match async_std::future::timeout(deadline - now, self.handle_message(message)).await {
+                Ok(Ok(..)) => {}
+                Ok(Err(e)) => return self.handle_error(e),
+                Err(..) => {
+                    warn!(
+                        "Couldn't flush pending messages in {:?}. Force closing connection",
+                        self.shutdown_timeout_dur
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn handle_error(&self, e: anyhow::Error) -> Result<()> {
         match e.downcast_ref::<AlmeticaError>() {
             Some(AlmeticaError::ConnectionClosed { .. }) => Ok(()),
@@ -220,19 +608,45 @@ impl<'a> GameSession<'a> {
 
     /// Handles the incoming messages from the global or local ECS.
     async fn handle_message(&mut self, message: EcsMessage) -> Result<()> {
+        // The messages of a batch are written out without reading the channel in between.
+        if let Message::ResponseBatch { messages, .. } = &*message {
+            debug!("Sending batch of {} messages", messages.len());
+            for message in messages {
+                self.handle_single_message(message).await?;
+            }
+            return Ok(());
+        }
+        self.handle_single_message(&message).await
+    }
+
+    async fn handle_single_message(&mut self, message: &EcsMessage) -> Result<()> {
         // Handle special messages
-        match &*message {
+        match &**message {
             Message::DropConnection { .. } => {
                 debug!("Received drop connection message");
                 bail!(AlmeticaError::ConnectionClosed);
             }
-            Message::ResponseLoginArbiter { account_id, .. } => {
+            Message::ResponseLoginArbiter {
+                account_id, packet, ..
+            } => {
                 debug!("Connection is authenticated with account ID {}", account_id);
                 self.account_id = Some(*account_id);
+                // The connection span was created with an empty account field.
+                Span::current().record("account_id", &account_id.0);
+                if packet.success {
+                    self.phase = ConnectionPhase::Authenticated;
+                }
+            }
+            Message::ResponseCheckVersion { packet, .. } => {
+                if packet.ok {
+                    self.schema_version = self.requested_schema_version.take();
+                    debug!("Connection uses schema version {:?}", self.schema_version);
+                }
             }
             Message::ResponseLogin { user_id, .. } => {
                 debug!("Connection is authenticated with user ID {}", user_id);
                 self.user_id = Some(*user_id);
+                self.phase = ConnectionPhase::InGame;
             }
             // TODO send the RegisterLocalWorld message somewhere
             Message::RegisterLocalWorld {
@@ -251,43 +665,78 @@ impl<'a> GameSession<'a> {
         }
 
         // Send out packet messages to the client.
-        match message.data()? {
-            Some(data) => match message.opcode() {
+        let min_length = message
+            .opcode()
+            .map(|opcode| self.min_body_length(opcode))
+            .unwrap_or(0);
+        // The buffer is taken out of the session while it's written, since sending needs the
+        // session mutably.
+        let mut data = mem::take(&mut self.packet_buffer);
+        if message.write_data_into(&mut data, min_length, self.schema_version)? {
+            match message.opcode() {
                 Some(opcode) => {
-                    debug!("Sending packet {:?}", opcode);
+                    log_packet(self.settings.packet_log_level(opcode), "Sending", opcode);
                     trace!("Packet data: {:?}", data);
-                    self.send_packet(opcode, data).await?;
+                    trace_packet(&self.settings.traced_opcodes, "Sending", opcode, message);
+                    self.send_packet(opcode, &data).await?;
                 }
                 None => {
                     error!("Can't find opcode in message {:?}", message);
                 }
-            },
-            None => {
-                error!("Can't find data in message {:?}", message);
             }
+        } else {
+            error!("Can't find data in message {:?}", message);
         }
+        self.packet_buffer = data;
 
         Ok(())
     }
 
-    /// Send packet to client.
-    async fn send_packet(&mut self, opcode: Opcode, mut data: Vec<u8>) -> Result<()> {
+    /// Sends a packet directly to the client without routing it through the ECS. Only meant for
+    /// protocol level responses of the session (like nacks). Gameplay responses are always sent
+    /// by the ECS.
+    pub async fn send_out_of_band<T: ::serde::Serialize>(
+        &mut self,
+        opcode: Opcode,
+        packet: &T,
+    ) -> Result<()> {
+        debug!("Sending out of band packet {:?}", opcode);
+        let data = to_vec_with_min_length(packet, self.min_body_length(opcode))
+            .context("Can't serialize out of band packet")?;
+        self.send_packet(opcode, &data).await
+    }
+
+    /// Minimal body length of the packet with the given opcode.
+    fn min_body_length(&self, opcode: Opcode) -> usize {
+        self.settings
+            .min_body_lengths
+            .get(&opcode)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Send packet to client. This is the write half of the session, which is used by the
+    /// messages of the ECS and the out of band packets.
+    async fn send_packet(&mut self, opcode: Opcode, data: &[u8]) -> Result<()> {
         match self.reverse_opcode_table.get(&opcode) {
             Some(opcode_value) => {
-                let len = data.len() + 4;
-                if len > std::u16::MAX as usize {
-                    error!(
-                        "Length of packet {:?} too big for u16 length ({}). Dropping packet.",
-                        opcode, len
-                    );
-                } else {
-                    let mut buffer = Vec::with_capacity(4 + data.len());
-                    WriteBytesExt::write_u16::<LittleEndian>(&mut buffer, len as u16)?;
-                    WriteBytesExt::write_u16::<LittleEndian>(&mut buffer, *opcode_value)?;
-                    buffer.append(&mut data);
+                if let Some(header) = FrameHeader::for_body(*opcode_value, data.len()) {
+                    let mut buffer = vec![0u8; HEADER_LENGTH];
+                    buffer.reserve(data.len());
+                    header.write(&mut buffer);
+                    buffer.extend_from_slice(data);
 
                     self.cipher.crypt_server_data(buffer.as_mut_slice());
+                    if let Some(obfuscation) = &mut self.obfuscation {
+                        obfuscation.obfuscate(buffer.as_mut_slice());
+                    }
                     timeout(self.write_timeout_dur, self.stream.write_all(&buffer)).await?;
+                } else {
+                    error!(
+                        "Length of packet {:?} too big for u16 length ({}). Dropping packet.",
+                        opcode,
+                        data.len() + HEADER_LENGTH
+                    );
                 }
             }
             None => {
@@ -300,31 +749,83 @@ impl<'a> GameSession<'a> {
         Ok(())
     }
 
-    /// Decodes a packet from the given `Vec<u8>` and sends it to game server logic.
-    async fn handle_packet(&mut self, opcode: usize, packet_data: Vec<u8>) -> Result<()> {
+    /// Decodes a packet with the given deserializer and sends it to game server logic.
+    async fn handle_packet(
+        &mut self,
+        opcode: usize,
+        deserializer: &mut Deserializer,
+    ) -> Result<()> {
         let opcode_type = self.opcode_table[opcode];
         match opcode_type {
             Opcode::UNKNOWN => {
                 warn!("Unmapped and unhandled packet with opcode value {}", opcode);
             }
             _ => {
-                match Message::new_from_packet(
+                // Packets outside of their phases are not even decoded.
+                if !self
+                    .settings
+                    .allowed_phases(opcode_type)
+                    .contains(&self.phase)
+                {
+                    match self.settings.out_of_phase_policy {
+                        OutOfPhasePolicy::Drop => {
+                            warn!(
+                                "Dropping packet {:?} that was send in phase {:?}",
+                                opcode_type, self.phase
+                            );
+                            self.send_out_of_band(
+                                Opcode::S_CHAT,
+                                &SChat::notice(OUT_OF_PHASE_NOTICE.to_string()),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                        OutOfPhasePolicy::Disconnect => bail!(
+                            "Client did try to send packet {:?} in phase {:?}",
+                            opcode_type,
+                            self.phase
+                        ),
+                    }
+                }
+
+                deserializer.set_schema_version(self.schema_version);
+                match Message::new_from_deserializer(
                     self.connection_global_world_id,
                     self.connection_local_world_id,
                     self.account_id,
                     self.user_id,
                     opcode_type,
-                    packet_data,
+                    deserializer,
                 ) {
                     Ok(message) => {
-                        debug!("Received valid packet {:?}", opcode_type);
+                        log_packet(
+                            self.settings.packet_log_level(opcode_type),
+                            "Received valid",
+                            opcode_type,
+                        );
+                        self.decode_failures = 0;
+                        if let Message::RequestCheckVersion { packet, .. } = &message {
+                            // A failed version check drops the connection.
+                            self.phase = ConnectionPhase::VersionChecked;
+                            self.requested_schema_version = packet.schema_version();
+                        }
+                        trace_packet(
+                            &self.settings.traced_opcodes,
+                            "Received",
+                            opcode_type,
+                            &message,
+                        );
                         match message.target() {
                             MessageTarget::Global => {
-                                self.global_request_channel.send(Box::new(message)).await;
+                                send_to_world(
+                                    &self.global_request_channel,
+                                    EcsMessage::new(message),
+                                )
+                                .await?;
                             }
                             MessageTarget::Local => {
                                 if let Some(channel) = &self.local_request_channel {
-                                    channel.send(Box::new(message)).await;
+                                    send_to_world(channel, EcsMessage::new(message)).await?;
                                 } else {
                                     error!("Local world channel is not set. Dropping {}", message);
                                 }
@@ -350,10 +851,23 @@ impl<'a> GameSession<'a> {
                         Some(AlmeticaError::UnauthorizedPacket) => {
                             bail!("Unauthorized client did try to send a packet that needs authorization");
                         }
-                        Some(..) | None => error!(
-                            "Can't create message from valid packet {:?}: {:?}",
-                            opcode_type, e
-                        ),
+                        Some(..) | None => {
+                            error!(
+                                "Can't create message from valid packet {:?}: {:?}",
+                                opcode_type, e
+                            );
+                            if self.settings.log_bad_frames {
+                                log_bad_frame(opcode_type, opcode, deserializer.buffer());
+                            }
+                            // A client that keeps sending broken packets is out of sync or malicious.
+                            self.decode_failures += 1;
+                            if self.decode_failures >= self.settings.max_decode_failures {
+                                bail!(
+                                    "Client sent {} packets in a row that couldn't be decoded",
+                                    self.decode_failures
+                                );
+                            }
+                        }
                     },
                 }
             }
@@ -362,18 +876,90 @@ impl<'a> GameSession<'a> {
     }
 }
 
+/// Sends a request into the channel of a world. Waits while the channel is full. Fails with
+/// `AlmeticaError::WorldChannelClosed` once the world dropped it's receiver, which happens while
+/// the world shuts down.
+async fn send_to_world(channel: &Sender<EcsMessage>, message: EcsMessage) -> Result<()> {
+    match channel.try_send(message) {
+        Ok(..) => Ok(()),
+        Err(TrySendError::Full(message)) => {
+            channel.send(message).await;
+            Ok(())
+        }
+        Err(TrySendError::Disconnected(..)) => bail!(AlmeticaError::WorldChannelClosed),
+    }
+}
+
+/// Returns true if the message is a plain packet response, which can be dropped when the client
+/// doesn't keep up. Control messages, batches and the responses that move the connection into
+/// another phase are always kept.
+fn is_evictable(message: &Message) -> bool {
+    match message {
+        Message::ResponseCheckVersion { .. }
+        | Message::ResponseLoginArbiter { .. }
+        | Message::ResponseLogin { .. } => false,
+        _ => message.opcode().is_some(),
+    }
+}
+
+/// Dumps the data of a packet that couldn't be decoded, so that the failure can be reproduced.
+fn log_bad_frame(opcode: Opcode, opcode_value: usize, data: &[u8]) {
+    debug!(
+        "Data of bad packet {:?} with opcode value {}: {}",
+        opcode,
+        opcode_value,
+        hex::encode(data)
+    );
+}
+
+/// Logs that a packet was sent or received. The level of a tracing event has to be known at
+/// compile time, so every level needs it's own event.
+fn log_packet(level: PacketLogLevel, direction: &str, opcode: Opcode) {
+    match level {
+        PacketLogLevel::Trace => trace!("{} packet {:?}", direction, opcode),
+        PacketLogLevel::Debug => debug!("{} packet {:?}", direction, opcode),
+        PacketLogLevel::Info => info!("{} packet {:?}", direction, opcode),
+        PacketLogLevel::Warn => warn!("{} packet {:?}", direction, opcode),
+        PacketLogLevel::Error => error!("{} packet {:?}", direction, opcode),
+    }
+}
+
+/// Dumps the decoded content of a packet at trace level if it's opcode is traced. Only selected
+/// opcodes are dumped, since the dumps of all packets would flood the log.
+fn trace_packet(
+    traced_opcodes: &HashSet<Opcode>,
+    direction: &str,
+    opcode: Opcode,
+    message: &Message,
+) {
+    if traced_opcodes.contains(&opcode) {
+        trace!("{} packet {:?}: {:#?}", direction, opcode, message);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::dataloader::*;
     use crate::ecs::component::GlobalConnection;
-    use crate::ecs::message::Message::{RegisterConnection, RegisterConnectionFinished};
+    use crate::ecs::message::Message::{
+        DropConnection, RegisterConnection, RegisterConnectionFinished, RegisterLocalWorld,
+        RequestCheckVersion, RequestConnectionClosed, ResponseBatch, ResponseCanCreateUser,
+        ResponseCheckVersion, ShutdownConnection,
+    };
+    use crate::ecs::system::send_message;
     use crate::protocol::opcode::Opcode;
+    use crate::protocol::packet::{
+        CCheckVersion, CCheckVersionEntry, CGetUserList, SCanCreateUser, SCheckVersion,
+    };
+    use crate::protocol::serde::to_vec;
+    use crate::protocol::test_support::frame_packet;
     use crate::protocol::GameSession;
+    use crate::test_support::capture_logs;
     use crate::Result;
     use async_std::future::timeout;
     use async_std::net::{TcpListener, TcpStream};
-    use async_std::sync::channel;
+    use async_std::sync::{channel, Receiver, Sender};
     use async_std::task::{self, JoinHandle};
     use byteorder::{ByteOrder, LittleEndian};
     use shipyard::EntityId;
@@ -388,6 +974,10 @@ mod tests {
             "
         C_CHECK_VERSION: 1
         S_CHECK_VERSION: 2
+        C_CHECK_USERNAME: 3
+        C_GET_USER_LIST: 4
+        S_CAN_CREATE_USER: 5
+        S_CHAT: 6
         "
             .as_bytes(),
         )
@@ -414,58 +1004,184 @@ mod tests {
                         is_authenticated: false,
                         last_pong: Instant::now(),
                         waiting_for_pong: false,
+                        peer_addr: "127.0.0.1:10001".parse().unwrap(),
+                        connected_since: Instant::now(),
+                        schema_version: None,
                     },
                 )
             },
         )
     }
 
-    async fn spawn_dummy_server() -> Result<(SocketAddr, JoinHandle<()>, JoinHandle<()>)> {
+    /// Spawns a game session with the given settings for the first client that connects.
+    /// `prepare` is called with the session before it handles the connection. Returns the address
+    /// of the session, the reverse opcode mapping and the channel of the global world.
+    async fn spawn_session<F>(
+        settings: SessionSettings,
+        prepare: F,
+    ) -> Result<(
+        SocketAddr,
+        HashMap<Opcode, u16>,
+        Receiver<EcsMessage>,
+        JoinHandle<Result<()>>,
+    )>
+    where
+        F: FnOnce(&mut GameSession<'_>) + Send + 'static,
+    {
+        spawn_session_when_ready(settings, prepare, None).await
+    }
+
+    /// Like `spawn_session`, but the session only starts to handle the connection once `ready`
+    /// receives a message. Lets the world queue responses before the session reads them.
+    async fn spawn_session_when_ready<F>(
+        settings: SessionSettings,
+        prepare: F,
+        ready: Option<Receiver<()>>,
+    ) -> Result<(
+        SocketAddr,
+        HashMap<Opcode, u16>,
+        Receiver<EcsMessage>,
+        JoinHandle<Result<()>>,
+    )>
+    where
+        F: FnOnce(&mut GameSession<'_>) + Send + 'static,
+    {
         let srv = TcpListener::bind("127.0.0.1:0").await?;
         let addr = srv.local_addr()?;
         let (opcode_mapping, reverse_opcode_mapping) = get_opcode_tables().await?;
         let (tx_channel, rx_channel) = channel(1024);
 
-        // TCP server
+        let reverse_map = reverse_opcode_mapping.clone();
         let tcp_join = task::spawn(async move {
-            let (mut socket, _) = srv.accept().await.unwrap();
-            let _session = GameSession::new(
+            let (mut socket, _) = srv.accept().await?;
+            let mut session = GameSession::new(
                 &mut socket,
                 tx_channel,
                 Arc::new(opcode_mapping),
-                Arc::new(reverse_opcode_mapping),
+                Arc::new(reverse_map),
+                Arc::new(settings),
             )
-            .await
-            .unwrap();
+            .await?;
+            prepare(&mut session);
+            if let Some(ready) = ready {
+                ready.recv().await?;
+            }
+            session.handle_connection().await
         });
 
-        // World loop mock
-        let world_join = task::spawn(async move {
+        Ok((addr, reverse_opcode_mapping, rx_channel, tcp_join))
+    }
+
+    /// World loop mock. Registers the connection and sends it the messages that `responses`
+    /// returns for the ID and the channel of the connection. Returns all other messages it
+    /// received until the connection was closed.
+    fn spawn_world_mock<F>(
+        rx_channel: Receiver<EcsMessage>,
+        responses: F,
+    ) -> JoinHandle<Vec<EcsMessage>>
+    where
+        F: FnOnce(EntityId, Sender<EcsMessage>) -> Vec<EcsMessage> + Send + 'static,
+    {
+        task::spawn(async move {
             let connection_global_world_id = get_new_entity_with_connection_component();
-            loop {
-                task::yield_now().await;
-                if let Ok(message) = rx_channel.recv().await {
-                    match &*message {
-                        RegisterConnection { connection_channel } => {
-                            let tx = connection_channel.clone();
-                            tx.send(Box::new(RegisterConnectionFinished {
+            let mut responses = Some(responses);
+            let mut received = Vec::new();
+            while let Ok(message) = rx_channel.recv().await {
+                match &*message {
+                    RegisterConnection {
+                        connection_channel, ..
+                    } => {
+                        connection_channel
+                            .send(EcsMessage::new(RegisterConnectionFinished {
                                 connection_global_world_id,
                             }))
                             .await;
-                            break;
+                        if let Some(responses) = responses.take() {
+                            let responses =
+                                responses(connection_global_world_id, connection_channel.clone());
+                            for response in responses {
+                                connection_channel.send(response).await;
+                            }
                         }
-                        _ => break,
                     }
+                    RequestConnectionClosed { .. } => {
+                        received.push(message);
+                        break;
+                    }
+                    _ => received.push(message),
                 }
             }
-        });
+            received
+        })
+    }
 
-        Ok((addr, tcp_join, world_join))
+    /// Returns the kind of the closed connection. Closing the connection is the last message
+    /// that a session sends to the world.
+    fn close_kind(received: &[EcsMessage]) -> CloseKind {
+        match received.last().map(|message| &**message) {
+            Some(RequestConnectionClosed { kind, .. }) => *kind,
+            _ => panic!("The connection wasn't closed"),
+        }
+    }
+
+    /// Connects to a game session and exchanges the keys.
+    async fn connect_encrypted_client(addr: &SocketAddr) -> Result<(TcpStream, CryptSession)> {
+        let mut stream = TcpStream::connect(addr).await?;
+
+        let mut hello_buffer = vec![0u8; 4];
+        stream.read_exact(&mut hello_buffer).await?;
+
+        let mut client_key1 = vec![0u8; 128];
+        let mut client_key2 = vec![0u8; 128];
+        let mut server_key1 = vec![0u8; 128];
+        let mut server_key2 = vec![0u8; 128];
+        OsRng.fill_bytes(&mut client_key1);
+        OsRng.fill_bytes(&mut client_key2);
+
+        stream.write_all(&client_key1).await?;
+        stream.read_exact(&mut server_key1).await?;
+        stream.write_all(&client_key2).await?;
+        stream.read_exact(&mut server_key2).await?;
+
+        let cipher = CryptSession::new([client_key1, client_key2], [server_key1, server_key2]);
+        Ok((stream, cipher))
+    }
+
+    async fn read_packet(
+        stream: &mut TcpStream,
+        cipher: &mut CryptSession,
+    ) -> Result<(u16, Vec<u8>)> {
+        let mut header_buf = vec![0u8; HEADER_LENGTH];
+        timeout(Duration::from_secs(1), stream.read_exact(&mut header_buf)).await??;
+        cipher.crypt_server_data(&mut header_buf);
+        let header = FrameHeader::read(&header_buf);
+
+        let mut data_buf = vec![0u8; header.body_length()];
+        timeout(Duration::from_secs(1), stream.read_exact(&mut data_buf)).await??;
+        cipher.crypt_server_data(&mut data_buf);
+        Ok((header.opcode, data_buf))
+    }
+
+    /// Waits until the client closed the connection.
+    async fn assert_closed(stream: &mut TcpStream) -> Result<()> {
+        let mut rest = Vec::new();
+        let read = timeout(Duration::from_secs(1), stream.read_to_end(&mut rest)).await??;
+        assert_eq!(read, 0);
+        Ok(())
+    }
+
+    fn check_version(connection_global_world_id: EntityId, ok: bool) -> EcsMessage {
+        EcsMessage::new(ResponseCheckVersion {
+            connection_global_world_id,
+            packet: SCheckVersion { ok },
+        })
     }
 
     #[async_std::test]
     async fn test_gamesession_creation() -> Result<()> {
-        let (addr, tcp_join, world_join) = spawn_dummy_server().await?;
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
         let mut stream = TcpStream::connect(&addr).await?;
 
         // hello stage
@@ -520,8 +1236,651 @@ mod tests {
             panic!("{}", e);
         }
 
-        tcp_join.await;
+        drop(stream);
+        tcp_join.await?;
         world_join.await;
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_gamesession_flushes_responses_on_shutdown() -> Result<()> {
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        // Queues a response and then shuts the connection down.
+        let world_join = spawn_world_mock(rx_channel, |connection_global_world_id, _| {
+            vec![
+                check_version(connection_global_world_id, true),
+                EcsMessage::new(ShutdownConnection {
+                    connection_global_world_id,
+                }),
+            ]
+        });
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // The queued response needs to arrive before the connection is closed.
+        assert_eq!(read_packet(&mut stream, &mut cipher).await?, (2, vec![1]));
+        assert_closed(&mut stream).await?;
+
+        tcp_join.await?;
+        assert_eq!(close_kind(&world_join.await), CloseKind::Server);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_decodes_frame_split_into_single_bytes() -> Result<()> {
+        let (addr, reverse_map, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+
+        let packet = CCheckVersion {
+            version: vec![
+                CCheckVersionEntry {
+                    index: 0,
+                    value: 366_222,
+                },
+                CCheckVersionEntry {
+                    index: 1,
+                    value: 365_535,
+                },
+            ],
+        };
+        let mut frame = frame_packet(Opcode::C_CHECK_VERSION, &packet, &reverse_map);
+
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+        stream.set_nodelay(true)?;
+        cipher.crypt_client_data(&mut frame);
+
+        // Every byte arrives with its own read.
+        for byte in frame.iter() {
+            stream.write_all(&[*byte]).await?;
+            task::sleep(Duration::from_millis(2)).await;
+        }
+        drop(stream);
+
+        tcp_join.await?;
+        let received = timeout(Duration::from_secs(1), world_join).await?;
+        assert_eq!(received.len(), 2);
+        match &*received[0] {
+            RequestCheckVersion {
+                packet: decoded, ..
+            } => assert_eq!(*decoded, packet),
+            m => panic!("Unexpected message {}", m),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_reports_client_close() -> Result<()> {
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+
+        let (stream, _) = connect_encrypted_client(&addr).await?;
+        drop(stream);
+
+        let received = timeout(Duration::from_secs(1), world_join).await?;
+        assert_eq!(close_kind(&received), CloseKind::Client);
+        tcp_join.await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_reports_server_drop() -> Result<()> {
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |connection_global_world_id, _| {
+            vec![EcsMessage::new(DropConnection {
+                connection_global_world_id,
+            })]
+        });
+
+        let (mut stream, _) = connect_encrypted_client(&addr).await?;
+
+        let received = timeout(Duration::from_secs(1), world_join).await?;
+        assert_eq!(close_kind(&received), CloseKind::Server);
+        tcp_join.await?;
+        assert_closed(&mut stream).await
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_delivers_global_responses_after_local_world_change() -> Result<()> {
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        // Moves the connection into a local world and then answers from the global world.
+        let world_join = spawn_world_mock(rx_channel, |connection_global_world_id, _| {
+            let (local_world_channel, _) = channel(1024);
+            vec![
+                EcsMessage::new(RegisterLocalWorld {
+                    connection_local_world_id: get_new_entity_with_connection_component(),
+                    local_world_channel,
+                }),
+                check_version(connection_global_world_id, true),
+                EcsMessage::new(ShutdownConnection {
+                    connection_global_world_id,
+                }),
+            ]
+        });
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // The response of the global world still reaches the client.
+        assert_eq!(read_packet(&mut stream, &mut cipher).await?, (2, vec![1]));
+
+        tcp_join.await?;
+        world_join.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_sends_batch_contiguously() -> Result<()> {
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        // Sends batches of three packets while a second producer races them with single packets.
+        let world_join = spawn_world_mock(
+            rx_channel,
+            |connection_global_world_id, connection_channel| {
+                task::spawn(async move {
+                    for _ in 0..20 {
+                        connection_channel
+                            .send(check_version(connection_global_world_id, false))
+                            .await;
+                        task::yield_now().await;
+                    }
+                });
+                (0..10)
+                    .map(|_| {
+                        EcsMessage::new(ResponseBatch {
+                            connection_global_world_id,
+                            messages: vec![
+                                check_version(connection_global_world_id, true),
+                                check_version(connection_global_world_id, true),
+                                check_version(connection_global_world_id, true),
+                            ],
+                        })
+                    })
+                    .collect()
+            },
+        );
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        let mut packets = Vec::new();
+        for _ in 0..50 {
+            packets.push(read_packet(&mut stream, &mut cipher).await?);
+        }
+        drop(stream);
+
+        // Single packets only show up between whole batches.
+        let batched = packets.iter().filter(|packet| packet.1 == vec![1]).count();
+        assert_eq!(batched, 30);
+        for run in packets.split(|packet| packet.1 == vec![0]) {
+            assert_eq!(run.len() % 3, 0);
+        }
+
+        tcp_join.await?;
+        world_join.await;
+        Ok(())
+    }
+
+    /// Spawns a session with a response channel capacity of one. The world queues the responses
+    /// like the systems do before the session reads any of them.
+    async fn spawn_full_channel_session<F>(
+        channel_full_policy: ChannelFullPolicy,
+        responses: F,
+    ) -> Result<(
+        SocketAddr,
+        JoinHandle<Result<()>>,
+        JoinHandle<Vec<EcsMessage>>,
+    )>
+    where
+        F: FnOnce(EntityId) -> Vec<EcsMessage> + Send + 'static,
+    {
+        let settings = SessionSettings {
+            response_channel_capacity: 1,
+            channel_full_policy,
+            ..Default::default()
+        };
+        let (ready_tx, ready_rx) = channel(1);
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session_when_ready(settings, |_| {}, Some(ready_rx)).await?;
+        let world_join = spawn_world_mock(
+            rx_channel,
+            move |connection_global_world_id, connection_channel| {
+                for response in responses(connection_global_world_id) {
+                    send_message(response, &connection_channel);
+                }
+                ready_tx.try_send(()).unwrap();
+                vec![]
+            },
+        );
+        Ok((addr, tcp_join, world_join))
+    }
+
+    fn can_create_user(connection_global_world_id: EntityId, ok: bool) -> EcsMessage {
+        EcsMessage::new(ResponseCanCreateUser {
+            connection_global_world_id,
+            packet: SCanCreateUser { ok },
+        })
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_full_channel_drops_newest() -> Result<()> {
+        let (addr, tcp_join, world_join) =
+            spawn_full_channel_session(ChannelFullPolicy::DropNewest, |id| {
+                vec![can_create_user(id, false), can_create_user(id, true)]
+            })
+            .await?;
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // The response that didn't fit into the channel is never sent.
+        assert_eq!(read_packet(&mut stream, &mut cipher).await?, (5, vec![0]));
+        let mut rest = [0u8; 1];
+        assert!(timeout(Duration::from_millis(100), stream.read(&mut rest))
+            .await
+            .is_err());
+        drop(stream);
+
+        tcp_join.await?;
+        assert_eq!(close_kind(&world_join.await), CloseKind::Client);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_full_channel_drops_oldest() -> Result<()> {
+        let (addr, tcp_join, world_join) =
+            spawn_full_channel_session(ChannelFullPolicy::DropOldest, |id| {
+                vec![can_create_user(id, false), can_create_user(id, true)]
+            })
+            .await?;
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // Only the newest response is sent.
+        assert_eq!(read_packet(&mut stream, &mut cipher).await?, (5, vec![1]));
+        drop(stream);
+
+        tcp_join.await?;
+        assert_eq!(close_kind(&world_join.await), CloseKind::Client);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_full_channel_keeps_control_messages() -> Result<()> {
+        let (addr, tcp_join, world_join) =
+            spawn_full_channel_session(ChannelFullPolicy::DropOldest, |id| {
+                vec![
+                    EcsMessage::new(DropConnection {
+                        connection_global_world_id: id,
+                    }),
+                    can_create_user(id, true),
+                ]
+            })
+            .await?;
+        let (mut stream, _) = connect_encrypted_client(&addr).await?;
+
+        // The response is dropped in place of the older drop connection message.
+        assert_closed(&mut stream).await?;
+
+        tcp_join.await?;
+        assert_eq!(close_kind(&world_join.await), CloseKind::Server);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_full_channel_disconnects() -> Result<()> {
+        let (addr, tcp_join, world_join) =
+            spawn_full_channel_session(ChannelFullPolicy::Disconnect, |id| {
+                vec![check_version(id, true), check_version(id, true)]
+            })
+            .await?;
+        let (mut stream, _) = connect_encrypted_client(&addr).await?;
+
+        // No queued response is sent.
+        assert_closed(&mut stream).await?;
+
+        tcp_join.await?;
+        assert_eq!(close_kind(&world_join.await), CloseKind::Server);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_sends_out_of_band() -> Result<()> {
+        let srv = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = srv.local_addr()?;
+        let (opcode_mapping, reverse_opcode_mapping) = get_opcode_tables().await?;
+        let (tx_channel, rx_channel) = channel(1024);
+
+        // TCP server that sends a packet without the ECS.
+        let tcp_join = task::spawn(async move {
+            let (mut socket, _) = srv.accept().await.unwrap();
+            let mut session = GameSession::new(
+                &mut socket,
+                tx_channel,
+                Arc::new(opcode_mapping),
+                Arc::new(reverse_opcode_mapping),
+                Arc::new(SessionSettings::default()),
+            )
+            .await
+            .unwrap();
+            session
+                .send_out_of_band(Opcode::S_CHECK_VERSION, &SCheckVersion { ok: false })
+                .await
+                .unwrap();
+        });
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        assert_eq!(read_packet(&mut stream, &mut cipher).await?, (2, vec![0]));
+
+        tcp_join.await;
+        // The packet didn't pass through the ECS.
+        assert!(world_join.await.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_rejects_gameplay_packets_before_authentication() -> Result<()> {
+        let (addr, _, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // C_CHECK_USERNAME needs an authenticated account.
+        let mut frame = vec![0u8; HEADER_LENGTH];
+        FrameHeader::for_body(3, 0).unwrap().write(&mut frame);
+        cipher.crypt_client_data(&mut frame);
+        stream.write_all(&frame).await?;
+
+        assert_closed(&mut stream).await?;
+        assert!(tcp_join.await.is_err());
+        // The packet never reached the ECS.
+        let received = world_join.await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(close_kind(&received), CloseKind::Error);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_disconnects_out_of_phase_packet() -> Result<()> {
+        let settings = SessionSettings {
+            out_of_phase_policy: OutOfPhasePolicy::Disconnect,
+            ..Default::default()
+        };
+        let (addr, reverse_map, rx_channel, tcp_join) = spawn_session(settings, |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // The user list is only valid after the login.
+        let mut frame = frame_packet(Opcode::C_GET_USER_LIST, &CGetUserList {}, &reverse_map);
+        cipher.crypt_client_data(&mut frame);
+        stream.write_all(&frame).await?;
+
+        assert_closed(&mut stream).await?;
+        assert!(tcp_join.await.is_err());
+        let received = world_join.await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(close_kind(&received), CloseKind::Error);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_drops_out_of_phase_packet() -> Result<()> {
+        let settings = SessionSettings {
+            out_of_phase_policy: OutOfPhasePolicy::Drop,
+            ..Default::default()
+        };
+        let (addr, reverse_map, rx_channel, tcp_join) = spawn_session(settings, |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        let mut frame = frame_packet(Opcode::C_GET_USER_LIST, &CGetUserList {}, &reverse_map);
+        frame.append(&mut frame_packet(
+            Opcode::C_CHECK_VERSION,
+            &CCheckVersion::default(),
+            &reverse_map,
+        ));
+        cipher.crypt_client_data(&mut frame);
+        stream.write_all(&frame).await?;
+
+        // The session itself tells the client that the user list was dropped.
+        let notice = to_vec(&SChat::notice(OUT_OF_PHASE_NOTICE.to_string()))?;
+        assert_eq!(read_packet(&mut stream, &mut cipher).await?, (6, notice));
+        drop(stream);
+
+        // The connection stays open for the packets of the right phase.
+        tcp_join.await?;
+        let received = world_join.await;
+        // Neither the user list nor the notice passed through the ECS.
+        assert_eq!(received.len(), 2);
+        match &*received[0] {
+            RequestCheckVersion { .. } => {}
+            m => panic!("Unexpected message {}", m),
+        }
+        assert_eq!(close_kind(&received), CloseKind::Client);
+        Ok(())
+    }
+
+    /// Stub obfuscation that XORs every byte with a fixed key.
+    struct XorObfuscation(u8);
+
+    impl StreamObfuscation for XorObfuscation {
+        fn obfuscate(&mut self, data: &mut [u8]) {
+            data.iter_mut().for_each(|b| *b ^= self.0);
+        }
+
+        fn deobfuscate(&mut self, data: &mut [u8]) {
+            self.obfuscate(data);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_applies_obfuscation() -> Result<()> {
+        // Everything after the key exchange is obfuscated.
+        let (addr, reverse_map, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |session| {
+                session.set_obfuscation(Box::new(XorObfuscation(0x5a)))
+            })
+            .await?;
+        let world_join = spawn_world_mock(rx_channel, |connection_global_world_id, _| {
+            vec![check_version(connection_global_world_id, true)]
+        });
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+        let mut obfuscation = XorObfuscation(0x5a);
+
+        // Encoded by the session.
+        let mut frame = vec![0u8; HEADER_LENGTH + 1];
+        timeout(Duration::from_secs(1), stream.read_exact(&mut frame)).await??;
+        obfuscation.deobfuscate(&mut frame);
+        cipher.crypt_server_data(&mut frame);
+        let header = FrameHeader::read(&frame);
+        assert_eq!(header.opcode, 2);
+        assert_eq!(header.body_length(), 1);
+        assert_eq!(frame[HEADER_LENGTH], 1);
+
+        // Decoded by the session.
+        let mut frame = frame_packet(
+            Opcode::C_CHECK_VERSION,
+            &CCheckVersion::default(),
+            &reverse_map,
+        );
+        cipher.crypt_client_data(&mut frame);
+        obfuscation.obfuscate(&mut frame);
+        stream.write_all(&frame).await?;
+        drop(stream);
+
+        tcp_join.await?;
+        let received = world_join.await;
+        assert_eq!(received.len(), 2);
+        match &*received[0] {
+            RequestCheckVersion { .. } => {}
+            m => panic!("Unexpected message {}", m),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_closes_when_world_channel_is_closed() -> Result<()> {
+        let (addr, reverse_map, rx_channel, tcp_join) =
+            spawn_session(SessionSettings::default(), |_| {}).await?;
+
+        // World loop mock that stops receiving after the connection is registered. The channel
+        // of the connection stays open.
+        let world_join = task::spawn(async move {
+            let connection_global_world_id = get_new_entity_with_connection_component();
+            let message = rx_channel.recv().await.unwrap();
+            match &*message {
+                RegisterConnection {
+                    connection_channel, ..
+                } => {
+                    connection_channel
+                        .send(EcsMessage::new(RegisterConnectionFinished {
+                            connection_global_world_id,
+                        }))
+                        .await;
+                    connection_channel.clone()
+                }
+                m => panic!("Unexpected message {}", m),
+            }
+        });
+
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+        let _connection_channel = world_join.await;
+
+        let mut frame = frame_packet(
+            Opcode::C_CHECK_VERSION,
+            &CCheckVersion::default(),
+            &reverse_map,
+        );
+        cipher.crypt_client_data(&mut frame);
+        stream.write_all(&frame).await?;
+
+        // The session closes the connection without an error.
+        assert_closed(&mut stream).await?;
+        tcp_join.await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gamesession_drops_repeated_decode_failures() -> Result<()> {
+        // Drops the client after three broken packets.
+        let settings = SessionSettings {
+            max_decode_failures: 3,
+            ..Default::default()
+        };
+        let (addr, _, rx_channel, tcp_join) = spawn_session(settings, |_| {}).await?;
+        let world_join = spawn_world_mock(rx_channel, |_, _| vec![]);
+        let (mut stream, mut cipher) = connect_encrypted_client(&addr).await?;
+
+        // C_CHECK_VERSION with a body that is too short to be decoded.
+        for _ in 0..3 {
+            let mut frame = vec![0u8; HEADER_LENGTH + 1];
+            FrameHeader::for_body(1, 1).unwrap().write(&mut frame);
+            cipher.crypt_client_data(&mut frame);
+            stream.write_all(&frame).await?;
+        }
+
+        assert_closed(&mut stream).await?;
+        assert!(tcp_join.await.is_err());
+        // No broken packet made it to the ECS.
+        let received = world_join.await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(close_kind(&received), CloseKind::Error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_queue_monitor_warns_above_threshold() {
+        let mut monitor = SendQueueMonitor::new(4);
+        let output = capture_logs(tracing::Level::WARN, || {
+            for depth in 1..=4 {
+                monitor.record(depth);
+            }
+        });
+        assert!(output.is_empty());
+
+        let output = capture_logs(tracing::Level::WARN, || {
+            monitor.record(5);
+            monitor.record(6);
+        });
+        assert_eq!(monitor.depth, 6);
+        assert_eq!(monitor.max_depth, 6);
+
+        assert!(output.contains("WARN"));
+        assert!(output.contains("5 responses are queued (threshold 4)"));
+        // Only crossing the threshold warns.
+        assert_eq!(output.matches("responses are queued").count(), 1);
+
+        monitor.record(1);
+        assert_eq!(monitor.depth, 1);
+        assert_eq!(monitor.max_depth, 6);
+    }
+
+    #[test]
+    fn test_log_bad_frame_dumps_hex() {
+        let output = capture_logs(tracing::Level::DEBUG, || {
+            log_bad_frame(Opcode::C_CHECK_VERSION, 1, &[0x02, 0xab, 0xff]);
+        });
+
+        assert!(output.contains("DEBUG"));
+        assert!(output.contains("C_CHECK_VERSION with opcode value 1: 02abff"));
+    }
+
+    #[test]
+    fn test_log_packet_uses_configured_level() {
+        let settings = SessionSettings {
+            packet_log_levels: vec![(Opcode::C_LOGIN_ARBITER, PacketLogLevel::Info)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let level = |opcode: Opcode| settings.packet_log_level(opcode);
+
+        let output = capture_logs(tracing::Level::TRACE, || {
+            log_packet(
+                level(Opcode::C_LOGIN_ARBITER),
+                "Received valid",
+                Opcode::C_LOGIN_ARBITER,
+            );
+            log_packet(level(Opcode::C_CHAT), "Received valid", Opcode::C_CHAT);
+        });
+
+        let line_of = |opcode: &str| {
+            output
+                .lines()
+                .find(|line| line.contains(opcode))
+                .unwrap()
+                .to_string()
+        };
+        assert!(line_of("C_LOGIN_ARBITER").contains("INFO"));
+        assert!(line_of("C_CHAT").contains("DEBUG"));
+    }
+
+    #[test]
+    fn test_trace_packet_dumps_traced_opcodes() {
+        let connection_global_world_id =
+            World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        let message = ResponseCheckVersion {
+            connection_global_world_id,
+            packet: SCheckVersion { ok: true },
+        };
+        let traced_opcodes: HashSet<Opcode> = vec![Opcode::S_CHECK_VERSION].into_iter().collect();
+
+        let output = capture_logs(tracing::Level::TRACE, || {
+            trace_packet(
+                &traced_opcodes,
+                "Sending",
+                Opcode::S_CHECK_VERSION,
+                &message,
+            );
+            trace_packet(
+                &traced_opcodes,
+                "Received",
+                Opcode::C_CHECK_VERSION,
+                &message,
+            );
+        });
+
+        assert!(output.contains("TRACE"));
+        assert!(output.contains("Sending packet S_CHECK_VERSION"));
+        assert!(output.contains("ok: true"));
+        // Opcodes that aren't traced are not dumped.
+        assert!(!output.contains("Received packet"));
+    }
 }
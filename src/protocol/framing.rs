@@ -0,0 +1,315 @@
+/// Handles the framing of the TERA network protocol.
+///
+/// Every packet on the wire starts with a 4 byte header: the u16 length of the whole frame
+/// (header included) followed by the u16 opcode value. Offsets inside of a packet body
+/// (strings, byte buffers, arrays) are always relative to the start of the frame, so
+/// they include the header. This module is the only place that should know about this.
+use crate::Result;
+use anyhow::ensure;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Length of the frame header (u16 length + u16 opcode).
+pub const HEADER_LENGTH: usize = 4;
+
+/// Biggest packet body that still fits into the u16 frame length.
+pub const MAX_BODY_LENGTH: usize = std::u16::MAX as usize - HEADER_LENGTH;
+
+/// Biggest frame (header included) the u16 frame length can describe.
+pub const MAX_FRAME_LENGTH: usize = std::u16::MAX as usize;
+
+/// The header of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameHeader {
+    /// Length of the whole frame including the header.
+    pub length: u16,
+    /// The opcode value of the packet (not yet mapped to an `Opcode`).
+    pub opcode: u16,
+}
+
+impl FrameHeader {
+    /// Creates the header for a packet body of the given length.
+    pub fn for_body(opcode: u16, body_length: usize) -> Option<FrameHeader> {
+        if body_length > MAX_BODY_LENGTH {
+            None
+        } else {
+            Some(FrameHeader {
+                length: (body_length + HEADER_LENGTH) as u16,
+                opcode,
+            })
+        }
+    }
+
+    /// Reads the header from the first `HEADER_LENGTH` bytes of the given buffer.
+    /// The buffer must hold at least `HEADER_LENGTH` bytes.
+    pub fn read(buf: &[u8]) -> FrameHeader {
+        FrameHeader {
+            length: LittleEndian::read_u16(&buf[0..2]),
+            opcode: LittleEndian::read_u16(&buf[2..4]),
+        }
+    }
+
+    /// Writes the header into the first `HEADER_LENGTH` bytes of the given buffer.
+    pub fn write(&self, buf: &mut [u8]) {
+        LittleEndian::write_u16(&mut buf[0..2], self.length);
+        LittleEndian::write_u16(&mut buf[2..4], self.opcode);
+    }
+
+    /// Length of the packet body that follows the header.
+    pub fn body_length(&self) -> usize {
+        (self.length as usize).saturating_sub(HEADER_LENGTH)
+    }
+}
+
+/// Reads the frame length and the opcode value from the start of the given buffer without
+/// parsing the packet body. Used to route or filter a packet before it's deserialized.
+pub fn peek_frame_header(buf: &[u8]) -> Result<(u16, u16)> {
+    ensure!(
+        buf.len() >= HEADER_LENGTH,
+        "frame header needs {} bytes, but only {} are available",
+        HEADER_LENGTH,
+        buf.len()
+    );
+    let header = FrameHeader::read(buf);
+    Ok((header.length, header.opcode))
+}
+
+/// Accumulates the bytes read from the stream until a whole frame is available. TCP doesn't
+/// keep the boundaries of the frames, so a read can end in the middle of a header or body.
+#[derive(Clone, Debug, Default)]
+pub struct FrameBuffer {
+    pending: Vec<u8>,
+}
+
+impl FrameBuffer {
+    /// Returns true if no bytes of a started frame are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Number of bytes that can still be added. The buffer never holds more than
+    /// `MAX_FRAME_LENGTH` bytes.
+    pub fn free_capacity(&self) -> usize {
+        MAX_FRAME_LENGTH - self.pending.len()
+    }
+
+    /// Appends the (already decrypted) bytes of a read.
+    pub fn extend(&mut self, data: &[u8]) -> Result<()> {
+        ensure!(
+            data.len() <= self.free_capacity(),
+            "pending frame data would exceed the maximal frame length of {} bytes",
+            MAX_FRAME_LENGTH
+        );
+        self.pending.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Returns the header of the next frame once all of it's bytes are buffered.
+    pub fn peek_frame(&self) -> Result<Option<FrameHeader>> {
+        if self.pending.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+        let header = FrameHeader::read(&self.pending);
+        ensure!(
+            header.length as usize >= HEADER_LENGTH,
+            "frame length {} is shorter than the frame header",
+            header.length
+        );
+        if self.pending.len() < header.length as usize {
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    /// Copies the body of the frame returned by `peek_frame` into the given buffer and removes
+    /// the frame. The buffer must have the body length of the frame.
+    pub fn consume_frame(&mut self, body: &mut [u8]) {
+        let length = HEADER_LENGTH + body.len();
+        body.copy_from_slice(&self.pending[HEADER_LENGTH..length]);
+        self.pending.drain(..length);
+    }
+}
+
+/// Converts an offset found inside a packet (relative to the frame) into a position
+/// inside the packet body. An offset of 0 marks an unset offset and is kept as is.
+#[inline]
+pub fn frame_offset_to_body(offset: usize) -> usize {
+    if offset == 0 {
+        offset
+    } else {
+        offset - HEADER_LENGTH
+    }
+}
+
+/// Converts a position inside the packet body into an offset relative to the frame.
+#[inline]
+pub fn body_offset_to_frame(position: usize) -> usize {
+    position + HEADER_LENGTH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::serde::{from_vec, to_vec};
+    use crate::Result;
+    use serde::{Deserialize, Serialize};
+
+    #[test]
+    fn test_offsets_resolve_like_before() {
+        // Body of a C_CHECK_VERSION packet. The array offset points at the first element and
+        // every element starts with its own offset followed by the offset of the next one.
+        let body = [
+            0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0x14, 0x0, 0x0, 0x0, 0x0, 0x0, 0x8e, 0x96, 0x5, 0x0,
+            0x14, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xdf, 0x93, 0x5, 0x0,
+        ];
+
+        let first = LittleEndian::read_u16(&body[2..4]) as usize;
+        assert_eq!(first, 8);
+        assert_eq!(frame_offset_to_body(first), 4);
+        assert_eq!(&body[4..6], &[0x8, 0x0]);
+
+        let second = LittleEndian::read_u16(&body[6..8]) as usize;
+        assert_eq!(second, 20);
+        assert_eq!(frame_offset_to_body(second), 16);
+        assert_eq!(&body[16..18], &[0x14, 0x0]);
+
+        // The last element has no next element.
+        let next = LittleEndian::read_u16(&body[18..20]) as usize;
+        assert_eq!(frame_offset_to_body(next), 0);
+    }
+
+    #[test]
+    fn test_offset_conversion_is_symmetric() {
+        for position in &[0usize, 1, 17, 4096] {
+            assert_eq!(
+                frame_offset_to_body(body_offset_to_frame(*position)),
+                *position
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_read_write() {
+        let header = FrameHeader::for_body(0x4dbc, 6).unwrap();
+        assert_eq!(header.length, 10);
+        assert_eq!(header.body_length(), 6);
+
+        let mut buf = vec![0u8; HEADER_LENGTH];
+        header.write(&mut buf);
+        assert_eq!(buf, vec![0x0a, 0x00, 0xbc, 0x4d]);
+        assert_eq!(FrameHeader::read(&buf), header);
+    }
+
+    #[test]
+    fn test_header_too_big() {
+        assert!(FrameHeader::for_body(1, std::u16::MAX as usize - HEADER_LENGTH).is_some());
+        assert!(FrameHeader::for_body(1, std::u16::MAX as usize - HEADER_LENGTH + 1).is_none());
+    }
+
+    #[test]
+    fn test_peek_frame_header() -> Result<()> {
+        // The body is never looked at.
+        let buf = vec![0x0a, 0x00, 0xbc, 0x4d, 0xff];
+        assert_eq!(peek_frame_header(&buf)?, (10, 0x4dbc));
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek_frame_header_too_short() {
+        assert!(peek_frame_header(&[]).is_err());
+        assert!(peek_frame_header(&[0x0a, 0x00, 0xbc]).is_err());
+    }
+
+    #[test]
+    fn test_frame_buffer_byte_by_byte() -> Result<()> {
+        let frame = vec![0x07, 0x00, 0xbc, 0x4d, 0x01, 0x02, 0x03];
+        let mut buffer = FrameBuffer::default();
+        for (i, byte) in frame.iter().enumerate() {
+            assert_eq!(buffer.peek_frame()?, None);
+            buffer.extend(&[*byte])?;
+            assert_eq!(buffer.is_empty(), false, "byte {}", i);
+        }
+
+        let header = buffer.peek_frame()?.unwrap();
+        assert_eq!(header.opcode, 0x4dbc);
+        let mut body = vec![0u8; header.body_length()];
+        buffer.consume_frame(&mut body);
+        assert_eq!(body, vec![0x01, 0x02, 0x03]);
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_buffer_multiple_frames() -> Result<()> {
+        // Two frames and the start of a third one in one read.
+        let data = vec![
+            0x05, 0x00, 0x01, 0x00, 0xaa, 0x04, 0x00, 0x02, 0x00, 0x06, 0x00,
+        ];
+        let mut buffer = FrameBuffer::default();
+        buffer.extend(&data)?;
+
+        let header = buffer.peek_frame()?.unwrap();
+        assert_eq!(header.opcode, 1);
+        let mut body = vec![0u8; header.body_length()];
+        buffer.consume_frame(&mut body);
+        assert_eq!(body, vec![0xaa]);
+
+        let header = buffer.peek_frame()?.unwrap();
+        assert_eq!(header.opcode, 2);
+        buffer.consume_frame(&mut []);
+
+        assert_eq!(buffer.peek_frame()?, None);
+        assert_eq!(buffer.is_empty(), false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_buffer_capacity() -> Result<()> {
+        let mut buffer = FrameBuffer::default();
+        buffer.extend(&vec![0xff; MAX_FRAME_LENGTH - 1])?;
+        assert_eq!(buffer.free_capacity(), 1);
+        assert!(buffer.extend(&[0xff, 0xff]).is_err());
+        buffer.extend(&[0xff])?;
+        assert_eq!(buffer.free_capacity(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_buffer_invalid_length() -> Result<()> {
+        let mut buffer = FrameBuffer::default();
+        buffer.extend(&[0x02, 0x00, 0x01, 0x00])?;
+        assert!(buffer.peek_frame().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_body_length_underflow() {
+        let header = FrameHeader {
+            length: 2,
+            opcode: 1,
+        };
+        assert_eq!(header.body_length(), 0);
+    }
+
+    #[test]
+    fn test_string_offset_round_trip() -> Result<()> {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct StringStruct {
+            a: u32,
+            name: String,
+        }
+
+        let value = StringStruct {
+            a: 42,
+            name: "Almetica".to_string(),
+        };
+        let data = to_vec(&value)?;
+
+        // The string offset is written relative to the frame and points right after the fields.
+        let offset = LittleEndian::read_u16(&data[4..6]) as usize;
+        assert_eq!(offset, body_offset_to_frame(6));
+        assert_eq!(frame_offset_to_body(offset), 6);
+
+        assert_eq!(from_vec::<StringStruct>(data)?, value);
+        Ok(())
+    }
+}
@@ -1731,6 +1731,7 @@ pub enum Opcode {
     S_RESULT_SEREN_GUIDE,
     S_RESULT_USABLE_CHARACTER_NAME,
     S_RESULT_USABLE_GUILD_NAME,
+    S_RESUME_TOKEN,
     S_RETURN_PARCEL,
     S_RETURN_TO_LOBBY,
     S_RETURN_USER,
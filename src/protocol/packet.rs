@@ -27,13 +27,42 @@ macro_rules! packet_test {
             let data = org.clone();
             let expected = $struct;
             // FIXME: expected value needs to be on the right side (but then we need a type hint for the methods).
-            assert_eq!(expected, from_vec::<_>(data)?);
+            assert_eq!(expected, from_vec::<_>(data.clone())?);
+            // All offsets of a known good packet need to resolve inside of the packet body.
+            assert_eq!(
+                expected,
+                $crate::protocol::serde::from_vec_checked::<_>(data)?
+            );
             assert_eq!(org, to_vec(expected)?);
             Ok(())
         }
     };
 }
 
+/// Used in unit tests to check that a value survives the serialization and deserialization.
+/// Covers values that are hard to capture from a real client, like empty strings and arrays or
+/// boundary integers.
+#[allow(unused_macros)]
+#[macro_export]
+macro_rules! packet_roundtrip_test {
+    (
+        name: $name:ident,
+        value: $struct:expr
+    ) => {
+        #[test]
+        fn $name() -> Result<()> {
+            let expected = $struct;
+            let data = to_vec(expected.clone())?;
+            assert_eq!(expected, from_vec::<_>(data.clone())?);
+            assert_eq!(
+                expected,
+                $crate::protocol::serde::from_vec_checked::<_>(data)?
+            );
+            Ok(())
+        }
+    };
+}
+
 /// For debugging only.
 #[allow(unused_macros)]
 #[macro_export]
@@ -1,38 +1,71 @@
 /// Module for client network packages.
-use crate::model::{Class, Customization, Gender, Race, Region};
+use crate::model::{Class, Customization, Gender, Race, Region, UserId};
+use crate::protocol::serde::SchemaVersion;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CCanCreateUser {}
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CChangeUserLobbySlotId {
     pub user_positions: Vec<CChangeUserLobbySlotIdEntry>,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CChangeUserLobbySlotIdEntry {
-    pub database_id: i32,
+    pub database_id: UserId,
     pub lobby_slot: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CCheckVersion {
     pub version: Vec<CCheckVersionEntry>,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+/// Indices of the version entries the client sends.
+const CHECK_VERSION_INDICES: [i32; 2] = [0, 1];
+
+impl CCheckVersion {
+    /// Returns the version value with the given index. The entries can be in any order.
+    pub fn value(&self, index: i32) -> Option<i32> {
+        self.version
+            .iter()
+            .find(|entry| entry.index == index)
+            .map(|entry| entry.value)
+    }
+
+    /// Returns the schema version of the packet layouts the client uses.
+    pub fn schema_version(&self) -> Option<SchemaVersion> {
+        self.value(0)
+            .filter(|value| *value >= 0)
+            .map(|value| SchemaVersion(value as u32))
+    }
+
+    /// Returns true if every expected index is present exactly once and no other index is.
+    pub fn has_expected_indices(&self) -> bool {
+        self.version.len() == CHECK_VERSION_INDICES.len()
+            && CHECK_VERSION_INDICES.iter().all(|index| {
+                self.version
+                    .iter()
+                    .filter(|entry| entry.index == *index)
+                    .count()
+                    == 1
+            })
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CCheckVersionEntry {
     pub index: i32,
     pub value: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CCheckUserName {
     pub name: String,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CCreateUser {
     pub name: String,
     #[serde(with = "serde_bytes")]
@@ -47,24 +80,24 @@ pub struct CCreateUser {
     pub appearance2: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CDeleteUser {
-    pub database_id: i32,
+    pub database_id: UserId,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CGetUserList {}
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CGetUserGuildLogo {
     pub player_id: i32,
     pub guild_id: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CLoadTopoFin {}
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CLoginArbiter {
     pub master_account_name: String,
     #[serde(with = "serde_bytes")]
@@ -75,16 +108,17 @@ pub struct CLoginArbiter {
     pub patch_version: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+// Carries no sequence number. The client answers every S_PING with a C_PONG.
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CPong {}
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CSelectUser {
-    pub database_id: i32,
+    pub database_id: UserId,
     pub unk1: u8,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct CSetVisibleRange {
     pub range: u32,
 }
@@ -92,7 +126,7 @@ pub struct CSetVisibleRange {
 #[cfg(test)]
 #[macro_use]
 mod tests {
-    use crate::model::{Class, Customization, Gender, Race, Region};
+    use crate::model::{Class, Customization, Gender, Race, Region, UserId};
     use crate::protocol::serde::{from_vec, to_vec, Result};
 
     use super::*;
@@ -109,11 +143,11 @@ mod tests {
         expected: CChangeUserLobbySlotId {
             user_positions: vec![
                 CChangeUserLobbySlotIdEntry {
-                    database_id: 5,
+                    database_id: UserId(5),
                     lobby_slot: 1,
                 },
                 CChangeUserLobbySlotIdEntry {
-                    database_id: 6,
+                    database_id: UserId(6),
                     lobby_slot: 2,
                 },
             ],
@@ -140,6 +174,65 @@ mod tests {
         }
     );
 
+    #[test]
+    fn test_check_version_lookup_in_order() {
+        let packet = CCheckVersion {
+            version: vec![
+                CCheckVersionEntry {
+                    index: 0,
+                    value: 366_222,
+                },
+                CCheckVersionEntry {
+                    index: 1,
+                    value: 365_535,
+                },
+            ],
+        };
+        assert!(packet.has_expected_indices());
+        assert_eq!(packet.value(0), Some(366_222));
+        assert_eq!(packet.value(1), Some(365_535));
+        assert_eq!(packet.value(2), None);
+    }
+
+    #[test]
+    fn test_check_version_lookup_out_of_order() {
+        let packet = CCheckVersion {
+            version: vec![
+                CCheckVersionEntry {
+                    index: 1,
+                    value: 365_535,
+                },
+                CCheckVersionEntry {
+                    index: 0,
+                    value: 366_222,
+                },
+            ],
+        };
+        assert!(packet.has_expected_indices());
+        assert_eq!(packet.value(0), Some(366_222));
+        assert_eq!(packet.value(1), Some(365_535));
+    }
+
+    #[test]
+    fn test_check_version_unexpected_indices() {
+        let duplicate = CCheckVersion {
+            version: vec![
+                CCheckVersionEntry { index: 0, value: 1 },
+                CCheckVersionEntry { index: 0, value: 2 },
+            ],
+        };
+        assert!(!duplicate.has_expected_indices());
+
+        let unknown = CCheckVersion {
+            version: vec![
+                CCheckVersionEntry { index: 0, value: 1 },
+                CCheckVersionEntry { index: 2, value: 2 },
+            ],
+        };
+        assert!(!unknown.has_expected_indices());
+        assert!(!CCheckVersion::default().has_expected_indices());
+    }
+
     packet_test!(
         name: test_check_user_name,
         data: vec![
@@ -188,7 +281,7 @@ mod tests {
         name: test_delete_user,
         data: vec![0x13, 0x12, 0x11, 0x32],
         expected: CDeleteUser {
-            database_id: 839979539,
+            database_id: UserId(839979539),
         }
     );
 
@@ -248,7 +341,7 @@ mod tests {
         name: test_select_user,
         data: vec![0x3, 0x2f, 0x32, 0x1, 0x0],
         expected: CSelectUser {
-            database_id: 20066051,
+            database_id: UserId(20066051),
             unk1: 0,
         }
     );
@@ -260,4 +353,115 @@ mod tests {
             range: 2000,
         }
     );
+
+    packet_roundtrip_test!(
+        name: test_change_user_lobby_slot_id_roundtrip,
+        value: CChangeUserLobbySlotId {
+            user_positions: vec![
+                CChangeUserLobbySlotIdEntry {
+                    database_id: UserId(std::i32::MAX),
+                    lobby_slot: std::i32::MIN,
+                },
+                CChangeUserLobbySlotIdEntry {
+                    database_id: UserId(0),
+                    lobby_slot: -1,
+                },
+            ],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_change_user_lobby_slot_id_empty_roundtrip,
+        value: CChangeUserLobbySlotId {
+            user_positions: vec![],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_check_version_roundtrip,
+        value: CCheckVersion {
+            version: vec![
+                CCheckVersionEntry {
+                    index: std::i32::MIN,
+                    value: std::i32::MAX,
+                },
+            ],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_check_version_empty_roundtrip,
+        value: CCheckVersion { version: vec![] }
+    );
+
+    packet_roundtrip_test!(
+        name: test_check_user_name_empty_roundtrip,
+        value: CCheckUserName {
+            name: "".to_string(),
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_check_user_name_multi_byte_roundtrip,
+        value: CCheckUserName {
+            name: "Ärgernis€".to_string(),
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_create_user_roundtrip,
+        value: CCreateUser {
+            name: "".to_string(),
+            details: vec![],
+            shape: vec![],
+            gender: Gender::Female,
+            race: Race::Baraka,
+            class: Class::Valkyrie,
+            appearance: Customization(vec![0xff; 8]),
+            is_second_character: true,
+            appearance2: std::i32::MIN,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_delete_user_roundtrip,
+        value: CDeleteUser {
+            database_id: UserId(std::i32::MIN),
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_get_user_guild_logo_roundtrip,
+        value: CGetUserGuildLogo {
+            player_id: std::i32::MIN,
+            guild_id: std::i32::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_login_arbiter_roundtrip,
+        value: CLoginArbiter {
+            master_account_name: "".to_string(),
+            ticket: vec![],
+            unk1: std::i32::MIN,
+            unk2: std::u8::MAX,
+            region: Region::Russia,
+            patch_version: std::i32::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_select_user_roundtrip,
+        value: CSelectUser {
+            database_id: UserId(std::i32::MAX),
+            unk1: std::u8::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_set_visible_range_roundtrip,
+        value: CSetVisibleRange {
+            range: std::u32::MAX,
+        }
+    );
 }
@@ -1,47 +1,83 @@
 /// Module for server network packages.
 use crate::model::{
-    Angle, Class, Customization, Gender, Race, Region, ServantType, TemplateID, Vec3, Vec3a,
+    AccountId, Angle, Class, Customization, Gender, Race, Region, ServantType, TemplateID, UserId,
+    Vec3, Vec3a,
 };
 use serde::{Deserialize, Serialize};
 use shipyard::EntityId;
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SAccountPackageList {
     pub account_benefits: Vec<SAccountPackageListEntry>,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SAccountPackageListEntry {
     pub package_id: u32,
     pub expiration_date: i64,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SCanCreateUser {
     pub ok: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
+pub struct SChat {
+    pub author_name: String,
+    pub message: String,
+    pub channel: u32,
+    pub author_id: u64,
+    pub unk1: u8,
+    pub gm: bool,
+    pub founder: bool,
+}
+
+/// Chat channel of the notices, which the client shows prominently.
+pub const NOTICE_CHAT_CHANNEL: u32 = 21;
+
+impl SChat {
+    /// A message without an author that the client shows as a notice.
+    pub fn notice(message: String) -> Self {
+        SChat {
+            message,
+            channel: NOTICE_CHAT_CHANNEL,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SCheckVersion {
     pub ok: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+impl SCheckVersion {
+    pub fn accepted() -> Self {
+        SCheckVersion { ok: true }
+    }
+
+    pub fn rejected() -> Self {
+        SCheckVersion { ok: false }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SCheckUserName {
     pub ok: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SCreateUser {
     pub ok: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SDeleteUser {
     pub ok: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SGetUserList {
     pub characters: Vec<SGetUserListCharacter>,
     pub veteran: bool,
@@ -55,7 +91,7 @@ pub struct SGetUserList {
     pub delete_character_expire_hour2: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SGetUserListCharacter {
     pub custom_strings: Vec<SGetUserListCharacterCustomString>,
     pub name: String,
@@ -64,7 +100,7 @@ pub struct SGetUserListCharacter {
     #[serde(with = "serde_bytes")]
     pub shape: Vec<u8>,
     pub guild_name: String,
-    pub db_id: i32,
+    pub db_id: UserId,
     pub gender: Gender,
     pub race: Race,
     pub class: Class,
@@ -156,13 +192,13 @@ pub struct SGetUserListCharacter {
     pub has_broker_sales: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SGetUserListCharacterCustomString {
     pub string: String,
     pub id: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SGuildName {
     pub guild_name: String,
     pub guild_rank: String,
@@ -171,7 +207,7 @@ pub struct SGuildName {
     pub game_id: u64,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SImageData {
     pub name: String,
 
@@ -179,39 +215,39 @@ pub struct SImageData {
     pub data: Vec<u8>,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SItemCustomString {
     pub custom_strings: Vec<SItemCustomStringEntry>,
     pub game_id: u64,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SItemCustomStringEntry {
     pub string: String,
     pub id: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SLoadingScreenControlInfo {
     pub custom_screen_enabled: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SLoadHint {
     pub unk1: u32, // TODO try to identify the usage of the field
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SLoadTopo {
     pub zone: i32,
     pub location: Vec3,
     pub disable_loading_screen: bool,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SLoginAccountInfo {
     pub server_name: String,
-    pub account_id: i64,
+    pub account_id: AccountId,
     pub integrity_iv: u32, // IV for the custom hash function of some client packets
 }
 
@@ -295,7 +331,89 @@ pub struct SLogin {
     pub guild_logo_id: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+// EntityId has no default value, so a dead entity is used as the ID.
+impl Default for SLogin {
+    fn default() -> Self {
+        SLogin {
+            servants: Default::default(),
+            name: Default::default(),
+            details: Default::default(),
+            shape: Default::default(),
+            template_id: Default::default(),
+            id: EntityId::dead(),
+            server_id: Default::default(),
+            db_id: Default::default(),
+            action_mode: Default::default(),
+            alive: Default::default(),
+            status: Default::default(),
+            walk_speed: Default::default(),
+            run_speed: Default::default(),
+            appearance: Default::default(),
+            visible: Default::default(),
+            is_second_character: Default::default(),
+            level: Default::default(),
+            awakening_level: Default::default(),
+            profession_mineral: Default::default(),
+            profession_bug: Default::default(),
+            profession_herb: Default::default(),
+            profession_energy: Default::default(),
+            profession_pet: Default::default(),
+            pvp_declared_count: Default::default(),
+            pvp_kill_count: Default::default(),
+            total_exp: Default::default(),
+            level_exp: Default::default(),
+            total_level_exp: Default::default(),
+            ep_level: Default::default(),
+            ep_exp: Default::default(),
+            ep_daily_exp: Default::default(),
+            rest_bonus_exp: Default::default(),
+            max_rest_bonus_exp: Default::default(),
+            exp_bonus_percent: Default::default(),
+            drop_bonus_percent: Default::default(),
+            weapon: Default::default(),
+            body: Default::default(),
+            hand: Default::default(),
+            feet: Default::default(),
+            underwear: Default::default(),
+            head: Default::default(),
+            face: Default::default(),
+            server_time: Default::default(),
+            is_pvp_server: Default::default(),
+            chat_ban_end_time: Default::default(),
+            title: Default::default(),
+            weapon_model: Default::default(),
+            body_model: Default::default(),
+            hand_model: Default::default(),
+            feet_model: Default::default(),
+            weapon_dye: Default::default(),
+            body_dye: Default::default(),
+            hand_dye: Default::default(),
+            feet_dye: Default::default(),
+            underwear_dye: Default::default(),
+            style_back_dye: Default::default(),
+            style_head_dye: Default::default(),
+            style_face_dye: Default::default(),
+            weapon_enchant: Default::default(),
+            is_world_event_target: Default::default(),
+            infamy: Default::default(),
+            show_face: Default::default(),
+            style_head: Default::default(),
+            style_face: Default::default(),
+            style_back: Default::default(),
+            style_weapon: Default::default(),
+            style_body: Default::default(),
+            style_footprint: Default::default(),
+            style_body_dye: Default::default(),
+            show_style: Default::default(),
+            title_count: Default::default(),
+            appearance2: Default::default(),
+            scale: Default::default(),
+            guild_logo_id: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SLoginServantEntry {
     pub database_id: i64,
     pub id: i32,
@@ -304,7 +422,7 @@ pub struct SLoginServantEntry {
     pub slot: i32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SLoginArbiter {
     pub success: bool,
     pub login_queue: bool,
@@ -316,10 +434,41 @@ pub struct SLoginArbiter {
     pub unk3: u16, // 0
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+impl SLoginArbiter {
+    /// Answer for an authenticated login. The region must be the one of the request.
+    pub fn accepted(region: Region) -> Self {
+        SLoginArbiter {
+            success: true,
+            status: 65538,
+            region,
+            ..Default::default()
+        }
+    }
+
+    /// Answer for a rejected login. The region must be the one of the request.
+    pub fn rejected(region: Region) -> Self {
+        SLoginArbiter {
+            region,
+            ..Default::default()
+        }
+    }
+
+    /// Answer for a login that can't proceed yet. The client is put into the login queue and
+    /// can retry on the same connection. The region must be the one of the request.
+    pub fn queued(region: Region) -> Self {
+        SLoginArbiter {
+            login_queue: true,
+            region,
+            ..Default::default()
+        }
+    }
+}
+
+// Empty, the client answers it with a C_PONG.
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SPing {}
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SRemainPlayTime {
     // 1 = P2P (active subscription)
     // 2 = P2P (no active subscription),
@@ -330,7 +479,13 @@ pub struct SRemainPlayTime {
     pub minutes_left: u32,
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Debug)]
+// Not part of the original protocol. Only a client whose opcode mapping knows it receives it.
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
+pub struct SResumeToken {
+    pub token: [u8; 32],
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug)]
 pub struct SSelectUser {
     unk1: u8, // TODO try to identify the usage of the fields
     unk2: u16,
@@ -346,6 +501,18 @@ pub struct SSpawnMe {
     pub is_lord: bool, // TODO try to identify the usage of the field
 }
 
+impl Default for SSpawnMe {
+    fn default() -> Self {
+        SSpawnMe {
+            user_id: EntityId::dead(),
+            location: Default::default(),
+            rotation: Default::default(),
+            is_alive: Default::default(),
+            is_lord: Default::default(),
+        }
+    }
+}
+
 #[cfg(test)]
 #[macro_use]
 mod tests {
@@ -376,6 +543,23 @@ mod tests {
         }
     );
 
+    packet_test!(
+        name: test_chat,
+        data: vec![
+            0x17, 0x0, 0x19, 0x0, 0x15, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+            0x0, 0x0, 0x0, 0x48, 0x0, 0x69, 0x0, 0x0, 0x0,
+        ],
+        expected: SChat {
+            author_name: "".to_string(),
+            message: "Hi".to_string(),
+            channel: 21,
+            author_id: 0,
+            unk1: 0,
+            gm: false,
+            founder: false,
+        }
+    );
+
     packet_test!(
         name: test_check_username,
         data: vec![
@@ -496,7 +680,7 @@ mod tests {
                     0, 0,
                 ],
                 guild_name: "Unlimited Power".to_string(),
-                db_id: 2_000_131,
+                db_id: UserId(2_000_131),
                 gender: Gender::Female,
                 race: Race::ElinPopori,
                 class: Class::Lancer,
@@ -805,7 +989,7 @@ mod tests {
         ],
         expected: SLoginAccountInfo {
             server_name: "PlanetDB_27".to_string(),
-            account_id: 482_558,
+            account_id: AccountId(482_558),
             integrity_iv: 4278124286,
         }
     );
@@ -827,6 +1011,42 @@ mod tests {
         }
     );
 
+    #[test]
+    fn test_login_arbiter_builders() {
+        assert_eq!(
+            SLoginArbiter::accepted(Region::Europe),
+            SLoginArbiter {
+                success: true,
+                login_queue: false,
+                status: 65538,
+                unk1: 0,
+                region: Region::Europe,
+                pvp_disabled: false,
+                unk2: 0,
+                unk3: 0,
+            }
+        );
+        assert_eq!(
+            SLoginArbiter::rejected(Region::Europe),
+            SLoginArbiter {
+                success: false,
+                login_queue: false,
+                status: 0,
+                unk1: 0,
+                region: Region::Europe,
+                pvp_disabled: false,
+                unk2: 0,
+                unk3: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_version_builders() {
+        assert_eq!(SCheckVersion::accepted(), SCheckVersion { ok: true });
+        assert_eq!(SCheckVersion::rejected(), SCheckVersion { ok: false });
+    }
+
     packet_test!(
         name: test_ping,
         data: vec![],
@@ -870,4 +1090,293 @@ mod tests {
             is_lord: false,
         }
     );
+
+    packet_roundtrip_test!(
+        name: test_account_package_list_roundtrip,
+        value: SAccountPackageList {
+            account_benefits: vec![
+                SAccountPackageListEntry {
+                    package_id: std::u32::MAX,
+                    expiration_date: std::i64::MIN,
+                },
+                SAccountPackageListEntry {
+                    package_id: 0,
+                    expiration_date: std::i64::MAX,
+                },
+            ],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_account_package_list_empty_roundtrip,
+        value: SAccountPackageList {
+            account_benefits: vec![],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_can_create_user_roundtrip,
+        value: SCanCreateUser { ok: false }
+    );
+
+    packet_roundtrip_test!(
+        name: test_chat_roundtrip,
+        value: SChat {
+            author_name: "".to_string(),
+            message: "".to_string(),
+            channel: std::u32::MAX,
+            author_id: std::u64::MAX,
+            unk1: std::u8::MAX,
+            gm: true,
+            founder: true,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_check_username_roundtrip,
+        value: SCheckUserName { ok: false }
+    );
+
+    packet_roundtrip_test!(
+        name: test_check_version_roundtrip,
+        value: SCheckVersion { ok: false }
+    );
+
+    packet_roundtrip_test!(
+        name: test_create_user_roundtrip,
+        value: SCreateUser { ok: false }
+    );
+
+    packet_roundtrip_test!(
+        name: test_delete_user_roundtrip,
+        value: SDeleteUser { ok: false }
+    );
+
+    packet_roundtrip_test!(
+        name: test_get_user_list_empty_roundtrip,
+        value: SGetUserList {
+            characters: vec![],
+            veteran: true,
+            bonus_buf_sec: std::i32::MIN,
+            max_characters: std::i32::MAX,
+            first: true,
+            more: true,
+            left_del_time_account_over: -1,
+            deletion_section_classify_level: 0,
+            delete_character_expire_hour1: std::i32::MAX,
+            delete_character_expire_hour2: std::i32::MIN,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_get_user_list_roundtrip,
+        value: SGetUserList {
+            characters: vec![
+                SGetUserListCharacter {
+                    custom_strings: vec![],
+                    name: "".to_string(),
+                    details: vec![],
+                    shape: vec![],
+                    guild_name: "".to_string(),
+                    db_id: UserId(std::i32::MAX),
+                    gender: Gender::Female,
+                    race: Race::Castanic,
+                    class: Class::Ninja,
+                    level: std::i32::MAX,
+                    hp: std::i64::MAX,
+                    last_logout_time: std::i64::MIN,
+                    ban_remain_sec: -1,
+                    appearance: Customization(vec![0xff; 8]),
+                    rest_bonus_xp: std::i64::MIN,
+                    style_head_scale: std::f32::MAX,
+                    style_head_rotation: Vec3a {
+                        x: std::i32::MIN,
+                        y: std::i32::MAX,
+                        z: -1,
+                    },
+                    style_head_translation: Vec3 {
+                        x: std::f32::MIN,
+                        y: std::f32::MIN_POSITIVE,
+                        z: std::f32::EPSILON,
+                    },
+                    has_broker_sales: true,
+                    ..Default::default()
+                },
+                SGetUserListCharacter {
+                    custom_strings: vec![
+                        SGetUserListCharacterCustomString {
+                            string: "".to_string(),
+                            id: std::i32::MIN,
+                        },
+                        SGetUserListCharacterCustomString {
+                            string: "Custom".to_string(),
+                            id: std::i32::MAX,
+                        },
+                    ],
+                    name: "Asuna".to_string(),
+                    details: vec![0xff; 32],
+                    shape: vec![0x0; 64],
+                    guild_name: "Guild".to_string(),
+                    lobby_slot: 2,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_guild_name_roundtrip,
+        value: SGuildName {
+            guild_name: "".to_string(),
+            guild_rank: "".to_string(),
+            guild_title: "".to_string(),
+            guild_logo: "".to_string(),
+            game_id: std::u64::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_image_data_roundtrip,
+        value: SImageData {
+            name: "".to_string(),
+            data: vec![],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_item_custom_string_roundtrip,
+        value: SItemCustomString {
+            custom_strings: vec![
+                SItemCustomStringEntry {
+                    string: "".to_string(),
+                    id: std::i32::MIN,
+                },
+                SItemCustomStringEntry {
+                    string: "Pantsu".to_string(),
+                    id: std::i32::MAX,
+                },
+            ],
+            game_id: std::u64::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_loading_screen_control_info_roundtrip,
+        value: SLoadingScreenControlInfo {
+            custom_screen_enabled: true,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_load_hint_roundtrip,
+        value: SLoadHint { unk1: std::u32::MAX }
+    );
+
+    packet_roundtrip_test!(
+        name: test_load_topo_roundtrip,
+        value: SLoadTopo {
+            zone: std::i32::MIN,
+            location: Vec3 {
+                x: std::f32::MAX,
+                y: std::f32::MIN,
+                z: -0.5,
+            },
+            disable_loading_screen: true,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_login_account_info_roundtrip,
+        value: SLoginAccountInfo {
+            server_name: "".to_string(),
+            account_id: AccountId(std::i64::MAX),
+            integrity_iv: std::u32::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_login_roundtrip,
+        value: SLogin {
+            servants: vec![
+                SLoginServantEntry {
+                    database_id: std::i64::MIN,
+                    id: std::i32::MAX,
+                    servant_type: ServantType::Partner,
+                    energy: std::u32::MAX,
+                    slot: -1,
+                },
+            ],
+            name: "".to_string(),
+            details: vec![],
+            shape: vec![],
+            template_id: TemplateID {
+                race: Race::Baraka,
+                gender: Gender::Female,
+                class: Class::Valkyrie,
+            },
+            level: std::i16::MIN,
+            profession_pet: std::i16::MAX,
+            total_exp: std::i64::MAX,
+            server_time: std::u64::MAX,
+            chat_ban_end_time: std::u64::MAX,
+            exp_bonus_percent: std::f32::MAX,
+            title_count: std::i64::MIN,
+            scale: std::f32::MIN_POSITIVE,
+            ..Default::default()
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_login_arbiter_roundtrip,
+        value: SLoginArbiter {
+            success: true,
+            login_queue: true,
+            status: std::i32::MIN,
+            unk1: std::u32::MAX,
+            region: Region::Russia,
+            pvp_disabled: true,
+            unk2: std::u16::MAX,
+            unk3: std::u16::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_remain_play_time_roundtrip,
+        value: SRemainPlayTime {
+            account_type: std::u32::MAX,
+            minutes_left: std::u32::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_resume_token_roundtrip,
+        value: SResumeToken {
+            token: [std::u8::MAX; 32],
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_select_user_roundtrip,
+        value: SSelectUser {
+            unk1: std::u8::MAX,
+            unk2: std::u16::MAX,
+            unk3: std::u64::MAX,
+        }
+    );
+
+    packet_roundtrip_test!(
+        name: test_spawn_me_roundtrip,
+        value: SSpawnMe {
+            user_id: EntityId::dead(),
+            location: Vec3 {
+                x: std::f32::MIN,
+                y: std::f32::MAX,
+                z: std::f32::EPSILON,
+            },
+            rotation: Angle::from_deg(359.0),
+            is_alive: false,
+            is_lord: true,
+        }
+    );
 }
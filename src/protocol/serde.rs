@@ -1,8 +1,29 @@
 /// Custom serde de/serializer for the TERA network protocol.
+///
+/// Fields are encoded by their position. Field names are ignored, so `#[serde(rename)]` has no
+/// effect on the encoding. Fields marked with `#[serde(skip)]` are neither written nor read and
+/// are set to their default value when decoding. Only skip a field in both directions, since
+/// `skip_serializing`, `skip_deserializing` and `skip_serializing_if` alone change the position
+/// of the following fields in only one direction.
 mod de;
+mod dynamic;
 mod error;
+mod pool;
 mod ser;
+mod types;
 
-pub use de::{from_vec, Deserializer};
+#[cfg(test)]
+pub(crate) use de::from_vec_checked;
+pub use de::{from_vec, from_vec_with_layout, Deserializer, FieldLayout, MAX_STRING_LENGTH};
+pub use dynamic::{from_vec_dynamic, DynField, DynType, DynValue};
 pub use error::{Error, Result};
-pub use ser::{to_vec, Serializer};
+pub use pool::DeserializerPool;
+pub use ser::{
+    pad_to_min_length, to_vec, to_vec_into, to_vec_into_with_schema_version,
+    to_vec_with_max_length, to_vec_with_min_length, Serializer,
+};
+pub use types::{
+    Boxed, Checksum, ChecksumAlgorithm, Checksummed, Conditional, ConditionalSeed, CountPrefixed,
+    Crc32, Crc32c, DiscriminantWidth, EnumWidth, FlagSet, Flags, InlineBytes, MaybeMissing,
+    NarrowEnum, SchemaVersion, SinceVersion, TrailingBytes, UnknownBits, VersionBound,
+};
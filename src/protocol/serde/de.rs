@@ -1,15 +1,74 @@
 /// Implements the de-serialization of the TERA network protocol using serde.
 use super::error::{Error, Result};
+use super::types::{
+    ChecksumAlgorithm, DiscriminantWidth, SchemaVersion, BOXED_NAME, MAYBE_MISSING_NAME,
+    SINCE_VERSION_NAME, TRAILING_BYTES_NAME,
+};
+use crate::protocol::framing;
 use byteorder::{ByteOrder, LittleEndian};
 use serde::de::IntoDeserializer;
 use serde::{self, Deserialize};
-use std::str;
+
+/// Maximal number of UCS-2 units of a string. Strings of the protocol are names, chat messages
+/// and similar short texts, while a frame could hold a string almost four times as long. The
+/// limit keeps the buffers of a decoded string below 48 KiB. It's checked while looking for the
+/// null terminator, so an unterminated string can't make the deserializer allocate a buffer for
+/// the whole packet. The serializer refuses longer strings as well.
+pub const MAX_STRING_LENGTH: usize = 8192;
 
 /// A Deserializer that reads bytes from a vector.
 #[derive(Clone, Debug)]
 pub struct Deserializer {
     data: Vec<u8>,
     pos: usize,
+    // End of the fixed size region of the packet. Strings, bytes and arrays are stored behind it.
+    fixed_end: usize,
+    // Name of the struct that is currently read. Used for the error context.
+    struct_name: &'static str,
+    // Records the positions of the fields. Only used by `from_vec_with_layout`.
+    layout: Option<LayoutRecorder>,
+    // Schema version of the client. Selects the layout of `SinceVersion` fields.
+    schema_version: Option<SchemaVersion>,
+    // Width of the discriminant of the next enum. Set by a `NarrowEnum`.
+    enum_width: Option<DiscriminantWidth>,
+    // Regions behind resolved offsets. Only tracked in tests to verify the framing math.
+    #[cfg(test)]
+    regions: Vec<OffsetRegion>,
+}
+
+/// A region of the packet body that was reached over an offset.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct OffsetRegion {
+    kind: OffsetRegionKind,
+    start: usize,
+    len: usize,
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OffsetRegionKind {
+    Boxed,
+    String,
+    Bytes,
+    SeqEntry,
+}
+
+/// The position and size of a field inside of the packet body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldLayout {
+    /// Path of the field. Fields of nested structs are separated by dots.
+    pub name: String,
+    pub offset: usize,
+    /// Number of bytes the field takes up at its offset. Fields that are referenced by an offset
+    /// (strings, bytes, arrays) only count the bytes of the reference.
+    pub size: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct LayoutRecorder {
+    prefix: String,
+    fields: Vec<FieldLayout>,
 }
 
 // TODO we are currently too trustworthy with the client data and need to fet it more (we sometimes can get out of a slice boundary!)
@@ -24,20 +83,308 @@ where
     Ok(t)
 }
 
+/// Parses the given `Vec<u8>` and records the position and size of every field that was read.
+/// Meant to document and verify packet layouts against captured packets.
+pub fn from_vec_with_layout<'a, T>(v: Vec<u8>) -> Result<(T, Vec<FieldLayout>)>
+where
+    T: Deserialize<'a>,
+{
+    let mut deserializer = Deserializer::from_vec(v);
+    deserializer.layout = Some(LayoutRecorder::default());
+    let t = T::deserialize(&mut deserializer)?;
+    let layout = deserializer.layout.take().unwrap_or_default();
+    Ok((t, layout.fields))
+}
+
+/// Parses the given `Vec<u8>` and asserts that every region that was reached over an offset
+/// lies inside of the packet body. String regions and the headers of array entries are made
+/// of u16 values, so they also need to span whole 2 byte units.
+#[cfg(test)]
+pub(crate) fn from_vec_checked<'a, T>(v: Vec<u8>) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    let mut deserializer = Deserializer::from_vec(v);
+    let t = T::deserialize(&mut deserializer)?;
+    deserializer.assert_regions_in_frame();
+    Ok(t)
+}
+
 impl<'de> Deserializer {
     /// Creates a new Deserializer with a given `Vec<u8>`.
     pub fn from_vec(r: Vec<u8>) -> Self {
-        Deserializer { data: r, pos: 0 }
+        let fixed_end = r.len();
+        Deserializer {
+            data: r,
+            pos: 0,
+            fixed_end,
+            struct_name: "<root>",
+            layout: None,
+            schema_version: None,
+            enum_width: None,
+            #[cfg(test)]
+            regions: Vec::new(),
+        }
     }
 
-    fn abs_offset(&self, offset: usize) -> usize {
-        // The array we have doesn't include the leading opcode / length u16, so -4 bytes
-        if offset == 0 {
-            offset
-        } else {
-            offset - 4
+    /// Clears the state of the last read and resizes the buffer to the given length. The
+    /// buffer is zeroed and keeps it's capacity.
+    pub fn reset(&mut self, len: usize) {
+        self.data.clear();
+        self.data.resize(len, 0);
+        self.pos = 0;
+        self.fixed_end = len;
+        self.struct_name = "<root>";
+        self.layout = None;
+        self.enum_width = None;
+        #[cfg(test)]
+        self.regions.clear();
+    }
+
+    /// Decodes a packet that is followed by a checksum. The checksum is cut off the data while
+    /// the packet is decoded, so the packet sees the end of the frame where the checksum starts.
+    fn deserialize_checksummed<V>(
+        &mut self,
+        algorithm: ChecksumAlgorithm,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let checksum_pos = self
+            .data
+            .len()
+            .checked_sub(4)
+            .ok_or_else(|| Error::UnexpectedEof(self.struct_name, self.data.len()))?;
+        let trailer = self.data.split_off(checksum_pos);
+        let old_fixed_end = self.fixed_end;
+        self.fixed_end = self.fixed_end.min(checksum_pos);
+
+        let value = visitor.visit_newtype_struct(&mut *self);
+
+        let actual = algorithm.checksum(&self.data);
+        self.data.extend_from_slice(&trailer);
+        self.fixed_end = old_fixed_end;
+        let value = value?;
+
+        let expected = LittleEndian::read_u32(&trailer);
+        if expected != actual {
+            return Err(Error::ChecksumMismatch(expected, actual));
         }
+        Ok(value)
+    }
+
+    /// Sets the schema version the packets are decoded with. It's kept when the deserializer is
+    /// reset. Without a version the newest layout is used.
+    pub fn set_schema_version(&mut self, schema_version: Option<SchemaVersion>) {
+        self.schema_version = schema_version;
+    }
+
+    /// Returns the packet data.
+    pub fn buffer(&self) -> &[u8] {
+        &self.data
     }
+
+    /// Returns the buffer the packet data is read from.
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    #[cfg(test)]
+    fn record_region(&mut self, kind: OffsetRegionKind, start: usize, len: usize) {
+        self.regions.push(OffsetRegion { kind, start, len });
+    }
+
+    #[cfg(test)]
+    fn assert_regions_in_frame(&self) {
+        for region in &self.regions {
+            assert!(
+                region.start < self.data.len(),
+                "{:?} starts outside of the body (len {})",
+                region,
+                self.data.len()
+            );
+            assert!(
+                region.start + region.len <= self.data.len(),
+                "{:?} ends outside of the body (len {})",
+                region,
+                self.data.len()
+            );
+            if region.kind == OffsetRegionKind::String || region.kind == OffsetRegionKind::SeqEntry
+            {
+                assert_eq!(
+                    region.len % 2,
+                    0,
+                    "{:?} doesn't span whole u16 values",
+                    region
+                );
+            }
+        }
+    }
+
+    /// Starts the layout entry of a field at the current position.
+    fn begin_field(&mut self, name: &str) -> Option<(usize, usize)> {
+        let pos = self.pos;
+        self.layout.as_mut().map(|recorder| {
+            let entry = recorder.fields.len();
+            recorder.fields.push(FieldLayout {
+                name: format!("{}{}", recorder.prefix, name),
+                offset: pos,
+                size: 0,
+            });
+            let prefix_len = recorder.prefix.len();
+            recorder.prefix.push_str(name);
+            recorder.prefix.push('.');
+            (entry, prefix_len)
+        })
+    }
+
+    /// Finishes the layout entry that was started by `begin_field`.
+    fn end_field(&mut self, started: Option<(usize, usize)>) {
+        let pos = self.pos;
+        if let (Some(recorder), Some((entry, prefix_len))) = (self.layout.as_mut(), started) {
+            let field = &mut recorder.fields[entry];
+            field.size = pos.saturating_sub(field.offset);
+            recorder.prefix.truncate(prefix_len);
+        }
+    }
+
+    /// Makes sure that `size` more bytes can be read at the current position.
+    fn check_remaining(&self, size: usize) -> Result<()> {
+        self.checked_end(self.pos, size).map(|_| ())
+    }
+
+    /// Returns the end of the `size` bytes starting at `start` if all of them lie inside of the
+    /// data.
+    fn checked_end(&self, start: usize, size: usize) -> Result<usize> {
+        match start.checked_add(size) {
+            Some(end) if end <= self.data.len() => Ok(end),
+            _ => Err(Error::UnexpectedEof(self.struct_name, start)),
+        }
+    }
+
+    /// Reads the next `size` bytes and moves the position behind them.
+    fn read_bytes(&mut self, size: usize) -> Result<&[u8]> {
+        let start = self.pos;
+        let end = self.checked_end(start, size)?;
+        self.pos = end;
+        Ok(&self.data[start..end])
+    }
+
+    /// Moves to the array entry at the given offset and reads it's header. Returns the offset of
+    /// the next entry. Arrays are linked lists, so every entry knows it's successor. The index of
+    /// the entry is only used to report a broken list.
+    fn enter_seq_entry(&mut self, entry_offset: usize, index: usize) -> Result<usize> {
+        if entry_offset >= self.data.len() {
+            return Err(Error::OffsetOutsideData(self.pos, entry_offset));
+        }
+        self.pos = entry_offset;
+        self.check_remaining(4)?;
+        #[cfg(test)]
+        self.record_region(OffsetRegionKind::SeqEntry, entry_offset, 4);
+
+        let tmp_offset: usize = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let abs_offset: usize = self.abs_offset(tmp_offset)?;
+        if abs_offset != entry_offset {
+            return Err(Error::InvalidSeqEntry(index, entry_offset, abs_offset));
+        }
+
+        let tmp_offset: usize = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        self.abs_offset(tmp_offset)
+    }
+
+    fn abs_offset(&mut self, offset: usize) -> Result<usize> {
+        // Offsets that point into the frame header can't reference data of the body.
+        if offset != 0 && offset < framing::HEADER_LENGTH {
+            return Err(Error::OffsetOutsideData(self.pos, offset));
+        }
+        let abs_offset = framing::frame_offset_to_body(offset);
+        // The first dynamic data marks the end of the fixed size region.
+        if offset != 0 && abs_offset < self.fixed_end {
+            self.fixed_end = abs_offset;
+        }
+        Ok(abs_offset)
+    }
+}
+
+macro_rules! impl_read_le {
+    ($(#[$attr:meta])* $ty:ty, $method:ident, $reader_method:ident, $size:literal) => {
+        $(#[$attr])*
+        pub fn $method(&mut self) -> Result<$ty> {
+            Ok(LittleEndian::$reader_method(self.read_bytes($size)?))
+        }
+    };
+}
+
+/// Bounds checked readers for hand written `Deserialize` implementations. All values of the TERA
+/// network protocol are little endian. A failed read doesn't move the position.
+impl Deserializer {
+    /// Reads a `u8` at the current position and moves the position behind it.
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Reads an `i8` at the current position and moves the position behind it.
+    pub fn read_i8(&mut self) -> Result<i8> {
+        Ok(self.read_bytes(1)?[0] as i8)
+    }
+
+    impl_read_le!(
+        /// Reads a little endian `u16` at the current position and moves the position behind it.
+        u16,
+        read_u16_le,
+        read_u16,
+        2
+    );
+    impl_read_le!(
+        /// Reads a little endian `i16` at the current position and moves the position behind it.
+        i16,
+        read_i16_le,
+        read_i16,
+        2
+    );
+    impl_read_le!(
+        /// Reads a little endian `u32` at the current position and moves the position behind it.
+        u32,
+        read_u32_le,
+        read_u32,
+        4
+    );
+    impl_read_le!(
+        /// Reads a little endian `i32` at the current position and moves the position behind it.
+        i32,
+        read_i32_le,
+        read_i32,
+        4
+    );
+    impl_read_le!(
+        /// Reads a little endian `u64` at the current position and moves the position behind it.
+        u64,
+        read_u64_le,
+        read_u64,
+        8
+    );
+    impl_read_le!(
+        /// Reads a little endian `i64` at the current position and moves the position behind it.
+        i64,
+        read_i64_le,
+        read_i64,
+        8
+    );
+    impl_read_le!(
+        /// Reads a little endian `f32` at the current position and moves the position behind it.
+        f32,
+        read_f32_le,
+        read_f32,
+        4
+    );
+    impl_read_le!(
+        /// Reads a little endian `f64` at the current position and moves the position behind it.
+        f64,
+        read_f64_le,
+        read_f64,
+        8
+    );
 }
 
 macro_rules! impl_nums {
@@ -47,8 +394,7 @@ macro_rules! impl_nums {
         where
             V: serde::de::Visitor<'de>,
         {
-            let d = LittleEndian::$reader_method(&self.data[self.pos..self.pos + $size]);
-            self.pos += $size;
+            let d = LittleEndian::$reader_method(self.read_bytes($size)?);
             visitor.$visitor_method(d)
         }
     };
@@ -84,8 +430,8 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.pos += 1;
-        visitor.visit_i8(self.data[self.pos - 1] as i8)
+        let b = self.read_bytes(1)?[0];
+        visitor.visit_i8(b as i8)
     }
 
     #[inline]
@@ -93,8 +439,8 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
     where
         V: serde::de::Visitor<'de>,
     {
-        self.pos += 1;
-        visitor.visit_u8(self.data[self.pos - 1])
+        let b = self.read_bytes(1)?[0];
+        visitor.visit_u8(b)
     }
 
     impl_nums!(u16, deserialize_u16, visit_u16, read_u16, 2);
@@ -118,30 +464,37 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
     where
         V: serde::de::Visitor<'de>,
     {
-        let tmp_offset = LittleEndian::read_u16(&self.data[self.pos..self.pos + 2]) as usize;
-        let abs_pos = self.abs_offset(tmp_offset as usize);
-        self.pos += 2;
+        let tmp_offset = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let abs_pos = self.abs_offset(tmp_offset)?;
 
         if abs_pos >= self.data.len() {
             return Err(Error::OffsetOutsideData(self.pos, abs_pos));
         }
 
-        for i in (abs_pos..self.data.len()).step_by(2) {
+        // A trailing odd byte can't hold a null terminator.
+        for i in (abs_pos..self.data.len() - 1).step_by(2) {
             // Look for null terminator
             if self.data[i] == 0 && self.data[i + 1] == 0 {
+                #[cfg(test)]
+                self.record_region(OffsetRegionKind::String, abs_pos, i + 2 - abs_pos);
+
                 let mut aligned = vec![0u16; (i - abs_pos) / 2];
                 for (j, el) in aligned.iter_mut().enumerate() {
                     *el = LittleEndian::read_u16(&self.data[abs_pos + j * 2..abs_pos + j * 2 + 2]);
                 }
-                let mut utf8 = vec![0u8; aligned.len() * 3];
-                let size = ucs2::decode(&aligned, &mut utf8).unwrap();
-                let s: &str;
-
-                unsafe {
-                    s = str::from_utf8_unchecked(&utf8[..size]);
-                }
-
-                return visitor.visit_string(s.to_string());
+                // A UTF-8 code point needs at most 4 bytes.
+                let mut utf8 = vec![0u8; aligned.len() * 4];
+                let size = ucs2::decode(&aligned, &mut utf8)
+                    .map_err(|_| Error::InvalidStringEncoding(abs_pos))?;
+                utf8.truncate(size);
+
+                // Surrogate units are not valid UCS-2 and would produce invalid UTF-8.
+                let s =
+                    String::from_utf8(utf8).map_err(|_| Error::InvalidStringEncoding(abs_pos))?;
+                return visitor.visit_string(s);
+            }
+            if (i - abs_pos) / 2 >= MAX_STRING_LENGTH {
+                return Err(Error::StringTooLong(self.pos));
             }
         }
         Err(Error::StringNotNullTerminated(self.pos))
@@ -166,18 +519,24 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
     where
         V: serde::de::Visitor<'de>,
     {
-        let tmp_offset = LittleEndian::read_u16(&self.data[self.pos..self.pos + 2]) as usize;
-        let abs_offset = self.abs_offset(tmp_offset as usize);
-        self.pos += 2;
+        self.check_remaining(4)?;
+        let tmp_offset = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let abs_offset = self.abs_offset(tmp_offset)?;
 
-        let len = LittleEndian::read_u16(&self.data[self.pos..self.pos + 2]) as usize;
-        self.pos += 2;
+        let len = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
 
-        if (abs_offset + len as usize) > self.data.len() {
-            return Err(Error::BytesTooBig(self.pos));
-        };
+        let end = self
+            .checked_end(abs_offset, len)
+            .map_err(|_| Error::BytesTooBig(self.pos))?;
 
-        let b = &self.data[abs_offset..abs_offset + len as usize];
+        #[cfg(test)]
+        {
+            if len > 0 {
+                self.record_region(OffsetRegionKind::Bytes, abs_offset, len);
+            }
+        }
+
+        let b = &self.data[abs_offset..end];
         visitor.visit_byte_buf(b.to_vec())
     }
 
@@ -203,11 +562,94 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
         visitor.visit_unit()
     }
 
-    fn deserialize_newtype_struct<V>(self, _name: &str, visitor: V) -> Result<V::Value>
+    fn deserialize_newtype_struct<V>(self, name: &str, visitor: V) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        visitor.visit_newtype_struct(self)
+        if name == TRAILING_BYTES_NAME {
+            // The blob ends where the data pool starts. The data itself is only the body of
+            // the current frame.
+            if self.pos > self.fixed_end {
+                return Err(Error::UnexpectedEof(self.struct_name, self.pos));
+            }
+            let b = self.data[self.pos..self.fixed_end].to_vec();
+            self.pos = self.fixed_end;
+            return visitor.visit_byte_buf(b);
+        }
+        if name == SINCE_VERSION_NAME {
+            struct Access<'a> {
+                deserializer: &'a mut Deserializer,
+                schema_version_read: bool,
+            }
+
+            impl<'de, 'a> serde::de::SeqAccess<'de> for Access<'a> {
+                type Error = Error;
+
+                fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+                where
+                    T: serde::de::DeserializeSeed<'de>,
+                {
+                    if self.schema_version_read {
+                        return seed.deserialize(&mut *self.deserializer).map(Some);
+                    }
+                    self.schema_version_read = true;
+                    // Without a negotiated schema version the newest layout is used.
+                    let schema_version = self
+                        .deserializer
+                        .schema_version
+                        .map_or(std::u32::MAX, |schema_version| schema_version.0);
+                    let value: Result<_> = seed.deserialize(schema_version.into_deserializer());
+                    value.map(Some)
+                }
+            }
+
+            // The field decides with the schema version of the connection if it's part of the
+            // layout.
+            return visitor.visit_seq(Access {
+                deserializer: self,
+                schema_version_read: false,
+            });
+        }
+        if name == MAYBE_MISSING_NAME {
+            // Trailing fields that newer clients send are missing if the fixed size region
+            // ends before them.
+            if self.pos >= self.fixed_end {
+                return visitor.visit_none();
+            }
+            return visitor.visit_some(self);
+        }
+        if let Some(algorithm) = ChecksumAlgorithm::from_newtype_name(name) {
+            return self.deserialize_checksummed(algorithm, visitor);
+        }
+        if let Some(width) = DiscriminantWidth::from_newtype_name(name) {
+            self.enum_width = Some(width);
+            let value = visitor.visit_newtype_struct(&mut *self);
+            // Don't leak the width to a later enum if the wrapped type wasn't an enum.
+            self.enum_width = None;
+            return value;
+        }
+        if name != BOXED_NAME {
+            return visitor.visit_newtype_struct(self);
+        }
+
+        // A boxed struct is referenced by an offset like a string.
+        let tmp_offset = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let abs_pos = self.abs_offset(tmp_offset)?;
+
+        if abs_pos >= self.data.len() {
+            return Err(Error::OffsetOutsideData(self.pos, abs_pos));
+        }
+
+        let old_pos = self.pos;
+        self.pos = abs_pos;
+        let value = visitor.visit_newtype_struct(&mut *self);
+        #[cfg(test)]
+        {
+            let len = self.pos.saturating_sub(abs_pos);
+            self.record_region(OffsetRegionKind::Boxed, abs_pos, len);
+        }
+        self.pos = old_pos;
+        value
     }
 
     fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
@@ -217,9 +659,8 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
         struct Access<'a> {
             deserializer: &'a mut Deserializer,
             count: usize,
-            data_len: usize,
+            index: usize,
             next_offset: usize,
-            old_pos: usize,
         }
 
         impl<'de, 'a, 'b: 'a> serde::de::SeqAccess<'de> for Access<'a> {
@@ -231,39 +672,15 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
             {
                 if self.count > 0 {
                     self.count -= 1;
-
-                    // The array is a linked list
-                    if self.next_offset >= self.data_len {
-                        return Err(Error::OffsetOutsideData(
-                            self.deserializer.pos,
-                            self.next_offset,
-                        ));
-                    }
-                    self.deserializer.pos = self.next_offset;
-
-                    let tmp_offset: usize = LittleEndian::read_u16(
-                        &self.deserializer.data[self.deserializer.pos..self.deserializer.pos + 2],
-                    ) as usize;
-                    let abs_offset: usize = self.deserializer.abs_offset(tmp_offset);
-                    self.deserializer.pos += 2;
-
-                    if abs_offset != self.next_offset {
-                        return Err(Error::InvalidSeqEntry(abs_offset));
-                    }
-
-                    let tmp_offset: usize = LittleEndian::read_u16(
-                        &self.deserializer.data[self.deserializer.pos..self.deserializer.pos + 2],
-                    ) as usize;
-                    let abs_offset: usize = self.deserializer.abs_offset(tmp_offset);
-                    self.next_offset = abs_offset;
-                    self.deserializer.pos += 2;
+                    self.next_offset = self
+                        .deserializer
+                        .enter_seq_entry(self.next_offset, self.index)?;
+                    self.index += 1;
 
                     let value =
                         serde::de::DeserializeSeed::deserialize(seed, &mut *self.deserializer)?;
                     Ok(Some(value))
                 } else {
-                    // Return to the end of the array header
-                    self.deserializer.pos = self.old_pos;
                     Ok(None)
                 }
             }
@@ -273,22 +690,25 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
             }
         }
 
-        let count: usize = LittleEndian::read_u16(&self.data[self.pos..self.pos + 2]) as usize;
-        self.pos += 2;
-        let tmp_offset: usize = LittleEndian::read_u16(&self.data[self.pos..self.pos + 2]) as usize;
-        let next_offset: usize = self.abs_offset(tmp_offset);
-        self.pos += 2;
+        self.check_remaining(4)?;
+        let count: usize = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let tmp_offset: usize = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let next_offset: usize = self.abs_offset(tmp_offset)?;
 
         let old_pos = self.pos;
-        let data_len = self.data.len();
 
-        visitor.visit_seq(Access {
-            deserializer: self,
+        let value = visitor.visit_seq(Access {
+            deserializer: &mut *self,
             count,
-            data_len,
+            index: 0,
             next_offset,
-            old_pos,
-        })
+        })?;
+
+        // Return to the end of the array header. Every seq keeps it's own position on the
+        // stack, so nested arrays can't clobber the position of the outer array. The position
+        // is also restored if the visitor stopped before the end of the array.
+        self.pos = old_pos;
+        Ok(value)
     }
 
     fn deserialize_tuple<V>(self, count: usize, visitor: V) -> Result<V::Value>
@@ -340,50 +760,175 @@ impl<'de, 'a> serde::Deserializer<'de> for &'a mut Deserializer {
         self.deserialize_tuple(len, visitor)
     }
 
-    fn deserialize_map<V>(self, _visitor: V) -> Result<V::Value>
+    // Maps are stored like arrays. Every entry holds the key followed by the value.
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        Err(Error::DeserializeMapNotSupported(self.pos))
+        struct Access<'a> {
+            deserializer: &'a mut Deserializer,
+            count: usize,
+            index: usize,
+            next_offset: usize,
+        }
+
+        impl<'de, 'a> serde::de::MapAccess<'de> for Access<'a> {
+            type Error = Error;
+
+            fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+            where
+                K: serde::de::DeserializeSeed<'de>,
+            {
+                if self.count > 0 {
+                    self.count -= 1;
+                    self.next_offset = self
+                        .deserializer
+                        .enter_seq_entry(self.next_offset, self.index)?;
+                    self.index += 1;
+                    serde::de::DeserializeSeed::deserialize(seed, &mut *self.deserializer).map(Some)
+                } else {
+                    Ok(None)
+                }
+            }
+
+            fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+            where
+                V: serde::de::DeserializeSeed<'de>,
+            {
+                serde::de::DeserializeSeed::deserialize(seed, &mut *self.deserializer)
+            }
+
+            fn size_hint(&self) -> Option<usize> {
+                Some(self.count)
+            }
+        }
+
+        self.check_remaining(4)?;
+        let count: usize = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let tmp_offset: usize = LittleEndian::read_u16(self.read_bytes(2)?) as usize;
+        let next_offset: usize = self.abs_offset(tmp_offset)?;
+
+        let old_pos = self.pos;
+        let value = visitor.visit_map(Access {
+            deserializer: &mut *self,
+            count,
+            index: 0,
+            next_offset,
+        })?;
+        self.pos = old_pos;
+        Ok(value)
     }
 
     fn deserialize_struct<V>(
         self,
-        _name: &str,
+        name: &'static str,
         fields: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        self.deserialize_tuple(fields.len(), visitor)
+        struct FieldAccess<'a> {
+            deserializer: &'a mut Deserializer,
+            fields: &'static [&'static str],
+            index: usize,
+        }
+
+        impl<'de, 'a> serde::de::SeqAccess<'de> for FieldAccess<'a> {
+            type Error = Error;
+
+            fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+            where
+                T: serde::de::DeserializeSeed<'de>,
+            {
+                match self.fields.get(self.index) {
+                    Some(name) => {
+                        self.index += 1;
+                        let started = self.deserializer.begin_field(name);
+                        let value =
+                            serde::de::DeserializeSeed::deserialize(seed, &mut *self.deserializer)?;
+                        self.deserializer.end_field(started);
+                        Ok(Some(value))
+                    }
+                    None => Ok(None),
+                }
+            }
+
+            fn size_hint(&self) -> Option<usize> {
+                Some(self.fields.len() - self.index)
+            }
+        }
+
+        let parent_name = self.struct_name;
+        self.struct_name = name;
+        let value = if self.layout.is_some() {
+            visitor.visit_seq(FieldAccess {
+                deserializer: &mut *self,
+                fields,
+                index: 0,
+            })
+        } else {
+            serde::Deserializer::deserialize_tuple(&mut *self, fields.len(), visitor)
+        };
+        self.struct_name = parent_name;
+        value
     }
 
     fn deserialize_enum<V>(
         self,
-        _enum: &'static str,
-        _variants: &'static [&'static str],
+        name: &'static str,
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        impl<'de, 'a> serde::de::EnumAccess<'de> for &'a mut Deserializer {
+        struct Access<'a> {
+            deserializer: &'a mut Deserializer,
+            name: &'static str,
+            variants: &'static [&'static str],
+            width: DiscriminantWidth,
+        }
+
+        impl<'de, 'a> serde::de::EnumAccess<'de> for Access<'a> {
             type Error = Error;
-            type Variant = Self;
+            type Variant = &'a mut Deserializer;
 
             fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant)>
             where
                 V: serde::de::DeserializeSeed<'de>,
             {
-                // Enums in packets have to be a u32!
-                let idx: u32 = serde::de::Deserialize::deserialize(&mut *self)?;
+                let pos = self.deserializer.pos;
+                let idx = match self.width {
+                    DiscriminantWidth::U8 => u32::from(self.deserializer.read_u8()?),
+                    DiscriminantWidth::U16 => u32::from(self.deserializer.read_u16()?),
+                    DiscriminantWidth::U32 => self.deserializer.read_u32()?,
+                };
+                // A narrow enum that is read as u32 takes the following bytes as part of it's
+                // discriminant, which then doesn't name a variant anymore.
+                if idx as usize >= self.variants.len() {
+                    return Err(Error::InvalidEnumDiscriminant(self.name, idx, pos));
+                }
                 let val: Result<_> = seed.deserialize(idx.into_deserializer());
-                Ok((val?, self))
+                Ok((val?, self.deserializer))
             }
         }
 
-        visitor.visit_enum(self)
+        // Enums in packets are a u32, if they are not a `NarrowEnum`.
+        let width = self.enum_width.take().unwrap_or(DiscriminantWidth::U32);
+        debug_assert!(
+            variants.len() as u64 <= width.max_variants(),
+            "enum {} has {} variants, which don't fit into a {:?} discriminant",
+            name,
+            variants.len(),
+            width
+        );
+        visitor.visit_enum(Access {
+            deserializer: self,
+            name,
+            variants,
+            width,
+        })
     }
 
     fn deserialize_identifier<V>(self, _visitor: V) -> Result<V::Value>
@@ -437,9 +982,57 @@ impl<'de, 'a> serde::de::VariantAccess<'de> for &'a mut Deserializer {
 // The serializer and deserializer are tested in the packet definition with real world data.
 #[cfg(test)]
 mod tests {
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
     use super::*;
+    use crate::protocol::packet::{CPong, SCheckVersion, SLoginArbiter};
+    use crate::protocol::serde::{to_vec, Boxed};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn test_read_le() -> Result<()> {
+        let mut deserializer = Deserializer::from_vec(vec![
+            0xff, 0xfe, 0x1, 0x2, 0xfe, 0xff, 0x1, 0x2, 0x3, 0x4, 0xfe, 0xff, 0xff, 0xff, 0x1, 0x2,
+            0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0, 0x0,
+            0xc0, 0x3f, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0xf8, 0x3f,
+        ]);
+        assert_eq!(deserializer.read_u8()?, 0xff);
+        assert_eq!(deserializer.read_i8()?, -2);
+        assert_eq!(deserializer.read_u16_le()?, 0x0201);
+        assert_eq!(deserializer.read_i16_le()?, -2);
+        assert_eq!(deserializer.read_u32_le()?, 0x0403_0201);
+        assert_eq!(deserializer.read_i32_le()?, -2);
+        assert_eq!(deserializer.read_u64_le()?, 0x0807_0605_0403_0201);
+        assert_eq!(deserializer.read_i64_le()?, -2);
+        assert!((deserializer.read_f32_le()? - 1.5).abs() < std::f32::EPSILON);
+        assert!((deserializer.read_f64_le()? - 1.5).abs() < std::f64::EPSILON);
+        assert_eq!(deserializer.pos, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_le_eof() {
+        fn assert_eof<T: std::fmt::Debug>(read: fn(&mut Deserializer) -> Result<T>, size: usize) {
+            let mut deserializer = Deserializer::from_vec(vec![0x0; size - 1]);
+            match read(&mut deserializer) {
+                Err(Error::UnexpectedEof(_, 0)) => { /* Expected result */ }
+                v => panic!("Expected an UnexpectedEof error, got {:?}", v),
+            }
+            // A failed read doesn't move the position.
+            assert_eq!(deserializer.pos, 0);
+        }
+
+        assert_eof(Deserializer::read_u8, 1);
+        assert_eof(Deserializer::read_i8, 1);
+        assert_eof(Deserializer::read_u16_le, 2);
+        assert_eof(Deserializer::read_i16_le, 2);
+        assert_eof(Deserializer::read_u32_le, 4);
+        assert_eof(Deserializer::read_i32_le, 4);
+        assert_eof(Deserializer::read_u64_le, 8);
+        assert_eof(Deserializer::read_i64_le, 8);
+        assert_eof(Deserializer::read_f32_le, 4);
+        assert_eof(Deserializer::read_f64_le, 8);
+    }
 
     #[test]
     fn test_primitive_struct() -> Result<()> {
@@ -465,4 +1058,501 @@ mod tests {
         assert_eq!(str, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_field_layout() -> Result<()> {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct SimpleStruct {
+            a: u8,
+            b: i8,
+            c: f32,
+            d: f64,
+        }
+
+        let data = vec![
+            0x12, 0xf3, 0xCD, 0xCC, 0x0C, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f,
+        ];
+
+        let (value, layout) = from_vec_with_layout::<SimpleStruct>(data.clone())?;
+        assert_eq!(value, from_vec::<SimpleStruct>(data)?);
+        let layout: Vec<_> = layout
+            .iter()
+            .map(|field| (field.name.as_str(), field.offset, field.size))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![("a", 0, 1), ("b", 1, 1), ("c", 2, 4), ("d", 6, 8)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_clears_layout() -> Result<()> {
+        let mut deserializer = Deserializer::from_vec(vec![0x12, 0x34]);
+        deserializer.layout = Some(LayoutRecorder::default());
+        assert_eq!(u16::deserialize(&mut deserializer)?, 0x3412);
+
+        deserializer.reset(2);
+        assert!(deserializer.layout.is_none());
+        deserializer.buffer_mut().copy_from_slice(&[0x56, 0x78]);
+        assert_eq!(u16::deserialize(&mut deserializer)?, 0x7856);
+        assert!(deserializer.layout.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_field_layout() -> Result<()> {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Inner {
+            x: u16,
+            y: u32,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Outer {
+            name: String,
+            inner: Inner,
+            tail: u8,
+        }
+
+        let data = vec![
+            0xd, 0x0, 0x1, 0x0, 0x2, 0x0, 0x0, 0x0, 0x3, 0x41, 0x0, 0x0, 0x0,
+        ];
+
+        let (_, layout) = from_vec_with_layout::<Outer>(data)?;
+        let layout: Vec<_> = layout
+            .iter()
+            .map(|field| (field.name.as_str(), field.offset, field.size))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                ("name", 0, 2),
+                ("inner", 2, 6),
+                ("inner.x", 2, 2),
+                ("inner.y", 4, 4),
+                ("tail", 8, 1),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_primitive_struct_too_short() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct SimpleStruct {
+            a: u8,
+            b: i8,
+            c: f32,
+            d: f64,
+        }
+
+        let data = vec![
+            0x12, 0xf3, 0xCD, 0xCC, 0x0C, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0,
+        ];
+
+        match from_vec::<SimpleStruct>(data) {
+            Err(Error::UnexpectedEof(name, pos)) => {
+                assert_eq!(name, "SimpleStruct");
+                assert_eq!(pos, 6);
+            }
+            v => panic!("Expected an UnexpectedEof error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_option_not_supported() {
+        // Only trailing fields can be missing, and only with `MaybeMissing`.
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct OptionStruct {
+            a: u8,
+            b: Option<u16>,
+            c: u8,
+        }
+
+        match from_vec::<OptionStruct>(vec![0x01, 0x02, 0x00, 0x03]) {
+            Err(Error::DeserializeOptionNotSupported(pos)) => assert_eq!(pos, 1),
+            v => panic!(
+                "Expected a DeserializeOptionNotSupported error, got {:?}",
+                v
+            ),
+        }
+    }
+
+    #[test]
+    fn test_empty_input() -> Result<()> {
+        match from_vec::<SCheckVersion>(vec![]) {
+            Err(Error::UnexpectedEof(name, pos)) => {
+                assert_eq!(name, "SCheckVersion");
+                assert_eq!(pos, 0);
+            }
+            v => panic!("Expected an UnexpectedEof error, got {:?}", v),
+        }
+        match from_vec::<SLoginArbiter>(vec![0x1, 0x0, 0x2]) {
+            Err(Error::UnexpectedEof(name, pos)) => {
+                assert_eq!(name, "SLoginArbiter");
+                assert_eq!(pos, 2);
+            }
+            v => panic!("Expected an UnexpectedEof error, got {:?}", v),
+        }
+
+        // Packets without fields don't need any bytes.
+        assert_eq!(from_vec::<CPong>(vec![])?, CPong {});
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct StringStruct {
+        s: String,
+    }
+
+    #[test]
+    fn test_string_multi_byte() -> Result<()> {
+        // Every unit of "€€€" needs the maximum of 3 UTF-8 bytes for the BMP.
+        let data = vec![0x6, 0x0, 0xac, 0x20, 0xac, 0x20, 0xac, 0x20, 0x0, 0x0];
+        let value = from_vec::<StringStruct>(data)?;
+        assert_eq!(value.s, "€€€");
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_too_long() -> Result<()> {
+        let mut data = vec![0x6, 0x0];
+        data.extend(std::iter::repeat(0x41).take(MAX_STRING_LENGTH * 2));
+        data.extend_from_slice(&[0x0, 0x0]);
+        let value = from_vec::<StringStruct>(data)?;
+        assert_eq!(value.s.chars().count(), MAX_STRING_LENGTH);
+
+        // Aborts at the limit. The scan never reaches the terminator at the end of the packet,
+        // so no buffer for the rest of the packet is allocated.
+        let mut data = vec![0x6, 0x0];
+        data.extend(std::iter::repeat(0x41).take(framing::MAX_BODY_LENGTH - 4));
+        data.extend_from_slice(&[0x0, 0x0]);
+        match from_vec::<StringStruct>(data) {
+            Err(Error::StringTooLong(pos)) => assert_eq!(pos, 2),
+            v => panic!("Expected a StringTooLong error, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_surrogate_pair() {
+        // U+1D11E would need 4 UTF-8 bytes, but UCS-2 can't represent it.
+        let data = vec![0x6, 0x0, 0x34, 0xd8, 0x1e, 0xdd, 0x0, 0x0];
+        match from_vec::<StringStruct>(data) {
+            Err(Error::InvalidStringEncoding(pos)) => assert_eq!(pos, 2),
+            v => panic!("Expected an InvalidStringEncoding error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_nested_seq() -> Result<()> {
+        #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+        struct NestedStruct {
+            outer: Vec<Vec<u32>>,
+            tail: u16,
+        }
+
+        let value = NestedStruct {
+            outer: vec![vec![1, 2, 3], vec![], vec![4], vec![5, 6]],
+            tail: 0xbeef,
+        };
+        let data = to_vec(&value)?;
+        assert_eq!(from_vec::<NestedStruct>(data)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_map() -> Result<()> {
+        // An entry is laid out like an array entry that holds the key and the value.
+        let mut map = BTreeMap::new();
+        map.insert(1u16, 2u16);
+        let data = to_vec(&map)?;
+        assert_eq!(
+            data,
+            vec![0x1, 0x0, 0x8, 0x0, 0x8, 0x0, 0x0, 0x0, 0x1, 0x0, 0x2, 0x0]
+        );
+        assert_eq!(from_vec::<BTreeMap<u16, u16>>(data)?, map);
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_map() -> Result<()> {
+        #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+        struct MapStruct {
+            values: BTreeMap<u32, String>,
+            empty: BTreeMap<u32, String>,
+            tail: u16,
+        }
+
+        let mut value = MapStruct {
+            tail: 0xbeef,
+            ..Default::default()
+        };
+        value.values.insert(1, "One".to_string());
+        value.values.insert(7, "Seven".to_string());
+        value.values.insert(300, "".to_string());
+        value.values.insert(std::u32::MAX, "Max".to_string());
+
+        let data = to_vec(&value)?;
+        assert_eq!(from_vec::<MapStruct>(data.clone())?, value);
+        assert_eq!(from_vec_checked::<MapStruct>(data)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_map_is_sorted() -> Result<()> {
+        let entries: Vec<(u16, Vec<String>)> = (0..32u16)
+            .map(|i| (i * 31, vec![format!("Entry {}", i); (i % 3) as usize]))
+            .collect();
+        let forward: HashMap<u16, Vec<String>> = entries.iter().cloned().collect();
+        let backward: HashMap<u16, Vec<String>> = entries.iter().rev().cloned().collect();
+
+        // Both maps hash with different keys, so they iterate in a different order.
+        let data = to_vec(&forward)?;
+        assert_eq!(data, to_vec(&backward)?);
+        assert_eq!(
+            from_vec::<HashMap<u16, Vec<String>>>(data.clone())?,
+            forward
+        );
+        assert_eq!(
+            from_vec_checked::<HashMap<u16, Vec<String>>>(data)?,
+            forward
+        );
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct DynamicStruct {
+        name: String,
+        bytes: Vec<u8>,
+        values: Vec<Vec<u32>>,
+        boxed: Boxed<u64>,
+        tail: u16,
+    }
+
+    #[test]
+    fn test_malformed_seeds() {
+        // Malformed and truncated inputs need to fail with an error and never panic on an
+        // index or an arithmetic overflow.
+        let seeds: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x1],
+            vec![0x1, 0x0, 0x2, 0x0, 0x3, 0x0],
+            vec![0xff; 3],
+            vec![0xff; 15],
+            vec![0xff; 64],
+            vec![0x0; 15],
+            vec![
+                0x4, 0x0, 0x5, 0x0, 0x3, 0x0, 0x1, 0x0, 0x4, 0x0, 0x2, 0x0, 0x0, 0x0, 0x0,
+            ],
+            vec![
+                0x12, 0x0, 0x14, 0x0, 0xff, 0xff, 0x1, 0x0, 0x13, 0x0, 0x16, 0x0, 0x0, 0x0, 0x0,
+                0x41, 0x0, 0x0,
+            ],
+        ];
+        for seed in seeds {
+            for len in 0..=seed.len() {
+                let data = seed[..len].to_vec();
+                let _ = from_vec::<DynamicStruct>(data.clone());
+                let _ = from_vec::<StringStruct>(data.clone());
+                let _ = from_vec::<Vec<u8>>(data.clone());
+                let _ = from_vec::<Vec<Vec<u32>>>(data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seq_entry_mismatch_reports_index() {
+        // The second entry is linked at 0xe, but claims to be at 0x10.
+        let data = vec![
+            0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0xe, 0x0, 0x5, 0x0, 0x10, 0x0, 0x0, 0x0, 0x6, 0x0,
+        ];
+        match from_vec::<Vec<u16>>(data) {
+            Err(e @ Error::InvalidSeqEntry(..)) => {
+                assert_eq!(
+                    e.to_string(),
+                    "InvalidSeqEntry. Index: 1 Expected: 10 Actual: 12"
+                );
+                assert_eq!(e.pos(), Some(10));
+            }
+            v => panic!("Expected an InvalidSeqEntry error, got {:?}", v),
+        }
+
+        // Maps are linked the same way.
+        match from_vec::<HashMap<u16, u16>>(vec![
+            0x1, 0x0, 0x8, 0x0, 0xa, 0x0, 0x0, 0x0, 0x5, 0x0, 0x6, 0x0,
+        ]) {
+            Err(Error::InvalidSeqEntry(index, expected, actual)) => {
+                assert_eq!((index, expected, actual), (0, 4, 6))
+            }
+            v => panic!("Expected an InvalidSeqEntry error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_string_odd_length() {
+        // The string starts at the last byte, which can't hold a null terminator.
+        let data = vec![0x6, 0x0, 0x41];
+        match from_vec::<StringStruct>(data) {
+            Err(Error::StringNotNullTerminated(_)) => { /* Expected result */ }
+            v => panic!("Expected a StringNotNullTerminated error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_seq_entry_self_offset() -> Result<()> {
+        let data = vec![0x1, 0x0, 0x8, 0x0, 0x8, 0x0, 0x0, 0x0, 0x5, 0x0];
+        assert_eq!(from_vec::<Vec<u16>>(data)?, vec![5]);
+
+        // The entry at 0x8 claims to be at 0xa.
+        let data = vec![0x1, 0x0, 0x8, 0x0, 0xa, 0x0, 0x0, 0x0, 0x5, 0x0];
+        match from_vec::<Vec<u16>>(data) {
+            Err(Error::InvalidSeqEntry(index, expected, actual)) => {
+                assert_eq!((index, expected, actual), (0, 4, 6))
+            }
+            v => panic!("Expected an InvalidSeqEntry error, got {:?}", v),
+        }
+
+        // The entry claims to be inside of the frame header.
+        let data = vec![0x1, 0x0, 0x8, 0x0, 0x1, 0x0, 0x0, 0x0, 0x5, 0x0];
+        match from_vec::<Vec<u16>>(data) {
+            Err(Error::OffsetOutsideData(pos, offset)) => {
+                assert_eq!(pos, 6);
+                assert_eq!(offset, 1);
+            }
+            v => panic!("Expected an OffsetOutsideData error, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct OddStringStruct {
+        s: String,
+        a: u8,
+    }
+
+    #[test]
+    fn test_string_odd_offset() -> Result<()> {
+        // Strings aren't aligned. The odd sized fixed part puts the string at an odd offset.
+        let data = vec![0x7, 0x0, 0x1, 0x41, 0x0, 0x0, 0x0];
+        let expected = OddStringStruct {
+            s: "A".to_string(),
+            a: 1,
+        };
+        assert_eq!(expected, from_vec(data)?);
+
+        // The terminator would need the byte behind the data.
+        let data = vec![0x7, 0x0, 0x1, 0x41, 0x0, 0x0];
+        match from_vec::<OddStringStruct>(data) {
+            Err(Error::StringNotNullTerminated(_)) => { /* Expected result */ }
+            v => panic!("Expected a StringNotNullTerminated error, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_offset_inside_header() {
+        let data = vec![0x2, 0x0, 0x41, 0x0, 0x0, 0x0];
+        match from_vec::<StringStruct>(data) {
+            Err(Error::OffsetOutsideData(pos, offset)) => {
+                assert_eq!(pos, 2);
+                assert_eq!(offset, 2);
+            }
+            v => panic!("Expected an OffsetOutsideData error, got {:?}", v),
+        }
+    }
+
+    // Fields are encoded by their position, so field names don't matter and skipped fields
+    // neither consume nor produce bytes.
+    #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+    struct EvolvedStruct {
+        a: u16,
+        #[serde(skip)]
+        cached: u32,
+        #[serde(rename = "renamed")]
+        b: u8,
+        name: String,
+    }
+
+    #[test]
+    fn test_skipped_and_renamed_fields() -> Result<()> {
+        let data = vec![0x01, 0x00, 0x02, 0x09, 0x00, 0x41, 0x00, 0x00, 0x00];
+
+        let value = EvolvedStruct {
+            a: 1,
+            cached: 42,
+            b: 2,
+            name: "A".to_string(),
+        };
+        assert_eq!(to_vec(value.clone())?, data);
+
+        // The skipped field is set to it's default value.
+        let expected = EvolvedStruct { cached: 0, ..value };
+        assert_eq!(from_vec::<EvolvedStruct>(data.clone())?, expected);
+        assert_eq!(from_vec_checked::<EvolvedStruct>(data)?, expected);
+        Ok(())
+    }
+
+    macro_rules! endianness_test {
+        ($name:ident, $ty:ty, $($data:expr => $expected:expr),+ $(,)?) => {
+            #[test]
+            // All expected floats are exactly representable.
+            #[allow(clippy::float_cmp)]
+            fn $name() -> Result<()> {
+                $(
+                    let value: $ty = from_vec($data.to_vec())?;
+                    assert_eq!(value, $expected);
+                )+
+                Ok(())
+            }
+        };
+    }
+
+    endianness_test!(test_endianness_u16, u16,
+        [0x34, 0x12] => 0x1234,
+        [0xff, 0x00] => 255,
+        [0x00, 0xff] => 65_280,
+    );
+
+    endianness_test!(test_endianness_u32, u32,
+        [0x78, 0x56, 0x34, 0x12] => 0x1234_5678,
+        [0x01, 0x00, 0x00, 0x80] => 2_147_483_649,
+    );
+
+    endianness_test!(test_endianness_u64, u64,
+        [0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01] => 0x0123_4567_89ab_cdef,
+        [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80] => 9_223_372_036_854_775_809,
+    );
+
+    endianness_test!(test_endianness_i16, i16,
+        [0x34, 0x12] => 0x1234,
+        [0xfe, 0xff] => -2,
+        [0x00, 0x80] => i16::MIN,
+    );
+
+    endianness_test!(test_endianness_i32, i32,
+        [0x78, 0x56, 0x34, 0x12] => 0x1234_5678,
+        [0x85, 0xff, 0xff, 0xff] => -123,
+        [0x00, 0x00, 0x00, 0x80] => i32::MIN,
+    );
+
+    endianness_test!(test_endianness_i64, i64,
+        [0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01] => 0x0123_4567_89ab_cdef,
+        [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff] => -1,
+        [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80] => i64::MIN,
+    );
+
+    endianness_test!(test_endianness_f32, f32,
+        [0x00, 0x00, 0xc0, 0x3f] => 1.5,
+        [0x00, 0x00, 0x20, 0xc1] => -10.0,
+        [0x00, 0x00, 0x80, 0x3e] => 0.25,
+    );
+
+    endianness_test!(test_endianness_f64, f64,
+        [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x3f] => 1.5,
+        [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x24, 0xc0] => -10.0,
+        [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xd0, 0x3f] => 0.25,
+    );
 }
@@ -0,0 +1,197 @@
+/// Schema driven decoding of packets for tooling that doesn't know the concrete packet struct.
+use super::de::Deserializer;
+use super::error::Result;
+use serde::de::{DeserializeSeed, SeqAccess, Visitor};
+use std::fmt;
+
+/// Describes the type of a value inside a packet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DynType {
+    Bool,
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    String,
+    Bytes,
+    Seq(Box<DynType>),
+    Struct(Vec<DynField>),
+}
+
+/// A named field of a struct schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynField {
+    pub name: String,
+    pub ty: DynType,
+}
+
+impl DynField {
+    pub fn new(name: &str, ty: DynType) -> Self {
+        DynField {
+            name: name.to_string(),
+            ty,
+        }
+    }
+}
+
+/// A generic tree of decoded values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DynValue {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Seq(Vec<DynValue>),
+    Struct(Vec<(String, DynValue)>),
+}
+
+/// Parses the given `Vec<u8>` guided by the given schema.
+pub fn from_vec_dynamic(v: Vec<u8>, schema: &DynType) -> Result<DynValue> {
+    let mut deserializer = Deserializer::from_vec(v);
+    schema.deserialize(&mut deserializer)
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for &'a DynType {
+    type Value = DynValue;
+
+    fn deserialize<D>(self, deserializer: D) -> std::result::Result<DynValue, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let visitor = DynVisitor { ty: self };
+        match self {
+            DynType::Bool => deserializer.deserialize_bool(visitor),
+            DynType::U8 => deserializer.deserialize_u8(visitor),
+            DynType::I8 => deserializer.deserialize_i8(visitor),
+            DynType::U16 => deserializer.deserialize_u16(visitor),
+            DynType::I16 => deserializer.deserialize_i16(visitor),
+            DynType::U32 => deserializer.deserialize_u32(visitor),
+            DynType::I32 => deserializer.deserialize_i32(visitor),
+            DynType::U64 => deserializer.deserialize_u64(visitor),
+            DynType::I64 => deserializer.deserialize_i64(visitor),
+            DynType::F32 => deserializer.deserialize_f32(visitor),
+            DynType::F64 => deserializer.deserialize_f64(visitor),
+            DynType::String => deserializer.deserialize_string(visitor),
+            DynType::Bytes => deserializer.deserialize_byte_buf(visitor),
+            DynType::Seq(_) => deserializer.deserialize_seq(visitor),
+            // Structs are read like tuples, so we don't need static field names.
+            DynType::Struct(fields) => deserializer.deserialize_tuple(fields.len(), visitor),
+        }
+    }
+}
+
+struct DynVisitor<'a> {
+    ty: &'a DynType,
+}
+
+impl<'de, 'a> Visitor<'de> for DynVisitor<'a> {
+    type Value = DynValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a value of type {:?}", self.ty)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> std::result::Result<DynValue, E> {
+        Ok(DynValue::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> std::result::Result<DynValue, E> {
+        Ok(DynValue::Int(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> std::result::Result<DynValue, E> {
+        Ok(DynValue::UInt(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> std::result::Result<DynValue, E> {
+        Ok(DynValue::Float(v))
+    }
+
+    fn visit_string<E>(self, v: String) -> std::result::Result<DynValue, E> {
+        Ok(DynValue::String(v))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<DynValue, E> {
+        Ok(DynValue::Bytes(v))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<DynValue, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        match self.ty {
+            DynType::Seq(element) => {
+                let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(value) = seq.next_element_seed(element.as_ref())? {
+                    values.push(value);
+                }
+                Ok(DynValue::Seq(values))
+            }
+            DynType::Struct(fields) => {
+                let mut values = Vec::with_capacity(fields.len());
+                for field in fields {
+                    let value = seq.next_element_seed(&field.ty)?.ok_or_else(|| {
+                        serde::de::Error::custom(format!("missing field {}", field.name))
+                    })?;
+                    values.push((field.name.clone(), value));
+                }
+                Ok(DynValue::Struct(values))
+            }
+            _ => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::Seq,
+                &self,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::packet::CCheckVersion;
+    use crate::protocol::serde::from_vec;
+
+    #[test]
+    fn test_dynamic_check_version() -> Result<()> {
+        let data = vec![
+            0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0x14, 0x0, 0x0, 0x0, 0x0, 0x0, 0x8e, 0x96, 0x5, 0x0,
+            0x14, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xdf, 0x93, 0x5, 0x0,
+        ];
+        let schema = DynType::Struct(vec![DynField::new(
+            "version",
+            DynType::Seq(Box::new(DynType::Struct(vec![
+                DynField::new("index", DynType::I32),
+                DynField::new("value", DynType::I32),
+            ]))),
+        )]);
+
+        let typed: CCheckVersion = from_vec(data.clone())?;
+        let dynamic = from_vec_dynamic(data, &schema)?;
+
+        let expected = DynValue::Struct(vec![(
+            "version".to_string(),
+            DynValue::Seq(
+                typed
+                    .version
+                    .iter()
+                    .map(|entry| {
+                        DynValue::Struct(vec![
+                            ("index".to_string(), DynValue::Int(entry.index as i64)),
+                            ("value".to_string(), DynValue::Int(entry.value as i64)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        )]);
+        assert_eq!(dynamic, expected);
+        Ok(())
+    }
+}
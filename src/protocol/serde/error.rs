@@ -1,6 +1,6 @@
 /// Custom errors used by the TERA network (de-)serializer.
 use serde::{de, ser};
-use std::fmt::Display;
+use std::fmt::{Display, Write};
 use thiserror::Error;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -31,14 +31,34 @@ pub enum Error {
     #[error("StringNotNullTerminated. Pos: {0}")]
     StringNotNullTerminated(usize),
 
-    #[error("InvalidSeqEntry. Pos: {0}")]
-    InvalidSeqEntry(usize),
+    #[error("InvalidStringEncoding. Pos: {0}")]
+    InvalidStringEncoding(usize),
+
+    #[error("StringTooLong. Pos: {0}")]
+    StringTooLong(usize),
+
+    #[error("UnencodableString. Value: {0:?}")]
+    UnencodableString(String),
+
+    /// The array entry with the index isn't at the offset the previous entry linked it at.
+    #[error("InvalidSeqEntry. Index: {0} Expected: {1} Actual: {2}")]
+    InvalidSeqEntry(usize, usize, usize),
+
+    /// A sequence or map has more entries than the u16 count of the protocol can hold.
+    #[error("TooManyEntries. Len: {0}")]
+    TooManyEntries(usize),
+
+    /// A map has a different number of entries than it announced.
+    #[error("MapLengthMismatch. Announced: {0} Actual: {1}")]
+    MapLengthMismatch(usize, usize),
 
     #[error("InvalidTagEncoding. Tag: {0} Pos: {1}")]
     InvalidTagEncoding(u8, usize),
 
-    #[error("DeserializeMapNotSupported. Pos: {0}")]
-    DeserializeMapNotSupported(usize),
+    /// The discriminant doesn't name a variant of the enum. An enum with a narrower discriminant
+    /// than u32 has to be wrapped in a `NarrowEnum`.
+    #[error("InvalidEnumDiscriminant. Enum: {0} Value: {1} Pos: {2}")]
+    InvalidEnumDiscriminant(&'static str, u32, usize),
 
     #[error("DeserializeIdentifierNotSupported. Pos: {0}")]
     DeserializeIdentifierNotSupported(usize),
@@ -55,10 +75,104 @@ pub enum Error {
     #[error("BytesTooBig. Pos: {0}")]
     BytesTooBig(usize),
 
+    #[error("UnexpectedEof. Struct: {0} Pos: {1}")]
+    UnexpectedEof(&'static str, usize),
+
+    #[error("PacketTooLarge. Len: {0}")]
+    PacketTooLarge(usize),
+
+    #[error("ChecksumMismatch. Expected: {0:#010x} Actual: {1:#010x}")]
+    ChecksumMismatch(u32, u32),
+
     #[error("serde error: {0}")]
     Serde(#[from] serde_yaml::Error),
 }
 
+/// Number of bytes shown before and after the failing position in a hex context.
+const HEX_CONTEXT_RADIUS: usize = 16;
+
+impl Error {
+    /// Returns the position inside the data at which the error occurred, if known.
+    pub fn pos(&self) -> Option<usize> {
+        match *self {
+            Error::DeserializeAnyNotSupported(pos)
+            | Error::DeserializeBytesNotSupported(pos)
+            | Error::InvalidBoolEncoding(_, pos)
+            | Error::InvalidCharEncoding(pos)
+            | Error::DeserializeCharNotSupported(pos)
+            | Error::DeserializeOptionNotSupported(pos)
+            | Error::StringNotNullTerminated(pos)
+            | Error::InvalidStringEncoding(pos)
+            | Error::StringTooLong(pos)
+            | Error::InvalidSeqEntry(_, pos, _)
+            | Error::InvalidTagEncoding(_, pos)
+            | Error::InvalidEnumDiscriminant(_, _, pos)
+            | Error::DeserializeIdentifierNotSupported(pos)
+            | Error::DeserializeIgnoredAnyNotSupported(pos)
+            | Error::OffsetOutsideData(pos, _)
+            | Error::BytesTooBig(pos)
+            | Error::UnexpectedEof(_, pos) => Some(pos),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the kind of the error. Used to count the errors by kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Custom(..) => "Custom",
+            Error::DeserializeAnyNotSupported(..) => "DeserializeAnyNotSupported",
+            Error::DeserializeBytesNotSupported(..) => "DeserializeBytesNotSupported",
+            Error::InvalidBoolEncoding(..) => "InvalidBoolEncoding",
+            Error::InvalidCharEncoding(..) => "InvalidCharEncoding",
+            Error::DeserializeCharNotSupported(..) => "DeserializeCharNotSupported",
+            Error::DeserializeOptionNotSupported(..) => "DeserializeOptionNotSupported",
+            Error::StringNotNullTerminated(..) => "StringNotNullTerminated",
+            Error::InvalidStringEncoding(..) => "InvalidStringEncoding",
+            Error::StringTooLong(..) => "StringTooLong",
+            Error::UnencodableString(..) => "UnencodableString",
+            Error::InvalidSeqEntry(..) => "InvalidSeqEntry",
+            Error::TooManyEntries(..) => "TooManyEntries",
+            Error::MapLengthMismatch(..) => "MapLengthMismatch",
+            Error::InvalidTagEncoding(..) => "InvalidTagEncoding",
+            Error::InvalidEnumDiscriminant(..) => "InvalidEnumDiscriminant",
+            Error::DeserializeIdentifierNotSupported(..) => "DeserializeIdentifierNotSupported",
+            Error::DeserializeIgnoredAnyNotSupported(..) => "DeserializeIgnoredAnyNotSupported",
+            Error::OffsetOutsideData(..) => "OffsetOutsideData",
+            Error::NotImplemented() => "NotImplemented",
+            Error::BytesTooBig(..) => "BytesTooBig",
+            Error::UnexpectedEof(..) => "UnexpectedEof",
+            Error::PacketTooLarge(..) => "PacketTooLarge",
+            Error::ChecksumMismatch(..) => "ChecksumMismatch",
+            Error::Serde(..) => "Serde",
+        }
+    }
+
+    /// Renders a hex dump of the data surrounding the failing position. The byte at the
+    /// position is put into brackets. Only computed on demand, since it's meant for debugging.
+    pub fn context_hex(&self, data: &[u8]) -> Option<String> {
+        let pos = self.pos()?;
+        let start = pos.saturating_sub(HEX_CONTEXT_RADIUS).min(data.len());
+        let end = pos
+            .saturating_add(HEX_CONTEXT_RADIUS + 1)
+            .min(data.len())
+            .max(start);
+
+        let mut context = format!("{:04x}:", start);
+        for (i, byte) in data[start..end].iter().enumerate() {
+            if start + i == pos {
+                write!(context, " [{:02x}]", byte).ok()?;
+            } else {
+                write!(context, " {:02x}", byte).ok()?;
+            }
+        }
+        // Mark a position directly behind the data (for example an unexpected EOF).
+        if pos >= data.len() {
+            context.push_str(" [..]");
+        }
+        Some(context)
+    }
+}
+
 impl de::Error for Error {
     fn custom<T: Display>(desc: T) -> Error {
         Error::Custom(desc.to_string())
@@ -70,3 +184,45 @@ impl ser::Error for Error {
         Error::Custom(msg.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_hex_window() {
+        let data: Vec<u8> = (0..64).collect();
+        let err = Error::InvalidBoolEncoding(0x20, 32);
+
+        let context = err.context_hex(&data).unwrap();
+        let expected = format!(
+            "0010: {} [20] {}",
+            (16..32)
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" "),
+            (33..49)
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        assert_eq!(context, expected);
+    }
+
+    #[test]
+    fn test_context_hex_clamped() {
+        let data = vec![0xaa, 0xbb, 0xcc];
+
+        let err = Error::StringNotNullTerminated(1);
+        assert_eq!(err.context_hex(&data).unwrap(), "0000: aa [bb] cc");
+
+        let err = Error::UnexpectedEof("Test", 3);
+        assert_eq!(err.context_hex(&data).unwrap(), "0000: aa bb cc [..]");
+    }
+
+    #[test]
+    fn test_context_hex_without_pos() {
+        let err = Error::PacketTooLarge(100);
+        assert!(err.context_hex(&[0x00]).is_none());
+    }
+}
@@ -0,0 +1,87 @@
+/// Pool of re-usable deserializers.
+use super::de::Deserializer;
+
+/// Maximal number of idle deserializers a pool keeps.
+const MAX_IDLE_DESERIALIZERS: usize = 4;
+
+/// Hands out re-usable deserializers. The buffer of a released deserializer keeps it's
+/// capacity, so that reading a packet doesn't need to allocate a new buffer each time.
+#[derive(Debug, Default)]
+pub struct DeserializerPool {
+    idle: Vec<Deserializer>,
+}
+
+impl DeserializerPool {
+    /// Returns a deserializer with a zeroed buffer of the given length.
+    pub fn acquire(&mut self, len: usize) -> Deserializer {
+        let mut deserializer = self
+            .idle
+            .pop()
+            .unwrap_or_else(|| Deserializer::from_vec(Vec::new()));
+        deserializer.reset(len);
+        deserializer
+    }
+
+    /// Returns a deserializer back into the pool.
+    pub fn release(&mut self, deserializer: Deserializer) {
+        if self.idle.len() < MAX_IDLE_DESERIALIZERS {
+            self.idle.push(deserializer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::packet::{CCheckVersion, CSetVisibleRange};
+    use crate::protocol::serde::{from_vec, Result};
+    use serde::Deserialize;
+
+    const CHECK_VERSION_DATA: [u8; 28] = [
+        0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0x14, 0x0, 0x0, 0x0, 0x0, 0x0, 0x8e, 0x96, 0x5, 0x0, 0x14,
+        0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xdf, 0x93, 0x5, 0x0,
+    ];
+    const SET_VISIBLE_RANGE_DATA: [u8; 4] = [0x40, 0x1f, 0x0, 0x0];
+
+    fn decode_pooled<'a, T: Deserialize<'a>>(
+        pool: &mut DeserializerPool,
+        data: &[u8],
+    ) -> Result<T> {
+        let mut deserializer = pool.acquire(data.len());
+        deserializer.buffer_mut().copy_from_slice(data);
+        let value = T::deserialize(&mut deserializer);
+        pool.release(deserializer);
+        value
+    }
+
+    #[test]
+    fn test_pooled_deserializer_matches_fresh() -> Result<()> {
+        let mut pool = DeserializerPool::default();
+
+        // Alternate between a big and a small packet, so that stale state of the previous
+        // packet would show up in the result.
+        for _ in 0..3 {
+            let pooled: CCheckVersion = decode_pooled(&mut pool, &CHECK_VERSION_DATA)?;
+            let fresh: CCheckVersion = from_vec(CHECK_VERSION_DATA.to_vec())?;
+            assert_eq!(pooled, fresh);
+
+            let pooled: CSetVisibleRange = decode_pooled(&mut pool, &SET_VISIBLE_RANGE_DATA)?;
+            let fresh: CSetVisibleRange = from_vec(SET_VISIBLE_RANGE_DATA.to_vec())?;
+            assert_eq!(pooled, fresh);
+        }
+        assert_eq!(pool.idle.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_acquired_buffer_is_zeroed() {
+        let mut pool = DeserializerPool::default();
+        let mut deserializer = pool.acquire(4);
+        deserializer.buffer_mut().copy_from_slice(&[1, 2, 3, 4]);
+        pool.release(deserializer);
+
+        let mut deserializer = pool.acquire(2);
+        assert_eq!(deserializer.buffer_mut(), &[0, 0]);
+    }
+}
@@ -3,12 +3,55 @@ use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use serde::{ser, Serialize};
 use std::collections::HashMap;
 
+use super::de::MAX_STRING_LENGTH;
+use super::types::{
+    ChecksumAlgorithm, DiscriminantWidth, SchemaVersion, BOXED_NAME, MAYBE_MISSING_NAME,
+    SINCE_VERSION_NAME,
+};
 use super::{Error, Result};
+use crate::protocol::framing;
 
 #[derive(Debug, Clone)]
 pub struct Serializer {
     current_node: usize,
     nodes: HashMap<usize, DataNode>,
+    // Set if the packet is followed by a checksum.
+    checksum: Option<ChecksumAlgorithm>,
+    // Maps that are currently serialized. Maps can be nested, so they form a stack.
+    maps: Vec<MapState>,
+    // Width of the discriminant of the next enum. Set by a `NarrowEnum`.
+    enum_width: Option<DiscriminantWidth>,
+    // Schema version of the packet layouts. Without a version the newest layout is used.
+    schema_version: Option<SchemaVersion>,
+    // State of the `SinceVersion` field that is currently written.
+    since_version: Option<SinceVersionState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SinceVersionState {
+    // The next element is the schema version that added the field.
+    Bound,
+    // The next element is the field, which is not part of the layout.
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+struct MapState {
+    // Data node of the entries. Not set for an empty map, which has no data node.
+    node: Option<usize>,
+    // Number of entries the map announced. It's written before the entries.
+    length: usize,
+    entries: Vec<MapEntry>,
+}
+
+#[derive(Debug, Clone)]
+struct MapEntry {
+    // The entries are sorted by the serialized key, so that the output of a HashMap is stable.
+    sort_key: Vec<u8>,
+    // Position of the entry header in the data of the map node.
+    start: usize,
+    // Index of the first child node of the entry in the map node.
+    first_child: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -25,6 +68,7 @@ struct DataNode {
 enum DataNodeType {
     Root,
     Array,
+    Boxed,
     Bytes,
     String,
 }
@@ -75,6 +119,47 @@ impl Serializer {
         }
         node.data
     }
+
+    /// Sorts the entries of a map node by their serialized keys. The data and the child nodes of
+    /// an entry are moved together, so the offsets are still written correctly.
+    fn sort_map_entries(&mut self, num_node: usize, entries: Vec<MapEntry>) {
+        let node = self.nodes.get_mut(&num_node).unwrap();
+
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by(|a, b| entries[*a].sort_key.cmp(&entries[*b].sort_key));
+        if order.iter().enumerate().all(|(i, j)| i == *j) {
+            return;
+        }
+
+        let mut data = Vec::with_capacity(node.data.len());
+        let mut childs = Vec::with_capacity(node.childs.len());
+        let mut array_offsets = Vec::with_capacity(entries.len());
+        let mut moved_childs = Vec::with_capacity(node.childs.len());
+        for i in order {
+            let entry = &entries[i];
+            let (end, child_end) = entries
+                .get(i + 1)
+                .map_or((node.data.len(), node.childs.len()), |next| {
+                    (next.start, next.first_child)
+                });
+
+            let new_start = data.len();
+            data.extend_from_slice(&node.data[entry.start..end]);
+            array_offsets.push(new_start);
+            for child in &node.childs[entry.first_child..child_end] {
+                childs.push(*child);
+                moved_childs.push((*child, entry.start, new_start));
+            }
+        }
+        node.data = data;
+        node.childs = childs;
+        node.array_offsets = array_offsets;
+
+        for (child, start, new_start) in moved_childs {
+            let child = self.nodes.get_mut(&child).unwrap();
+            child.parent_offset = child.parent_offset - start + new_start;
+        }
+    }
 }
 
 /// Serializes the given structure into a `Vec<u8>` byte stream for the TERA network protocol.
@@ -82,24 +167,110 @@ pub fn to_vec<T>(value: T) -> Result<Vec<u8>>
 where
     T: Serialize,
 {
+    to_vec_with_max_length(value, framing::MAX_BODY_LENGTH)
+}
+
+/// Serializes the given structure and fails if the packet body is bigger than `max_length`.
+pub fn to_vec_with_max_length<T>(value: T, max_length: usize) -> Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    let mut data = Vec::with_capacity(1024); // TODO benchmark me
+    serialize_into(value, &mut data, max_length, None)?;
+    Ok(data)
+}
+
+/// Serializes the given structure into the given buffer. The buffer is cleared first and keeps
+/// it's capacity, so one buffer can be reused for many packets. The buffer is left empty if the
+/// serialization fails.
+pub fn to_vec_into<T>(value: T, buf: &mut Vec<u8>) -> Result<()>
+where
+    T: Serialize,
+{
+    serialize_into(value, buf, framing::MAX_BODY_LENGTH, None)
+}
+
+/// Serializes the given structure into the given buffer like `to_vec_into`, with the packet layout
+/// of the given schema version. `SinceVersion` fields that the schema version doesn't know are not
+/// written. Without a version the newest layout is used.
+pub fn to_vec_into_with_schema_version<T>(
+    value: T,
+    buf: &mut Vec<u8>,
+    schema_version: Option<SchemaVersion>,
+) -> Result<()>
+where
+    T: Serialize,
+{
+    serialize_into(value, buf, framing::MAX_BODY_LENGTH, schema_version)
+}
+
+fn serialize_into<T>(
+    value: T,
+    buf: &mut Vec<u8>,
+    max_length: usize,
+    schema_version: Option<SchemaVersion>,
+) -> Result<()>
+where
+    T: Serialize,
+{
+    // The root node writes into the buffer and the child nodes are appended to it.
+    let mut data = std::mem::take(buf);
+    data.clear();
     let root_node = DataNode {
         node_type: DataNodeType::Root,
         parent: 0,
         childs: Vec::with_capacity(0),
         array_offsets: Vec::with_capacity(0),
-        data: Vec::with_capacity(1024), // TODO benchmark me
+        data,
         parent_offset: 0,
     };
 
     let mut serializer = Serializer {
         current_node: 0,
         nodes: HashMap::new(),
+        checksum: None,
+        maps: Vec::new(),
+        enum_width: None,
+        schema_version,
+        since_version: None,
     };
     serializer.nodes.insert(0, root_node);
     value.serialize(&mut serializer)?;
 
     // Recursively assemble the data
-    Ok(serializer.assemble_node(0, 4)) // 4 bytes packet header
+    let mut data = serializer.assemble_node(0, framing::HEADER_LENGTH);
+    if let Some(algorithm) = serializer.checksum {
+        let checksum = algorithm.checksum(&data);
+        data.write_u32::<LittleEndian>(checksum).unwrap();
+    }
+    if data.len() > max_length {
+        return Err(Error::PacketTooLarge(data.len()));
+    }
+    *buf = data;
+    Ok(())
+}
+
+/// Serializes the given structure and pads the packet body with zero bytes after the real content
+/// until it's at least `min_length` long. The frame length is derived from the body, so it covers
+/// the padding.
+pub fn to_vec_with_min_length<T>(value: T, min_length: usize) -> Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    let mut data = to_vec(value)?;
+    pad_to_min_length(&mut data, min_length)?;
+    Ok(data)
+}
+
+/// Pads the given packet body with zero bytes until it's at least `min_length` long.
+pub fn pad_to_min_length(data: &mut Vec<u8>, min_length: usize) -> Result<()> {
+    if data.len() < min_length {
+        if min_length > framing::MAX_BODY_LENGTH {
+            return Err(Error::PacketTooLarge(min_length));
+        }
+        data.resize(min_length, 0);
+    }
+    Ok(())
 }
 
 macro_rules! impl_nums {
@@ -176,9 +347,22 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         let nodes = &mut self.nodes;
         let parent_node = nodes.get_mut(&self.current_node).unwrap();
 
+        // The deserializer refuses longer strings. Checked before the buffers for the encoding
+        // are allocated.
+        if value.chars().count() > MAX_STRING_LENGTH {
+            return Err(Error::StringTooLong(parent_node.data.len()));
+        }
+
+        // A null character would terminate the string early and code points outside of the BMP
+        // have no UCS2 representation.
+        if value.contains('\0') {
+            return Err(Error::UnencodableString(value.to_string()));
+        }
+
         // Convert UTF-8 to UCS2
         let mut aligned = vec![0; value.len() * 3];
-        let len = ucs2::encode(value, aligned.as_mut_slice()).unwrap();
+        let len = ucs2::encode(value, aligned.as_mut_slice())
+            .map_err(|_| Error::UnencodableString(value.to_string()))?;
         let mut buffer = vec![0; len * 2];
         LittleEndian::write_u16_into(&aligned[..len], &mut buffer);
 
@@ -248,7 +432,11 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok(())
     }
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+    fn serialize_unit_struct(self, name: &'static str) -> Result<()> {
+        // A missing trailing field is not written at all.
+        if name == MAYBE_MISSING_NAME {
+            return Ok(());
+        }
         Err(Error::NotImplemented())
     }
 
@@ -259,8 +447,12 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         variant_index: u32,
         _variant: &'static str,
     ) -> Result<()> {
-        let mut buf = vec![0; 4];
-        LittleEndian::write_u32(&mut buf, variant_index);
+        let mut buf = Vec::with_capacity(4);
+        match self.enum_width.take().unwrap_or(DiscriminantWidth::U32) {
+            DiscriminantWidth::U8 => buf.write_u8(variant_index as u8).unwrap(),
+            DiscriminantWidth::U16 => buf.write_u16::<LittleEndian>(variant_index as u16).unwrap(),
+            DiscriminantWidth::U32 => buf.write_u32::<LittleEndian>(variant_index).unwrap(),
+        }
         self.nodes
             .get_mut(&self.current_node)
             .unwrap()
@@ -269,11 +461,57 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok(())
     }
 
-    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(self)
+        if let Some(algorithm) = ChecksumAlgorithm::from_newtype_name(name) {
+            // The checksum is appended once the whole packet is assembled.
+            self.checksum = Some(algorithm);
+            return value.serialize(self);
+        }
+        if let Some(width) = DiscriminantWidth::from_newtype_name(name) {
+            self.enum_width = Some(width);
+            let result = value.serialize(&mut *self);
+            self.enum_width = None;
+            return result;
+        }
+        if name == SINCE_VERSION_NAME {
+            // The value is a tuple of the schema version that added the field and the field.
+            self.since_version = Some(SinceVersionState::Bound);
+            let result = value.serialize(&mut *self);
+            self.since_version = None;
+            return result;
+        }
+        if name != BOXED_NAME {
+            return value.serialize(self);
+        }
+
+        let num_node = self.nodes.len();
+        let parent = self.current_node;
+        let nodes = &mut self.nodes;
+        let parent_node = nodes.get_mut(&parent).unwrap();
+
+        // Add new data node, link parent and register as child in parent.
+        let new_node = DataNode {
+            node_type: DataNodeType::Boxed,
+            parent,
+            childs: Vec::new(),
+            array_offsets: Vec::with_capacity(0),
+            data: Vec::new(),
+            parent_offset: parent_node.data.len(),
+        };
+        parent_node.childs.push(num_node);
+
+        // Write u16 offset as dummy in parent data buffer
+        parent_node.data.write_u16::<LittleEndian>(0xfefe).unwrap();
+
+        // Write the boxed value into the new data node.
+        self.nodes.insert(num_node, new_node);
+        self.current_node = num_node;
+        let result = value.serialize(&mut *self);
+        self.current_node = parent;
+        result
     }
 
     fn serialize_newtype_variant<T>(
@@ -298,6 +536,9 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         // Don't know why len is an optional...
         if len != Some(0) && len != None {
             let length = len.unwrap();
+            if length > std::u16::MAX as usize {
+                return Err(Error::TooManyEntries(length));
+            }
 
             // Add new data node, link parent and register as child in parent.
             let new_node = DataNode {
@@ -352,8 +593,53 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Err(Error::NotImplemented())
     }
 
-    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        Err(Error::NotImplemented())
+    // Maps are written like arrays. Every entry holds the key followed by the value.
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+        // The count is written before the entries, so it needs to be known upfront.
+        let length = len.ok_or_else(Error::NotImplemented)?;
+        if length > std::u16::MAX as usize {
+            return Err(Error::TooManyEntries(length));
+        }
+        let num_node = self.nodes.len();
+        let parent_node = self.nodes.get_mut(&self.current_node).unwrap();
+
+        if length == 0 {
+            // Both count and offset are 0
+            parent_node.data.write_u32::<LittleEndian>(0x0).unwrap();
+            self.maps.push(MapState {
+                node: None,
+                length,
+                entries: Vec::new(),
+            });
+            return Ok(self);
+        }
+
+        // Add new data node, link parent and register as child in parent.
+        let new_node = DataNode {
+            node_type: DataNodeType::Array,
+            parent: self.current_node,
+            childs: Vec::new(),
+            array_offsets: Vec::with_capacity(length),
+            data: Vec::new(),
+            parent_offset: parent_node.data.len() + 2,
+        };
+        parent_node.childs.push(num_node);
+
+        // Write u16 count and u16 offset as dummy in parent data buffer
+        parent_node
+            .data
+            .write_u16::<LittleEndian>(length as u16)
+            .unwrap();
+        parent_node.data.write_u16::<LittleEndian>(0xfefe).unwrap();
+
+        self.nodes.insert(num_node, new_node);
+        self.maps.push(MapState {
+            node: Some(num_node),
+            length,
+            entries: Vec::with_capacity(length),
+        });
+        self.current_node = num_node;
+        Ok(self)
     }
 
     fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
@@ -416,7 +702,25 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        match self.since_version.take() {
+            Some(SinceVersionState::Bound) => {
+                // The schema version that added the field is not part of the packet. It's read
+                // back and removed again.
+                let start = self.nodes.get(&self.current_node).unwrap().data.len();
+                value.serialize(&mut **self)?;
+                let data = &mut self.nodes.get_mut(&self.current_node).unwrap().data;
+                let since = LittleEndian::read_u32(&data[start..]);
+                data.truncate(start);
+                if let Some(schema_version) = self.schema_version {
+                    if schema_version.0 < since {
+                        self.since_version = Some(SinceVersionState::Skipped);
+                    }
+                }
+                Ok(())
+            }
+            Some(SinceVersionState::Skipped) => Ok(()),
+            None => value.serialize(&mut **self),
+        }
     }
 
     fn end(self) -> Result<()> {
@@ -460,22 +764,51 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_key<T>(&mut self, _key: &T) -> Result<()>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        Err(Error::NotImplemented())
+        let sort_key = to_vec(key)?;
+        let node = self.nodes.get_mut(&self.current_node).unwrap();
+        let state = self.maps.last_mut().unwrap();
+        if state.entries.len() >= state.length {
+            return Err(Error::MapLengthMismatch(
+                state.length,
+                state.entries.len() + 1,
+            ));
+        }
+        state.entries.push(MapEntry {
+            sort_key,
+            start: node.data.len(),
+            first_child: node.childs.len(),
+        });
+        node.array_offsets.push(node.data.len());
+
+        // Write u16 current element offset as dummy
+        node.data.write_u16::<LittleEndian>(0xfefe).unwrap();
+        // Write u16 next element offset as dummy
+        node.data.write_u16::<LittleEndian>(0x0).unwrap();
+
+        key.serialize(&mut **self)
     }
 
-    fn serialize_value<T>(&mut self, _value: &T) -> Result<()>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        Err(Error::NotImplemented())
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<()> {
-        Err(Error::NotImplemented())
+        let state = self.maps.pop().unwrap();
+        if state.entries.len() != state.length {
+            return Err(Error::MapLengthMismatch(state.length, state.entries.len()));
+        }
+        if let Some(num_node) = state.node {
+            self.sort_map_entries(num_node, state.entries);
+            self.current_node = self.nodes.get(&num_node).unwrap().parent;
+        }
+        Ok(())
     }
 }
 
@@ -542,4 +875,170 @@ mod tests {
         assert_eq!(vec, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_unencodable_string() {
+        for value in &["A\0B", "\u{1F600}"] {
+            match to_vec(value.to_string()) {
+                Err(Error::UnencodableString(s)) => assert_eq!(s, *value),
+                v => panic!("Expected an UnencodableString error, got {:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn test_string_too_long() -> Result<()> {
+        let value = "A".repeat(MAX_STRING_LENGTH);
+        assert_eq!(to_vec(&value)?.len(), MAX_STRING_LENGTH * 2 + 4);
+
+        let value = "A".repeat(framing::MAX_BODY_LENGTH);
+        match to_vec(&value) {
+            Err(Error::StringTooLong(pos)) => assert_eq!(pos, 0),
+            v => panic!("Expected a StringTooLong error, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    /// A map that announces a different number of entries than it has.
+    struct MisreportedMap {
+        announced: usize,
+        actual: usize,
+    }
+
+    impl Serialize for MisreportedMap {
+        fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+        where
+            S: ser::Serializer,
+        {
+            use serde::ser::SerializeMap;
+
+            let mut map = serializer.serialize_map(Some(self.announced))?;
+            for i in 0..self.actual {
+                map.serialize_entry(&(i as u32), &(i as u32))?;
+            }
+            map.end()
+        }
+    }
+
+    #[test]
+    fn test_map_length_mismatch() {
+        for (announced, actual) in &[(1, 2), (0, 1), (2, 1)] {
+            let map = MisreportedMap {
+                announced: *announced,
+                actual: *actual,
+            };
+            match to_vec(&map) {
+                Err(Error::MapLengthMismatch(a, b)) => assert_eq!((a, b), (*announced, *actual)),
+                v => panic!("Expected a MapLengthMismatch error, got {:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn test_too_many_entries() {
+        let map = MisreportedMap {
+            announced: std::u16::MAX as usize + 1,
+            actual: 0,
+        };
+        match to_vec(&map) {
+            Err(Error::TooManyEntries(len)) => assert_eq!(len, std::u16::MAX as usize + 1),
+            v => panic!("Expected a TooManyEntries error, got {:?}", v),
+        }
+
+        let seq = vec![0u8; std::u16::MAX as usize + 1];
+        match to_vec(&seq) {
+            Err(Error::TooManyEntries(len)) => assert_eq!(len, std::u16::MAX as usize + 1),
+            v => panic!("Expected a TooManyEntries error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_option_not_supported() {
+        #[derive(Serialize)]
+        struct OptionStruct {
+            a: u8,
+            b: Option<u16>,
+            c: u8,
+        }
+
+        for b in &[Some(2), None] {
+            match to_vec(OptionStruct { a: 1, b: *b, c: 3 }) {
+                Err(Error::NotImplemented()) => { /* Expected result */ }
+                v => panic!("Expected a NotImplemented error, got {:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_too_large() {
+        #[derive(Serialize)]
+        struct ArrayStruct {
+            values: Vec<u32>,
+        }
+
+        let data = ArrayStruct {
+            values: vec![0; 10000],
+        };
+
+        match to_vec(data) {
+            Err(Error::PacketTooLarge(len)) => assert!(len > framing::MAX_BODY_LENGTH),
+            v => panic!("Expected a PacketTooLarge error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_packet_max_length() -> Result<()> {
+        let data = (1u32, 2u32);
+
+        assert_eq!(to_vec_with_max_length(data, 8)?.len(), 8);
+        match to_vec_with_max_length(data, 7) {
+            Err(Error::PacketTooLarge(8)) => { /* Expected result */ }
+            v => panic!("Expected a PacketTooLarge error, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_vec_into() -> Result<()> {
+        #[derive(Serialize)]
+        struct ArrayStruct {
+            name: String,
+            values: Vec<u32>,
+        }
+
+        let value = ArrayStruct {
+            name: "A".to_string(),
+            values: vec![1, 2, 3],
+        };
+
+        let mut buf = vec![0xff; 16];
+        buf.reserve(1024);
+        let capacity = buf.capacity();
+        let ptr = buf.as_ptr();
+
+        to_vec_into(&value, &mut buf)?;
+        assert_eq!(buf, to_vec(&value)?);
+        assert_eq!(buf.capacity(), capacity);
+        assert_eq!(buf.as_ptr(), ptr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_packet_min_length() -> Result<()> {
+        let data = (1u16, 2u8);
+
+        let vec = to_vec_with_min_length(data, 8)?;
+        assert_eq!(vec, vec![0x1, 0x0, 0x2, 0x0, 0x0, 0x0, 0x0, 0x0]);
+        let header = framing::FrameHeader::for_body(0x1234, vec.len()).unwrap();
+        assert_eq!(header.length, 8 + framing::HEADER_LENGTH as u16);
+
+        // Bodies that are already long enough stay untouched.
+        assert_eq!(to_vec_with_min_length(data, 2)?, vec![0x1, 0x0, 0x2]);
+
+        match to_vec_with_min_length(data, framing::MAX_BODY_LENGTH + 1) {
+            Err(Error::PacketTooLarge(_)) => { /* Expected result */ }
+            v => panic!("Expected a PacketTooLarge error, got {:?}", v),
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,1315 @@
+/// Special types that packets can use for fields that don't follow the normal encoding.
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+use serde::ser::{self, SerializeTuple};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Name of the newtype struct that marks a `Boxed` value for the (de)serializer.
+pub(crate) const BOXED_NAME: &str = "__AlmeticaBoxed";
+
+/// Name of the newtype struct that marks a `MaybeMissing` field for the (de)serializer.
+pub(crate) const MAYBE_MISSING_NAME: &str = "__AlmeticaMaybeMissing";
+
+/// Name of the newtype struct that marks `TrailingBytes` for the deserializer.
+pub(crate) const TRAILING_BYTES_NAME: &str = "__AlmeticaTrailingBytes";
+
+/// Name of the newtype struct that marks a `SinceVersion` field for the (de)serializer. The
+/// deserializer passes the schema version of the connection in front of the field, the serializer
+/// gets the schema version that added the field in front of it.
+pub(crate) const SINCE_VERSION_NAME: &str = "__AlmeticaSinceVersion";
+
+/// Names of the newtype struct that marks a `Checksummed` packet. The name carries the algorithm,
+/// since the (de)serializer has no access to the type parameter.
+const CRC32_CHECKSUMMED_NAME: &str = "__AlmeticaChecksummedCrc32";
+const CRC32C_CHECKSUMMED_NAME: &str = "__AlmeticaChecksummedCrc32c";
+
+/// Names of the newtype struct that marks a `NarrowEnum`. The name carries the width of the
+/// discriminant.
+const U8_ENUM_NAME: &str = "__AlmeticaEnumU8";
+const U16_ENUM_NAME: &str = "__AlmeticaEnumU16";
+const U32_ENUM_NAME: &str = "__AlmeticaEnumU32";
+
+/// A trailing field that is only sent by newer clients.
+///
+/// If the frame ends before the field, it's decoded as `None`. Since the protocol is positional,
+/// only the last fields of a packet can be `MaybeMissing`. Its value is not written at all if it's
+/// `None`. Plain `Option` fields are not supported.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaybeMissing<T>(pub Option<T>);
+
+impl<'de, T> Deserialize<'de> for MaybeMissing<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct MaybeMissingVisitor<T>(PhantomData<T>);
+
+        impl<'de, T> Visitor<'de> for MaybeMissingVisitor<T>
+        where
+            T: Deserialize<'de>,
+        {
+            type Value = MaybeMissing<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a trailing field")
+            }
+
+            fn visit_none<E>(self) -> std::result::Result<Self::Value, E> {
+                Ok(MaybeMissing(None))
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                T::deserialize(deserializer).map(|value| MaybeMissing(Some(value)))
+            }
+
+            fn visit_newtype_struct<D>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                Option::<T>::deserialize(deserializer).map(MaybeMissing)
+            }
+        }
+
+        deserializer
+            .deserialize_newtype_struct(MAYBE_MISSING_NAME, MaybeMissingVisitor(PhantomData))
+    }
+}
+
+impl<T> Serialize for MaybeMissing<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match &self.0 {
+            Some(value) => serializer.serialize_newtype_struct(MAYBE_MISSING_NAME, value),
+            None => serializer.serialize_unit_struct(MAYBE_MISSING_NAME),
+        }
+    }
+}
+
+/// A field that is only part of the packet if an earlier field of the packet says so, like a
+/// value that follows a boolean flag.
+///
+/// A derived `Deserialize` can't look at the fields it already read, so a packet with a
+/// conditional field implements `Deserialize` by hand: It calls `deserialize_struct` with a
+/// visitor, reads the fields in order with `SeqAccess::next_element` and reads the conditional
+/// field with `next_element_seed(Conditional::present_if(..))`. A field that is not present is
+/// not read at all, so the following fields are read from the same position. The value is not
+/// written if it's `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Conditional<T>(pub Option<T>);
+
+impl<T> Conditional<T> {
+    /// Returns the seed that reads the field if `present` is true and skips it otherwise.
+    pub fn present_if(present: bool) -> ConditionalSeed<T> {
+        ConditionalSeed {
+            present,
+            value: PhantomData,
+        }
+    }
+}
+
+/// Reads a `Conditional` field. Created by `Conditional::present_if`.
+pub struct ConditionalSeed<T> {
+    present: bool,
+    value: PhantomData<T>,
+}
+
+impl<'de, T> DeserializeSeed<'de> for ConditionalSeed<T>
+where
+    T: Deserialize<'de>,
+{
+    type Value = Conditional<T>;
+
+    fn deserialize<D>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if self.present {
+            T::deserialize(deserializer).map(|value| Conditional(Some(value)))
+        } else {
+            Ok(Conditional(None))
+        }
+    }
+}
+
+impl<T> Serialize for Conditional<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // A field that is not present is not written.
+        match &self.0 {
+            Some(value) => value.serialize(serializer),
+            None => serializer.serialize_unit(),
+        }
+    }
+}
+
+/// Protocol version of the packet layouts a client uses. It's the value with index 0 of
+/// `C_CHECK_VERSION`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SchemaVersion(pub u32);
+
+/// Names the schema version that added a `SinceVersion` field. Implement it on a marker type.
+pub trait VersionBound {
+    const SINCE: SchemaVersion;
+}
+
+/// A field that a client patch added to a packet. It's only part of the layout if the schema
+/// version of the connection is at least `V::SINCE`, older clients don't send it at all. Without
+/// a negotiated schema version the newest layout is used. The value is not written if it's `None`
+/// or if the schema version of the connection is older than `V::SINCE`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SinceVersion<T, V> {
+    pub value: Option<T>,
+    version: PhantomData<V>,
+}
+
+impl<T, V> SinceVersion<T, V> {
+    pub fn new(value: Option<T>) -> Self {
+        SinceVersion {
+            value,
+            version: PhantomData,
+        }
+    }
+}
+
+impl<'de, T, V> Deserialize<'de> for SinceVersion<T, V>
+where
+    T: Deserialize<'de>,
+    V: VersionBound,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct SinceVersionVisitor<T, V>(PhantomData<(T, V)>);
+
+        impl<'de, T, V> Visitor<'de> for SinceVersionVisitor<T, V>
+        where
+            T: Deserialize<'de>,
+            V: VersionBound,
+        {
+            type Value = SinceVersion<T, V>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("the schema version of the connection and a field")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let schema_version: u32 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                // Older clients don't send the field at all.
+                if schema_version < V::SINCE.0 {
+                    return Ok(SinceVersion::new(None));
+                }
+                Ok(SinceVersion::new(seq.next_element()?))
+            }
+        }
+
+        deserializer
+            .deserialize_newtype_struct(SINCE_VERSION_NAME, SinceVersionVisitor(PhantomData))
+    }
+}
+
+impl<T, V> Serialize for SinceVersion<T, V>
+where
+    T: Serialize,
+    V: VersionBound,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // A field that is not present is not written.
+        serializer.serialize_newtype_struct(
+            SINCE_VERSION_NAME,
+            &(V::SINCE.0, MaybeMissing(self.value.as_ref())),
+        )
+    }
+}
+
+/// Bytes that are written inline at the position of the field, without an offset / length
+/// indirection. Use it with fixed sized arrays (`InlineBytes<[u8; 16]>`), since the length is
+/// not part of the encoding. Bytes that use `serde_bytes` are written into the data pool.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct InlineBytes<A>(pub A);
+
+/// A struct that is referenced by an offset, like strings and arrays are. The struct itself is
+/// written into the data pool behind the fixed size region of the packet.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Boxed<T>(pub T);
+
+impl<'de, T> Deserialize<'de> for Boxed<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BoxedVisitor<T>(PhantomData<T>);
+
+        impl<'de, T> Visitor<'de> for BoxedVisitor<T>
+        where
+            T: Deserialize<'de>,
+        {
+            type Value = Boxed<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a struct referenced by an offset")
+            }
+
+            fn visit_newtype_struct<D>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                T::deserialize(deserializer).map(Boxed)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(BOXED_NAME, BoxedVisitor(PhantomData))
+    }
+}
+
+impl<T> Serialize for Boxed<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_newtype_struct(BOXED_NAME, &self.0)
+    }
+}
+
+/// An array that is written inline as a u16 count followed by the elements. Unlike normal arrays
+/// it's not a linked list in the data pool, the elements have no offsets.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CountPrefixed<T>(pub Vec<T>);
+
+impl<'de, T> Deserialize<'de> for CountPrefixed<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct CountPrefixedVisitor<T>(PhantomData<T>);
+
+        impl<'de, T> Visitor<'de> for CountPrefixedVisitor<T>
+        where
+            T: Deserialize<'de>,
+        {
+            type Value = CountPrefixed<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a u16 count followed by the elements")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let count: u16 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let mut values = Vec::with_capacity(count as usize);
+                for i in 0..count as usize {
+                    let value = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i + 1, &self))?;
+                    values.push(value);
+                }
+                Ok(CountPrefixed(values))
+            }
+        }
+
+        // The real length is only known after the count was read.
+        deserializer.deserialize_tuple(std::usize::MAX, CountPrefixedVisitor(PhantomData))
+    }
+}
+
+impl<T> Serialize for CountPrefixed<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.0.len() > std::u16::MAX as usize {
+            return Err(ser::Error::custom(format!(
+                "too many elements for a u16 count: {}",
+                self.0.len()
+            )));
+        }
+
+        let mut tuple = serializer.serialize_tuple(self.0.len() + 1)?;
+        tuple.serialize_element(&(self.0.len() as u16))?;
+        for value in &self.0 {
+            tuple.serialize_element(value)?;
+        }
+        tuple.end()
+    }
+}
+
+/// A blob without a length prefix that runs to the end of the packet. It's written inline, so it
+/// has to be the last field of the packet. When decoding it takes the remaining bytes of the fixed
+/// size region, which never reaches into the data pool or behind the frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrailingBytes(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for TrailingBytes {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct TrailingBytesVisitor;
+
+        impl<'de> Visitor<'de> for TrailingBytesVisitor {
+            type Value = TrailingBytes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("the remaining bytes of the packet")
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<Self::Value, E> {
+                Ok(TrailingBytes(v))
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(TRAILING_BYTES_NAME, TrailingBytesVisitor)
+    }
+}
+
+impl Serialize for TrailingBytes {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for b in &self.0 {
+            tuple.serialize_element(b)?;
+        }
+        tuple.end()
+    }
+}
+
+/// How the bits of a `Flags` field without a name are handled while decoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownBits {
+    /// Unknown bits are kept and written back when the field is encoded.
+    Preserve,
+    /// Unknown bits are a decoding error.
+    Reject,
+}
+
+/// Names the flags of a `Flags` field. Implement it on a marker type that also holds the flags as
+/// `u32` constants.
+pub trait FlagSet {
+    /// Name and bit of every known flag.
+    const FLAGS: &'static [(&'static str, u32)];
+    const UNKNOWN_BITS: UnknownBits;
+
+    /// Returns all bits that have a name.
+    fn known_bits() -> u32 {
+        Self::FLAGS.iter().fold(0, |bits, (_, bit)| bits | bit)
+    }
+}
+
+/// Boolean flags that are packed into a u32. `T` names the flags and decides how unknown bits
+/// are decoded.
+pub struct Flags<T> {
+    bits: u32,
+    flags: PhantomData<T>,
+}
+
+impl<T> Flags<T> {
+    /// Creates the flags from their raw bits. Unknown bits are kept.
+    pub fn from_bits(bits: u32) -> Self {
+        Flags {
+            bits,
+            flags: PhantomData,
+        }
+    }
+
+    /// Returns the raw bits.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Returns true if all of the given bits are set.
+    pub fn contains(&self, bits: u32) -> bool {
+        self.bits & bits == bits
+    }
+
+    pub fn insert(&mut self, bits: u32) {
+        self.bits |= bits;
+    }
+
+    pub fn remove(&mut self, bits: u32) {
+        self.bits &= !bits;
+    }
+}
+
+impl<T: FlagSet> Flags<T> {
+    /// Returns the set bits that have no name.
+    pub fn unknown_bits(&self) -> u32 {
+        self.bits & !T::known_bits()
+    }
+}
+
+impl<T> Clone for Flags<T> {
+    fn clone(&self) -> Self {
+        Flags::from_bits(self.bits)
+    }
+}
+
+impl<T> Copy for Flags<T> {}
+
+impl<T> Default for Flags<T> {
+    fn default() -> Self {
+        Flags::from_bits(0)
+    }
+}
+
+impl<T> PartialEq for Flags<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits == other.bits
+    }
+}
+
+impl<T> Eq for Flags<T> {}
+
+impl<T: FlagSet> fmt::Debug for Flags<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = T::FLAGS
+            .iter()
+            .filter(|(_, bit)| *bit != 0 && self.contains(*bit))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        if self.unknown_bits() != 0 || parts.is_empty() {
+            parts.push(format!("{:#x}", self.unknown_bits()));
+        }
+        write!(f, "Flags({})", parts.join(" | "))
+    }
+}
+
+impl<'de, T: FlagSet> Deserialize<'de> for Flags<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bits = u32::deserialize(deserializer)?;
+        if T::UNKNOWN_BITS == UnknownBits::Reject && bits & !T::known_bits() != 0 {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Unsigned(u64::from(bits)),
+                &"only known flags",
+            ));
+        }
+        Ok(Flags::from_bits(bits))
+    }
+}
+
+impl<T> Serialize for Flags<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u32(self.bits)
+    }
+}
+
+/// Algorithms for the checksum of a `Checksummed` packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 (IEEE 802.3), as used by zlib.
+    Crc32,
+    /// CRC-32C (Castagnoli).
+    Crc32c,
+}
+
+impl ChecksumAlgorithm {
+    /// Calculates the checksum of the data.
+    pub fn checksum(self, data: &[u8]) -> u32 {
+        let polynomial = match self {
+            ChecksumAlgorithm::Crc32 => 0xedb8_8320,
+            ChecksumAlgorithm::Crc32c => 0x82f6_3b78,
+        };
+        let mut crc = !0u32;
+        for b in data {
+            crc ^= u32::from(*b);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ polynomial
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    pub(crate) fn newtype_name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32 => CRC32_CHECKSUMMED_NAME,
+            ChecksumAlgorithm::Crc32c => CRC32C_CHECKSUMMED_NAME,
+        }
+    }
+
+    pub(crate) fn from_newtype_name(name: &str) -> Option<Self> {
+        match name {
+            CRC32_CHECKSUMMED_NAME => Some(ChecksumAlgorithm::Crc32),
+            CRC32C_CHECKSUMMED_NAME => Some(ChecksumAlgorithm::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+/// Selects the algorithm of a `Checksummed` packet.
+pub trait Checksum {
+    const ALGORITHM: ChecksumAlgorithm;
+}
+
+/// Marker for a CRC-32 checksum.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Crc32;
+
+impl Checksum for Crc32 {
+    const ALGORITHM: ChecksumAlgorithm = ChecksumAlgorithm::Crc32;
+}
+
+/// Marker for a CRC-32C checksum.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Crc32c;
+
+impl Checksum for Crc32c {
+    const ALGORITHM: ChecksumAlgorithm = ChecksumAlgorithm::Crc32c;
+}
+
+/// A packet that is followed by a u32 checksum over all of it's bytes. The checksum is the last
+/// field of the frame, so this can only wrap the whole packet. Decoding fails with
+/// `Error::ChecksumMismatch` if the checksum doesn't match the data.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checksummed<T, C = Crc32> {
+    pub value: T,
+    checksum: PhantomData<C>,
+}
+
+impl<T, C> Checksummed<T, C> {
+    pub fn new(value: T) -> Self {
+        Checksummed {
+            value,
+            checksum: PhantomData,
+        }
+    }
+}
+
+impl<'de, T, C> Deserialize<'de> for Checksummed<T, C>
+where
+    T: Deserialize<'de>,
+    C: Checksum,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ChecksummedVisitor<T, C>(PhantomData<(T, C)>);
+
+        impl<'de, T, C> Visitor<'de> for ChecksummedVisitor<T, C>
+        where
+            T: Deserialize<'de>,
+        {
+            type Value = Checksummed<T, C>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a packet followed by a checksum")
+            }
+
+            fn visit_newtype_struct<D>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                T::deserialize(deserializer).map(Checksummed::new)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(
+            C::ALGORITHM.newtype_name(),
+            ChecksummedVisitor(PhantomData),
+        )
+    }
+}
+
+impl<T, C> Serialize for Checksummed<T, C>
+where
+    T: Serialize,
+    C: Checksum,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_newtype_struct(C::ALGORITHM.newtype_name(), &self.value)
+    }
+}
+
+/// Width of the discriminant of an enum on the wire.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiscriminantWidth {
+    U8,
+    U16,
+    /// Used by every enum that isn't wrapped in a `NarrowEnum`.
+    U32,
+}
+
+impl DiscriminantWidth {
+    /// Number of variants the discriminant can tell apart.
+    pub fn max_variants(self) -> u64 {
+        match self {
+            DiscriminantWidth::U8 => 1 << 8,
+            DiscriminantWidth::U16 => 1 << 16,
+            DiscriminantWidth::U32 => 1 << 32,
+        }
+    }
+
+    pub(crate) fn newtype_name(self) -> &'static str {
+        match self {
+            DiscriminantWidth::U8 => U8_ENUM_NAME,
+            DiscriminantWidth::U16 => U16_ENUM_NAME,
+            DiscriminantWidth::U32 => U32_ENUM_NAME,
+        }
+    }
+
+    pub(crate) fn from_newtype_name(name: &str) -> Option<Self> {
+        match name {
+            U8_ENUM_NAME => Some(DiscriminantWidth::U8),
+            U16_ENUM_NAME => Some(DiscriminantWidth::U16),
+            U32_ENUM_NAME => Some(DiscriminantWidth::U32),
+            _ => None,
+        }
+    }
+}
+
+/// Declares the width of the discriminant of an enum. Implement it on the enum and use the enum
+/// as a `NarrowEnum` field.
+pub trait EnumWidth {
+    const WIDTH: DiscriminantWidth;
+}
+
+/// An enum whose discriminant is send with `E::WIDTH` instead of the u32 the (de)serializer uses
+/// for plain enums. Decoding a narrow enum without this wrapper reads the following bytes as
+/// part of the discriminant, which usually fails with `Error::InvalidEnumDiscriminant`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NarrowEnum<E>(pub E);
+
+impl<'de, E> Deserialize<'de> for NarrowEnum<E>
+where
+    E: Deserialize<'de> + EnumWidth,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct NarrowEnumVisitor<E>(PhantomData<E>);
+
+        impl<'de, E> Visitor<'de> for NarrowEnumVisitor<E>
+        where
+            E: Deserialize<'de>,
+        {
+            type Value = NarrowEnum<E>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an enum with a narrow discriminant")
+            }
+
+            fn visit_newtype_struct<D>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                E::deserialize(deserializer).map(NarrowEnum)
+            }
+        }
+
+        deserializer
+            .deserialize_newtype_struct(E::WIDTH.newtype_name(), NarrowEnumVisitor(PhantomData))
+    }
+}
+
+impl<E> Serialize for NarrowEnum<E>
+where
+    E: Serialize + EnumWidth,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_newtype_struct(E::WIDTH.newtype_name(), &self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::serde::{
+        from_vec, from_vec_checked, to_vec, to_vec_into_with_schema_version, Error, Result,
+    };
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct TrailingStruct {
+        name: String,
+        a: u32,
+        b: MaybeMissing<u16>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize)]
+    struct ConditionalStruct {
+        has_target: bool,
+        target: Conditional<u32>,
+        count: u16,
+    }
+
+    impl<'de> Deserialize<'de> for ConditionalStruct {
+        fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            struct ConditionalStructVisitor;
+
+            impl<'de> Visitor<'de> for ConditionalStructVisitor {
+                type Value = ConditionalStruct;
+
+                fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                    formatter.write_str("struct ConditionalStruct")
+                }
+
+                fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+                where
+                    A: SeqAccess<'de>,
+                {
+                    let has_target = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                    let target = seq
+                        .next_element_seed(Conditional::present_if(has_target))?
+                        .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                    let count = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                    Ok(ConditionalStruct {
+                        has_target,
+                        target,
+                        count,
+                    })
+                }
+            }
+
+            deserializer.deserialize_struct(
+                "ConditionalStruct",
+                &["has_target", "target", "count"],
+                ConditionalStructVisitor,
+            )
+        }
+    }
+
+    #[test]
+    fn test_conditional_present() -> Result<()> {
+        let data = vec![0x01, 0x2a, 0x00, 0x00, 0x00, 0x03, 0x00];
+        let expected = ConditionalStruct {
+            has_target: true,
+            target: Conditional(Some(42)),
+            count: 3,
+        };
+        assert_eq!(
+            from_vec_checked::<ConditionalStruct>(data.clone())?,
+            expected
+        );
+        assert_eq!(to_vec(expected)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_absent() -> Result<()> {
+        let data = vec![0x00, 0x03, 0x00];
+        let expected = ConditionalStruct {
+            has_target: false,
+            target: Conditional(None),
+            count: 3,
+        };
+        assert_eq!(
+            from_vec_checked::<ConditionalStruct>(data.clone())?,
+            expected
+        );
+        assert_eq!(to_vec(expected)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_maybe_missing_present() -> Result<()> {
+        let data = vec![
+            0x0c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x41, 0x00, 0x00, 0x00,
+        ];
+        let expected = TrailingStruct {
+            name: "A".to_string(),
+            a: 1,
+            b: MaybeMissing(Some(2)),
+        };
+        assert_eq!(from_vec::<TrailingStruct>(data.clone())?, expected);
+        assert_eq!(to_vec(expected)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_maybe_missing_absent() -> Result<()> {
+        let data = vec![0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x41, 0x00, 0x00, 0x00];
+        let expected = TrailingStruct {
+            name: "A".to_string(),
+            a: 1,
+            b: MaybeMissing(None),
+        };
+        assert_eq!(from_vec::<TrailingStruct>(data.clone())?, expected);
+        assert_eq!(to_vec(expected)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_maybe_missing_without_dynamic_data() -> Result<()> {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct PrimitiveStruct {
+            a: u8,
+            b: MaybeMissing<u32>,
+        }
+
+        assert_eq!(
+            from_vec::<PrimitiveStruct>(vec![0x01])?,
+            PrimitiveStruct {
+                a: 1,
+                b: MaybeMissing(None)
+            }
+        );
+        assert_eq!(
+            from_vec::<PrimitiveStruct>(vec![0x01, 0x02, 0x00, 0x00, 0x00])?,
+            PrimitiveStruct {
+                a: 1,
+                b: MaybeMissing(Some(2))
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_bytes() -> Result<()> {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        struct KeyStruct {
+            key: InlineBytes<[u8; 16]>,
+            #[serde(with = "serde_bytes")]
+            pooled: Vec<u8>,
+            a: u8,
+        }
+
+        let value = KeyStruct {
+            key: InlineBytes([
+                0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xa, 0xb, 0xc, 0xd, 0xe, 0xf,
+            ]),
+            pooled: vec![0xaa, 0xbb],
+            a: 0x42,
+        };
+        let data = vec![
+            0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xa, 0xb, 0xc, 0xd, 0xe, 0xf, 0x19,
+            0x0, 0x2, 0x0, 0x42, 0xaa, 0xbb,
+        ];
+
+        assert_eq!(to_vec(value.clone())?, data);
+        assert_eq!(from_vec::<KeyStruct>(data)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_boxed() -> Result<()> {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        struct SubStruct {
+            a: u16,
+            name: String,
+        }
+
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        struct BoxedStruct {
+            b: u8,
+            sub: Boxed<SubStruct>,
+            c: u32,
+        }
+
+        let value = BoxedStruct {
+            b: 0x1,
+            sub: Boxed(SubStruct {
+                a: 0x3,
+                name: "A".to_string(),
+            }),
+            c: 0x2,
+        };
+        let data = vec![
+            0x1, 0xb, 0x0, 0x2, 0x0, 0x0, 0x0, 0x3, 0x0, 0xf, 0x0, 0x41, 0x0, 0x0, 0x0,
+        ];
+
+        assert_eq!(to_vec(value.clone())?, data);
+        assert_eq!(from_vec::<BoxedStruct>(data.clone())?, value);
+        assert_eq!(from_vec_checked::<BoxedStruct>(data)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_prefixed() -> Result<()> {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        struct CountStruct {
+            a: u8,
+            values: CountPrefixed<u32>,
+            b: u16,
+        }
+
+        let value = CountStruct {
+            a: 0x1,
+            values: CountPrefixed(vec![0x1, 0x2, 0x3]),
+            b: 0x2,
+        };
+        let data = vec![
+            0x1, 0x3, 0x0, 0x1, 0x0, 0x0, 0x0, 0x2, 0x0, 0x0, 0x0, 0x3, 0x0, 0x0, 0x0, 0x2, 0x0,
+        ];
+
+        assert_eq!(to_vec(value.clone())?, data);
+        assert_eq!(from_vec::<CountStruct>(data.clone())?, value);
+        assert_eq!(from_vec_checked::<CountStruct>(data)?, value);
+
+        let empty = CountStruct {
+            a: 0x1,
+            values: CountPrefixed(vec![]),
+            b: 0x2,
+        };
+        let data = vec![0x1, 0x0, 0x0, 0x2, 0x0];
+        assert_eq!(to_vec(empty.clone())?, data);
+        assert_eq!(from_vec::<CountStruct>(data)?, empty);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_bytes() -> Result<()> {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        struct PayloadStruct {
+            name: String,
+            a: u16,
+            payload: TrailingBytes,
+        }
+
+        let value = PayloadStruct {
+            name: "A".to_string(),
+            a: 0x2,
+            payload: TrailingBytes(vec![0xaa, 0xbb, 0xcc]),
+        };
+        // The payload ends where the string of the data pool starts.
+        let data = vec![0xb, 0x0, 0x2, 0x0, 0xaa, 0xbb, 0xcc, 0x41, 0x0, 0x0, 0x0];
+
+        assert_eq!(to_vec(value.clone())?, data);
+        assert_eq!(from_vec::<PayloadStruct>(data.clone())?, value);
+        assert_eq!(from_vec_checked::<PayloadStruct>(data)?, value);
+
+        let empty = PayloadStruct {
+            payload: TrailingBytes(vec![]),
+            ..value
+        };
+        let data = to_vec(empty.clone())?;
+        assert_eq!(from_vec::<PayloadStruct>(data)?, empty);
+        Ok(())
+    }
+
+    struct StatusFlags;
+
+    impl StatusFlags {
+        const GM: u32 = 0x1;
+        const FOUNDER: u32 = 0x4;
+    }
+
+    impl FlagSet for StatusFlags {
+        const FLAGS: &'static [(&'static str, u32)] =
+            &[("GM", StatusFlags::GM), ("FOUNDER", StatusFlags::FOUNDER)];
+        const UNKNOWN_BITS: UnknownBits = UnknownBits::Preserve;
+    }
+
+    struct StrictStatusFlags;
+
+    impl FlagSet for StrictStatusFlags {
+        const FLAGS: &'static [(&'static str, u32)] = StatusFlags::FLAGS;
+        const UNKNOWN_BITS: UnknownBits = UnknownBits::Reject;
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct FlagsStruct {
+        a: u8,
+        flags: Flags<StatusFlags>,
+    }
+
+    #[test]
+    fn test_flags() -> Result<()> {
+        let mut flags = Flags::<StatusFlags>::default();
+        flags.insert(StatusFlags::GM | StatusFlags::FOUNDER);
+        let value = FlagsStruct { a: 0x1, flags };
+        let data = vec![0x1, 0x5, 0x0, 0x0, 0x0];
+
+        assert_eq!(to_vec(value.clone())?, data);
+        let decoded = from_vec::<FlagsStruct>(data)?;
+        assert_eq!(decoded, value);
+        assert!(decoded.flags.contains(StatusFlags::GM));
+        assert!(decoded.flags.contains(StatusFlags::FOUNDER));
+        assert_eq!(decoded.flags.unknown_bits(), 0);
+        assert_eq!(format!("{:?}", decoded.flags), "Flags(GM | FOUNDER)");
+
+        flags.remove(StatusFlags::GM);
+        assert!(!flags.contains(StatusFlags::GM));
+        assert_eq!(flags.bits(), StatusFlags::FOUNDER);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flags_unknown_bits() -> Result<()> {
+        let data = vec![0x1, 0x4, 0x0, 0x0, 0x80];
+
+        // Preserved unknown bits are written back.
+        let value = from_vec::<FlagsStruct>(data.clone())?;
+        assert!(value.flags.contains(StatusFlags::FOUNDER));
+        assert_eq!(value.flags.unknown_bits(), 0x8000_0000);
+        assert_eq!(format!("{:?}", value.flags), "Flags(FOUNDER | 0x80000000)");
+        assert_eq!(to_vec(value)?, data);
+
+        match from_vec::<Flags<StrictStatusFlags>>(vec![0x4, 0x0, 0x0, 0x80]) {
+            Err(Error::Custom(_)) => { /* Expected result */ }
+            v => panic!("Expected a custom error, got {:?}", v),
+        }
+        assert_eq!(
+            from_vec::<Flags<StrictStatusFlags>>(vec![0x4, 0x0, 0x0, 0x0])?.bits(),
+            StatusFlags::FOUNDER
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_algorithms() {
+        assert_eq!(ChecksumAlgorithm::Crc32.checksum(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            ChecksumAlgorithm::Crc32c.checksum(b"123456789"),
+            0xe306_9283
+        );
+        assert_eq!(ChecksumAlgorithm::Crc32.checksum(&[]), 0);
+    }
+
+    #[test]
+    fn test_checksummed() -> Result<()> {
+        let value = Checksummed::<TrailingStruct, Crc32c>::new(TrailingStruct {
+            name: "A".to_string(),
+            a: 1,
+            b: MaybeMissing(Some(2)),
+        });
+        let mut data = to_vec(&value)?;
+        let checksum_pos = data.len() - 4;
+        assert_eq!(
+            &data[checksum_pos..],
+            &ChecksumAlgorithm::Crc32c
+                .checksum(&data[..checksum_pos])
+                .to_le_bytes()
+        );
+        assert_eq!(
+            from_vec_checked::<Checksummed<_, Crc32c>>(data.clone())?,
+            value
+        );
+
+        // Trailing fields don't mistake the checksum as their data.
+        let value = Checksummed::<_, Crc32>::new(TrailingStruct {
+            name: "A".to_string(),
+            a: 1,
+            b: MaybeMissing(None),
+        });
+        assert_eq!(from_vec(to_vec(&value)?)?, value);
+
+        data[2] ^= 0xff;
+        match from_vec::<Checksummed<TrailingStruct, Crc32c>>(data) {
+            Err(Error::ChecksumMismatch(..)) => { /* Expected result */ }
+            v => panic!("Expected a ChecksumMismatch error, got {:?}", v),
+        }
+
+        match from_vec::<Checksummed<u8>>(vec![0x1, 0x2, 0x3]) {
+            Err(Error::UnexpectedEof(..)) => { /* Expected result */ }
+            v => panic!("Expected an UnexpectedEof error, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_since_version() -> Result<()> {
+        use crate::protocol::serde::Deserializer;
+
+        #[derive(Clone, Debug, Default, PartialEq)]
+        struct Patch100;
+
+        impl VersionBound for Patch100 {
+            const SINCE: SchemaVersion = SchemaVersion(100);
+        }
+
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        struct VersionedStruct {
+            a: u16,
+            b: SinceVersion<u32, Patch100>,
+            c: u8,
+        }
+
+        let decode = |data: Vec<u8>, schema_version| {
+            let mut deserializer = Deserializer::from_vec(data);
+            deserializer.set_schema_version(schema_version);
+            VersionedStruct::deserialize(&mut deserializer)
+        };
+        let encode = |value: &VersionedStruct, schema_version| -> Result<Vec<u8>> {
+            let mut data = Vec::new();
+            to_vec_into_with_schema_version(value, &mut data, schema_version)?;
+            Ok(data)
+        };
+
+        let new_layout = vec![0x1, 0x0, 0x2, 0x0, 0x0, 0x0, 0x3];
+        let new_value = VersionedStruct {
+            a: 1,
+            b: SinceVersion::new(Some(2)),
+            c: 3,
+        };
+        assert_eq!(
+            decode(new_layout.clone(), Some(SchemaVersion(100)))?,
+            new_value
+        );
+        assert_eq!(decode(new_layout.clone(), None)?, new_value);
+        assert_eq!(to_vec(&new_value)?, new_layout);
+        assert_eq!(encode(&new_value, Some(SchemaVersion(100)))?, new_layout);
+
+        let old_layout = vec![0x1, 0x0, 0x3];
+        let old_value = VersionedStruct {
+            a: 1,
+            b: SinceVersion::new(None),
+            c: 3,
+        };
+        assert_eq!(
+            decode(old_layout.clone(), Some(SchemaVersion(99)))?,
+            old_value
+        );
+        assert_eq!(to_vec(&old_value)?, old_layout);
+
+        // Older clients don't get the field, even if it's set.
+        assert_eq!(encode(&new_value, Some(SchemaVersion(99)))?, old_layout);
+        Ok(())
+    }
+
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+    enum Stance {
+        Standing,
+        Sitting,
+        Lying,
+    }
+
+    impl EnumWidth for Stance {
+        const WIDTH: DiscriminantWidth = DiscriminantWidth::U8;
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct NarrowStruct {
+        stance: NarrowEnum<Stance>,
+        count: u16,
+        flag: u8,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct PlainStruct {
+        stance: Stance,
+        count: u16,
+        flag: u8,
+    }
+
+    #[test]
+    fn test_narrow_enum() -> Result<()> {
+        let data = vec![0x2, 0x5, 0x1, 0xff];
+        let expected = NarrowStruct {
+            stance: NarrowEnum(Stance::Lying),
+            count: 0x105,
+            flag: 0xff,
+        };
+        assert_eq!(from_vec::<NarrowStruct>(data.clone())?, expected);
+        assert_eq!(to_vec(expected)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_narrow_enum_read_as_u32() {
+        // The u32 discriminant swallows the following fields.
+        let data = vec![0x2, 0x5, 0x1, 0xff, 0x0, 0x0, 0x0];
+        match from_vec::<PlainStruct>(data) {
+            Err(Error::InvalidEnumDiscriminant(name, value, pos)) => {
+                assert_eq!(name, "Stance");
+                assert_eq!(value, 0xff01_0502);
+                assert_eq!(pos, 0);
+            }
+            v => panic!("Expected an invalid enum discriminant, got {:?}", v),
+        }
+    }
+
+    static TOO_MANY_VARIANTS: [&str; 257] = ["Variant"; 257];
+
+    /// An enum that declares a discriminant that can't tell all of it's variants apart.
+    #[derive(Debug)]
+    struct TooManyVariants;
+
+    impl<'de> Deserialize<'de> for TooManyVariants {
+        fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer
+                .deserialize_enum("TooManyVariants", &TOO_MANY_VARIANTS, de::IgnoredAny)
+                .map(|_| TooManyVariants)
+        }
+    }
+
+    impl EnumWidth for TooManyVariants {
+        const WIDTH: DiscriminantWidth = DiscriminantWidth::U8;
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "don't fit into a U8 discriminant")]
+    fn test_narrow_enum_width_too_small() {
+        let _ = from_vec::<NarrowEnum<TooManyVariants>>(vec![0x0]);
+    }
+}
@@ -0,0 +1,85 @@
+/// Helpers to create client traffic in tests.
+use crate::protocol::framing::{FrameHeader, HEADER_LENGTH};
+use crate::protocol::opcode::Opcode;
+use crate::protocol::serde::to_vec;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Serializes the packet and returns the whole frame (header and body) as it's send on the wire.
+/// Panics if the opcode has no mapping or the packet can't be serialized.
+pub fn frame_packet(
+    opcode: Opcode,
+    packet: &impl Serialize,
+    reverse_map: &HashMap<Opcode, u16>,
+) -> Vec<u8> {
+    let opcode_value = *reverse_map
+        .get(&opcode)
+        .unwrap_or_else(|| panic!("Can't find opcode {:?} in reverse mapping", opcode));
+    let mut body = to_vec(packet).expect("Can't serialize packet");
+    let header = FrameHeader::for_body(opcode_value, body.len())
+        .expect("Packet body is too big for a frame");
+
+    let mut frame = vec![0u8; HEADER_LENGTH];
+    header.write(&mut frame);
+    frame.append(&mut body);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::message::Message;
+    use crate::protocol::packet::{CCheckVersion, CCheckVersionEntry};
+    use crate::Result;
+    use shipyard::{EntitiesViewMut, World};
+
+    #[test]
+    fn test_frame_packet_round_trip() -> Result<()> {
+        let opcode_table = vec![Opcode::UNKNOWN, Opcode::C_CHECK_VERSION];
+        let mut reverse_map = HashMap::new();
+        reverse_map.insert(Opcode::C_CHECK_VERSION, 1);
+
+        let packet = CCheckVersion {
+            version: vec![
+                CCheckVersionEntry {
+                    index: 0,
+                    value: 366_222,
+                },
+                CCheckVersionEntry {
+                    index: 1,
+                    value: 365_535,
+                },
+            ],
+        };
+        let frame = frame_packet(Opcode::C_CHECK_VERSION, &packet, &reverse_map);
+
+        // Decode the frame like the session does.
+        let header = FrameHeader::read(&frame);
+        assert_eq!(header.length as usize, frame.len());
+        assert_eq!(header.body_length(), frame.len() - HEADER_LENGTH);
+        let opcode = opcode_table[header.opcode as usize];
+        assert_eq!(opcode, Opcode::C_CHECK_VERSION);
+
+        let entity = World::new().borrow::<EntitiesViewMut>().add_entity((), ());
+        let message = Message::new_from_packet(
+            entity,
+            None,
+            None,
+            None,
+            opcode,
+            frame[HEADER_LENGTH..].to_vec(),
+        )?;
+        match message {
+            Message::RequestCheckVersion {
+                connection_global_world_id,
+                packet: decoded,
+            } => {
+                assert_eq!(connection_global_world_id, entity);
+                assert_eq!(decoded, packet);
+            }
+            _ => panic!("Decoded the wrong message"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+/// Validates the packet definitions against a corpus of captured packets.
+///
+/// A capture file (`.bin`) contains decrypted frames (header and body) written back to back.
+/// Every frame is decoded with the packet definition of it's opcode. This gives a quick
+/// regression signal when packet definitions change.
+use crate::ecs::message::Message;
+use crate::protocol::framing::{FrameHeader, HEADER_LENGTH};
+use crate::protocol::opcode::Opcode;
+use crate::{AlmeticaError, Result};
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The result of a validation run.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub successes: usize,
+    /// Frames with an unknown opcode or without a packet definition.
+    pub skipped: usize,
+    pub failures: Vec<ValidationFailure>,
+}
+
+/// A frame that couldn't be decoded.
+#[derive(Debug)]
+pub struct ValidationFailure {
+    pub file: PathBuf,
+    /// Offset of the frame inside the capture file.
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub error: String,
+}
+
+impl ValidationReport {
+    pub fn is_successful(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Validates all `.bin` capture files inside the given folder.
+pub fn validate_corpus(path: &Path, opcode_table: &[Opcode]) -> Result<ValidationReport> {
+    let mut files = fs::read_dir(path)
+        .context(format!("Can't read capture folder {:?}", path))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().map_or(false, |ext| ext == "bin"))
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+
+    let mut report = ValidationReport::default();
+    for file in files {
+        let data = fs::read(&file).context(format!("Can't read capture file {:?}", file))?;
+        validate_capture(&file, &data, opcode_table, &mut report);
+    }
+    Ok(report)
+}
+
+/// Validates all frames of a single capture.
+pub fn validate_capture(
+    file: &Path,
+    data: &[u8],
+    opcode_table: &[Opcode],
+    report: &mut ValidationReport,
+) {
+    let mut offset = 0;
+    while offset < data.len() {
+        if data.len() - offset < HEADER_LENGTH {
+            report.failures.push(ValidationFailure {
+                file: file.to_path_buf(),
+                offset,
+                opcode: Opcode::UNKNOWN,
+                error: "Capture ends inside of a frame header".to_string(),
+            });
+            return;
+        }
+
+        let header = FrameHeader::read(&data[offset..]);
+        let opcode = opcode_table[header.opcode as usize];
+        let frame_end = offset + header.length as usize;
+        if (header.length as usize) < HEADER_LENGTH || frame_end > data.len() {
+            report.failures.push(ValidationFailure {
+                file: file.to_path_buf(),
+                offset,
+                opcode,
+                error: format!("Invalid frame length {}", header.length),
+            });
+            return;
+        }
+
+        let body = data[offset + HEADER_LENGTH..frame_end].to_vec();
+        match Message::validate_packet(opcode, body) {
+            Ok(()) => report.successes += 1,
+            Err(e) => match e.downcast_ref::<AlmeticaError>() {
+                Some(AlmeticaError::NoMessageMappingForPacket) => report.skipped += 1,
+                _ => report.failures.push(ValidationFailure {
+                    file: file.to_path_buf(),
+                    offset,
+                    opcode,
+                    error: format!("{:#}", e),
+                }),
+            },
+        }
+        offset = frame_end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataloader::read_opcode_table;
+
+    fn get_opcode_table() -> Result<Vec<Opcode>> {
+        let mut mapping = "
+        C_CHECK_VERSION: 1
+        S_CHECK_VERSION: 2
+        C_PONG: 3
+        "
+        .as_bytes();
+        read_opcode_table(&mut mapping)
+    }
+
+    fn create_corpus() -> Result<PathBuf> {
+        let path = std::env::temp_dir().join(format!("almetica-captures-{}", std::process::id()));
+        fs::create_dir_all(&path)?;
+
+        // Valid C_CHECK_VERSION followed by a valid S_CHECK_VERSION
+        let mut valid = vec![0x20, 0x0, 0x1, 0x0];
+        valid.extend_from_slice(&[
+            0x2, 0x0, 0x8, 0x0, 0x8, 0x0, 0x14, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1d, 0x8a, 0x5, 0x0,
+            0x14, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xce, 0x7b, 0x5, 0x0,
+        ]);
+        valid.extend_from_slice(&[0x5, 0x0, 0x2, 0x0, 0x1]);
+        fs::write(path.join("01_valid.bin"), valid)?;
+
+        // C_CHECK_VERSION with an array offset outside of the frame and an unmapped opcode
+        let invalid = vec![0x8, 0x0, 0x1, 0x0, 0x2, 0x0, 0x8, 0x0, 0x4, 0x0, 0x99, 0x0];
+        fs::write(path.join("02_invalid.bin"), invalid)?;
+
+        // Not a capture file
+        fs::write(path.join("README.txt"), b"ignored")?;
+
+        Ok(path)
+    }
+
+    #[test]
+    fn test_validate_corpus() -> Result<()> {
+        let path = create_corpus()?;
+        let report = validate_corpus(&path, &get_opcode_table()?)?;
+        fs::remove_dir_all(&path)?;
+
+        assert_eq!(report.successes, 2);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert!(!report.is_successful());
+
+        let failure = &report.failures[0];
+        assert!(failure.file.ends_with("02_invalid.bin"));
+        assert_eq!(failure.offset, 0);
+        assert_eq!(failure.opcode, Opcode::C_CHECK_VERSION);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_truncated_frame() -> Result<()> {
+        let mut report = ValidationReport::default();
+        validate_capture(
+            Path::new("truncated.bin"),
+            &[0x5, 0x0, 0x2, 0x0, 0x1, 0x10, 0x0, 0x2],
+            &get_opcode_table()?,
+            &mut report,
+        );
+
+        assert_eq!(report.successes, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].offset, 5);
+        Ok(())
+    }
+}
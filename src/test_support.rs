@@ -0,0 +1,98 @@
+/// Helpers that are shared by the tests of all modules.
+use crate::ecs::resource::Clock;
+use crate::model::entity::User;
+use crate::model::{Class, Gender, Race};
+use chrono::{TimeZone, Utc};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Clock that only advances when it's told to. Sleeping advances it without blocking. A clone
+/// shares the time with the original.
+#[derive(Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Runs the function with a tracing subscriber of the given level and returns everything it
+/// logged.
+pub fn capture_logs<F: FnOnce()>(level: tracing::Level, f: F) -> String {
+    let log = CapturedLog::default();
+    let writer = log.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, f);
+
+    let output = log.0.lock().unwrap().clone();
+    String::from_utf8(output).unwrap()
+}
+
+/// Log writer for a tracing subscriber that keeps everything that was written in memory.
+#[derive(Clone, Default)]
+struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for CapturedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns a user of the account that isn't stored yet. `num` makes its name and lobby slot
+/// unique.
+pub fn new_user(account_id: i64, num: i32) -> User {
+    User {
+        id: -1,
+        account_id,
+        name: format!("name-{}", num),
+        gender: Gender::Male,
+        race: Race::Human,
+        class: Class::Warrior,
+        shape: vec![],
+        details: vec![],
+        appearance: Default::default(),
+        appearance2: 0,
+        level: 0,
+        awakening_level: 0,
+        laurel: 0,
+        achievement_points: 0,
+        playtime: 0,
+        rest_bonus_xp: 0,
+        show_face: false,
+        show_style: false,
+        lobby_slot: num,
+        is_new_character: false,
+        tutorial_state: 0,
+        is_deleting: false,
+        delete_at: None,
+        last_logout_at: Utc.ymd(2007, 7, 8).and_hms(9, 10, 11),
+        created_at: Utc.ymd(2009, 7, 8).and_hms(9, 10, 11),
+    }
+}
@@ -0,0 +1,84 @@
+/// Checks that too long strings are refused before their buffers are allocated. The allocator
+/// that tracks the allocations is only installed in this test binary.
+use almetica::protocol::framing::MAX_BODY_LENGTH;
+use almetica::protocol::serde::{from_vec, to_vec, Error, MAX_STRING_LENGTH};
+use serde::Deserialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+thread_local! {
+    // Size of the largest allocation of the thread. Tests run on their own thread, so they only
+    // see their own allocations.
+    static LARGEST_ALLOCATION: Cell<usize> = Cell::new(0);
+}
+
+/// Remembers the largest allocation of every thread.
+struct TrackingAllocator;
+
+impl TrackingAllocator {
+    fn track(size: usize) {
+        // The thread local is gone while the thread is torn down.
+        let _ = LARGEST_ALLOCATION.try_with(|largest| largest.set(largest.get().max(size)));
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::track(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::track(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::track(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Returns the size of the largest allocation of the current thread since the last call.
+fn take_largest_allocation() -> usize {
+    LARGEST_ALLOCATION.with(|largest| largest.replace(0))
+}
+
+#[derive(Debug, Deserialize)]
+struct StringStruct {
+    s: String,
+}
+
+#[test]
+fn test_deserialize_string_too_long() {
+    // The scan never reaches the terminator at the end of the packet, so no buffer for the rest
+    // of the packet is allocated.
+    let mut data = vec![0x6, 0x0];
+    data.extend(std::iter::repeat(0x41).take(MAX_BODY_LENGTH - 4));
+    data.extend_from_slice(&[0x0, 0x0]);
+    take_largest_allocation();
+    match from_vec::<StringStruct>(data) {
+        Err(Error::StringTooLong(pos)) => assert_eq!(pos, 2),
+        v => panic!("Expected a StringTooLong error, got {:?}", v),
+    }
+    assert!(take_largest_allocation() < MAX_STRING_LENGTH);
+}
+
+#[test]
+fn test_serialize_string_too_long() {
+    let value = "A".repeat(MAX_BODY_LENGTH);
+    take_largest_allocation();
+    match to_vec(&value) {
+        Err(Error::StringTooLong(pos)) => assert_eq!(pos, 0),
+        v => panic!("Expected a StringTooLong error, got {:?}", v),
+    }
+    // The check runs before the buffers for the encoded string are allocated.
+    assert!(take_largest_allocation() < MAX_STRING_LENGTH);
+}